@@ -0,0 +1,397 @@
+use std::{net::SocketAddr, time::Duration};
+
+/// How [`Balancer::pick`] chooses among the healthy backends.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Each backend in turn.
+    RoundRobin,
+    /// The backend with the fewest open connections, in turn among equals.
+    LeastConnections,
+    /// The backend a key hashes to on a ring of virtual nodes, so a key keeps its backend and
+    /// only the keys of a backend that went away move elsewhere.
+    ConsistentHash(HashKey),
+}
+
+/// What a connection is hashed by under [`Policy::ConsistentHash`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HashKey {
+    /// The address the connection comes from.
+    Client,
+    /// The value of this header in the first HTTP request on the connection; the backend is only
+    /// connected to once that request's head arrived. Connections without it, or that do not
+    /// speak HTTP, are hashed by client address.
+    Header(String),
+}
+
+/// The bytes a client address contributes to a consistent hash.
+pub trait ClientKey {
+    fn client_key(&self) -> Vec<u8>;
+}
+
+impl ClientKey for SocketAddr {
+    /// The IP address alone: a client reconnects from a new port every time.
+    fn client_key(&self) -> Vec<u8> {
+        match self {
+            SocketAddr::V4(addr) => addr.ip().octets().to_vec(),
+            SocketAddr::V6(addr) => addr.ip().octets().to_vec(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl ClientKey for mio::net::SocketAddr {
+    fn client_key(&self) -> Vec<u8> {
+        use std::os::unix::ffi::OsStrExt;
+
+        self.as_pathname()
+            .map(|path| path.as_os_str().as_bytes().to_vec())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug)]
+struct Backend<A> {
+    addr: A,
+    healthy: bool,
+    connections: usize,
+}
+
+/// Backends with their health and load, and the policy that picks one for every connection.
+///
+/// Backends may be remote servers or local single threaded workers, each running a
+/// [`Server`](crate::Server) on a port of its own; [`Proxy::balance`](crate::proxy::Proxy::balance)
+/// fronts them.
+#[derive(Debug)]
+pub struct Balancer<A> {
+    policy: Policy,
+    backends: Vec<Backend<A>>,
+    next: usize,
+    /// Virtual nodes of the hash ring, sorted by position.
+    points: Vec<(u64, usize)>,
+    checks: Option<(Duration, Duration)>,
+}
+
+impl<A> Balancer<A> {
+    /// Positions each backend takes on the hash ring.
+    pub const POINTS: usize = 64;
+
+    pub fn new(policy: Policy, addrs: impl IntoIterator<Item = A>) -> Self {
+        let backends: Vec<Backend<A>> = addrs
+            .into_iter()
+            .map(|addr| Backend {
+                addr,
+                healthy: true,
+                connections: 0,
+            })
+            .collect();
+        let mut points = Vec::new();
+        if let Policy::ConsistentHash(_) = policy {
+            for backend in 0..backends.len() {
+                for point in 0..Self::POINTS {
+                    let node = [(backend as u64).to_le_bytes(), (point as u64).to_le_bytes()];
+                    points.push((hash(&node.concat()), backend));
+                }
+            }
+            points.sort_unstable();
+        }
+        Self {
+            policy,
+            backends,
+            next: 0,
+            points,
+            checks: None,
+        }
+    }
+
+    /// Connect to every backend each `interval`; one that refuses or does not accept within
+    /// `timeout` is out of rotation until it accepts again.
+    pub fn health_checks(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.checks = Some((interval, timeout));
+        self
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    /// The health check interval and timeout, if checks are on.
+    pub fn checks(&self) -> Option<(Duration, Duration)> {
+        self.checks
+    }
+
+    pub fn len(&self) -> usize {
+        self.backends.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.backends.is_empty()
+    }
+
+    pub fn addr(&self, backend: usize) -> &A {
+        &self.backends[backend].addr
+    }
+
+    pub fn is_healthy(&self, backend: usize) -> bool {
+        self.backends[backend].healthy
+    }
+
+    pub fn set_healthy(&mut self, backend: usize, healthy: bool) {
+        self.backends[backend].healthy = healthy;
+    }
+
+    /// Connections currently open to `backend`.
+    pub fn connections(&self, backend: usize) -> usize {
+        self.backends[backend].connections
+    }
+
+    /// A connection to `backend` was opened.
+    pub fn opened(&mut self, backend: usize) {
+        self.backends[backend].connections += 1;
+    }
+
+    /// A connection to `backend` was closed.
+    pub fn closed(&mut self, backend: usize) {
+        let connections = &mut self.backends[backend].connections;
+        *connections = connections.saturating_sub(1);
+    }
+
+    /// The healthy backend, other than those `tried`, a connection hashed by `key` goes to;
+    /// `key` only matters under [`Policy::ConsistentHash`].
+    pub fn pick(&mut self, key: &[u8], tried: &[usize]) -> Option<usize> {
+        let len = self.backends.len();
+        let eligible = |backends: &[Backend<A>], backend: usize| {
+            backends[backend].healthy && !tried.contains(&backend)
+        };
+        let picked = match self.policy {
+            Policy::RoundRobin => (0..len)
+                .map(|i| (self.next + i) % len)
+                .find(|&backend| eligible(&self.backends, backend)),
+            Policy::LeastConnections => (0..len)
+                .map(|i| (self.next + i) % len)
+                .filter(|&backend| eligible(&self.backends, backend))
+                .min_by_key(|&backend| self.backends[backend].connections),
+            Policy::ConsistentHash(_) => {
+                let position = hash(key);
+                let start = self.points.partition_point(|&(point, _)| point < position);
+                (0..self.points.len())
+                    .map(|i| self.points[(start + i) % self.points.len()].1)
+                    .find(|&backend| eligible(&self.backends, backend))
+            }
+        }?;
+        self.next = (picked + 1) % len;
+        Some(picked)
+    }
+}
+
+/// FNV-1a, finished with the SplitMix64 mixer so that similar keys land far apart on the ring.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in bytes {
+        h ^= u64::from(b);
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::SocketAddr,
+        thread,
+        time::Duration,
+    };
+
+    use mio::net::TcpStream;
+
+    use super::{Balancer, HashKey, Policy};
+    use crate::{
+        proxy::Proxy,
+        ring::RingBuffer,
+        tests::{new_loopback_address, Reply, TestServer},
+        Listener, MioEventLoop, ReadWriteConnectorAdapter, Server, Shutdown,
+    };
+
+    struct Balancing;
+
+    impl MioEventLoop for Balancing {}
+    impl ReadWriteConnectorAdapter for Balancing {}
+    impl Proxy<TcpStream, TcpStream> for Balancing {}
+
+    const REPLIES: [&[u8]; 3] = [b"zero", b"one!", b"two!"];
+
+    /// Run a local worker per reply and a balancer in front of them, then `exchange` with the
+    /// balancer's address; returns the requests every worker received.
+    fn run<F>(balancer: &mut Balancer<SocketAddr>, workers: usize, exchange: F) -> Vec<Vec<Vec<u8>>>
+    where
+        F: FnOnce(SocketAddr),
+    {
+        let addr = new_loopback_address();
+        let listener = <Balancing as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+        let mut ring = RingBuffer::new(40);
+        let mut handlers: Vec<Reply> = REPLIES[..workers].iter().map(|r| Reply::new(r)).collect();
+        let mut rings: Vec<RingBuffer> = (0..workers).map(|_| RingBuffer::new(8)).collect();
+
+        thread::scope(|s| {
+            let servers: Vec<_> = handlers
+                .iter_mut()
+                .zip(&mut rings)
+                .enumerate()
+                .map(|(backend, (handler, ring))| {
+                    let addr = *balancer.addr(backend);
+                    let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+                    let shutdown = &shutdown;
+                    s.spawn(move || {
+                        TestServer::serve::<1, _>(listener, 128, ring, handler, shutdown)
+                    })
+                })
+                .collect();
+            let proxy = s.spawn(|| {
+                Balancing::balance::<1>(listener, &mut *balancer, 128, &mut ring, &shutdown)
+            });
+            let exchanged =
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| exchange(addr)));
+            shutdown.signal().unwrap();
+            for server in servers {
+                server.join().unwrap().unwrap();
+            }
+            proxy.join().unwrap().unwrap();
+            if let Err(panic) = exchanged {
+                std::panic::resume_unwind(panic);
+            }
+        });
+
+        assert_eq!(ring.in_use(), 0);
+        handlers
+            .into_iter()
+            .map(|handler| handler.requests)
+            .collect()
+    }
+
+    fn request(addr: SocketAddr, send: &[u8]) -> Vec<u8> {
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.write_all(send).unwrap();
+        let mut received = Vec::new();
+        let _ = client.read_to_end(&mut received);
+        received
+    }
+
+    #[test]
+    fn policies_pick_healthy_backends() {
+        let mut balancer = Balancer::new(Policy::RoundRobin, ["a", "b", "c"]);
+        let picks: Vec<_> = (0..4).map(|_| balancer.pick(b"", &[]).unwrap()).collect();
+        assert_eq!(picks, [0, 1, 2, 0]);
+        balancer.set_healthy(1, false);
+        let picks: Vec<_> = (0..3).map(|_| balancer.pick(b"", &[]).unwrap()).collect();
+        assert_eq!(picks, [2, 0, 2]);
+        assert_eq!(balancer.pick(b"", &[0, 2]), None);
+
+        let mut balancer = Balancer::new(Policy::LeastConnections, ["a", "b", "c"]);
+        for backend in [0, 0, 1] {
+            balancer.opened(backend);
+        }
+        assert_eq!(balancer.pick(b"", &[]), Some(2));
+        balancer.opened(2);
+        balancer.opened(2);
+        assert_eq!(balancer.pick(b"", &[]), Some(1));
+        balancer.closed(0);
+        balancer.closed(0);
+        assert_eq!(
+            (balancer.pick(b"", &[]), balancer.connections(0)),
+            (Some(0), 0)
+        );
+    }
+
+    #[test]
+    fn consistent_hash_moves_only_keys_of_a_lost_backend() {
+        let policy = Policy::ConsistentHash(HashKey::Client);
+        let mut balancer = Balancer::new(policy, 0..5);
+        let keys: Vec<Vec<u8>> = (0..1000u32).map(|key| key.to_le_bytes().to_vec()).collect();
+        let before: Vec<usize> = keys
+            .iter()
+            .map(|key| balancer.pick(key, &[]).unwrap())
+            .collect();
+        for backend in 0..5 {
+            let share = before.iter().filter(|&&b| b == backend).count();
+            assert!(
+                (100..300).contains(&share),
+                "backend {backend} got {share} keys"
+            );
+        }
+        balancer.set_healthy(3, false);
+        let after: Vec<usize> = keys
+            .iter()
+            .map(|key| balancer.pick(key, &[]).unwrap())
+            .collect();
+        for (before, after) in before.iter().zip(&after) {
+            assert!(*after != 3);
+            assert!(before == after || 3 == *before);
+        }
+    }
+
+    #[test]
+    fn health_checks_take_dead_backends_out_of_rotation() {
+        // the last backend has no worker behind it
+        let addrs: Vec<SocketAddr> = (0..3).map(|_| new_loopback_address()).collect();
+        let mut balancer = Balancer::new(Policy::RoundRobin, addrs)
+            .health_checks(Duration::from_millis(20), Duration::from_secs(1));
+        let received = run(&mut balancer, 2, |addr| {
+            thread::sleep(Duration::from_millis(200));
+            let replies: Vec<Vec<u8>> = (0..4).map(|_| request(addr, b"ping")).collect();
+            assert_eq!(replies, [REPLIES[0], REPLIES[1], REPLIES[0], REPLIES[1]]);
+        });
+
+        assert_eq!((received[0].len(), received[1].len()), (2, 2));
+        assert!(balancer.is_healthy(0) && balancer.is_healthy(1) && !balancer.is_healthy(2));
+        assert!((0..3).all(|backend| 0 == balancer.connections(backend)));
+    }
+
+    #[test]
+    fn hashes_by_header() {
+        let addrs: Vec<SocketAddr> = (0..3).map(|_| new_loopback_address()).collect();
+        let policy = Policy::ConsistentHash(HashKey::Header("x-user".into()));
+        let mut balancer = Balancer::new(policy, addrs);
+        let users = ["alice", "bob", "carol", "dave", "erin", "frank"];
+        let received = run(&mut balancer, 3, |addr| {
+            for user in users.iter().chain(&users) {
+                let head = format!("GET / HTTP/1.1\r\nHost: x\r\nX-User: {user}\r\n\r\n");
+                // the head arrives in two parts, so the backend waits for the rest
+                let mut client = std::net::TcpStream::connect(addr).unwrap();
+                client.write_all(&head.as_bytes()[..20]).unwrap();
+                thread::sleep(Duration::from_millis(5));
+                client.write_all(&head.as_bytes()[20..]).unwrap();
+                let mut reply = Vec::new();
+                client.read_to_end(&mut reply).unwrap();
+                assert!(REPLIES.contains(&&reply[..]));
+            }
+        });
+
+        for user in users {
+            let backends: Vec<usize> = received
+                .iter()
+                .enumerate()
+                .flat_map(|(backend, requests)| {
+                    requests
+                        .iter()
+                        .filter(|request| request.ends_with(format!("{user}\r\n\r\n").as_bytes()))
+                        .map(move |_| backend)
+                })
+                .collect();
+            assert_eq!(backends.len(), 2);
+            assert_eq!(backends[0], backends[1], "{user} moved");
+        }
+        assert!(
+            received
+                .iter()
+                .filter(|requests| !requests.is_empty())
+                .count()
+                > 1
+        );
+    }
+}
@@ -0,0 +1,409 @@
+use std::fmt;
+
+use crate::pipeline::{Ratio, Stage};
+
+const STANDARD: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const URL_SAFE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+const INVALID: u8 = 0xff;
+
+const fn decode_table(alphabet: &[u8; 64]) -> [u8; 256] {
+    let mut table = [INVALID; 256];
+    let mut i = 0;
+    while i < 64 {
+        table[alphabet[i] as usize] = i as u8;
+        i += 1;
+    }
+    table
+}
+
+static STANDARD_DECODE: [u8; 256] = decode_table(STANDARD);
+static URL_SAFE_DECODE: [u8; 256] = decode_table(URL_SAFE);
+
+/// The two alphabets of RFC 4648; they differ only in the last two symbols.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Alphabet {
+    /// `+` and `/`.
+    Standard,
+    /// `-` and `_`, safe in URLs and file names.
+    UrlSafe,
+}
+
+impl Alphabet {
+    fn encode_table(self) -> &'static [u8; 64] {
+        match self {
+            Alphabet::Standard => STANDARD,
+            Alphabet::UrlSafe => URL_SAFE,
+        }
+    }
+
+    fn decode_table(self) -> &'static [u8; 256] {
+        match self {
+            Alphabet::Standard => &STANDARD_DECODE,
+            Alphabet::UrlSafe => &URL_SAFE_DECODE,
+        }
+    }
+}
+
+/// Why base64 input was rejected; offsets count from the start of the input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// `byte` is not a symbol of the alphabet.
+    InvalidByte { offset: usize, byte: u8 },
+    /// Padding before the final symbols.
+    InvalidPadding { offset: usize },
+    /// No encoding has this many symbols.
+    InvalidLength(usize),
+    /// The last symbol carries bits that belong to no byte, so the input is not canonical.
+    TrailingBits { offset: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::InvalidByte { offset, byte } => {
+                write!(f, "invalid base64 byte {byte:#04x} at offset {offset}")
+            }
+            DecodeError::InvalidPadding { offset } => {
+                write!(f, "misplaced base64 padding at offset {offset}")
+            }
+            DecodeError::InvalidLength(len) => write!(f, "invalid base64 length {len}"),
+            DecodeError::TrailingBits { offset } => {
+                write!(f, "non canonical base64 symbol at offset {offset}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<DecodeError> for std::io::Error {
+    fn from(err: DecodeError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Exact length of the encoding of `len` bytes.
+pub fn encoded_len(len: usize, padding: bool) -> usize {
+    match (padding, len % 3) {
+        (true, _) => len.div_ceil(3) * 4,
+        (false, rem) => len / 3 * 4 + [0, 2, 3][rem],
+    }
+}
+
+/// Number of symbols in `input` before its padding, which is at most two `=` ending the last
+/// group of four.
+fn symbols(input: &[u8]) -> Result<usize, DecodeError> {
+    let padding = input.iter().rev().take_while(|&&b| b'=' == b).count();
+    if padding > 2 {
+        return Err(DecodeError::InvalidPadding {
+            offset: input.len() - padding,
+        });
+    }
+    if padding > 0 && !input.len().is_multiple_of(4) {
+        return Err(DecodeError::InvalidLength(input.len()));
+    }
+    Ok(input.len() - padding)
+}
+
+/// Exact length `input` decodes to, without decoding it.
+pub fn decoded_len(input: &[u8]) -> Result<usize, DecodeError> {
+    let symbols = symbols(input)?;
+    match symbols % 4 {
+        1 => Err(DecodeError::InvalidLength(input.len())),
+        rem => Ok(symbols / 4 * 3 + [0, 0, 1, 2][rem]),
+    }
+}
+
+/// Encode `buffer[..len]` into the front of `buffer` and return the encoded length.
+///
+/// Groups are encoded from the back, so every group is read before the growing output reaches
+/// it. `buffer` must hold [`encoded_len`] bytes.
+pub fn encode_in_place(buffer: &mut [u8], len: usize, alphabet: Alphabet, padding: bool) -> usize {
+    let encoded = encoded_len(len, padding);
+    assert!(buffer.len() >= encoded);
+    let table = alphabet.encode_table();
+    let groups = len / 3;
+    let symbol = |bits: u32| table[(bits & 0x3f) as usize];
+    match len % 3 {
+        0 => {}
+        rem => {
+            let (i, o) = (groups * 3, groups * 4);
+            let b0 = u32::from(buffer[i]);
+            let b1 = if 2 == rem {
+                u32::from(buffer[i + 1])
+            } else {
+                0
+            };
+            let bits = (b0 << 16) | (b1 << 8);
+            buffer[o] = symbol(bits >> 18);
+            buffer[o + 1] = symbol(bits >> 12);
+            let mut end = o + 2;
+            if 2 == rem {
+                buffer[end] = symbol(bits >> 6);
+                end += 1;
+            }
+            if padding {
+                buffer[end..o + 4].fill(b'=');
+            }
+        }
+    }
+    for group in (0..groups).rev() {
+        let (i, o) = (group * 3, group * 4);
+        let bits = (u32::from(buffer[i]) << 16)
+            | (u32::from(buffer[i + 1]) << 8)
+            | u32::from(buffer[i + 2]);
+        buffer[o] = symbol(bits >> 18);
+        buffer[o + 1] = symbol(bits >> 12);
+        buffer[o + 2] = symbol(bits >> 6);
+        buffer[o + 3] = symbol(bits);
+    }
+    encoded
+}
+
+fn sextet(table: &[u8; 256], buffer: &[u8], offset: usize) -> Result<u32, DecodeError> {
+    let byte = buffer[offset];
+    match table[byte as usize] {
+        INVALID if b'=' == byte => Err(DecodeError::InvalidPadding { offset }),
+        INVALID => Err(DecodeError::InvalidByte { offset, byte }),
+        bits => Ok(u32::from(bits)),
+    }
+}
+
+/// Decode all of `buffer` into its front and return the decoded length; padding is optional.
+///
+/// Groups are decoded from the front, and the output of a group never reaches past its input.
+pub fn decode_in_place(buffer: &mut [u8], alphabet: Alphabet) -> Result<usize, DecodeError> {
+    let decoded = decoded_len(buffer)?;
+    let table = alphabet.decode_table();
+    let symbols = symbols(buffer)?;
+    let groups = symbols / 4;
+    for group in 0..groups {
+        let (i, o) = (group * 4, group * 3);
+        let bits = (sextet(table, buffer, i)? << 18)
+            | (sextet(table, buffer, i + 1)? << 12)
+            | (sextet(table, buffer, i + 2)? << 6)
+            | sextet(table, buffer, i + 3)?;
+        buffer[o] = (bits >> 16) as u8;
+        buffer[o + 1] = (bits >> 8) as u8;
+        buffer[o + 2] = bits as u8;
+    }
+    let (i, o) = (groups * 4, groups * 3);
+    match symbols % 4 {
+        2 => {
+            let bits = (sextet(table, buffer, i)? << 18) | (sextet(table, buffer, i + 1)? << 12);
+            if 0 != bits & 0xffff {
+                return Err(DecodeError::TrailingBits { offset: i + 1 });
+            }
+            buffer[o] = (bits >> 16) as u8;
+        }
+        3 => {
+            let bits = (sextet(table, buffer, i)? << 18)
+                | (sextet(table, buffer, i + 1)? << 12)
+                | (sextet(table, buffer, i + 2)? << 6);
+            if 0 != bits & 0xff {
+                return Err(DecodeError::TrailingBits { offset: i + 2 });
+            }
+            buffer[o] = (bits >> 16) as u8;
+            buffer[o + 1] = (bits >> 8) as u8;
+        }
+        0 => {}
+        _ => return Err(DecodeError::InvalidLength(buffer.len())),
+    }
+    Ok(decoded)
+}
+
+/// Base64 encoding as a [`Stage`]; needs a third of the input as headroom.
+///
+/// Every run is encoded as a whole message, padding included. [`Pipeline::run_chain`] runs
+/// each handler call's output on its own, so in an outbound pipeline the stream is only
+/// encoded as one when every call but the last writes a multiple of three bytes; anything
+/// else belongs in one [`Pipeline::run_span`].
+///
+/// [`Pipeline::run_chain`]: crate::pipeline::Pipeline::run_chain
+/// [`Pipeline::run_span`]: crate::pipeline::Pipeline::run_span
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Encode {
+    pub alphabet: Alphabet,
+    pub padding: bool,
+}
+
+impl Encode {
+    /// The standard alphabet with padding.
+    pub fn standard() -> Self {
+        Self {
+            alphabet: Alphabet::Standard,
+            padding: true,
+        }
+    }
+
+    /// The URL safe alphabet without padding.
+    pub fn url_safe() -> Self {
+        Self {
+            alphabet: Alphabet::UrlSafe,
+            padding: false,
+        }
+    }
+
+    /// Exact headroom encoding `len` bytes in place needs after them.
+    pub fn required(&self, len: usize) -> usize {
+        encoded_len(len, self.padding) - len
+    }
+}
+
+impl Stage for Encode {
+    fn ratio(&self) -> Ratio {
+        Ratio::new(4, 3)
+    }
+
+    fn run(&mut self, buffer: &mut [u8], len: usize) -> std::io::Result<usize> {
+        Ok(encode_in_place(buffer, len, self.alphabet, self.padding))
+    }
+}
+
+/// Base64 decoding as a [`Stage`]; frees a quarter of the input.
+///
+/// Every run must be whole quads, so like [`Encode`] it only suits an outbound pipeline
+/// when every handler call but the last writes a multiple of four symbols.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Decode {
+    pub alphabet: Alphabet,
+}
+
+impl Decode {
+    pub fn standard() -> Self {
+        Self {
+            alphabet: Alphabet::Standard,
+        }
+    }
+
+    pub fn url_safe() -> Self {
+        Self {
+            alphabet: Alphabet::UrlSafe,
+        }
+    }
+
+    /// Exact number of bytes decoding `input` in place frees at its end.
+    pub fn reclaimed(&self, input: &[u8]) -> Result<usize, DecodeError> {
+        Ok(input.len() - decoded_len(input)?)
+    }
+}
+
+impl Stage for Decode {
+    fn ratio(&self) -> Ratio {
+        Ratio::new(3, 4)
+    }
+
+    fn run(&mut self, buffer: &mut [u8], len: usize) -> std::io::Result<usize> {
+        Ok(decode_in_place(&mut buffer[..len], self.alphabet)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_in_place, encode_in_place, encoded_len, Alphabet, Decode, DecodeError};
+    use super::{decoded_len, Encode};
+    use crate::pipeline::Pipeline;
+    use crate::ring::{RingBuffer, Span};
+
+    const VECTORS: [(&str, &str); 7] = [
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+        ("fooba", "Zm9vYmE="),
+        ("foobar", "Zm9vYmFy"),
+    ];
+
+    #[test]
+    fn round_trips_rfc_4648_vectors() {
+        for (plain, encoded) in VECTORS {
+            let mut buffer = [0; 8];
+            buffer[..plain.len()].copy_from_slice(plain.as_bytes());
+            let n = encode_in_place(&mut buffer, plain.len(), Alphabet::Standard, true);
+            assert_eq!(&buffer[..n], encoded.as_bytes());
+            assert_eq!(Encode::standard().required(plain.len()), n - plain.len());
+
+            assert_eq!(decoded_len(encoded.as_bytes()), Ok(plain.len()));
+            let n = decode_in_place(&mut buffer[..n], Alphabet::Standard).unwrap();
+            assert_eq!(&buffer[..n], plain.as_bytes());
+
+            let unpadded = encoded.trim_end_matches('=');
+            let mut buffer = unpadded.as_bytes().to_vec();
+            let n = decode_in_place(&mut buffer, Alphabet::UrlSafe).unwrap();
+            assert_eq!(&buffer[..n], plain.as_bytes());
+        }
+    }
+
+    #[test]
+    fn url_safe_uses_its_own_symbols() {
+        let mut buffer = [0xfb, 0xff, 0xbf, 0, 0];
+        let n = encode_in_place(&mut buffer, 3, Alphabet::UrlSafe, false);
+        assert_eq!(&buffer[..n], b"-_-_");
+        assert_eq!(encoded_len(4, false), 6);
+
+        let mut buffer = *b"+/+/";
+        assert_eq!(
+            decode_in_place(&mut buffer, Alphabet::UrlSafe),
+            Err(DecodeError::InvalidByte {
+                offset: 0,
+                byte: b'+'
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let cases: [(&[u8], DecodeError); 7] = [
+            (
+                b"Zm9v!A==",
+                DecodeError::InvalidByte {
+                    offset: 4,
+                    byte: b'!',
+                },
+            ),
+            (b"Zm=v", DecodeError::InvalidPadding { offset: 2 }),
+            (b"Zm9vY", DecodeError::InvalidLength(5)),
+            (b"Zh==", DecodeError::TrailingBits { offset: 1 }),
+            (b"Zm9v====", DecodeError::InvalidPadding { offset: 4 }),
+            (b"Z===", DecodeError::InvalidPadding { offset: 1 }),
+            (b"====", DecodeError::InvalidPadding { offset: 0 }),
+        ];
+        for (input, expected) in cases {
+            let mut buffer = input.to_vec();
+            assert_eq!(
+                decode_in_place(&mut buffer, Alphabet::Standard),
+                Err(expected)
+            );
+        }
+        let err = std::io::Error::from(DecodeError::InvalidLength(5));
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.get_ref().unwrap().is::<DecodeError>());
+    }
+
+    #[test]
+    fn stages_account_for_headroom_exactly() {
+        let mut ring = RingBuffer::with_segment_len(4, 8);
+        let mut span = Span::default();
+        let encoded = b"aGVsbG8gd29ybGQ=";
+        ring.reserve(&mut span, 1, encoded.len()).unwrap();
+        ring.spare_mut(&mut span, 1).unwrap()[..encoded.len()].copy_from_slice(encoded);
+        ring.commit(&mut span, encoded.len());
+        assert_eq!(Decode::standard().reclaimed(ring.bytes(&span)), Ok(5));
+
+        let mut decode = Pipeline::new().stage(Decode::standard());
+        assert_eq!(decode.headroom(encoded.len()), 0);
+        decode.run_span(&mut ring, &mut span, 1).unwrap();
+        assert_eq!(ring.bytes(&span), b"hello world");
+
+        let mut encode = Pipeline::new().stage(Encode::url_safe());
+        assert_eq!(encode.headroom(11), 5);
+        encode.run_span(&mut ring, &mut span, 1).unwrap();
+        assert_eq!(ring.bytes(&span), b"aGVsbG8gd29ybGQ");
+
+        let mut buffer = *b"Zm9v!A==";
+        let err = decode.run(&mut buffer, 8).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
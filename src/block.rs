@@ -0,0 +1,657 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fs::{File, OpenOptions},
+    io::{ErrorKind::*, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use ring::digest::{digest, SHA256, SHA256_OUTPUT_LEN as DIGEST_LEN};
+
+use crate::{Flow, Handler};
+
+/// Longest frame accepted, its kind included.
+pub const MAX_FRAME: usize = 16 << 20;
+
+/// Bytes of blocks a `Walk` is answered with unless [`BlockStore::window`] says otherwise.
+pub const WINDOW: usize = 32 * 1024;
+
+const PUT: u8 = 1;
+const GET: u8 = 2;
+const WALK: u8 = 3;
+const STORED: u8 = 4;
+const BLOCK: u8 = 5;
+const BLOCKS: u8 = 6;
+const NOT_FOUND: u8 = 7;
+const ERROR: u8 = 8;
+
+/// The SHA-256 of a block's encoding, which is the only name it goes by.
+pub type Hash = [u8; DIGEST_LEN];
+
+/// A block reached by [`BlockStore::walk`]: its hash and encoding.
+pub type Reached = (Hash, Vec<u8>);
+
+/// Bytes of an index entry: the hash, then the offset and length of the encoding in the blocks
+/// file as big endian `u64` and `u32`.
+const INDEX_ENTRY: usize = DIGEST_LEN + 8 + 4;
+
+fn sha256(bytes: &[u8]) -> Hash {
+    digest(&SHA256, bytes)
+        .as_ref()
+        .try_into()
+        .expect("SHA-256 digest length")
+}
+
+fn invalid(what: &str) -> std::io::Error {
+    std::io::Error::new(InvalidData, format!("malformed block: {what}"))
+}
+
+fn hex(hash: &Hash) -> String {
+    hash.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> std::io::Result<u32> {
+    let bytes = bytes.get(at..at + 4).ok_or_else(|| invalid("truncated"))?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn hash_at(bytes: &[u8], at: usize) -> std::io::Result<Hash> {
+    let bytes = bytes.get(at..at + DIGEST_LEN);
+    Ok(bytes
+        .ok_or_else(|| invalid("truncated"))?
+        .try_into()
+        .unwrap())
+}
+
+/// An immutable key and value that names the blocks it relates to by their hashes.
+///
+/// A block can only relate to blocks stored before it, and since no hash can be known before
+/// its block is, the relations never form a cycle.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block<'a> {
+    pub key: &'a [u8],
+    pub value: &'a [u8],
+    pub relations: Vec<Hash>,
+}
+
+impl<'a> Block<'a> {
+    /// A big endian `u32` key length, the key, a `u32` count of relations, their hashes and the
+    /// value; the hash is taken over exactly these bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let len = 8 + self.key.len() + DIGEST_LEN * self.relations.len() + self.value.len();
+        let mut encoded = Vec::with_capacity(len);
+        encoded.extend_from_slice(&(self.key.len() as u32).to_be_bytes());
+        encoded.extend_from_slice(self.key);
+        encoded.extend_from_slice(&(self.relations.len() as u32).to_be_bytes());
+        for relation in &self.relations {
+            encoded.extend_from_slice(relation);
+        }
+        encoded.extend_from_slice(self.value);
+        encoded
+    }
+
+    pub fn decode(encoded: &'a [u8]) -> std::io::Result<Self> {
+        let key_len = u32_at(encoded, 0)? as usize;
+        let key = encoded
+            .get(4..4 + key_len)
+            .ok_or_else(|| invalid("truncated"))?;
+        let count = u32_at(encoded, 4 + key_len)? as usize;
+        let start = 8 + key_len;
+        if count > (encoded.len() - start) / DIGEST_LEN {
+            return Err(invalid("truncated"));
+        }
+        let relations = (0..count)
+            .map(|i| hash_at(encoded, start + DIGEST_LEN * i))
+            .collect::<std::io::Result<_>>()?;
+        Ok(Self {
+            key,
+            value: &encoded[start + DIGEST_LEN * count..],
+            relations,
+        })
+    }
+
+    pub fn hash(&self) -> Hash {
+        sha256(&self.encode())
+    }
+}
+
+/// Blocks by hash in two append-only files of a directory: `blocks` holds every encoding behind
+/// a big endian `u32` length, `index` an entry per block saying where it is.
+///
+/// Only `blocks` is synced as blocks are stored. Opening a store brings the index up to date
+/// with what `blocks` holds past its last entry, and cuts both files before anything a crash
+/// left half written.
+#[derive(Debug)]
+pub struct BlockStore {
+    blocks: File,
+    blocks_len: u64,
+    index_file: File,
+    /// Offset and length of each block's encoding in `blocks`.
+    index: BTreeMap<Hash, (u64, u32)>,
+    window: usize,
+}
+
+impl BlockStore {
+    pub fn open<P: AsRef<Path>>(dir: P) -> std::io::Result<Self> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let open = |name| {
+            OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(dir.join(name))
+        };
+        let mut blocks = open("blocks")?;
+        let mut index_file = open("index")?;
+        let blocks_len = blocks.metadata()?.len();
+        let mut entries = Vec::new();
+        index_file.read_to_end(&mut entries)?;
+
+        let mut index = BTreeMap::new();
+        let mut indexed = 0;
+        let mut end = 0;
+        for entry in entries.chunks_exact(INDEX_ENTRY) {
+            let hash: Hash = entry[..DIGEST_LEN].try_into().unwrap();
+            let offset = u64::from_be_bytes(entry[DIGEST_LEN..DIGEST_LEN + 8].try_into().unwrap());
+            let len = u32::from_be_bytes(entry[DIGEST_LEN + 8..].try_into().unwrap());
+            if offset + u64::from(len) > blocks_len {
+                break;
+            }
+            index.insert(hash, (offset, len));
+            indexed += INDEX_ENTRY;
+            end = end.max(offset + u64::from(len));
+        }
+        if indexed < entries.len() {
+            index_file.set_len(indexed as u64)?;
+        }
+
+        // blocks stored after the last index entry reached the disk
+        let mut rest = Vec::new();
+        blocks.seek(SeekFrom::Start(end))?;
+        blocks.read_to_end(&mut rest)?;
+        let mut at = 0;
+        while let Ok(len) = u32_at(&rest, at) {
+            let Some(encoded) = rest.get(at + 4..at + 4 + len as usize) else {
+                break;
+            };
+            let offset = end + at as u64 + 4;
+            let hash = sha256(encoded);
+            index_file.write_all(&index_entry(&hash, offset, len))?;
+            index.insert(hash, (offset, len));
+            at += 4 + len as usize;
+        }
+        if at < rest.len() {
+            blocks.set_len(end + at as u64)?;
+            blocks.sync_data()?;
+        }
+        Ok(Self {
+            blocks,
+            blocks_len: end + at as u64,
+            index_file,
+            index,
+            window: WINDOW,
+        })
+    }
+
+    /// Most bytes of blocks, each behind its length, a `Walk` is answered with; the answer holds
+    /// the first block reached however long it is. The ring of the server the store is served
+    /// by must have room for this much and a frame head.
+    pub fn window(mut self, window: usize) -> Self {
+        self.window = window;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.index.contains_key(hash)
+    }
+
+    /// The encoding of the block called `hash`, read from disk.
+    pub fn get(&self, hash: &Hash) -> std::io::Result<Option<Vec<u8>>> {
+        let Some(&(offset, len)) = self.index.get(hash) else {
+            return Ok(None);
+        };
+        let mut blocks = &self.blocks;
+        let mut encoded = vec![0; len as usize];
+        blocks.seek(SeekFrom::Start(offset))?;
+        blocks.read_exact(&mut encoded)?;
+        Ok(Some(encoded))
+    }
+
+    /// Store `block` unless it is stored already and return its hash; fails with `NotFound`
+    /// when it relates to a block that is not stored.
+    pub fn insert(&mut self, block: &Block<'_>) -> std::io::Result<Hash> {
+        if let Some(missing) = block.relations.iter().find(|hash| !self.contains(hash)) {
+            let missing = hex(missing);
+            let message = format!("related block {missing} does not exist");
+            return Err(std::io::Error::new(NotFound, message));
+        }
+        let encoded = block.encode();
+        let hash = sha256(&encoded);
+        if self.contains(&hash) {
+            return Ok(hash);
+        }
+        let len = u32::try_from(encoded.len()).map_err(|_| invalid("too long"))?;
+        let mut record = Vec::with_capacity(4 + encoded.len());
+        record.extend_from_slice(&len.to_be_bytes());
+        record.extend_from_slice(&encoded);
+        let written = self.blocks.write_all(&record);
+        if let Err(err) = written.and_then(|()| self.blocks.sync_data()) {
+            // the next block would be filed at an offset inside what this one left behind
+            self.blocks.set_len(self.blocks_len)?;
+            return Err(err);
+        }
+        let offset = self.blocks_len + 4;
+        self.blocks_len += record.len() as u64;
+        if let Err(err) = self.index_file.write_all(&index_entry(&hash, offset, len)) {
+            // entries after a torn one would be read out of step; the block is left in `blocks`
+            // without one and stored again when it is inserted again
+            let indexed = self.index_file.metadata()?.len();
+            self.index_file
+                .set_len(indexed - indexed % INDEX_ENTRY as u64)?;
+            return Err(err);
+        }
+        self.index.insert(hash, (offset, len));
+        Ok(hash)
+    }
+
+    /// Hashes and encodings of the blocks reached from `from` by following at most `depth`
+    /// relations in a row, nearest first and `from` itself first of all, up to `limit` of them.
+    pub fn walk(&self, from: &Hash, depth: usize, limit: usize) -> std::io::Result<Vec<Reached>> {
+        self.walk_within(from, depth, limit, usize::MAX)
+    }
+
+    /// Like [`BlockStore::walk`], but stop before the block past the first that would take the
+    /// encodings, each behind its length, beyond `window` bytes.
+    fn walk_within(
+        &self,
+        from: &Hash,
+        depth: usize,
+        limit: usize,
+        window: usize,
+    ) -> std::io::Result<Vec<Reached>> {
+        let mut reached = Vec::new();
+        let mut len = 0;
+        let mut seen = BTreeSet::from([*from]);
+        let mut queue = VecDeque::from([(*from, 0)]);
+        while let Some((hash, distance)) = queue.pop_front() {
+            if reached.len() == limit {
+                break;
+            }
+            let Some(encoded) = self.get(&hash)? else {
+                continue;
+            };
+            len += 4 + encoded.len();
+            if len > window && !reached.is_empty() {
+                break;
+            }
+            if distance < depth {
+                for relation in Block::decode(&encoded)?.relations {
+                    if seen.insert(relation) {
+                        queue.push_back((relation, distance + 1));
+                    }
+                }
+            }
+            reached.push((hash, encoded));
+        }
+        Ok(reached)
+    }
+}
+
+fn index_entry(hash: &Hash, offset: u64, len: u32) -> [u8; INDEX_ENTRY] {
+    let mut entry = [0; INDEX_ENTRY];
+    entry[..DIGEST_LEN].copy_from_slice(hash);
+    entry[DIGEST_LEN..DIGEST_LEN + 8].copy_from_slice(&offset.to_be_bytes());
+    entry[DIGEST_LEN + 8..].copy_from_slice(&len.to_be_bytes());
+    entry
+}
+
+/// A unit of the block protocol: a big endian `u32` length, a kind byte and a body.
+///
+/// Clients send `Put` with a block's encoding and are answered with `Stored` and its hash, or
+/// with `Error` when it is malformed or relates to a block that is not stored. `Get` is answered
+/// with the `Block` called by the hash, `Walk` with the `Blocks` [`BlockStore::walk`] reaches as
+/// far as the [`BlockStore::window`] goes,
+/// and both with `NotFound` when there is no block by that hash.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Frame<'a> {
+    Put(&'a [u8]),
+    Get(Hash),
+    Walk { from: Hash, depth: u32, limit: u32 },
+    Stored(Hash),
+    Block(&'a [u8]),
+    Blocks(Vec<&'a [u8]>),
+    NotFound,
+    Error(&'a [u8]),
+}
+
+impl<'a> Frame<'a> {
+    /// The frame at the start of `buffer` with its length, or `None` until all of it arrived.
+    pub fn parse(buffer: &'a [u8]) -> std::io::Result<Option<(Self, usize)>> {
+        let Ok(len) = u32_at(buffer, 0) else {
+            return Ok(None);
+        };
+        let len = len as usize;
+        if 0 == len || len > MAX_FRAME {
+            return Err(invalid("frame length"));
+        }
+        let Some(frame) = buffer.get(4..4 + len) else {
+            return Ok(None);
+        };
+        let body = &frame[1..];
+        let frame = match frame[0] {
+            PUT => Frame::Put(body),
+            GET => Frame::Get(hash_at(body, 0)?),
+            WALK => Frame::Walk {
+                from: hash_at(body, 0)?,
+                depth: u32_at(body, DIGEST_LEN)?,
+                limit: u32_at(body, DIGEST_LEN + 4)?,
+            },
+            STORED => Frame::Stored(hash_at(body, 0)?),
+            BLOCK => Frame::Block(body),
+            BLOCKS => {
+                let mut blocks = Vec::new();
+                let mut at = 0;
+                while at < body.len() {
+                    let len = u32_at(body, at)? as usize;
+                    let block = body.get(at + 4..at + 4 + len);
+                    blocks.push(block.ok_or_else(|| invalid("truncated"))?);
+                    at += 4 + len;
+                }
+                Frame::Blocks(blocks)
+            }
+            NOT_FOUND => Frame::NotFound,
+            ERROR => Frame::Error(body),
+            _ => return Err(invalid("frame kind")),
+        };
+        Ok(Some((frame, 4 + len)))
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        let mut walk = [0; DIGEST_LEN + 8];
+        let body: &[u8] = match self {
+            Frame::Put(block) | Frame::Block(block) => block,
+            Frame::Get(hash) | Frame::Stored(hash) => hash,
+            Frame::Walk { from, depth, limit } => {
+                walk[..DIGEST_LEN].copy_from_slice(from);
+                walk[DIGEST_LEN..DIGEST_LEN + 4].copy_from_slice(&depth.to_be_bytes());
+                walk[DIGEST_LEN + 4..].copy_from_slice(&limit.to_be_bytes());
+                &walk
+            }
+            Frame::Blocks(blocks) => {
+                let len: usize = blocks.iter().map(|block| 4 + block.len()).sum();
+                out.write_all(&(1 + len as u32).to_be_bytes())?;
+                out.write_all(&[BLOCKS])?;
+                for block in blocks {
+                    out.write_all(&(block.len() as u32).to_be_bytes())?;
+                    out.write_all(block)?;
+                }
+                return Ok(());
+            }
+            Frame::NotFound => &[],
+            Frame::Error(text) => text,
+        };
+        let kind = match self {
+            Frame::Put(_) => PUT,
+            Frame::Get(_) => GET,
+            Frame::Walk { .. } => WALK,
+            Frame::Stored(_) => STORED,
+            Frame::Block(_) => BLOCK,
+            Frame::Blocks(_) => BLOCKS,
+            Frame::NotFound => NOT_FOUND,
+            Frame::Error(_) => ERROR,
+        };
+        out.write_all(&((1 + body.len()) as u32).to_be_bytes())?;
+        out.write_all(&[kind])?;
+        out.write_all(body)
+    }
+}
+
+/// Serves the store over the [`Frame`] protocol.
+impl<C> Handler<C> for BlockStore {
+    fn received<W: Write>(
+        &mut self,
+        _token: usize,
+        receive: &[u8],
+        send: &mut W,
+    ) -> std::io::Result<(usize, Flow)> {
+        let mut consumed = 0;
+        while let Some((frame, len)) = Frame::parse(&receive[consumed..])? {
+            consumed += len;
+            match frame {
+                Frame::Put(encoded) => {
+                    match Block::decode(encoded).and_then(|block| self.insert(&block)) {
+                        Ok(hash) => Frame::Stored(hash).write_to(send)?,
+                        Err(err) => Frame::Error(err.to_string().as_bytes()).write_to(send)?,
+                    }
+                }
+                Frame::Get(hash) => match self.get(&hash)? {
+                    Some(encoded) => Frame::Block(&encoded).write_to(send)?,
+                    None => Frame::NotFound.write_to(send)?,
+                },
+                Frame::Walk { from, depth, limit } => {
+                    let window = self.window.min(MAX_FRAME - 1);
+                    let reached =
+                        self.walk_within(&from, depth as usize, limit as usize, window)?;
+                    if reached.is_empty() {
+                        Frame::NotFound.write_to(send)?;
+                        continue;
+                    }
+                    let blocks = reached.iter().map(|(_, encoded)| encoded.as_slice());
+                    Frame::Blocks(blocks.collect()).write_to(send)?;
+                }
+                _ => return Err(invalid("answer sent as a request")),
+            }
+        }
+        Ok((consumed, Flow::Continue))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        sync::atomic::{AtomicUsize, Ordering::SeqCst},
+        thread,
+    };
+
+    use mio::net::TcpStream;
+
+    use super::{Block, BlockStore, Frame, Hash, Reached, INDEX_ENTRY};
+    use crate::{
+        ring::RingBuffer,
+        tests::{new_loopback_address, TestServer},
+        Listener, Server, Shutdown,
+    };
+
+    static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+    fn store_dir() -> std::path::PathBuf {
+        let n = DIRS.fetch_add(1, SeqCst);
+        std::env::temp_dir().join(format!("elog-{}-{n}.blocks", std::process::id()))
+    }
+
+    fn block<'a>(key: &'a [u8], value: &'a [u8], relations: &[Hash]) -> Block<'a> {
+        Block {
+            key,
+            value,
+            relations: relations.to_vec(),
+        }
+    }
+
+    fn hashes(reached: std::io::Result<Vec<Reached>>) -> Vec<Hash> {
+        reached.unwrap().into_iter().map(|(hash, _)| hash).collect()
+    }
+
+    /// Send `frame` to `stream` and return the answer.
+    fn call(stream: &mut std::net::TcpStream, frame: Frame<'_>) -> Vec<u8> {
+        let mut send = Vec::new();
+        frame.write_to(&mut send).unwrap();
+        stream.write_all(&send).unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+        while let Ok(None) = Frame::parse(&received) {
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0, "connection closed before the answer");
+            received.extend_from_slice(&buf[..n]);
+        }
+        received
+    }
+
+    #[test]
+    fn blocks_are_named_by_content_and_relate_to_stored_ones() {
+        let dir = store_dir();
+        let mut store = BlockStore::open(&dir).unwrap();
+        let root = store.insert(&block(b"root", b"", &[])).unwrap();
+        assert_eq!(store.insert(&block(b"root", b"", &[])).unwrap(), root);
+        assert_eq!(root, block(b"root", b"", &[]).hash());
+
+        let missing = block(b"missing", b"", &[]).hash();
+        let err = store
+            .insert(&block(b"leaf", b"1", &[root, missing]))
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(store.len(), 1);
+
+        let left = store.insert(&block(b"left", b"2", &[root])).unwrap();
+        let right = store.insert(&block(b"right", b"3", &[root])).unwrap();
+        let leaf = store.insert(&block(b"leaf", b"4", &[left, right])).unwrap();
+        let encoded = store.get(&leaf).unwrap().unwrap();
+        assert_eq!(
+            Block::decode(&encoded).unwrap(),
+            block(b"leaf", b"4", &[left, right])
+        );
+        assert_eq!(store.get(&missing).unwrap(), None);
+
+        assert_eq!(hashes(store.walk(&leaf, 0, 10)), [leaf]);
+        assert_eq!(hashes(store.walk(&leaf, 1, 10)), [leaf, left, right]);
+        assert_eq!(hashes(store.walk(&leaf, 5, 10)), [leaf, left, right, root]);
+        assert_eq!(hashes(store.walk(&leaf, 5, 2)), [leaf, left]);
+        assert!(hashes(store.walk(&missing, 5, 10)).is_empty());
+        assert!(Block::decode(&encoded[..encoded.len() - 2 - 64]).is_err());
+        drop(store);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopening_indexes_blocks_the_index_missed() {
+        let dir = store_dir();
+        let mut store = BlockStore::open(&dir).unwrap();
+        let a = store.insert(&block(b"a", b"1", &[])).unwrap();
+        let b = store.insert(&block(b"b", b"2", &[a])).unwrap();
+        let c = store.insert(&block(b"c", b"3", &[a, b])).unwrap();
+        drop(store);
+
+        // the last entry never reached the index, half of one did, and so did half a block
+        let index = std::fs::read(dir.join("index")).unwrap();
+        let mut torn = index[..2 * INDEX_ENTRY].to_vec();
+        torn.extend_from_slice(&index[2 * INDEX_ENTRY..2 * INDEX_ENTRY + 10]);
+        std::fs::write(dir.join("index"), torn).unwrap();
+        let blocks_len = std::fs::metadata(dir.join("blocks")).unwrap().len();
+        let mut blocks = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join("blocks"))
+            .unwrap();
+        blocks.write_all(&[0, 0, 1, 0, 7]).unwrap();
+        drop(blocks);
+
+        let mut store = BlockStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(hashes(store.walk(&c, 1, 10)), [c, a, b]);
+        let len = |name| std::fs::metadata(dir.join(name)).unwrap().len();
+        assert_eq!(
+            (len("index"), len("blocks")),
+            (index.len() as u64, blocks_len)
+        );
+        let d = store.insert(&block(b"d", b"4", &[c])).unwrap();
+        drop(store);
+        let store = BlockStore::open(&dir).unwrap();
+        assert_eq!(hashes(store.walk(&d, 3, 10)), [d, c, a, b]);
+        drop(store);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn serves_blocks_and_relation_walks() {
+        let dir = store_dir();
+        let mut store = BlockStore::open(&dir).unwrap().window(100);
+        let addr = new_loopback_address();
+        let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                let mut ring = RingBuffer::new(16);
+                <TestServer as Server<TcpStream>>::serve::<1, _>(
+                    listener, 128, &mut ring, &mut store, &shutdown,
+                )
+            });
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            let mut put = |block: Block<'_>| {
+                let answer = call(&mut client, Frame::Put(&block.encode()));
+                match Frame::parse(&answer).unwrap() {
+                    Some((Frame::Stored(hash), _)) => Ok(hash),
+                    Some((Frame::Error(text), _)) => {
+                        Err(String::from_utf8_lossy(text).into_owned())
+                    }
+                    other => panic!("unexpected answer {other:?}"),
+                }
+            };
+            let root = put(block(b"root", b"r", &[])).unwrap();
+            let child = put(block(b"child", b"c", &[root])).unwrap();
+            let long = put(block(b"long", &[1; 100], &[child])).unwrap();
+            let err = put(block(b"orphan", b"o", &[[7; 32]])).unwrap_err();
+            assert!(err.contains("0707"), "{err}");
+
+            let mut encoded = Vec::new();
+            Frame::Block(&block(b"child", b"c", &[root]).encode())
+                .write_to(&mut encoded)
+                .unwrap();
+            assert_eq!(call(&mut client, Frame::Get(child)), encoded);
+            let mut not_found = Vec::new();
+            Frame::NotFound.write_to(&mut not_found).unwrap();
+            assert_eq!(call(&mut client, Frame::Get([7; 32])), not_found);
+
+            let walk = Frame::Walk {
+                from: child,
+                depth: 1,
+                limit: 10,
+            };
+            let answer = call(&mut client, walk);
+            let Some((Frame::Blocks(blocks), _)) = Frame::parse(&answer).unwrap() else {
+                panic!("blocks");
+            };
+            let keys: Vec<&[u8]> = blocks
+                .iter()
+                .map(|encoded| Block::decode(encoded).unwrap().key)
+                .collect();
+            assert_eq!(keys, [&b"child"[..], b"root"]);
+
+            // the first block is sent however long it is, no more beyond the window
+            let walk = Frame::Walk {
+                from: long,
+                depth: 2,
+                limit: 10,
+            };
+            let answer = call(&mut client, walk);
+            let Some((Frame::Blocks(blocks), _)) = Frame::parse(&answer).unwrap() else {
+                panic!("blocks");
+            };
+            assert_eq!(blocks.len(), 1);
+
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+
+        assert_eq!(store.len(), 3);
+        drop(store);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1274 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io::{ErrorKind::*, IoSlice, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use mio::{event::Source, Interest, Poll};
+
+use crate::{
+    http::{
+        parse_http_date, parse_request, parse_response, tokens, BodyScan, Framing, ResponseHead,
+        Status, MAX_HEAD,
+    },
+    mmap::{page_size, MapOptions, Mapping},
+    proxy::{read_side, write_side, HalfClose, Side},
+    ring::{Chain, RingBuffer},
+    slab::Slab,
+    Connect, Connector, EventLoop, Listener, ListenerRegistry, Registry, Shutdown, IO_SLICES,
+    SHUTDOWN,
+};
+
+/// A stored response, filed under its key.
+#[derive(Debug)]
+struct Entry {
+    path: PathBuf,
+    len: usize,
+    /// Bytes the file takes up, `len` rounded up to whole pages.
+    size: u64,
+    expires: Option<Instant>,
+    /// When the entry was last stored or hit, as a [`Cache`] tick.
+    used: u64,
+}
+
+/// Responses kept on disk in files of whole pages, one per key, that are mapped to serve them.
+///
+/// The index lives in memory: files are named by the order they were written in and removed
+/// when their entry is evicted, expires or the cache is dropped, and files left in the directory
+/// by an earlier cache are removed when a new one opens it. Once the files would take up more
+/// than the capacity, the least recently used entries are evicted.
+#[derive(Debug)]
+pub struct Cache {
+    dir: PathBuf,
+    capacity: u64,
+    max_entry: u64,
+    ttl: Option<Duration>,
+    entries: BTreeMap<Vec<u8>, Entry>,
+    /// Keys by the tick they were last used at, the least recently used first.
+    lru: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    used: u64,
+    next_file: u64,
+}
+
+impl Cache {
+    pub fn new<P: AsRef<Path>>(dir: P, capacity: u64) -> std::io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        for file in std::fs::read_dir(&dir)? {
+            let path = file?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| "entry" == extension)
+            {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(Self {
+            dir,
+            capacity,
+            max_entry: capacity,
+            ttl: None,
+            entries: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            used: 0,
+            next_file: 0,
+        })
+    }
+
+    /// How long responses stay fresh when nothing says otherwise. Without it, HTTP responses
+    /// without Cache-Control or Expires are not stored and raw responses stay until evicted.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Longest response stored, the capacity unless set; longer ones are passed on without
+    /// being stored. Responses are written to their file as they arrive and older entries only
+    /// evicted once they are complete, so every response being fetched can take up this much
+    /// beyond the capacity on disk for a while.
+    pub fn max_entry(mut self, max_entry: u64) -> Self {
+        self.max_entry = max_entry;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Bytes the files of the entries take up on disk.
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// The response stored under `key` unless it expired by `now` or its file cannot be mapped
+    /// any more, in which case it is removed.
+    pub fn get(&mut self, key: &[u8], now: Instant) -> std::io::Result<Option<Hit>> {
+        let Some(entry) = self.entries.get_mut(key) else {
+            return Ok(None);
+        };
+        if entry.expires.is_some_and(|expires| expires <= now) {
+            self.remove(key)?;
+            return Ok(None);
+        }
+        self.tick += 1;
+        let stored = self.lru.remove(&entry.used).expect("entry in lru order");
+        self.lru.insert(self.tick, stored);
+        entry.used = self.tick;
+        let mapping = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&entry.path)
+            .and_then(|file| MapOptions::new().file(&file, entry.size as usize));
+        match mapping {
+            Ok(mapping) => Ok(Some(Hit {
+                mapping,
+                len: entry.len,
+            })),
+            // a file removed behind the cache's back is a miss, the response is fetched again
+            Err(_) => {
+                self.remove(key)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Store `bytes` under `key` for `ttl` from `now`, or for good without one, replacing what
+    /// was stored under it and evicting entries until it fits; false when it would not fit into
+    /// an empty cache either.
+    pub fn insert(
+        &mut self,
+        key: &[u8],
+        bytes: &[u8],
+        ttl: Option<Duration>,
+        now: Instant,
+    ) -> std::io::Result<bool> {
+        if bytes.len() as u64 > self.max_entry {
+            self.remove(key)?;
+            return Ok(false);
+        }
+        let (path, mut file) = self.create()?;
+        if let Err(err) = file.write_all(bytes) {
+            let _ = std::fs::remove_file(&path);
+            return Err(err);
+        }
+        self.file(key, path, &file, bytes.len(), ttl, now)
+    }
+
+    /// A new file to write an entry to.
+    fn create(&mut self) -> std::io::Result<(PathBuf, File)> {
+        let path = self.dir.join(format!("{}.entry", self.next_file));
+        self.next_file += 1;
+        let file = File::create(&path)?;
+        Ok((path, file))
+    }
+
+    /// Store the `len` bytes written to `file` at `path` under `key` like [`Cache::insert`]; the
+    /// file is removed when it is not stored.
+    fn file(
+        &mut self,
+        key: &[u8],
+        path: PathBuf,
+        file: &File,
+        len: usize,
+        ttl: Option<Duration>,
+        now: Instant,
+    ) -> std::io::Result<bool> {
+        let size = self.make_room(key, len).and_then(|size| match size {
+            Some(size) => file.set_len(size).map(|_| Some(size)),
+            None => Ok(None),
+        });
+        let size = match size {
+            Ok(Some(size)) => size,
+            Ok(None) => {
+                std::fs::remove_file(&path)?;
+                return Ok(false);
+            }
+            Err(err) => {
+                let _ = std::fs::remove_file(&path);
+                return Err(err);
+            }
+        };
+        self.tick += 1;
+        self.lru.insert(self.tick, key.to_vec());
+        self.entries.insert(
+            key.to_vec(),
+            Entry {
+                path,
+                len,
+                size,
+                expires: ttl.map(|ttl| now + ttl),
+                used: self.tick,
+            },
+        );
+        self.used += size;
+        Ok(true)
+    }
+
+    /// Remove the entry under `key` and evict entries until a file for `len` bytes fits; returns
+    /// its size, or None when it would not fit into an empty cache either.
+    fn make_room(&mut self, key: &[u8], len: usize) -> std::io::Result<Option<u64>> {
+        self.remove(key)?;
+        let page = page_size() as u64;
+        let size = (len as u64).max(1).div_ceil(page) * page;
+        if size > self.capacity || len as u64 > self.max_entry {
+            return Ok(None);
+        }
+        while self.used + size > self.capacity {
+            let Some((_, oldest)) = self.lru.first_key_value() else {
+                break;
+            };
+            let oldest = oldest.clone();
+            self.remove(&oldest)?;
+        }
+        Ok(Some(size))
+    }
+
+    /// Remove the entry under `key` with its file; false if there was none.
+    pub fn remove(&mut self, key: &[u8]) -> std::io::Result<bool> {
+        let Some(entry) = self.entries.remove(key) else {
+            return Ok(false);
+        };
+        self.lru.remove(&entry.used);
+        self.used -= entry.size;
+        match std::fs::remove_file(&entry.path) {
+            Err(ref err) if NotFound == err.kind() => Ok(true),
+            removed => removed.map(|_| true),
+        }
+    }
+}
+
+impl Drop for Cache {
+    fn drop(&mut self) {
+        for entry in self.entries.values() {
+            let _ = std::fs::remove_file(&entry.path);
+        }
+    }
+}
+
+/// A response served from a [`Cache`], mapped from its file. The mapping stays readable when the
+/// entry is evicted while it is being sent.
+#[derive(Debug)]
+pub struct Hit {
+    mapping: Mapping,
+    len: usize,
+}
+
+impl Hit {
+    pub fn bytes(&self) -> &[u8] {
+        &self.mapping[..self.len]
+    }
+}
+
+/// What a [`CachingProxy`] files responses under.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Keying {
+    /// The host and target of GET requests without a body, as long as their Cache-Control
+    /// allows it and the host is a valid uri-host; responses are stored while their
+    /// Cache-Control or Expires says they are fresh, unless they carry Vary or answer a request
+    /// with Authorization without saying they may be shared. Requests with Authorization are
+    /// never answered from the cache.
+    Http,
+    /// Every byte the client sends before it ends its stream; the whole response until upstream
+    /// ends its stream is stored.
+    Raw,
+}
+
+/// How long `response` may be served from a cache according to its Cache-Control, Expires and
+/// Date headers, or `default` when they say nothing; None when it must not be stored.
+pub fn freshness(response: &ResponseHead<'_>, default: Option<Duration>) -> Option<Duration> {
+    if let Some(value) = response.header("cache-control") {
+        let (mut max_age, mut shared_max_age) = (None, None);
+        for directive in tokens(value) {
+            let (name, argument) = match directive.iter().position(|&b| b'=' == b) {
+                Some(i) => (&directive[..i], &directive[i + 1..]),
+                None => (directive, &[][..]),
+            };
+            let seconds = || {
+                let argument = argument.strip_prefix(b"\"").unwrap_or(argument);
+                let argument = argument.strip_suffix(b"\"").unwrap_or(argument);
+                std::str::from_utf8(argument).ok()?.parse::<u64>().ok()
+            };
+            if [&b"no-store"[..], b"no-cache", b"private"]
+                .iter()
+                .any(|directive| name.eq_ignore_ascii_case(directive))
+            {
+                return None;
+            } else if name.eq_ignore_ascii_case(b"s-maxage") {
+                shared_max_age = seconds();
+            } else if name.eq_ignore_ascii_case(b"max-age") {
+                max_age = seconds();
+            }
+        }
+        if let Some(seconds) = shared_max_age.or(max_age) {
+            return (seconds > 0).then(|| Duration::from_secs(seconds));
+        }
+    }
+    if let Some(expires) = response.header("expires") {
+        // a date that cannot be parsed means the response already expired
+        let expires = parse_http_date(expires)?;
+        let date = response
+            .header("date")
+            .and_then(parse_http_date)
+            .unwrap_or_else(SystemTime::now);
+        return expires
+            .duration_since(date)
+            .ok()
+            .filter(|ttl| !ttl.is_zero());
+    }
+    default
+}
+
+/// Whether the Cache-Control of `response` lets a shared cache store it for a request with
+/// credentials.
+fn shared(response: &ResponseHead<'_>) -> bool {
+    let mut directives = response
+        .header("cache-control")
+        .into_iter()
+        .flat_map(tokens);
+    directives.any(|directive| {
+        let name = directive.split(|&b| b'=' == b).next().unwrap_or_default();
+        [&b"public"[..], b"s-maxage", b"must-revalidate"]
+            .iter()
+            .any(|allowed| name.eq_ignore_ascii_case(allowed))
+    })
+}
+
+/// What to do with the request read so far.
+enum Route {
+    /// More of it has to arrive.
+    Wait,
+    /// Pass it and everything after on without the cache.
+    Pass,
+    /// Fetch it and store the response under the key without looking up what is stored.
+    Fetch(Vec<u8>),
+    /// It carries credentials: fetch it and store the response under the key only if the
+    /// response says it may be shared.
+    Authorized(Vec<u8>),
+    /// Serve what is stored under the key, or fetch and store it.
+    Lookup(Vec<u8>),
+}
+
+fn route(keying: Keying, head: &[u8], ended: bool, full: bool) -> Route {
+    if Keying::Raw == keying {
+        return match (ended, full) {
+            (true, _) if !head.is_empty() => Route::Lookup(head.to_vec()),
+            (false, false) => Route::Wait,
+            _ => Route::Pass,
+        };
+    }
+    let (request, len) = match parse_request(head) {
+        Ok(Status::Complete(parsed)) => parsed,
+        Ok(Status::Partial) if !ended && !full => return Route::Wait,
+        _ => return Route::Pass,
+    };
+    // pipelined requests after the first would be lost on a hit
+    if "GET" != request.method || Framing::None != request.framing || len != head.len() {
+        return Route::Pass;
+    }
+    let directives = ["cache-control", "pragma"]
+        .into_iter()
+        .filter_map(|name| request.header(name))
+        .flat_map(tokens);
+    let mut lookup = true;
+    for directive in directives {
+        if directive.eq_ignore_ascii_case(b"no-store") {
+            return Route::Pass;
+        }
+        lookup &= !directive.eq_ignore_ascii_case(b"no-cache");
+    }
+    // a host that is no uri-host could pass for part of another key
+    let host = request.header("host").unwrap_or_default();
+    if !host
+        .iter()
+        .all(|&b| b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:[]%".contains(&b))
+    {
+        return Route::Pass;
+    }
+    // neither the host nor the target contain a space
+    let key = [b"GET ", host, b" ", request.path.as_bytes()].concat();
+    match lookup {
+        _ if request.header("authorization").is_some() => Route::Authorized(key),
+        true => Route::Lookup(key),
+        false => Route::Fetch(key),
+    }
+}
+
+/// How much of the response written so far can be stored.
+enum Fill {
+    Partial,
+    /// All of it, to keep for the time given.
+    Complete(Option<Duration>),
+    Uncacheable,
+}
+
+/// An upstream response being written to an entry file while it may still be stored.
+struct Filling {
+    key: Vec<u8>,
+    /// The request carried credentials.
+    authorized: bool,
+    path: PathBuf,
+    file: File,
+    /// Bytes of the response written to the file.
+    len: usize,
+    /// The response head until all of it arrived and was parsed; raw responses have none.
+    head: Option<Vec<u8>>,
+    ttl: Option<Duration>,
+    /// Where the body ends, unless it ends with the stream.
+    body: Option<BodyScan>,
+}
+
+impl Filling {
+    fn new(
+        cache: &mut Cache,
+        key: Vec<u8>,
+        authorized: bool,
+        keying: Keying,
+    ) -> std::io::Result<Self> {
+        let (path, file) = cache.create()?;
+        Ok(Self {
+            key,
+            authorized,
+            path,
+            file,
+            len: 0,
+            head: (Keying::Http == keying).then(Vec::new),
+            ttl: cache.ttl,
+            body: None,
+        })
+    }
+
+    /// Write the next `bytes` of the response to the file, up to `max` bytes of it in all;
+    /// `ended` once no more follow. The head is parsed once it is complete, the body scanned as
+    /// it arrives.
+    fn feed(&mut self, bytes: &[u8], ended: bool, max: u64) -> std::io::Result<Fill> {
+        let Some(head) = self.head.as_mut() else {
+            return self.write_body(bytes, ended, max);
+        };
+        head.extend_from_slice(bytes);
+        let (response, len) = match parse_response(head) {
+            Ok(Status::Complete(parsed)) => parsed,
+            Ok(Status::Partial) if !ended && head.len() <= MAX_HEAD => return Ok(Fill::Partial),
+            _ => return Ok(Fill::Uncacheable),
+        };
+        match freshness(&response, self.ttl) {
+            Some(ttl) if 200 == response.status => self.ttl = Some(ttl),
+            _ => return Ok(Fill::Uncacheable),
+        }
+        // what Vary names is not part of the key
+        if self.authorized && !shared(&response) || response.header("vary").is_some() {
+            return Ok(Fill::Uncacheable);
+        }
+        self.body = (Framing::None != response.framing).then(|| BodyScan::new(response.framing));
+        let head = self.head.take().unwrap_or_default();
+        if len as u64 > max {
+            return Ok(Fill::Uncacheable);
+        }
+        self.file.write_all(&head[..len])?;
+        self.len = len;
+        self.write_body(&head[len..], ended, max)
+    }
+
+    fn write_body(&mut self, bytes: &[u8], ended: bool, max: u64) -> std::io::Result<Fill> {
+        let (complete, len) = match self.body.as_mut().map(|body| body.scan(bytes)) {
+            None => (ended, bytes.len()),
+            Some(Ok(Status::Complete(len))) => (true, len),
+            Some(Ok(Status::Partial)) if !ended => (false, bytes.len()),
+            Some(_) => return Ok(Fill::Uncacheable),
+        };
+        if (self.len + len) as u64 > max {
+            return Ok(Fill::Uncacheable);
+        }
+        self.file.write_all(&bytes[..len])?;
+        self.len += len;
+        Ok(match complete {
+            true => Fill::Complete(self.ttl),
+            false => Fill::Partial,
+        })
+    }
+
+    /// Store the response in `cache` if `fill` says it can be, or remove its file.
+    fn settle(self, fill: std::io::Result<Fill>, cache: &mut Cache) {
+        // a response that cannot be written to disk is still passed on
+        match fill {
+            Ok(Fill::Complete(ttl)) => {
+                let _ = cache.file(
+                    &self.key,
+                    self.path,
+                    &self.file,
+                    self.len,
+                    ttl,
+                    Instant::now(),
+                );
+            }
+            _ => {
+                let _ = std::fs::remove_file(&self.path);
+            }
+        }
+    }
+}
+
+/// An accepted connection, served either from the cache or through an upstream connection.
+struct Pair<C, U> {
+    downstream: Side<C>,
+    upstream: Option<Side<U>>,
+    /// Bytes read from downstream until it is known where to take them.
+    head: Chain,
+    /// The stored response being sent and how much of it was queued.
+    hit: Option<(Hit, usize)>,
+    /// The upstream response, while it may still be stored.
+    filling: Option<Filling>,
+}
+
+impl<C, U> Pair<C, U> {
+    fn new(downstream: C) -> Self {
+        Self {
+            downstream: Side::new(downstream),
+            upstream: None,
+            head: Chain::default(),
+            hit: None,
+            filling: None,
+        }
+    }
+}
+
+fn flatten(ring: &RingBuffer, chain: &Chain, skip: usize, into: &mut Vec<u8>) {
+    let mut slices = [IoSlice::new(&[]); IO_SLICES];
+    let count = ring.io_slices(chain, &mut slices);
+    let bytes = slices[..count].iter().flat_map(|slice| slice.iter());
+    into.extend(bytes.skip(skip));
+}
+
+/// Queue the rest of the hit on `pair` for downstream, at most `window` bytes at a time, and
+/// write it; returns true once all of it was written and writing was shut down.
+fn send_hit<C, U, X>(
+    pair: &mut Pair<C, U>,
+    token: usize,
+    ring: &mut RingBuffer,
+    window: usize,
+) -> std::io::Result<bool>
+where
+    C: Write + HalfClose,
+    X: Connector<C>,
+{
+    let Pair {
+        downstream,
+        hit: Some((hit, sent)),
+        ..
+    } = pair
+    else {
+        return Ok(false);
+    };
+    loop {
+        let end = hit
+            .len
+            .min(*sent + window.saturating_sub(downstream.send.len()));
+        let mut writer = ring.chain_writer(&mut downstream.send, token);
+        let mut queued = false;
+        while *sent < end {
+            match writer.write(&hit.bytes()[*sent..end]) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    *sent += n;
+                    queued = true;
+                }
+            }
+        }
+        if !queued && downstream.send.is_empty() && *sent < hit.len {
+            // the ring is too full to send any of it
+            return Err(OutOfMemory.into());
+        }
+        let written = write_side::<C, X>(ring, downstream, hit.len == *sent)?;
+        if downstream.shut {
+            return Ok(true);
+        }
+        if !queued && !written {
+            return Ok(false);
+        }
+    }
+}
+
+/// Move bytes both ways like [`crate::proxy::Proxy`] does, writing the response to its entry file
+/// until it can be stored in `cache` or turns out it cannot; returns true once both ends
+/// finished.
+fn fetch<C, U, X>(
+    pair: &mut Pair<C, U>,
+    token: usize,
+    ring: &mut RingBuffer,
+    window: usize,
+    cache: &mut Cache,
+) -> std::io::Result<bool>
+where
+    C: Read + Write + HalfClose,
+    U: Read + Write + HalfClose,
+    X: Connector<C> + Connector<U>,
+{
+    let Pair {
+        downstream,
+        upstream: Some(upstream),
+        filling,
+        ..
+    } = pair
+    else {
+        return Ok(false);
+    };
+    loop {
+        let mut progress = false;
+        progress |= read_side::<C, X>(ring, downstream, &mut upstream.send, token, window)?;
+        let before = downstream.send.len();
+        progress |= read_side::<U, X>(ring, upstream, &mut downstream.send, token, window)?;
+        if let Some(filled) = filling.as_mut() {
+            let mut slices = [IoSlice::new(&[]); IO_SLICES];
+            let count = ring.io_slices(&downstream.send, &mut slices);
+            let (mut skip, mut fill) = (before, Ok(Fill::Partial));
+            for slice in &slices[..count] {
+                let read = &slice[skip.min(slice.len())..];
+                skip -= slice.len() - read.len();
+                if !read.is_empty() {
+                    fill = filled.feed(read, false, cache.max_entry);
+                }
+                if !matches!(fill, Ok(Fill::Partial)) {
+                    break;
+                }
+            }
+            if upstream.eof && matches!(fill, Ok(Fill::Partial)) {
+                fill = filled.feed(&[], true, cache.max_entry);
+            }
+            if !matches!(fill, Ok(Fill::Partial)) {
+                if let Some(filled) = filling.take() {
+                    filled.settle(fill, cache);
+                }
+            }
+        }
+        progress |= write_side::<U, X>(ring, upstream, downstream.eof)?;
+        progress |= write_side::<C, X>(ring, downstream, upstream.eof)?;
+        if downstream.shut && upstream.shut {
+            return Ok(true);
+        }
+        if !progress {
+            return Ok(false);
+        }
+    }
+}
+
+/// Read the request on `pair` until it is known where to take it and set up the hit to send or
+/// the entry file to write the response to; returns true when upstream has to be connected to.
+fn route_pair<C, U, X>(
+    pair: &mut Pair<C, U>,
+    token: usize,
+    ring: &mut RingBuffer,
+    window: usize,
+    cache: &mut Cache,
+    keying: Keying,
+) -> std::io::Result<bool>
+where
+    C: Read,
+    X: Connector<C>,
+{
+    read_side::<C, X>(ring, &mut pair.downstream, &mut pair.head, token, window)?;
+    let mut head = Vec::new();
+    flatten(ring, &pair.head, 0, &mut head);
+    let full = pair.head.len() >= window;
+    let (key, authorized) = match route(keying, &head, pair.downstream.eof, full) {
+        Route::Wait => return Ok(false),
+        Route::Pass => return Ok(true),
+        Route::Fetch(key) => (key, false),
+        Route::Authorized(key) => (key, true),
+        Route::Lookup(key) => match cache.get(&key, Instant::now())? {
+            Some(hit) => {
+                ring.release_chain(&mut pair.head);
+                pair.hit = Some((hit, 0));
+                return Ok(false);
+            }
+            None => (key, false),
+        },
+    };
+    // a response that cannot be written to disk is still passed on
+    pair.filling = Filling::new(cache, key, authorized, keying).ok();
+    Ok(true)
+}
+
+/// Connect `pair` to `upstream` and queue what was read from downstream for it.
+fn open<C, U, X>(
+    pair: &mut Pair<C, U>,
+    token: usize,
+    upstream: <X as Connect<U>>::Addr,
+    poller: &Poll,
+) -> std::io::Result<()>
+where
+    X: Connect<U> + Registry<U> + EventLoop<Poller = Poll, Interest = Interest>,
+{
+    let mut stream = X::connect(upstream)?;
+    let interest = X::add_readable_to_interest(X::writeable_interest());
+    X::register(poller, &mut stream, token + 1, interest)?;
+    let mut upstream = Side::new(stream);
+    upstream.send = std::mem::take(&mut pair.head);
+    pair.upstream = Some(upstream);
+    Ok(())
+}
+
+fn close<C, U, X>(mut pair: Pair<C, U>, poller: &Poll, ring: &mut RingBuffer) -> std::io::Result<()>
+where
+    X: Registry<C> + Registry<U> + EventLoop<Poller = Poll>,
+{
+    if let Some(filling) = pair.filling.take() {
+        let _ = std::fs::remove_file(&filling.path);
+    }
+    ring.release_chain(&mut pair.head);
+    ring.release_chain(&mut pair.downstream.send);
+    <X as Registry<C>>::deregister(poller, &mut pair.downstream.stream)?;
+    if let Some(mut upstream) = pair.upstream {
+        ring.release_chain(&mut upstream.send);
+        <X as Registry<U>>::deregister(poller, &mut upstream.stream)?;
+    }
+    Ok(())
+}
+
+pub trait CachingProxy<C, U>:
+    ListenerRegistry<C>
+    + Listener<C, Listener = <Self as ListenerRegistry<C>>::Listener>
+    + Registry<U>
+    + Connect<U>
+    + Connector<C>
+    + Connector<U>
+    + EventLoop<Poller = Poll, Interest = Interest>
+    + Sized
+where
+    C: Read + Write + HalfClose,
+    U: Read + Write + HalfClose,
+    <Self as Listener<C>>::Listener: Source,
+    <Self as Connect<U>>::Addr: Clone,
+    <Self as EventLoop>::Event: Debug,
+{
+    /// Bytes buffered in each direction of a connection, and the most a raw request key can
+    /// take up.
+    const WINDOW: usize = 64 * 1024;
+
+    /// Proxy every connection accepted on `server` to `upstream` like [`crate::proxy::Proxy`],
+    /// but answer requests `cache` holds a fresh response for from its file without connecting
+    /// upstream, and store the responses to the others as `keying` says, until `shutdown` is
+    /// signalled; then unbind `server`.
+    ///
+    /// Only the first request on a connection goes through the cache; what follows is passed
+    /// on. A connection answered from the cache is closed once the response was sent.
+    fn serve<const SERVER: usize>(
+        mut server: <Self as Listener<C>>::Listener,
+        upstream: <Self as Connect<U>>::Addr,
+        cache: &mut Cache,
+        keying: Keying,
+        event_buffer_capacity: usize,
+        ring: &mut RingBuffer,
+        shutdown: &Shutdown,
+    ) -> std::io::Result<()> {
+        let mut poller = Self::new_poller()?;
+        let mut events = Self::new_events_buffer(event_buffer_capacity);
+        <Self as Registry<<Self as ListenerRegistry<C>>::Listener>>::register(
+            &poller,
+            &mut server,
+            SERVER,
+            Self::readable_interest(),
+        )?;
+        let _watch = shutdown.watch(&poller, SHUTDOWN)?;
+        let interest = Self::add_readable_to_interest(Self::writeable_interest());
+        // the accepted connection of the pair under `key` takes the token `SERVER + 1 + 2 * key`,
+        // its upstream the next one
+        let mut pairs: Slab<Pair<C, U>> = Slab::new();
+        while !shutdown.is_signalled() {
+            if let Err(err) = Self::poll(&mut poller, &mut events, None) {
+                if Interrupted == err.kind() {
+                    continue;
+                }
+                return Err(err);
+            }
+            for event in Self::events_iter(&events) {
+                let token = Self::event_token(event);
+                if SHUTDOWN == token {
+                    continue;
+                }
+                if SERVER == token {
+                    loop {
+                        let mut downstream = match Self::accept(&server) {
+                            Ok((downstream, _)) => downstream,
+                            Err(e) if WouldBlock == e.kind() => break,
+                            Err(e) if Interrupted == e.kind() => continue,
+                            Err(e) if ConnectionAborted == e.kind() => continue,
+                            Err(e) => return Err(e),
+                        };
+                        let token = SERVER + 1 + 2 * pairs.vacant_key();
+                        <Self as Registry<C>>::register(&poller, &mut downstream, token, interest)?;
+                        pairs.insert(Pair::new(downstream));
+                    }
+                    continue;
+                }
+                let Some(key) = token.checked_sub(SERVER + 1).map(|offset| offset / 2) else {
+                    continue;
+                };
+                let token = SERVER + 1 + 2 * key;
+                let Some(pair) = pairs.get_mut(key) else {
+                    continue;
+                };
+                let mut done = false;
+                if pair.upstream.is_none() && pair.hit.is_none() {
+                    let window = Self::WINDOW;
+                    done = match route_pair::<C, U, Self>(pair, token, ring, window, cache, keying)
+                    {
+                        Ok(true) => {
+                            open::<C, U, Self>(pair, token, upstream.clone(), &poller).is_err()
+                        }
+                        Ok(false) => false,
+                        Err(_) => true,
+                    };
+                }
+                if !done && pair.hit.is_some() {
+                    done = send_hit::<C, U, Self>(pair, token, ring, Self::WINDOW).unwrap_or(true);
+                } else if !done {
+                    done =
+                        fetch::<C, U, Self>(pair, token, ring, Self::WINDOW, cache).unwrap_or(true);
+                }
+                if done {
+                    if let Some(pair) = pairs.remove(key) {
+                        close::<C, U, Self>(pair, &poller, ring)?;
+                    }
+                }
+            }
+        }
+        for (_, pair) in pairs.drain() {
+            close::<C, U, Self>(pair, &poller, ring)?;
+        }
+        <Self as Registry<<Self as ListenerRegistry<C>>::Listener>>::deregister(
+            &poller,
+            &mut server,
+        )?;
+        Self::unbind(server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::Shutdown as HalfShutdown,
+        sync::atomic::{AtomicUsize, Ordering::SeqCst},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use mio::net::TcpStream;
+
+    use super::{freshness, Cache, CachingProxy, Keying};
+    use crate::{
+        http::{parse_response, Status},
+        mmap::page_size,
+        ring::RingBuffer,
+        tests::new_loopback_address,
+        Listener, MioEventLoop, ReadWriteConnectorAdapter, Shutdown,
+    };
+
+    static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+    struct CacheServer;
+
+    impl MioEventLoop for CacheServer {}
+    impl ReadWriteConnectorAdapter for CacheServer {}
+    impl CachingProxy<TcpStream, TcpStream> for CacheServer {}
+
+    fn cache_dir() -> std::path::PathBuf {
+        let n = DIRS.fetch_add(1, SeqCst);
+        std::env::temp_dir().join(format!("elog-{}-{n}.cache", std::process::id()))
+    }
+
+    fn files(dir: &std::path::Path) -> usize {
+        std::fs::read_dir(dir).unwrap().count()
+    }
+
+    /// Send `request` through the proxy at `addr`, end the stream and read the answer to its end.
+    fn exchange(addr: std::net::SocketAddr, request: &[u8]) -> Vec<u8> {
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream.write_all(request).unwrap();
+        stream.shutdown(HalfShutdown::Write).unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        received
+    }
+
+    /// Answer one request per connection with `answer` for it until one is `stop`, then return
+    /// the requests answered.
+    fn upstream(
+        listener: std::net::TcpListener,
+        stop: &[u8],
+        answer: impl Fn(&[u8]) -> Vec<u8>,
+    ) -> Vec<Vec<u8>> {
+        let mut requests = Vec::new();
+        loop {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") && !request.ends_with(b"!") {
+                match stream.read(&mut buf).unwrap() {
+                    0 => break,
+                    n => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            if request == stop {
+                return requests;
+            }
+            stream.write_all(&answer(&request)).unwrap();
+            requests.push(request);
+        }
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_entries_and_expired_ones() {
+        let page = page_size();
+        let dir = cache_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("7.entry"), b"left over").unwrap();
+        let mut cache = Cache::new(&dir, 3 * page as u64).unwrap();
+        assert_eq!(files(&dir), 0);
+        let now = Instant::now();
+
+        let large = vec![7; page + 1];
+        assert!(cache.insert(b"a", b"first", None, now).unwrap());
+        assert!(cache.insert(b"b", &large, None, now).unwrap());
+        assert_eq!((cache.len(), cache.used()), (2, 3 * page as u64));
+        // a was used more recently than b, which makes room for c
+        assert_eq!(cache.get(b"a", now).unwrap().unwrap().bytes(), b"first");
+        assert!(cache
+            .insert(b"c", b"third", Some(Duration::from_secs(1)), now)
+            .unwrap());
+        assert!(cache.get(b"b", now).unwrap().is_none());
+        assert_eq!(files(&dir), 2);
+        assert_eq!(
+            std::fs::metadata(dir.join("0.entry")).unwrap().len(),
+            page as u64
+        );
+
+        let later = now + Duration::from_secs(1);
+        assert!(cache.get(b"c", later).unwrap().is_none());
+        assert!(cache.get(b"a", later).unwrap().is_some());
+        assert!(!cache
+            .insert(b"d", &vec![0; 3 * page + 1], None, now)
+            .unwrap());
+        assert_eq!((cache.len(), files(&dir)), (1, 1));
+
+        // an entry whose file went missing is dropped instead of failing the lookup
+        std::fs::remove_file(&cache.entries[&b"a"[..]].path).unwrap();
+        assert!(cache.get(b"a", later).unwrap().is_none());
+        assert_eq!((cache.len(), cache.used()), (0, 0));
+
+        drop(cache);
+        assert_eq!(files(&dir), 0);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn freshness_follows_cache_control_and_expires() {
+        let fresh = |head: &[u8], default| {
+            let Ok(Status::Complete((response, _))) = parse_response(head) else {
+                panic!("response head");
+            };
+            freshness(&response, default)
+        };
+        let minute = Some(Duration::from_secs(60));
+        assert_eq!(fresh(b"HTTP/1.1 200 OK\r\n\r\n", None), None);
+        assert_eq!(fresh(b"HTTP/1.1 200 OK\r\n\r\n", minute), minute);
+        assert_eq!(
+            fresh(
+                b"HTTP/1.1 200 OK\r\nCache-Control: public, max-age=60\r\n\r\n",
+                None
+            ),
+            minute
+        );
+        assert_eq!(
+            fresh(
+                b"HTTP/1.1 200 OK\r\nCache-Control: max-age=5, s-maxage=\"60\"\r\n\r\n",
+                None
+            ),
+            minute
+        );
+        assert_eq!(
+            fresh(
+                b"HTTP/1.1 200 OK\r\nCache-Control: no-store\r\n\r\n",
+                minute
+            ),
+            None
+        );
+        assert_eq!(
+            fresh(
+                b"HTTP/1.1 200 OK\r\nCache-Control: max-age=0\r\n\r\n",
+                minute
+            ),
+            None
+        );
+        let expires = b"HTTP/1.1 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+            Expires: Sun, 06 Nov 1994 08:50:37 GMT\r\n\r\n";
+        assert_eq!(fresh(expires, None), minute);
+        let expired = b"HTTP/1.1 200 OK\r\nExpires: 0\r\n\r\n";
+        assert_eq!(fresh(expired, minute), None);
+    }
+
+    #[test]
+    fn serves_fresh_http_responses_from_disk() {
+        let dir = cache_dir();
+        let mut cache = Cache::new(&dir, 1 << 20).unwrap();
+        let mut ring = RingBuffer::new(16);
+        let upstream_listener = std::net::TcpListener::bind(new_loopback_address()).unwrap();
+        let upstream_addr = upstream_listener.local_addr().unwrap();
+        let addr = new_loopback_address();
+        let listener = <CacheServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+        let stop = b"GET /stop HTTP/1.1\r\nHost: a\r\n\r\n";
+        let answer = |request: &[u8]| {
+            let control = match request.starts_with(b"GET /public ") {
+                true => "max-age=60",
+                false => "no-store",
+            };
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nCache-Control: {control}\r\nContent-Length: 5\r\n\r\nhello"
+            );
+            response.into_bytes()
+        };
+
+        let requests = thread::scope(|s| {
+            let upstream = s.spawn(|| upstream(upstream_listener, stop, answer));
+            let proxy = s.spawn(|| {
+                <CacheServer as CachingProxy<TcpStream, TcpStream>>::serve::<1>(
+                    listener,
+                    upstream_addr,
+                    &mut cache,
+                    Keying::Http,
+                    128,
+                    &mut ring,
+                    &shutdown,
+                )
+            });
+            for path in ["/public", "/public", "/private", "/private"] {
+                let request = format!("GET {path} HTTP/1.1\r\nHost: cached\r\n\r\n");
+                let response = exchange(addr, request.as_bytes());
+                assert!(response.ends_with(b"\r\n\r\nhello"));
+            }
+            let no_cache = b"GET /public HTTP/1.1\r\nHost: cached\r\nPragma: no-cache\r\n\r\n";
+            assert!(exchange(addr, no_cache).ends_with(b"hello"));
+            // a host that is not one is passed, whatever the key it would make
+            let spaced = b"GET /public HTTP/1.1\r\nHost: cached /public\r\n\r\n";
+            assert!(exchange(addr, spaced).ends_with(b"hello"));
+            std::net::TcpStream::connect(upstream_addr)
+                .unwrap()
+                .write_all(stop)
+                .unwrap();
+            shutdown.signal().unwrap();
+            proxy.join().unwrap().unwrap();
+            upstream.join().unwrap()
+        });
+
+        let paths: Vec<&[u8]> = requests.iter().map(|request| &request[4..8]).collect();
+        assert_eq!(paths, [b"/pub", b"/pri", b"/pri", b"/pub", b"/pub"]);
+        assert_eq!((cache.len(), ring.in_use()), (1, 0));
+        assert!(cache
+            .get(b"GET cached /public", Instant::now())
+            .unwrap()
+            .is_some());
+        drop(cache);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn stores_only_responses_that_may_be_shared() {
+        let dir = cache_dir();
+        let mut cache = Cache::new(&dir, 1 << 20).unwrap();
+        let mut ring = RingBuffer::new(16);
+        let upstream_listener = std::net::TcpListener::bind(new_loopback_address()).unwrap();
+        let upstream_addr = upstream_listener.local_addr().unwrap();
+        let addr = new_loopback_address();
+        let listener = <CacheServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+        let stop = b"GET /stop HTTP/1.1\r\nHost: a\r\n\r\n";
+        let answer = |request: &[u8]| {
+            let headers = match &request[4..8] {
+                b"/var" => "Cache-Control: max-age=60\r\nVary: Accept",
+                b"/sha" => "Cache-Control: public, max-age=60",
+                _ => "Cache-Control: max-age=60",
+            };
+            let response =
+                format!("HTTP/1.1 200 OK\r\n{headers}\r\nContent-Length: 5\r\n\r\nhello");
+            response.into_bytes()
+        };
+
+        let requests = thread::scope(|s| {
+            let upstream = s.spawn(|| upstream(upstream_listener, stop, answer));
+            let proxy = s.spawn(|| {
+                <CacheServer as CachingProxy<TcpStream, TcpStream>>::serve::<1>(
+                    listener,
+                    upstream_addr,
+                    &mut cache,
+                    Keying::Http,
+                    128,
+                    &mut ring,
+                    &shutdown,
+                )
+            });
+            let exchanges = [
+                ("/vary", ""),
+                ("/vary", ""),
+                ("/auth", "Authorization: Basic YTpi\r\n"),
+                ("/auth", ""),
+                ("/shared", "Authorization: Basic YTpi\r\n"),
+                ("/shared", ""),
+            ];
+            for (path, credentials) in exchanges {
+                let request = format!("GET {path} HTTP/1.1\r\nHost: a\r\n{credentials}\r\n");
+                let response = exchange(addr, request.as_bytes());
+                assert!(response.ends_with(b"\r\n\r\nhello"));
+            }
+            std::net::TcpStream::connect(upstream_addr)
+                .unwrap()
+                .write_all(stop)
+                .unwrap();
+            shutdown.signal().unwrap();
+            proxy.join().unwrap().unwrap();
+            upstream.join().unwrap()
+        });
+
+        // the shared response stored for a request with credentials answered the one without
+        let paths: Vec<&[u8]> = requests.iter().map(|request| &request[4..8]).collect();
+        assert_eq!(paths, [b"/var", b"/var", b"/aut", b"/aut", b"/sha"]);
+        assert_eq!((cache.len(), ring.in_use()), (2, 0));
+        let now = Instant::now();
+        assert!(cache.get(b"GET a /auth", now).unwrap().is_some());
+        assert!(cache.get(b"GET a /vary", now).unwrap().is_none());
+        drop(cache);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn streams_responses_up_to_the_longest_entry_into_their_files() {
+        let dir = cache_dir();
+        let mut cache = Cache::new(&dir, 1 << 20).unwrap().max_entry(150_000);
+        let mut ring = RingBuffer::new(16);
+        let upstream_listener = std::net::TcpListener::bind(new_loopback_address()).unwrap();
+        let upstream_addr = upstream_listener.local_addr().unwrap();
+        let addr = new_loopback_address();
+        let listener = <CacheServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+        let stop = b"GET /stop HTTP/1.1\r\nHost: a\r\n\r\n";
+        // chunked bodies of 100 and 200 chunks of 1000 bytes, more than the ring holds
+        let body = |chunks: usize| {
+            let mut body = Vec::new();
+            for i in 0..chunks {
+                body.extend_from_slice(b"3e8\r\n");
+                body.extend_from_slice(&[b'a' + (i % 26) as u8; 1000]);
+                body.extend_from_slice(b"\r\n");
+            }
+            body.extend_from_slice(b"0\r\n\r\n");
+            body
+        };
+        let answer = |request: &[u8]| {
+            let chunks = match request.starts_with(b"GET /long ") {
+                true => 200,
+                false => 100,
+            };
+            let head = "HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\n";
+            let mut response = format!("{head}Transfer-Encoding: chunked\r\n\r\n").into_bytes();
+            response.extend_from_slice(&body(chunks));
+            response
+        };
+
+        let requests = thread::scope(|s| {
+            let upstream = s.spawn(|| upstream(upstream_listener, stop, answer));
+            let proxy = s.spawn(|| {
+                <CacheServer as CachingProxy<TcpStream, TcpStream>>::serve::<1>(
+                    listener,
+                    upstream_addr,
+                    &mut cache,
+                    Keying::Http,
+                    128,
+                    &mut ring,
+                    &shutdown,
+                )
+            });
+            for (path, chunks) in [
+                ("/long", 200),
+                ("/long", 200),
+                ("/fits", 100),
+                ("/fits", 100),
+            ] {
+                let request = format!("GET {path} HTTP/1.1\r\nHost: a\r\n\r\n");
+                let response = exchange(addr, request.as_bytes());
+                assert!(response.ends_with(&body(chunks)));
+            }
+            std::net::TcpStream::connect(upstream_addr)
+                .unwrap()
+                .write_all(stop)
+                .unwrap();
+            shutdown.signal().unwrap();
+            proxy.join().unwrap().unwrap();
+            upstream.join().unwrap()
+        });
+
+        let paths: Vec<&[u8]> = requests.iter().map(|request| &request[4..9]).collect();
+        assert_eq!(paths, [b"/long", b"/long", b"/fits"]);
+        assert_eq!((cache.len(), ring.in_use()), (1, 0));
+        let hit = cache.get(b"GET a /fits", Instant::now()).unwrap().unwrap();
+        assert!(hit.bytes().ends_with(&body(100)));
+        drop(hit);
+        drop(cache);
+        assert_eq!(files(&dir), 0);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn raw_responses_are_filed_under_the_whole_request() {
+        let dir = cache_dir();
+        let mut cache = Cache::new(&dir, 1 << 20).unwrap();
+        let mut ring = RingBuffer::new(16);
+        let upstream_listener = std::net::TcpListener::bind(new_loopback_address()).unwrap();
+        let upstream_addr = upstream_listener.local_addr().unwrap();
+        let addr = new_loopback_address();
+        let listener = <CacheServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+        let reverse = |request: &[u8]| request.iter().rev().copied().collect();
+
+        let requests = thread::scope(|s| {
+            let upstream = s.spawn(|| upstream(upstream_listener, b"stop!", reverse));
+            let proxy = s.spawn(|| {
+                <CacheServer as CachingProxy<TcpStream, TcpStream>>::serve::<1>(
+                    listener,
+                    upstream_addr,
+                    &mut cache,
+                    Keying::Raw,
+                    128,
+                    &mut ring,
+                    &shutdown,
+                )
+            });
+            for request in [&b"ping!"[..], b"ping!", b"pong!", b"ping!"] {
+                let expected: Vec<u8> = request.iter().rev().copied().collect();
+                assert_eq!(exchange(addr, request), expected);
+            }
+            std::net::TcpStream::connect(upstream_addr)
+                .unwrap()
+                .write_all(b"stop!")
+                .unwrap();
+            shutdown.signal().unwrap();
+            proxy.join().unwrap().unwrap();
+            upstream.join().unwrap()
+        });
+
+        assert_eq!(requests, [b"ping!", b"pong!"]);
+        assert_eq!((cache.len(), ring.in_use()), (2, 0));
+        drop(cache);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
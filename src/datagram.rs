@@ -0,0 +1,398 @@
+use std::{
+    fmt::Debug,
+    io::{ErrorKind::*, IoSlice, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use mio::{net::UdpSocket, Interest, Poll};
+
+use crate::{
+    ring::{Chain, RingBuffer, Span},
+    EventLoop, Registry, Shutdown, IO_SLICES, SHUTDOWN,
+};
+
+/// Largest payload a UDP datagram over IPv4 can carry.
+pub const MAX_DATAGRAM: usize = 65_507;
+
+pub trait Datagram<S> {
+    fn bind(addr: SocketAddr) -> std::io::Result<S>;
+    fn recv_from(socket: &S, receive: &mut [u8]) -> std::io::Result<(usize, SocketAddr)>;
+    /// Send the concatenation of `send` to `addr` as one datagram.
+    fn send_to_vectored(
+        socket: &S,
+        send: &[IoSlice<'_>],
+        addr: SocketAddr,
+    ) -> std::io::Result<usize>;
+}
+
+impl<T> Datagram<UdpSocket> for T {
+    #[inline]
+    fn bind(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+        UdpSocket::bind(addr)
+    }
+
+    #[inline]
+    fn recv_from(socket: &UdpSocket, receive: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        socket.recv_from(receive)
+    }
+
+    #[cfg(unix)]
+    fn send_to_vectored(
+        socket: &UdpSocket,
+        send: &[IoSlice<'_>],
+        addr: SocketAddr,
+    ) -> std::io::Result<usize> {
+        use std::os::fd::{AsRawFd, BorrowedFd};
+
+        // SAFETY: the descriptor stays open for as long as `socket` is borrowed
+        let fd = unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) };
+        socket2::SockRef::from(&fd).send_to_vectored(send, &addr.into())
+    }
+
+    #[cfg(not(unix))]
+    fn send_to_vectored(
+        socket: &UdpSocket,
+        send: &[IoSlice<'_>],
+        addr: SocketAddr,
+    ) -> std::io::Result<usize> {
+        socket.send_to(&send.concat(), addr)
+    }
+}
+
+/// Protocol logic driven by [`DatagramServer`]; every datagram stands on its own.
+pub trait DatagramHandler {
+    /// `datagram` arrived from `source`. Whatever is written to `reply` is sent back to `source`
+    /// as a single datagram; nothing is sent when nothing is written.
+    fn received<W: Write>(
+        &mut self,
+        source: SocketAddr,
+        datagram: &[u8],
+        reply: &mut W,
+    ) -> std::io::Result<()>;
+}
+
+pub trait DatagramServer<S>:
+    Registry<S> + Datagram<S> + EventLoop<Poller = Poll, Interest = Interest> + Sized
+where
+    <Self as EventLoop>::Event: Debug,
+{
+    /// Datagrams are received into a run of segments this long; longer ones are truncated.
+    const MAX_DATAGRAM: usize = MAX_DATAGRAM;
+
+    fn server<const SERVER: usize, H: DatagramHandler>(
+        addr: SocketAddr,
+        event_buffer_capacity: usize,
+        ring: &mut RingBuffer,
+        handler: &mut H,
+        shutdown: &Shutdown,
+    ) -> std::io::Result<()> {
+        let socket = Self::bind(addr)?;
+        Self::serve::<SERVER, H>(socket, event_buffer_capacity, ring, handler, shutdown)
+    }
+
+    /// Answer every datagram arriving on `socket` with `handler` until `shutdown` is signalled.
+    ///
+    /// Datagrams and replies live in segments of `ring`. A datagram that arrives while no run
+    /// of [`DatagramServer::MAX_DATAGRAM`] bytes is free, and a reply that fails or cannot be
+    /// sent right away, are dropped, as the network may drop any datagram; only failures of the
+    /// socket or the poller stop the server.
+    fn serve<const SERVER: usize, H: DatagramHandler>(
+        mut socket: S,
+        event_buffer_capacity: usize,
+        ring: &mut RingBuffer,
+        handler: &mut H,
+        shutdown: &Shutdown,
+    ) -> std::io::Result<()> {
+        let mut poller = Self::new_poller()?;
+        let mut events = Self::new_events_buffer(event_buffer_capacity);
+        Self::register(&poller, &mut socket, SERVER, Self::readable_interest())?;
+        let _watch = shutdown.watch(&poller, SHUTDOWN)?;
+        let mut received = Span::default();
+        let mut reply = Chain::default();
+        let mut serve = || -> std::io::Result<()> {
+            while !shutdown.is_signalled() {
+                if let Err(err) = Self::poll(&mut poller, &mut events, None) {
+                    if Interrupted == err.kind() {
+                        continue;
+                    }
+                    return Err(err);
+                }
+                for event in Self::events_iter(&events) {
+                    if SERVER != Self::event_token(event) {
+                        continue;
+                    }
+                    loop {
+                        let reserved = ring.reserve(&mut received, SERVER, Self::MAX_DATAGRAM);
+                        let mut dropped = [0; 1];
+                        let spare = match reserved {
+                            Ok(()) => ring.spare_mut(&mut received, SERVER)?,
+                            Err(ref err) if OutOfMemory == err.kind() => &mut dropped[..],
+                            Err(err) => return Err(err),
+                        };
+                        let (n, source) = match Self::recv_from(&socket, spare) {
+                            Ok(received) => received,
+                            Err(ref err) if WouldBlock == err.kind() => break,
+                            Err(ref err) if Interrupted == err.kind() => continue,
+                            // an earlier reply was refused by its destination
+                            Err(ref err) if ConnectionRefused == err.kind() => continue,
+                            Err(ref err) if ConnectionReset == err.kind() => continue,
+                            Err(err) => return Err(err),
+                        };
+                        if reserved.is_err() {
+                            continue;
+                        }
+                        ring.commit(&mut received, n);
+                        let (datagram, mut send) = ring.writer(&received, &mut reply, SERVER);
+                        let handled = handler.received(source, datagram, &mut send);
+                        ring.consume(&mut received, n);
+                        if handled.is_ok() && !reply.is_empty() {
+                            let mut send = [IoSlice::new(&[]); IO_SLICES];
+                            let count = ring.io_slices(&reply, &mut send);
+                            while let Err(ref err) =
+                                Self::send_to_vectored(&socket, &send[..count], source)
+                            {
+                                if Interrupted != err.kind() {
+                                    break;
+                                }
+                            }
+                        }
+                        ring.release_chain(&mut reply);
+                    }
+                }
+            }
+            Ok(())
+        };
+        let served = serve();
+        ring.release_span(&mut received);
+        ring.release_chain(&mut reply);
+        Self::deregister(&poller, &mut socket)?;
+        served
+    }
+}
+
+pub trait DatagramClient<S>:
+    Registry<S> + Datagram<S> + EventLoop<Poller = Poll, Interest = Interest>
+{
+    /// Send `send` to `addr` as one datagram from an ephemeral port and receive the first datagram
+    /// `addr` answers with into `ring`, failing with `TimedOut` if none arrives within `timeout`.
+    ///
+    /// An answer that arrives while no run of [`MAX_DATAGRAM`] bytes is free in `ring` is dropped
+    /// like one the network lost. The returned span is owned by `CLIENT` and released by the
+    /// caller once it is done with it.
+    fn client<const CLIENT: usize>(
+        addr: SocketAddr,
+        event_buffer_capacity: usize,
+        ring: &mut RingBuffer,
+        send: &[u8],
+        timeout: Option<Duration>,
+    ) -> std::io::Result<Span>
+    where
+        <Self as EventLoop>::Event: Debug,
+    {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut received = Span::default();
+        let mut exchange = || -> std::io::Result<()> {
+            let mut poller = Self::new_poller()?;
+            let mut events = Self::new_events_buffer(event_buffer_capacity);
+            let unspecified = match addr {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            };
+            let mut socket = Self::bind(SocketAddr::new(unspecified, 0))?;
+            let interest = Self::add_readable_to_interest(Self::writeable_interest());
+            Self::register(&poller, &mut socket, CLIENT, interest)?;
+            let mut sent = false;
+            loop {
+                let timeout = deadline.map(|deadline| deadline - Instant::now().min(deadline));
+                if Some(Duration::ZERO) == timeout {
+                    return Err(TimedOut.into());
+                }
+                if let Err(err) = Self::poll(&mut poller, &mut events, timeout) {
+                    if Interrupted == err.kind() {
+                        continue;
+                    }
+                    return Err(err);
+                }
+                for event in Self::events_iter(&events) {
+                    if CLIENT != Self::event_token(event) {
+                        continue;
+                    }
+                    if !sent && Self::event_is_writeable(event) {
+                        match Self::send_to_vectored(&socket, &[IoSlice::new(send)], addr) {
+                            Ok(_) => {
+                                sent = true;
+                                let interest = Self::readable_interest();
+                                Self::reregister(&poller, &mut socket, CLIENT, interest)?;
+                            }
+                            Err(ref err) if WouldBlock == err.kind() => {}
+                            Err(ref err) if Interrupted == err.kind() => {}
+                            Err(err) => return Err(err),
+                        }
+                    }
+                    if sent && Self::event_is_readable(event) {
+                        loop {
+                            let reserved = ring.reserve(&mut received, CLIENT, MAX_DATAGRAM);
+                            let mut dropped = [0; 1];
+                            let spare = match reserved {
+                                Ok(()) => ring.spare_mut(&mut received, CLIENT)?,
+                                Err(ref err) if OutOfMemory == err.kind() => &mut dropped[..],
+                                Err(err) => return Err(err),
+                            };
+                            match Self::recv_from(&socket, spare) {
+                                Ok((n, source)) if source == addr && reserved.is_ok() => {
+                                    ring.commit(&mut received, n);
+                                    return Ok(());
+                                }
+                                Ok(_) => {}
+                                Err(ref err) if WouldBlock == err.kind() => break,
+                                Err(ref err) if Interrupted == err.kind() => {}
+                                Err(err) => return Err(err),
+                            }
+                        }
+                    }
+                }
+            }
+        };
+        let exchanged = exchange();
+        if exchanged.is_err() {
+            ring.release_span(&mut received);
+        }
+        exchanged.map(|()| {
+            ring.shrink(&mut received);
+            received
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, net::SocketAddr, thread, time::Duration};
+
+    use mio::net::UdpSocket;
+
+    use crate::{
+        ring::{RingBuffer, Span},
+        tests::{new_loopback_address, TestClient, TestServer},
+        Shutdown,
+    };
+
+    use super::{Datagram, DatagramClient, DatagramHandler, DatagramServer};
+
+    impl DatagramServer<UdpSocket> for TestServer {}
+    impl DatagramClient<UdpSocket> for TestClient {}
+
+    /// Answers every datagram with its bytes reversed and remembers who sent what.
+    #[derive(Default)]
+    struct Reverse {
+        received: Vec<(SocketAddr, Vec<u8>)>,
+    }
+
+    impl DatagramHandler for Reverse {
+        fn received<W: Write>(
+            &mut self,
+            source: SocketAddr,
+            datagram: &[u8],
+            reply: &mut W,
+        ) -> std::io::Result<()> {
+            self.received.push((source, datagram.to_vec()));
+            let reversed: Vec<u8> = datagram.iter().rev().copied().collect();
+            reply.write_all(&reversed)
+        }
+    }
+
+    #[test]
+    fn replies_to_every_source() {
+        let mut ring = RingBuffer::new(20);
+        let mut client_ring = RingBuffer::new(20);
+        let mut handler = Reverse::default();
+        let addr = new_loopback_address();
+        let socket = TestServer::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                TestServer::serve::<1, _>(socket, 128, &mut ring, &mut handler, &shutdown)
+            });
+            for request in [&b"ping"[..], b"hello world", &[7; 5000]] {
+                let timeout = Some(Duration::from_secs(5));
+                let mut received =
+                    TestClient::client::<2>(addr, 128, &mut client_ring, request, timeout).unwrap();
+                let reversed: Vec<u8> = request.iter().rev().copied().collect();
+                assert_eq!(client_ring.bytes(&received), reversed);
+                assert_eq!(received.segments().count(), request.len().div_ceil(4096));
+                client_ring.release_span(&mut received);
+            }
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+
+        let sources: Vec<SocketAddr> = handler.received.iter().map(|(s, _)| *s).collect();
+        assert_eq!(sources.len(), 3);
+        assert!(sources.iter().all(|source| source.port() != addr.port()));
+        assert_eq!(handler.received[2].1.len(), 5000);
+        assert_eq!(handler.received[1].1, b"hello world");
+        assert_eq!((ring.in_use(), client_ring.in_use()), (0, 0));
+    }
+
+    #[test]
+    fn drops_datagrams_the_ring_has_no_room_for() {
+        // 8 of 20 segments are held elsewhere, fewer than a datagram may take are left
+        let mut ring = RingBuffer::new(20);
+        let mut client_ring = RingBuffer::new(20);
+        let mut held = [Span::default(), Span::default()];
+        ring.reserve(&mut held[0], 9, 8 * 4096).unwrap();
+        let mut handler = Reverse::default();
+        let addr = new_loopback_address();
+        let socket = TestServer::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                TestServer::serve::<1, _>(socket, 128, &mut ring, &mut handler, &shutdown)
+            });
+            let timeout = Some(Duration::from_millis(50));
+            let err =
+                TestClient::client::<2>(addr, 128, &mut client_ring, b"ping", timeout).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+        assert!(handler.received.is_empty());
+        ring.release_span(&mut held[0]);
+
+        // the answer is dropped by a client short of room in turn
+        client_ring.reserve(&mut held[1], 9, 8 * 4096).unwrap();
+        let shutdown = Shutdown::new();
+        let socket = TestServer::bind(addr).unwrap();
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                TestServer::serve::<1, _>(socket, 128, &mut ring, &mut handler, &shutdown)
+            });
+            let timeout = Some(Duration::from_millis(50));
+            let err =
+                TestClient::client::<2>(addr, 128, &mut client_ring, b"ping", timeout).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+        assert_eq!(handler.received.len(), 1);
+        client_ring.release_span(&mut held[1]);
+        assert_eq!((ring.in_use(), client_ring.in_use()), (0, 0));
+    }
+
+    #[test]
+    fn client_times_out_without_an_answer() {
+        let addr = new_loopback_address();
+        let silent = std::net::UdpSocket::bind(addr).unwrap();
+        let mut ring = RingBuffer::new(20);
+        let timeout = Some(Duration::from_millis(50));
+        let err = TestClient::client::<2>(addr, 128, &mut ring, b"anyone?", timeout).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(ring.in_use(), 0);
+
+        let mut request = [0; 16];
+        let (n, _) = silent.recv_from(&mut request).unwrap();
+        assert_eq!(&request[..n], b"anyone?");
+    }
+}
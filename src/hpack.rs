@@ -0,0 +1,579 @@
+use std::{collections::VecDeque, fmt};
+
+/// Size a decoder's dynamic table may grow to unless the peer is told otherwise.
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+/// The static table of RFC 7541 appendix A; index `i` is entry `i + 1`.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Code lengths of the canonical Huffman code of RFC 7541 appendix B, by symbol; 256 is EOS.
+const CODE_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28, //
+    28, 28, 28, 28, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 28, //
+    6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6, //
+    5, 5, 5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10, //
+    13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, //
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6, //
+    15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6, 6, 5, //
+    6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28, //
+    20, 22, 20, 20, 22, 22, 22, 23, 22, 23, 23, 23, 23, 23, 24, 23, //
+    24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24, //
+    22, 21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23, //
+    21, 21, 22, 21, 23, 22, 23, 23, 20, 22, 22, 22, 23, 22, 22, 23, //
+    26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25, //
+    19, 21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27, //
+    20, 24, 20, 21, 22, 21, 21, 23, 22, 22, 25, 25, 24, 24, 26, 23, //
+    26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26, //
+    30,
+];
+
+const MAX_CODE_LENGTH: usize = 30;
+const EOS: u16 = 256;
+
+/// The Huffman code rebuilt from its lengths: codes of one length are consecutive and ordered by
+/// symbol, so each length is described by its first code and where its symbols start.
+struct Huffman {
+    first: [u32; MAX_CODE_LENGTH + 1],
+    count: [u32; MAX_CODE_LENGTH + 1],
+    offset: [usize; MAX_CODE_LENGTH + 1],
+    symbols: [u16; 257],
+    codes: [u32; 257],
+}
+
+const HUFFMAN: Huffman = {
+    let mut huffman = Huffman {
+        first: [0; MAX_CODE_LENGTH + 1],
+        count: [0; MAX_CODE_LENGTH + 1],
+        offset: [0; MAX_CODE_LENGTH + 1],
+        symbols: [0; 257],
+        codes: [0; 257],
+    };
+    let (mut code, mut next) = (0u32, 0);
+    let mut len = 1;
+    while len <= MAX_CODE_LENGTH {
+        huffman.first[len] = code;
+        huffman.offset[len] = next;
+        let mut symbol = 0;
+        while symbol < 257 {
+            if CODE_LENGTHS[symbol] as usize == len {
+                huffman.symbols[next] = symbol as u16;
+                huffman.codes[symbol] = code;
+                huffman.count[len] += 1;
+                next += 1;
+                code += 1;
+            }
+            symbol += 1;
+        }
+        code <<= 1;
+        len += 1;
+    }
+    huffman
+};
+
+/// Why a header block could not be decoded; the connection it came from is unusable after this.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HpackError {
+    /// The block ended inside a representation.
+    Truncated,
+    /// An integer too large for this implementation.
+    Integer,
+    /// An index beyond the static and dynamic tables.
+    Index(usize),
+    /// A Huffman string with an EOS symbol or invalid padding.
+    Huffman,
+    /// A table size update above the size allowed by the decoder's settings.
+    TableSize(usize),
+    /// A table size update after the first field of a block.
+    TableSizeUpdate,
+    /// Fields adding up to more than the header list size the decoder allows.
+    ListSize(usize),
+}
+
+impl fmt::Display for HpackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HpackError::Truncated => write!(f, "truncated header block"),
+            HpackError::Integer => write!(f, "integer overflow in header block"),
+            HpackError::Index(index) => write!(f, "invalid header table index {index}"),
+            HpackError::Huffman => write!(f, "invalid huffman string"),
+            HpackError::TableSize(size) => write!(f, "header table size {size} too large"),
+            HpackError::TableSizeUpdate => write!(f, "header table size update after a field"),
+            HpackError::ListSize(size) => write!(f, "header list of {size} bytes too large"),
+        }
+    }
+}
+
+impl std::error::Error for HpackError {}
+
+impl From<HpackError> for std::io::Error {
+    fn from(err: HpackError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// A decoded header field.
+pub type Field = (Vec<u8>, Vec<u8>);
+
+fn decode_integer(block: &[u8], pos: &mut usize, prefix: u8) -> Result<usize, HpackError> {
+    let mask = (1u16 << prefix) as u8 - 1;
+    let first = *block.get(*pos).ok_or(HpackError::Truncated)?;
+    *pos += 1;
+    let mut value = usize::from(first & mask);
+    if value < usize::from(mask) {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let byte = *block.get(*pos).ok_or(HpackError::Truncated)?;
+        *pos += 1;
+        if shift > 28 {
+            return Err(HpackError::Integer);
+        }
+        value += usize::from(byte & 0x7f) << shift;
+        shift += 7;
+        if 0 == byte & 0x80 {
+            return Ok(value);
+        }
+    }
+}
+
+fn encode_integer(out: &mut Vec<u8>, flags: u8, prefix: u8, value: usize) {
+    let mask = (1u16 << prefix) as u8 - 1;
+    if value < usize::from(mask) {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | mask);
+    let mut value = value - usize::from(mask);
+    while value >= 0x80 {
+        out.push(0x80 | (value & 0x7f) as u8);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn decode_string(block: &[u8], pos: &mut usize) -> Result<Vec<u8>, HpackError> {
+    let huffman = 0x80 == block.get(*pos).ok_or(HpackError::Truncated)? & 0x80;
+    let len = decode_integer(block, pos, 7)?;
+    let end = pos.checked_add(len).ok_or(HpackError::Truncated)?;
+    let bytes = block.get(*pos..end).ok_or(HpackError::Truncated)?;
+    *pos = end;
+    match huffman {
+        true => huffman_decode(bytes),
+        false => Ok(bytes.to_vec()),
+    }
+}
+
+/// Decode a Huffman coded string.
+pub fn huffman_decode(bytes: &[u8]) -> Result<Vec<u8>, HpackError> {
+    let mut decoded = Vec::with_capacity(bytes.len() * 8 / 5);
+    let (mut code, mut len) = (0u32, 0);
+    for byte in bytes {
+        for bit in (0..8).rev() {
+            code = code << 1 | u32::from(byte >> bit & 1);
+            len += 1;
+            let rank = code.wrapping_sub(HUFFMAN.first[len]);
+            if rank < HUFFMAN.count[len] {
+                let symbol = HUFFMAN.symbols[HUFFMAN.offset[len] + rank as usize];
+                if EOS == symbol {
+                    return Err(HpackError::Huffman);
+                }
+                decoded.push(symbol as u8);
+                (code, len) = (0, 0);
+            } else if MAX_CODE_LENGTH == len {
+                return Err(HpackError::Huffman);
+            }
+        }
+    }
+    // what is left must be a prefix of EOS, which is all ones, and shorter than a byte
+    if len >= 8 || code != (1 << len) - 1 {
+        return Err(HpackError::Huffman);
+    }
+    Ok(decoded)
+}
+
+/// Huffman code `bytes` into `out`.
+pub fn huffman_encode(out: &mut Vec<u8>, bytes: &[u8]) {
+    let (mut bits, mut pending) = (0u64, 0);
+    for &byte in bytes {
+        let len = CODE_LENGTHS[usize::from(byte)];
+        bits = bits << len | u64::from(HUFFMAN.codes[usize::from(byte)]);
+        pending += len;
+        while pending >= 8 {
+            pending -= 8;
+            out.push((bits >> pending) as u8);
+        }
+    }
+    if pending > 0 {
+        // padded with the most significant bits of EOS
+        out.push((bits << (8 - pending)) as u8 | (0xff >> pending));
+    }
+}
+
+fn huffman_len(bytes: &[u8]) -> usize {
+    let bits: usize = bytes
+        .iter()
+        .map(|&b| usize::from(CODE_LENGTHS[usize::from(b)]))
+        .sum();
+    bits.div_ceil(8)
+}
+
+/// Turns header blocks back into fields, keeping the dynamic table the peer's encoder fills.
+#[derive(Debug)]
+pub struct Decoder {
+    table: VecDeque<Field>,
+    size: usize,
+    /// Current size limit, lowered or raised by the encoder within `max_size`.
+    limit: usize,
+    /// Limit announced to the peer.
+    max_size: usize,
+    /// Largest header list one block may decode to, in table entry sizes.
+    max_list: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new(DEFAULT_TABLE_SIZE)
+    }
+}
+
+impl Decoder {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            table: VecDeque::new(),
+            size: 0,
+            limit: max_size,
+            max_size,
+            max_list: usize::MAX,
+        }
+    }
+
+    /// Refuse blocks whose fields add up to more than `max_list`, counting every field like a
+    /// table entry, as SETTINGS_MAX_HEADER_LIST_SIZE does.
+    pub fn max_list(mut self, max_list: usize) -> Self {
+        self.max_list = max_list;
+        self
+    }
+
+    /// Decode a complete header block into its fields, in order.
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<Field>, HpackError> {
+        let mut fields = Vec::new();
+        let mut list = 0;
+        let mut pos = 0;
+        while pos < block.len() {
+            let byte = block[pos];
+            let field = if 0x80 == byte & 0x80 {
+                let index = decode_integer(block, &mut pos, 7)?;
+                self.get(index)?
+            } else if 0x40 == byte & 0xc0 {
+                let field = self.literal(block, &mut pos, 6)?;
+                self.insert(field.clone());
+                field
+            } else if 0x20 == byte & 0xe0 {
+                // size updates only open a block
+                if !fields.is_empty() {
+                    return Err(HpackError::TableSizeUpdate);
+                }
+                let size = decode_integer(block, &mut pos, 5)?;
+                if size > self.max_size {
+                    return Err(HpackError::TableSize(size));
+                }
+                self.limit = size;
+                self.evict(0);
+                continue;
+            } else {
+                // without indexing or never indexed, which only matters to intermediaries
+                self.literal(block, &mut pos, 4)?
+            };
+            list += entry_size(&field);
+            if list > self.max_list {
+                return Err(HpackError::ListSize(list));
+            }
+            fields.push(field);
+        }
+        Ok(fields)
+    }
+
+    fn literal(&self, block: &[u8], pos: &mut usize, prefix: u8) -> Result<Field, HpackError> {
+        let name = match decode_integer(block, pos, prefix)? {
+            0 => decode_string(block, pos)?,
+            index => self.get(index)?.0,
+        };
+        Ok((name, decode_string(block, pos)?))
+    }
+
+    fn get(&self, index: usize) -> Result<Field, HpackError> {
+        match index {
+            0 => Err(HpackError::Index(index)),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            }
+            _ => self
+                .table
+                .get(index - 62)
+                .cloned()
+                .ok_or(HpackError::Index(index)),
+        }
+    }
+
+    fn insert(&mut self, field: Field) {
+        let size = entry_size(&field);
+        self.evict(size);
+        // an entry larger than the whole table just empties it
+        if size <= self.limit {
+            self.size += size;
+            self.table.push_front(field);
+        }
+    }
+
+    /// Drop the oldest entries until `additional` more fits.
+    fn evict(&mut self, additional: usize) {
+        while self.size + additional > self.limit {
+            let Some(field) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= entry_size(&field);
+        }
+    }
+}
+
+fn entry_size((name, value): &Field) -> usize {
+    name.len() + value.len() + 32
+}
+
+/// Turns fields into header blocks without ever adding to the peer's dynamic table, so it needs
+/// no state of its own; strings are Huffman coded whenever that makes them shorter.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Encoder;
+
+impl Encoder {
+    /// Append the header block of `fields` to `out`; names must already be lower case.
+    pub fn encode<'a>(
+        &self,
+        fields: impl IntoIterator<Item = (&'a [u8], &'a [u8])>,
+        out: &mut Vec<u8>,
+    ) {
+        for (name, value) in fields {
+            let mut name_index = 0;
+            for (i, (static_name, static_value)) in STATIC_TABLE.iter().enumerate() {
+                if static_name.as_bytes() == name {
+                    if static_value.as_bytes() == value {
+                        encode_integer(out, 0x80, 7, i + 1);
+                        name_index = usize::MAX;
+                        break;
+                    }
+                    if 0 == name_index {
+                        name_index = i + 1;
+                    }
+                }
+            }
+            match name_index {
+                usize::MAX => continue,
+                0 => {
+                    out.push(0);
+                    encode_string(out, name);
+                }
+                index => encode_integer(out, 0, 4, index),
+            }
+            encode_string(out, value);
+        }
+    }
+}
+
+fn encode_string(out: &mut Vec<u8>, bytes: &[u8]) {
+    let len = huffman_len(bytes);
+    if len < bytes.len() {
+        encode_integer(out, 0x80, 7, len);
+        huffman_encode(out, bytes);
+    } else {
+        encode_integer(out, 0, 7, bytes.len());
+        out.extend_from_slice(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{huffman_decode, huffman_encode, Decoder, Encoder, HpackError};
+
+    fn hex(text: &str) -> Vec<u8> {
+        let digits: Vec<u8> = text.bytes().filter(u8::is_ascii_hexdigit).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    fn fields(fields: &[(&str, &str)]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        fields
+            .iter()
+            .map(|(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn decodes_rfc_7541_request_examples() {
+        // appendix C.3 without and C.4 with Huffman coding share one dynamic table each
+        for blocks in [
+            [
+                "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+                "8286 84be 5808 6e6f 2d63 6163 6865",
+                "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+            ],
+            [
+                "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+                "8286 84be 5886 a8eb 1064 9cbf",
+                "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+            ],
+        ] {
+            let mut decoder = Decoder::default();
+            let first = [
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ];
+            assert_eq!(decoder.decode(&hex(blocks[0])).unwrap(), fields(&first));
+            let mut second = first.to_vec();
+            second.push(("cache-control", "no-cache"));
+            assert_eq!(decoder.decode(&hex(blocks[1])).unwrap(), fields(&second));
+            let third = [
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ];
+            assert_eq!(decoder.decode(&hex(blocks[2])).unwrap(), fields(&third));
+            assert_eq!(decoder.size, 164);
+        }
+    }
+
+    #[test]
+    fn evicts_and_rejects() {
+        let mut decoder = Decoder::new(60);
+        // a new field of 42 bytes fits, a second one pushes the first out
+        let mut block = vec![0x40, 0x03];
+        block.extend_from_slice(b"abc");
+        block.push(0x07);
+        block.extend_from_slice(b"1234567");
+        decoder.decode(&block).unwrap();
+        block[2] = b'x';
+        decoder.decode(&block).unwrap();
+        assert_eq!(decoder.table.len(), 1);
+        assert_eq!(decoder.decode(&[0xbe]).unwrap()[0].0, b"xbc");
+        assert_eq!(decoder.decode(&[0xbf]), Err(HpackError::Index(63)));
+        assert_eq!(
+            decoder.decode(&[0x3f, 0x7f]),
+            Err(HpackError::TableSize(158))
+        );
+        assert_eq!(decoder.decode(&[0x20]).unwrap(), []);
+        assert!(decoder.table.is_empty());
+        assert_eq!(
+            decoder.decode(&[0x82, 0x20]),
+            Err(HpackError::TableSizeUpdate)
+        );
+        // :method GET and :path / are 42 and 38 bytes as table entries
+        let mut decoder = Decoder::default().max_list(80);
+        assert_eq!(decoder.decode(&[0x82, 0x84]).unwrap().len(), 2);
+        assert_eq!(
+            decoder.decode(&[0x82, 0x84, 0x84]),
+            Err(HpackError::ListSize(118))
+        );
+        assert_eq!(
+            decoder.decode(&[0x82, 0x84, 0x41]),
+            Err(HpackError::Truncated)
+        );
+        // EOS spelled out in full is never valid inside a string
+        assert_eq!(
+            huffman_decode(&[0xff, 0xff, 0xff, 0xff]),
+            Err(HpackError::Huffman)
+        );
+    }
+
+    #[test]
+    fn round_trips_through_the_encoder() {
+        let headers = [
+            (":status", "200"),
+            (":status", "302"),
+            ("content-type", "application/grpc"),
+            ("x-trace", "\x00\u{7f} binary"),
+        ];
+        let mut block = Vec::new();
+        Encoder.encode(
+            headers.iter().map(|(n, v)| (n.as_bytes(), v.as_bytes())),
+            &mut block,
+        );
+        assert_eq!(block[0], 0x88);
+        assert_eq!(Decoder::default().decode(&block).unwrap(), fields(&headers));
+
+        let mut coded = Vec::new();
+        huffman_encode(&mut coded, b"www.example.com");
+        assert_eq!(
+            coded,
+            [0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff]
+        );
+    }
+}
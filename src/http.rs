@@ -0,0 +1,1410 @@
+use std::{
+    fmt,
+    io::Write,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    pipeline::{Ratio, Stage},
+    Flow, Handler,
+};
+
+/// Most headers a request may carry.
+pub const MAX_HEADERS: usize = 64;
+
+/// Longest request line plus headers a connection may send before it is refused.
+pub const MAX_HEAD: usize = 64 * 1024;
+
+/// Largest request body, as sent, [`Http`] waits for before it refuses the request.
+pub const MAX_BODY: usize = 1 << 20;
+
+/// Outcome of parsing bytes that may not hold a whole message yet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status<T> {
+    Complete(T),
+    /// More bytes are needed; parse again from the start once they arrived.
+    Partial,
+}
+
+impl<T> Status<T> {
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Status<U> {
+        match self {
+            Status::Complete(value) => Status::Complete(f(value)),
+            Status::Partial => Status::Partial,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header<'a> {
+    pub name: &'a str,
+    /// The value without surrounding whitespace; not necessarily UTF-8.
+    pub value: &'a [u8],
+}
+
+impl Header<'_> {
+    const EMPTY: Header<'static> = Header {
+        name: "",
+        value: b"",
+    };
+}
+
+/// How the body of a message is delimited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Framing {
+    None,
+    Length(usize),
+    Chunked,
+}
+
+/// A request head borrowed from the receive buffer it was parsed from.
+#[derive(Clone, Debug)]
+pub struct Request<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub version: Version,
+    pub framing: Framing,
+    /// Whether the connection stays open for another request after this one.
+    pub keep_alive: bool,
+    headers: [Header<'a>; MAX_HEADERS],
+    header_count: usize,
+}
+
+impl<'a> Request<'a> {
+    pub fn headers(&self) -> &[Header<'a>] {
+        &self.headers[..self.header_count]
+    }
+
+    /// Value of the first header called `name`, compared case insensitively.
+    pub fn header(&self, name: &str) -> Option<&'a [u8]> {
+        self.headers()
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.value)
+    }
+
+    /// This request with `headers` in place of the parsed ones. Framing and keep-alive stay as
+    /// they were parsed.
+    pub fn with_headers<'b>(&self, headers: &[Header<'b>]) -> Result<Request<'b>, ParseError>
+    where
+        'a: 'b,
+    {
+        if headers.len() > MAX_HEADERS {
+            return Err(ParseError::TooManyHeaders);
+        }
+        let mut request = Request {
+            method: self.method,
+            path: self.path,
+            version: self.version,
+            framing: self.framing,
+            keep_alive: self.keep_alive,
+            headers: [Header::EMPTY; MAX_HEADERS],
+            header_count: headers.len(),
+        };
+        request.headers[..headers.len()].copy_from_slice(headers);
+        Ok(request)
+    }
+}
+
+/// Why a request was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    Method,
+    Target,
+    Version,
+    HeaderName,
+    HeaderValue,
+    TooManyHeaders,
+    HeadTooLarge,
+    BodyTooLarge,
+    ContentLength,
+    /// A transfer coding other than `chunked`, or `chunked` where it is not allowed.
+    TransferEncoding,
+    ChunkSize,
+    Chunk,
+    /// An HTTP/1.1 request without exactly one `Host` header.
+    Host,
+}
+
+impl ParseError {
+    /// Status code to answer the request with.
+    pub fn status(self) -> u16 {
+        match self {
+            ParseError::HeadTooLarge | ParseError::TooManyHeaders => 431,
+            ParseError::BodyTooLarge => 413,
+            ParseError::TransferEncoding => 501,
+            _ => 400,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self {
+            ParseError::Method => "invalid method",
+            ParseError::Target => "invalid request target",
+            ParseError::Version => "unsupported HTTP version",
+            ParseError::HeaderName => "invalid header name",
+            ParseError::HeaderValue => "invalid header value",
+            ParseError::TooManyHeaders => "too many headers",
+            ParseError::HeadTooLarge => "request head too large",
+            ParseError::BodyTooLarge => "request body too large",
+            ParseError::ContentLength => "invalid content length",
+            ParseError::TransferEncoding => "unsupported transfer encoding",
+            ParseError::ChunkSize => "invalid chunk size",
+            ParseError::Chunk => "chunk data not followed by a line break",
+            ParseError::Host => "missing or repeated host header",
+        };
+        f.write_str(what)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<ParseError> for std::io::Error {
+    fn from(err: ParseError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+    }
+}
+
+pub(crate) fn is_token(bytes: &[u8]) -> bool {
+    !bytes.is_empty()
+        && bytes
+            .iter()
+            .all(|&b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+fn as_str(bytes: &[u8]) -> &str {
+    // only called on bytes already checked to be ASCII
+    std::str::from_utf8(bytes).unwrap_or_default()
+}
+
+fn trim(bytes: &[u8]) -> &[u8] {
+    let is_space = |b: &u8| b' ' == *b || b'\t' == *b;
+    let start = bytes
+        .iter()
+        .position(|b| !is_space(b))
+        .unwrap_or(bytes.len());
+    let end = bytes
+        .iter()
+        .rposition(|b| !is_space(b))
+        .map_or(start, |i| i + 1);
+    &bytes[start..end]
+}
+
+/// The line starting at `pos` without its line break, and where the next one starts.
+fn line(buf: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let end = pos + buf[pos..].iter().position(|&b| b'\n' == b)?;
+    let line = &buf[pos..end];
+    Some((line.strip_suffix(b"\r").unwrap_or(line), end + 1))
+}
+
+pub(crate) fn tokens(value: &[u8]) -> impl Iterator<Item = &[u8]> {
+    value
+        .split(|&b| b',' == b)
+        .map(trim)
+        .filter(|token| !token.is_empty())
+}
+
+/// Parse a request head from the start of `buf`; returns it with the length of the head.
+pub fn parse_request(buf: &[u8]) -> Result<Status<(Request<'_>, usize)>, ParseError> {
+    let mut pos = 0;
+    // line breaks left over from a previous request are skipped
+    let request_line = loop {
+        let Some((line, next)) = line(buf, pos) else {
+            return Ok(Status::Partial);
+        };
+        pos = next;
+        if !line.is_empty() {
+            break line;
+        }
+    };
+    let mut parts = request_line.splitn(3, |&b| b' ' == b);
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().ok_or(ParseError::Target)?;
+    let version = parts.next().ok_or(ParseError::Version)?;
+    if !is_token(method) {
+        return Err(ParseError::Method);
+    }
+    if target.is_empty() || !target.iter().all(|b| (0x21..0x7f).contains(b)) {
+        return Err(ParseError::Target);
+    }
+    let version = match version {
+        b"HTTP/1.1" => Version::Http11,
+        b"HTTP/1.0" => Version::Http10,
+        _ => return Err(ParseError::Version),
+    };
+    let mut request = Request {
+        method: as_str(method),
+        path: as_str(target),
+        version,
+        framing: Framing::None,
+        keep_alive: Version::Http11 == version,
+        headers: [Header::EMPTY; MAX_HEADERS],
+        header_count: 0,
+    };
+    let Status::Complete((fields, pos)) =
+        parse_headers(buf, pos, &mut request.headers, &mut request.header_count)?
+    else {
+        return Ok(Status::Partial);
+    };
+    let Fields {
+        length,
+        chunked,
+        close,
+        keep_alive,
+    } = fields;
+    // RFC 9112 section 3.2: a 1.1 request names its host exactly once
+    let hosts = request
+        .headers()
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("host"))
+        .count();
+    if Version::Http11 == version && 1 != hosts || hosts > 1 {
+        return Err(ParseError::Host);
+    }
+    request.keep_alive |= keep_alive;
+    request.keep_alive &= !close;
+    request.framing = match (chunked, length) {
+        // a length next to chunked could smuggle a second request past a proxy
+        (true, Some(_)) => return Err(ParseError::ContentLength),
+        (true, None) if Version::Http10 == version => return Err(ParseError::TransferEncoding),
+        (true, None) => Framing::Chunked,
+        (false, Some(length)) => Framing::Length(length),
+        (false, None) => Framing::None,
+    };
+    Ok(Status::Complete((request, pos)))
+}
+
+/// What the headers say about framing and the connection.
+#[derive(Default)]
+struct Fields {
+    length: Option<usize>,
+    chunked: bool,
+    close: bool,
+    keep_alive: bool,
+}
+
+/// Parse the headers from `pos` up to and including the empty line that ends them into
+/// `headers`; returns what they say with where the head ends.
+fn parse_headers<'a>(
+    buf: &'a [u8],
+    mut pos: usize,
+    headers: &mut [Header<'a>; MAX_HEADERS],
+    count: &mut usize,
+) -> Result<Status<(Fields, usize)>, ParseError> {
+    let mut fields = Fields::default();
+    loop {
+        let Some((line, next)) = line(buf, pos) else {
+            return Ok(Status::Partial);
+        };
+        pos = next;
+        if line.is_empty() {
+            return Ok(Status::Complete((fields, pos)));
+        }
+        let colon = line
+            .iter()
+            .position(|&b| b':' == b)
+            .ok_or(ParseError::HeaderName)?;
+        let name = &line[..colon];
+        let value = trim(&line[colon + 1..]);
+        if !is_token(name) {
+            return Err(ParseError::HeaderName);
+        }
+        if !value
+            .iter()
+            .all(|&b| b'\t' == b || (b' ' <= b && 0x7f != b))
+        {
+            return Err(ParseError::HeaderValue);
+        }
+        if MAX_HEADERS == *count {
+            return Err(ParseError::TooManyHeaders);
+        }
+        let name = as_str(name);
+        headers[*count] = Header { name, value };
+        *count += 1;
+        if name.eq_ignore_ascii_case("content-length") {
+            let n = parse_length(value)?;
+            if fields.length.is_some_and(|length| length != n) {
+                return Err(ParseError::ContentLength);
+            }
+            fields.length = Some(n);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            for coding in tokens(value) {
+                if fields.chunked || !coding.eq_ignore_ascii_case(b"chunked") {
+                    return Err(ParseError::TransferEncoding);
+                }
+                fields.chunked = true;
+            }
+        } else if name.eq_ignore_ascii_case("connection") {
+            for option in tokens(value) {
+                if option.eq_ignore_ascii_case(b"close") {
+                    fields.close = true;
+                } else if option.eq_ignore_ascii_case(b"keep-alive") {
+                    fields.keep_alive = true;
+                }
+            }
+        }
+    }
+}
+
+/// A response head borrowed from the buffer it was parsed from, as a proxy or client sees it.
+#[derive(Clone, Debug)]
+pub struct ResponseHead<'a> {
+    pub version: Version,
+    pub status: u16,
+    /// [`Framing::None`] means the body runs until the connection closes; responses that never
+    /// have a body are framed as [`Framing::Length`] of `0`.
+    pub framing: Framing,
+    pub keep_alive: bool,
+    headers: [Header<'a>; MAX_HEADERS],
+    header_count: usize,
+}
+
+impl<'a> ResponseHead<'a> {
+    pub fn headers(&self) -> &[Header<'a>] {
+        &self.headers[..self.header_count]
+    }
+
+    /// Value of the first header called `name`, compared case insensitively.
+    pub fn header(&self, name: &str) -> Option<&'a [u8]> {
+        self.headers()
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.value)
+    }
+}
+
+/// Parse a response head from the start of `buf`; returns it with the length of the head.
+pub fn parse_response(buf: &[u8]) -> Result<Status<(ResponseHead<'_>, usize)>, ParseError> {
+    let Some((status_line, pos)) = line(buf, 0) else {
+        return Ok(Status::Partial);
+    };
+    let mut parts = status_line.splitn(3, |&b| b' ' == b);
+    let version = match parts.next() {
+        Some(b"HTTP/1.1") => Version::Http11,
+        Some(b"HTTP/1.0") => Version::Http10,
+        _ => return Err(ParseError::Version),
+    };
+    let status = match parts.next() {
+        Some(code) if 3 == code.len() && code.iter().all(u8::is_ascii_digit) => code
+            .iter()
+            .fold(0, |n, &digit| 10 * n + u16::from(digit - b'0')),
+        _ => return Err(ParseError::Version),
+    };
+    let mut response = ResponseHead {
+        version,
+        status,
+        framing: Framing::None,
+        keep_alive: Version::Http11 == version,
+        headers: [Header::EMPTY; MAX_HEADERS],
+        header_count: 0,
+    };
+    let Status::Complete((fields, pos)) =
+        parse_headers(buf, pos, &mut response.headers, &mut response.header_count)?
+    else {
+        return Ok(Status::Partial);
+    };
+    response.keep_alive = (response.keep_alive || fields.keep_alive) && !fields.close;
+    response.framing = match (fields.chunked, fields.length) {
+        _ if (100..200).contains(&status) || 204 == status || 304 == status => Framing::Length(0),
+        (true, Some(_)) => return Err(ParseError::ContentLength),
+        (true, None) => Framing::Chunked,
+        (false, Some(length)) => Framing::Length(length),
+        // without a length the body ends with the connection, which cannot be kept open
+        (false, None) => {
+            response.keep_alive = false;
+            Framing::None
+        }
+    };
+    Ok(Status::Complete((response, pos)))
+}
+
+const MONTHS: [&[u8]; 12] = [
+    b"Jan", b"Feb", b"Mar", b"Apr", b"May", b"Jun", b"Jul", b"Aug", b"Sep", b"Oct", b"Nov", b"Dec",
+];
+
+/// The time of an HTTP date such as `Sun, 06 Nov 1994 08:49:37 GMT`; dates in the obsolete
+/// formats are not understood.
+pub fn parse_http_date(value: &[u8]) -> Option<SystemTime> {
+    let value = trim(value);
+    let [_, _, _, b',', b' ', date @ .., b' ', b'G', b'M', b'T'] = value else {
+        return None;
+    };
+    let number = |digits: &[u8]| -> Option<u64> {
+        let all = !digits.is_empty() && digits.iter().all(u8::is_ascii_digit);
+        all.then(|| digits.iter().fold(0, |n, &d| 10 * n + u64::from(d - b'0')))
+    };
+    let &[d0, d1, b' ', ref month @ .., b' ', y0, y1, y2, y3, b' ', h0, h1, b':', m0, m1, b':', s0, s1] =
+        date
+    else {
+        return None;
+    };
+    let month = MONTHS.iter().position(|name| *name == month)? as u64 + 1;
+    let day = number(&[d0, d1])?;
+    let year = number(&[y0, y1, y2, y3])?;
+    let (hour, minute, second) = (number(&[h0, h1])?, number(&[m0, m1])?, number(&[s0, s1])?);
+    if !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 || year < 1970 {
+        return None;
+    }
+    // days since the epoch of a proleptic Gregorian date, with years starting in March
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y / 400;
+    let year_of_era = y - 400 * era;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = 365 * year_of_era + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (146097 * era + day_of_era).checked_sub(719468)?;
+    let seconds = 86400 * days + 3600 * hour + 60 * minute + second;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+fn parse_length(value: &[u8]) -> Result<usize, ParseError> {
+    if value.is_empty() || !value.iter().all(u8::is_ascii_digit) {
+        return Err(ParseError::ContentLength);
+    }
+    value.iter().try_fold(0usize, |n, &digit| {
+        n.checked_mul(10)
+            .and_then(|n| n.checked_add(usize::from(digit - b'0')))
+            .ok_or(ParseError::ContentLength)
+    })
+}
+
+/// The size a chunk size line without its line break announces.
+fn chunk_size(line: &[u8]) -> Result<usize, ParseError> {
+    let digits = trim(line.split(|&b| b';' == b).next().unwrap_or_default());
+    if digits.is_empty() || digits.len() > 2 * std::mem::size_of::<usize>() {
+        return Err(ParseError::ChunkSize);
+    }
+    let mut size = 0;
+    for &digit in digits {
+        let digit = char::from(digit)
+            .to_digit(16)
+            .ok_or(ParseError::ChunkSize)?;
+        size = size << 4 | digit as usize;
+    }
+    Ok(size)
+}
+
+/// The chunk starting at `pos`: where its data starts, how long it is and where the next chunk
+/// starts. A chunk of length `0` ends the body and is followed by the trailers.
+fn parse_chunk(buf: &[u8], pos: usize) -> Result<Status<(usize, usize, usize)>, ParseError> {
+    let Some((line, start)) = line(buf, pos) else {
+        return Ok(Status::Partial);
+    };
+    let size = chunk_size(line)?;
+    if 0 == size {
+        return Ok(Status::Complete((start, 0, start)));
+    }
+    let end = start.checked_add(size).ok_or(ParseError::ChunkSize)?;
+    let next = match buf.get(end..) {
+        None | Some([]) | Some([b'\r']) => return Ok(Status::Partial),
+        Some([b'\r', b'\n', ..]) => end + 2,
+        Some([b'\n', ..]) => end + 1,
+        Some(_) => return Err(ParseError::Chunk),
+    };
+    Ok(Status::Complete((start, size, next)))
+}
+
+/// A request body borrowed from the receive buffer, still chunked if it was sent chunked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Body<'a> {
+    encoded: &'a [u8],
+    chunked: bool,
+}
+
+impl<'a> Body<'a> {
+    /// The body bytes, unless they are split into chunks.
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        (!self.chunked).then_some(self.encoded)
+    }
+
+    /// The body bytes in order, one slice per chunk.
+    pub fn chunks(&self) -> Chunks<'a> {
+        Chunks {
+            encoded: self.encoded,
+            pos: 0,
+            chunked: self.chunked,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks().map(<[u8]>::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        0 == self.len()
+    }
+}
+
+pub struct Chunks<'a> {
+    encoded: &'a [u8],
+    pos: usize,
+    chunked: bool,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.chunked {
+            self.chunked = true;
+            self.pos = self.encoded.len();
+            return Some(self.encoded).filter(|bytes| !bytes.is_empty());
+        }
+        match parse_chunk(self.encoded, self.pos) {
+            Ok(Status::Complete((start, size, next))) if size > 0 => {
+                self.pos = next;
+                Some(&self.encoded[start..start + size])
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Find the body of `request` at the start of `buf`; returns it with the bytes it takes up.
+pub fn parse_body<'a>(
+    request: &Request<'_>,
+    buf: &'a [u8],
+) -> Result<Status<(Body<'a>, usize)>, ParseError> {
+    parse_framed(request.framing, buf)
+}
+
+/// Find a body framed as `framing` at the start of `buf`; returns it with the bytes it takes up.
+/// [`Framing::None`] is an empty body.
+pub fn parse_framed(framing: Framing, buf: &[u8]) -> Result<Status<(Body<'_>, usize)>, ParseError> {
+    let len = match framing {
+        Framing::None => 0,
+        Framing::Length(length) if length > buf.len() => return Ok(Status::Partial),
+        Framing::Length(length) => length,
+        Framing::Chunked => {
+            let mut pos = 0;
+            loop {
+                match parse_chunk(buf, pos)? {
+                    Status::Partial => return Ok(Status::Partial),
+                    Status::Complete((_, 0, next)) => {
+                        pos = next;
+                        break;
+                    }
+                    Status::Complete((_, _, next)) => pos = next,
+                }
+            }
+            // trailers are read past but not interpreted
+            loop {
+                let Some((line, next)) = line(buf, pos) else {
+                    return Ok(Status::Partial);
+                };
+                pos = next;
+                if line.is_empty() {
+                    break pos;
+                }
+            }
+        }
+    };
+    let body = Body {
+        encoded: &buf[..len],
+        chunked: Framing::Chunked == framing,
+    };
+    Ok(Status::Complete((body, len)))
+}
+
+/// Where [`BodyScan`] is in a body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Scan {
+    /// Bytes left of a body with a length, or of the data of a chunk.
+    Data(usize),
+    SizeLine,
+    /// The line break after the data of a chunk.
+    DataEnd,
+    Trailer,
+    Done,
+}
+
+/// Finds where a body framed as a [`Framing`] ends while it arrives in pieces, looking at every
+/// byte once; [`Framing::None`] is an empty body like for [`parse_framed`]. Only the line being
+/// read is kept, up to [`MAX_HEAD`] bytes of it.
+#[derive(Clone, Debug)]
+pub struct BodyScan {
+    scan: Scan,
+    chunked: bool,
+    line: Vec<u8>,
+}
+
+impl BodyScan {
+    pub fn new(framing: Framing) -> Self {
+        let scan = match framing {
+            Framing::None | Framing::Length(0) => Scan::Done,
+            Framing::Length(length) => Scan::Data(length),
+            Framing::Chunked => Scan::SizeLine,
+        };
+        Self {
+            scan,
+            chunked: Framing::Chunked == framing,
+            line: Vec::new(),
+        }
+    }
+
+    /// Scan the next bytes of the body; once it ends, returns how many of `bytes` belong to it.
+    pub fn scan(&mut self, bytes: &[u8]) -> Result<Status<usize>, ParseError> {
+        let mut pos = 0;
+        loop {
+            match self.scan {
+                Scan::Done => return Ok(Status::Complete(pos)),
+                Scan::Data(left) => {
+                    let taken = left.min(bytes.len() - pos);
+                    pos += taken;
+                    if taken < left {
+                        self.scan = Scan::Data(left - taken);
+                        return Ok(Status::Partial);
+                    }
+                    self.scan = match self.chunked {
+                        true => Scan::DataEnd,
+                        false => Scan::Done,
+                    };
+                }
+                Scan::DataEnd => match (self.line.as_slice(), bytes.get(pos)) {
+                    (_, None) => return Ok(Status::Partial),
+                    ([], Some(b'\r')) => {
+                        self.line.push(b'\r');
+                        pos += 1;
+                    }
+                    (_, Some(b'\n')) => {
+                        self.line.clear();
+                        self.scan = Scan::SizeLine;
+                        pos += 1;
+                    }
+                    _ => return Err(ParseError::Chunk),
+                },
+                _ => {
+                    let Some(end) = bytes[pos..].iter().position(|&b| b'\n' == b) else {
+                        self.line.extend_from_slice(&bytes[pos..]);
+                        return match self.line.len() > MAX_HEAD {
+                            true => Err(ParseError::HeadTooLarge),
+                            false => Ok(Status::Partial),
+                        };
+                    };
+                    self.line.extend_from_slice(&bytes[pos..pos + end]);
+                    pos += end + 1;
+                    let line = self.line.strip_suffix(b"\r").unwrap_or(&self.line);
+                    self.scan = match self.scan {
+                        Scan::SizeLine => match chunk_size(line)? {
+                            0 => Scan::Trailer,
+                            size => Scan::Data(size),
+                        },
+                        // trailers are read past but not interpreted
+                        _ if line.is_empty() => Scan::Done,
+                        _ => Scan::Trailer,
+                    };
+                    self.line.clear();
+                }
+            }
+        }
+    }
+}
+
+/// Removal of the chunked transfer coding from a whole body as a [`Stage`], moving the chunk
+/// data to the front of the segment it was received into.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Dechunk;
+
+impl Stage for Dechunk {
+    fn ratio(&self) -> Ratio {
+        Ratio::IDENTITY
+    }
+
+    fn run(&mut self, buffer: &mut [u8], len: usize) -> std::io::Result<usize> {
+        let end = match parse_framed(Framing::Chunked, &buffer[..len])? {
+            Status::Complete((_, end)) => end,
+            Status::Partial => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "chunked body ends early",
+                ))
+            }
+        };
+        if end != len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "bytes after the chunked body",
+            ));
+        }
+        // data only moves towards the front, over chunk lines already read
+        let (mut pos, mut n) = (0, 0);
+        while let Ok(Status::Complete((start, size, next))) = parse_chunk(&buffer[..len], pos) {
+            if 0 == size {
+                break;
+            }
+            buffer.copy_within(start..start + size, n);
+            n += size;
+            pos = next;
+        }
+        Ok(n)
+    }
+}
+
+/// Reason phrase for the status codes servers commonly send.
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+/// A response serialized straight into `send`, normally the connection's outgoing segments.
+///
+/// The status line is written on creation and every header as it is added; the body methods
+/// add the framing and `Connection` headers, end the head and return the [`Flow`] the
+/// connection continues with.
+pub struct Response<'w, W: Write> {
+    send: &'w mut W,
+    version: Version,
+    keep_alive: bool,
+}
+
+impl<'w, W: Write> Response<'w, W> {
+    /// Start a response to a client speaking `version` that wants the connection kept open or not.
+    pub fn new(
+        send: &'w mut W,
+        status: u16,
+        version: Version,
+        keep_alive: bool,
+    ) -> std::io::Result<Self> {
+        write!(send, "HTTP/1.1 {status} {}\r\n", reason(status))?;
+        Ok(Self {
+            send,
+            version,
+            keep_alive,
+        })
+    }
+
+    /// Start the response to `request`.
+    pub fn to(request: &Request<'_>, send: &'w mut W, status: u16) -> std::io::Result<Self> {
+        Self::new(send, status, request.version, request.keep_alive)
+    }
+
+    /// Add a header; names must be tokens and values must not contain line breaks.
+    pub fn header(&mut self, name: &str, value: impl AsRef<[u8]>) -> std::io::Result<&mut Self> {
+        let value = value.as_ref();
+        if !is_token(name.as_bytes()) || value.iter().any(|&b| b'\r' == b || b'\n' == b) {
+            return Err(std::io::ErrorKind::InvalidInput.into());
+        }
+        self.send.write_all(name.as_bytes())?;
+        self.send.write_all(b": ")?;
+        self.send.write_all(value)?;
+        self.send.write_all(b"\r\n")?;
+        Ok(self)
+    }
+
+    /// Close the connection after this response.
+    pub fn close(&mut self) -> &mut Self {
+        self.keep_alive = false;
+        self
+    }
+
+    /// Send `body` with a `Content-Length`.
+    pub fn body(mut self, body: &[u8]) -> std::io::Result<Flow> {
+        write!(self.send, "content-length: {}\r\n", body.len())?;
+        self.end_head()?;
+        self.send.write_all(body)?;
+        Ok(self.flow())
+    }
+
+    /// Send a body of unknown length as it is written, in chunks. An HTTP/1.0 client cannot read
+    /// chunks, so its body is ended by closing the connection instead.
+    pub fn chunked(mut self) -> std::io::Result<ChunkedBody<'w, W>> {
+        let chunked = Version::Http11 == self.version;
+        if chunked {
+            self.send.write_all(b"transfer-encoding: chunked\r\n")?;
+        } else {
+            self.keep_alive = false;
+        }
+        self.end_head()?;
+        Ok(ChunkedBody {
+            flow: self.flow(),
+            send: self.send,
+            chunked,
+        })
+    }
+
+    fn end_head(&mut self) -> std::io::Result<()> {
+        match (self.keep_alive, self.version) {
+            (false, _) => self.send.write_all(b"connection: close\r\n")?,
+            (true, Version::Http10) => self.send.write_all(b"connection: keep-alive\r\n")?,
+            (true, Version::Http11) => {}
+        }
+        self.send.write_all(b"\r\n")
+    }
+
+    fn flow(&self) -> Flow {
+        match self.keep_alive {
+            true => Flow::Continue,
+            false => Flow::Close,
+        }
+    }
+}
+
+/// The body of a [`Response::chunked`]; every write becomes one chunk.
+pub struct ChunkedBody<'w, W: Write> {
+    send: &'w mut W,
+    chunked: bool,
+    flow: Flow,
+}
+
+impl<W: Write> ChunkedBody<'_, W> {
+    /// Write the last chunk.
+    pub fn finish(self) -> std::io::Result<Flow> {
+        if self.chunked {
+            self.send.write_all(b"0\r\n\r\n")?;
+        }
+        Ok(self.flow)
+    }
+}
+
+impl<W: Write> Write for ChunkedBody<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // an empty chunk would end the body
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.chunked {
+            write!(self.send, "{:x}\r\n", buf.len())?;
+        }
+        self.send.write_all(buf)?;
+        if self.chunked {
+            self.send.write_all(b"\r\n")?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send.flush()
+    }
+}
+
+/// Application logic for [`Http`], called once per complete request.
+pub trait HttpHandler {
+    /// Write the response to `request` to `send`. Returning [`Flow::Close`] closes the connection
+    /// after the response even when the client asked to keep it open.
+    fn request<W: Write>(
+        &mut self,
+        token: usize,
+        request: &Request<'_>,
+        body: Body<'_>,
+        send: &mut W,
+    ) -> std::io::Result<Flow>;
+}
+
+/// A [`Handler`] that parses HTTP/1.0 and HTTP/1.1 requests in the receive buffer and hands them
+/// to an [`HttpHandler`], serving pipelined requests in order and keeping connections alive.
+/// Bodies over [`MAX_BODY`] are answered with 413 and the connection closed.
+pub struct Http<H> {
+    pub handler: H,
+}
+
+impl<H> Http<H> {
+    pub fn new(handler: H) -> Self {
+        Self { handler }
+    }
+}
+
+impl<C, H: HttpHandler> Handler<C> for Http<H> {
+    fn received<W: Write>(
+        &mut self,
+        token: usize,
+        receive: &[u8],
+        send: &mut W,
+    ) -> std::io::Result<(usize, Flow)> {
+        let mut consumed = 0;
+        loop {
+            let rest = &receive[consumed..];
+            let parsed = match parse_request(rest) {
+                Ok(Status::Partial) if rest.len() > MAX_HEAD => Err(ParseError::HeadTooLarge),
+                Ok(Status::Partial) => return Ok((consumed, Flow::Continue)),
+                Ok(Status::Complete((request, head))) => match request.framing {
+                    Framing::Length(length) if length > MAX_BODY => Err(ParseError::BodyTooLarge),
+                    _ => match parse_body(&request, &rest[head..]) {
+                        Ok(Status::Partial) if rest.len() - head > MAX_BODY => {
+                            Err(ParseError::BodyTooLarge)
+                        }
+                        body => {
+                            body.map(|body| body.map(|(body, len)| (request, body, head + len)))
+                        }
+                    },
+                },
+                Err(err) => Err(err),
+            };
+            let (request, body, len) = match parsed {
+                Ok(Status::Partial) => return Ok((consumed, Flow::Continue)),
+                Ok(Status::Complete(parsed)) => parsed,
+                Err(err) => {
+                    Response::new(send, err.status(), Version::Http11, false)?.body(b"")?;
+                    return Ok((receive.len(), Flow::Close));
+                }
+            };
+            let flow = self.handler.request(token, &request, body, send)?;
+            consumed += len;
+            if Flow::Close == flow || !request.keep_alive {
+                return Ok((consumed, Flow::Close));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        thread,
+    };
+
+    use mio::net::TcpStream;
+
+    use super::{
+        parse_body, parse_framed, parse_http_date, parse_request, parse_response, Body, BodyScan,
+        Dechunk, Framing, Http, HttpHandler, ParseError, Request, Response, Status, Version,
+        MAX_BODY,
+    };
+    use crate::{
+        pipeline::Stage,
+        ring::RingBuffer,
+        tests::{new_loopback_address, TestServer},
+        Flow, Listener, Server, Shutdown,
+    };
+
+    /// Answers with the method, path and body it received.
+    struct Describe;
+
+    impl HttpHandler for Describe {
+        fn request<W: Write>(
+            &mut self,
+            _token: usize,
+            request: &Request<'_>,
+            body: Body<'_>,
+            send: &mut W,
+        ) -> std::io::Result<Flow> {
+            let body: Vec<u8> = body.chunks().flatten().copied().collect();
+            let text = format!("{} {} {}", request.method, request.path, body.len());
+            Response::to(request, send, 200)?.body(text.as_bytes())
+        }
+    }
+
+    /// Streams `len` bytes back in chunks of at most `chunk` bytes.
+    struct Stream {
+        len: usize,
+        chunk: usize,
+    }
+
+    impl HttpHandler for Stream {
+        fn request<W: Write>(
+            &mut self,
+            _token: usize,
+            request: &Request<'_>,
+            _body: Body<'_>,
+            send: &mut W,
+        ) -> std::io::Result<Flow> {
+            let mut response = Response::to(request, send, 200)?;
+            response.header("content-type", "text/plain")?;
+            let mut body = response.chunked()?;
+            let bytes: Vec<u8> = (0..self.len).map(|i| b'a' + (i % 26) as u8).collect();
+            for chunk in bytes.chunks(self.chunk) {
+                body.write_all(chunk)?;
+            }
+            body.finish()
+        }
+    }
+
+    #[test]
+    fn parses_borrowed_request_heads() {
+        let buf = b"\r\nPOST /submit?x=1 HTTP/1.1\r\nHost: example.com\r\nContent-Length:  5 \r\nX-Empty:\r\n\r\nhelloGET";
+        let Status::Complete((request, head)) = parse_request(buf).unwrap() else {
+            panic!("request is complete");
+        };
+        assert_eq!((request.method, request.path), ("POST", "/submit?x=1"));
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.headers().len(), 3);
+        assert_eq!(request.header("content-length"), Some(&b"5"[..]));
+        assert_eq!(request.header("x-empty"), Some(&b""[..]));
+        assert_eq!(request.framing, Framing::Length(5));
+        assert!(request.keep_alive);
+        assert!(std::ptr::eq(request.path.as_ptr(), buf[7..].as_ptr()));
+
+        let Status::Complete((body, len)) = parse_body(&request, &buf[head..]).unwrap() else {
+            panic!("body is complete");
+        };
+        assert_eq!((body.as_bytes(), len), (Some(&b"hello"[..]), 5));
+
+        for split in 0..head {
+            assert!(matches!(parse_request(&buf[..split]), Ok(Status::Partial)));
+        }
+        let Status::Complete((request, _)) =
+            parse_request(b"GET / HTTP/1.0\nConnection: keep-alive\n\n").unwrap()
+        else {
+            panic!("request is complete");
+        };
+        assert!(request.keep_alive);
+    }
+
+    #[test]
+    fn parses_chunked_bodies_split_anywhere() {
+        let buf = b"PUT /c HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nTrailer: x\r\n\r\n";
+        let Status::Complete((request, head)) = parse_request(buf).unwrap() else {
+            panic!("request is complete");
+        };
+        assert_eq!(request.framing, Framing::Chunked);
+        let body = &buf[head..];
+        for split in 0..body.len() {
+            assert_eq!(
+                parse_body(&request, &body[..split]).unwrap(),
+                Status::Partial
+            );
+        }
+        let Status::Complete((body, len)) = parse_body(&request, body).unwrap() else {
+            panic!("body is complete");
+        };
+        assert_eq!(len, buf.len() - head);
+        assert_eq!(body.as_bytes(), None);
+        assert_eq!(
+            body.chunks().collect::<Vec<_>>(),
+            [&b"hello"[..], b", world"]
+        );
+        assert_eq!(body.len(), 12);
+
+        let mut buffer = buf[head..].to_vec();
+        let len = buffer.len();
+        assert_eq!(Dechunk.run(&mut buffer, len).unwrap(), 12);
+        assert_eq!(&buffer[..12], b"hello, world");
+        for split in [0, 4, len - 1] {
+            assert!(Dechunk
+                .run(&mut buf[head..head + split].to_vec(), split)
+                .is_err());
+        }
+
+        // fed a byte at a time, the scan ends on the last byte of the body
+        let mut scan = BodyScan::new(Framing::Chunked);
+        for (i, byte) in buf[head..].iter().enumerate() {
+            let status = scan.scan(std::slice::from_ref(byte)).unwrap();
+            match i + 1 == len {
+                true => assert_eq!(status, Status::Complete(1)),
+                false => assert_eq!(status, Status::Partial),
+            }
+        }
+        let mut scan = BodyScan::new(Framing::Chunked);
+        assert_eq!(scan.scan(&buffer[..0]), Ok(Status::Partial));
+        let mut followed = buf[head..].to_vec();
+        followed.extend_from_slice(b"GET");
+        assert_eq!(scan.scan(&followed), Ok(Status::Complete(len)));
+        let mut scan = BodyScan::new(Framing::Length(5));
+        assert_eq!(scan.scan(b"hel"), Ok(Status::Partial));
+        assert_eq!(scan.scan(b"lo!"), Ok(Status::Complete(2)));
+        let mut scan = BodyScan::new(Framing::Chunked);
+        assert_eq!(scan.scan(b"5\r\nhelloX"), Err(ParseError::Chunk));
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let cases: [(&[u8], ParseError); 9] = [
+            (b"G(T / HTTP/1.1\r\n\r\n", ParseError::Method),
+            (b"GET /a b HTTP/1.1\r\n\r\n", ParseError::Version),
+            (b"GET / HTTP/2.0\r\n\r\n", ParseError::Version),
+            (
+                b"GET / HTTP/1.1\r\nBad Name: x\r\n\r\n",
+                ParseError::HeaderName,
+            ),
+            (
+                b"GET / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n",
+                ParseError::ContentLength,
+            ),
+            (
+                b"GET / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n",
+                ParseError::TransferEncoding,
+            ),
+            (
+                b"GET / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n",
+                ParseError::ContentLength,
+            ),
+            (b"GET / HTTP/1.1\r\n\r\n", ParseError::Host),
+            (
+                b"GET / HTTP/1.0\r\nHost: a\r\nHost: b\r\n\r\n",
+                ParseError::Host,
+            ),
+        ];
+        for (buf, expected) in cases {
+            assert_eq!(parse_request(buf).unwrap_err(), expected);
+        }
+        let Status::Complete((request, head)) = parse_request(
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+        )
+        .unwrap() else {
+            panic!("request is complete");
+        };
+        assert_eq!(head, 56);
+        assert_eq!(parse_body(&request, b"zz\r\n"), Err(ParseError::ChunkSize));
+        assert_eq!(parse_body(&request, b"2\r\nabc"), Err(ParseError::Chunk));
+    }
+
+    #[test]
+    fn parses_response_heads_and_dates() {
+        let buf = b"HTTP/1.1 200 OK\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\nContent-Length: 2\r\n\r\nhi";
+        let Ok(Status::Complete((response, len))) = parse_response(buf) else {
+            panic!("complete head");
+        };
+        assert_eq!(
+            (response.status, response.framing, len),
+            (200, Framing::Length(2), buf.len() - 2)
+        );
+        assert!(response.keep_alive);
+        let Ok(Status::Complete((body, 2))) = parse_framed(response.framing, &buf[len..]) else {
+            panic!("complete body");
+        };
+        assert_eq!(body.as_bytes(), Some(&b"hi"[..]));
+        let date = parse_http_date(response.header("date").unwrap()).unwrap();
+        let since_epoch = date.duration_since(std::time::UNIX_EPOCH).unwrap();
+        assert_eq!(since_epoch.as_secs(), 784111777);
+
+        let Ok(Status::Complete((response, _))) =
+            parse_response(b"HTTP/1.0 304 Not Modified\r\n\r\n")
+        else {
+            panic!("complete head");
+        };
+        assert_eq!(
+            (response.framing, response.keep_alive),
+            (Framing::Length(0), false)
+        );
+        let Ok(Status::Complete((response, _))) = parse_response(b"HTTP/1.1 200 OK\r\n\r\n") else {
+            panic!("complete head");
+        };
+        assert_eq!(
+            (response.framing, response.keep_alive),
+            (Framing::None, false)
+        );
+        assert!(matches!(
+            parse_response(b"HTTP/1.1 200 OK\r\n"),
+            Ok(Status::Partial)
+        ));
+        assert!(parse_response(b"HTTP/1.1 2000 OK\r\n\r\n").is_err());
+        for date in [
+            &b"Sun, 06 Nov 1994 08:49:37 UTC"[..],
+            b"Sunday, 06-Nov-94 08:49:37 GMT",
+            b"Sun, 06 Nov 1994 25:49:37 GMT",
+        ] {
+            assert_eq!(parse_http_date(date), None);
+        }
+    }
+
+    #[test]
+    fn encodes_responses() {
+        let mut send = Vec::new();
+        let mut response = Response::new(&mut send, 404, Version::Http11, true).unwrap();
+        response.header("server", "elog").unwrap();
+        assert_eq!(response.body(b"gone").unwrap(), Flow::Continue);
+        assert_eq!(
+            send,
+            b"HTTP/1.1 404 Not Found\r\nserver: elog\r\ncontent-length: 4\r\n\r\ngone"
+        );
+
+        let mut send = Vec::new();
+        let mut response = Response::new(&mut send, 200, Version::Http11, true).unwrap();
+        let split = response.header("x-split", "a\r\nset-cookie: b");
+        assert_eq!(
+            split.err().unwrap().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        response.close();
+        let mut body = response.chunked().unwrap();
+        body.write_all(b"hello, ").unwrap();
+        body.write_all(b"").unwrap();
+        body.write_all(b"world").unwrap();
+        assert_eq!(body.finish().unwrap(), Flow::Close);
+        assert_eq!(
+            send,
+            b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\nconnection: close\r\n\r\n7\r\nhello, \r\n5\r\nworld\r\n0\r\n\r\n"
+        );
+
+        // chunks are not understood by HTTP/1.0 clients, so the body ends with the connection
+        let mut send = Vec::new();
+        let response = Response::new(&mut send, 200, Version::Http10, true).unwrap();
+        let mut body = response.chunked().unwrap();
+        body.write_all(b"raw").unwrap();
+        assert_eq!(body.finish().unwrap(), Flow::Close);
+        assert_eq!(send, b"HTTP/1.1 200 OK\r\nconnection: close\r\n\r\nraw");
+    }
+
+    #[test]
+    fn streams_chunked_responses_across_segments() {
+        let mut ring = RingBuffer::with_segment_len(64, 256);
+        let mut handler = Http::new(Stream {
+            len: 5000,
+            chunk: 700,
+        });
+        let addr = new_loopback_address();
+        let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                TestServer::serve::<1, _>(listener, 128, &mut ring, &mut handler, &shutdown)
+            });
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut reply = Vec::new();
+            client.read_to_end(&mut reply).unwrap();
+
+            let head = b"HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ntransfer-encoding: chunked\r\nconnection: close\r\n\r\n";
+            assert!(reply.starts_with(head));
+            let Status::Complete((request, _)) =
+                parse_request(b"PUT / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n")
+                    .unwrap()
+            else {
+                panic!("request is complete");
+            };
+            let Status::Complete((body, len)) = parse_body(&request, &reply[head.len()..]).unwrap()
+            else {
+                panic!("body is complete");
+            };
+            assert_eq!(head.len() + len, reply.len());
+            assert_eq!(body.chunks().count(), 8);
+            assert_eq!(body.len(), 5000);
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+
+        assert_eq!(ring.in_use(), 0);
+    }
+
+    #[test]
+    fn serves_keep_alive_connections() {
+        let mut ring = RingBuffer::new(16);
+        let mut handler = Http::new(Describe);
+        let addr = new_loopback_address();
+        let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                TestServer::serve::<1, _>(listener, 128, &mut ring, &mut handler, &shutdown)
+            });
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            // one request split across reads, then two pipelined ones, the last closing
+            for part in [
+                &b"POST /a HTTP/1.1\r\nHost: a\r\nContent-Le"[..],
+                b"ngth: 3\r\n\r\nab",
+                b"c",
+            ] {
+                client.write_all(part).unwrap();
+                thread::sleep(std::time::Duration::from_millis(20));
+            }
+            client
+                .write_all(b"GET /b HTTP/1.1\r\nHost: a\r\n\r\nPUT /c HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n2\r\nhi\r\n0\r\n\r\n")
+                .unwrap();
+            let mut replies = String::new();
+            client.read_to_string(&mut replies).unwrap();
+            assert_eq!(
+                replies,
+                "HTTP/1.1 200 OK\r\ncontent-length: 9\r\n\r\nPOST /a 3\
+                 HTTP/1.1 200 OK\r\ncontent-length: 8\r\n\r\nGET /b 0\
+                 HTTP/1.1 200 OK\r\ncontent-length: 8\r\nconnection: close\r\n\r\nPUT /c 2"
+            );
+
+            for request in [&b"GET / HTTP/9.9\r\n\r\n"[..], b"GET / HTTP/1.1\r\n\r\n"] {
+                let mut client = std::net::TcpStream::connect(addr).unwrap();
+                client.write_all(request).unwrap();
+                let mut reply = String::new();
+                client.read_to_string(&mut reply).unwrap();
+                assert!(reply.starts_with("HTTP/1.1 400 "));
+            }
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+
+        assert_eq!(ring.in_use(), 0);
+    }
+
+    #[test]
+    fn refuses_bodies_over_max_body() {
+        let mut ring = RingBuffer::new(2 * MAX_BODY / 4096);
+        let mut handler = Http::new(Describe);
+        let addr = new_loopback_address();
+        let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                TestServer::serve::<1, _>(listener, 128, &mut ring, &mut handler, &shutdown)
+            });
+            // a length over the limit is refused before the body arrives
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            let head = format!(
+                "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: {}\r\n\r\n",
+                MAX_BODY + 1
+            );
+            client.write_all(head.as_bytes()).unwrap();
+            let mut reply = String::new();
+            client.read_to_string(&mut reply).unwrap();
+            assert!(reply.starts_with("HTTP/1.1 413 "));
+
+            // a chunked body once one byte more than the limit is waiting
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            let mut request =
+                b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+            let size = format!("{:x}\r\n", MAX_BODY - 6);
+            request.extend(size.as_bytes());
+            request.resize(request.len() + MAX_BODY + 1 - size.len(), b'a');
+            client.write_all(&request).unwrap();
+            let mut reply = String::new();
+            client.read_to_string(&mut reply).unwrap();
+            assert!(reply.starts_with("HTTP/1.1 413 "));
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+
+        assert_eq!(ring.in_use(), 0);
+    }
+}
@@ -0,0 +1,1263 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::Write,
+};
+
+use crate::{
+    hpack::{Decoder, Encoder, Field, HpackError},
+    Flow, Handler,
+};
+
+/// What every client connection starts with.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+pub const FRAME_HEADER: usize = 9;
+/// Frame payloads are never larger than this unless the peer allows more.
+pub const DEFAULT_MAX_FRAME: usize = 16_384;
+pub const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+/// Streams a client may have open at once on one connection; more are refused.
+pub const MAX_STREAMS: usize = 100;
+/// Largest request body a stream may send. It is the stream window announced to clients, and
+/// bodies wait in memory until their request is complete.
+pub const MAX_BODY: usize = 1 << 20;
+/// Request body bytes all streams of a connection may have waiting in memory at once.
+pub const RECEIVE_WINDOW: i64 = 4 << 20;
+/// Response body bytes handed to a connection's output at once; the rest follows once the output
+/// has been written, so a fast client with wide windows cannot fill the ring.
+pub const MAX_PUMP: usize = 2 * DEFAULT_MAX_FRAME;
+/// Largest header list a request may send, counted as HPACK table entries, and largest header
+/// block it may be encoded in.
+pub const MAX_HEADER_LIST: usize = 64 * 1024;
+
+pub const DATA: u8 = 0x0;
+pub const HEADERS: u8 = 0x1;
+pub const PRIORITY: u8 = 0x2;
+pub const RST_STREAM: u8 = 0x3;
+pub const SETTINGS: u8 = 0x4;
+pub const PUSH_PROMISE: u8 = 0x5;
+pub const PING: u8 = 0x6;
+pub const GOAWAY: u8 = 0x7;
+pub const WINDOW_UPDATE: u8 = 0x8;
+pub const CONTINUATION: u8 = 0x9;
+
+pub const END_STREAM: u8 = 0x1;
+pub const ACK: u8 = 0x1;
+pub const END_HEADERS: u8 = 0x4;
+pub const PADDED: u8 = 0x8;
+pub const PRIORITY_FLAG: u8 = 0x20;
+
+pub const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+pub const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+pub const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+pub const NO_ERROR: u32 = 0x0;
+pub const PROTOCOL_ERROR: u32 = 0x1;
+pub const FLOW_CONTROL_ERROR: u32 = 0x3;
+pub const STREAM_CLOSED: u32 = 0x5;
+pub const FRAME_SIZE_ERROR: u32 = 0x6;
+pub const REFUSED_STREAM: u32 = 0x7;
+pub const COMPRESSION_ERROR: u32 = 0x9;
+pub const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+/// The fixed 9 byte header in front of every frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub len: usize,
+    pub kind: u8,
+    pub flags: u8,
+    pub stream: u32,
+}
+
+impl FrameHeader {
+    /// Parse the header at the start of `buf`, if it is all there.
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        let buf = buf.get(..FRAME_HEADER)?;
+        Some(Self {
+            len: usize::from(buf[0]) << 16 | usize::from(buf[1]) << 8 | usize::from(buf[2]),
+            kind: buf[3],
+            flags: buf[4],
+            stream: u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]) & 0x7fff_ffff,
+        })
+    }
+}
+
+/// Write one frame to `send`.
+pub fn write_frame<W: Write>(
+    send: &mut W,
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    let len = (payload.len() as u32).to_be_bytes();
+    let mut header = [0; FRAME_HEADER];
+    header[..3].copy_from_slice(&len[1..]);
+    header[3] = kind;
+    header[4] = flags;
+    header[5..].copy_from_slice(&stream.to_be_bytes());
+    send.write_all(&header)?;
+    send.write_all(payload)
+}
+
+/// Write a header block as one HEADERS frame followed by as many CONTINUATION frames as
+/// `max_frame` requires.
+pub fn write_headers<W: Write>(
+    send: &mut W,
+    stream: u32,
+    block: &[u8],
+    end_stream: bool,
+    max_frame: usize,
+) -> std::io::Result<()> {
+    let mut fragments = block.chunks(max_frame).peekable();
+    let mut kind = HEADERS;
+    let mut flags = if end_stream { END_STREAM } else { 0 };
+    loop {
+        let fragment = fragments.next().unwrap_or_default();
+        if fragments.peek().is_none() {
+            return write_frame(send, kind, flags | END_HEADERS, stream, fragment);
+        }
+        write_frame(send, kind, flags, stream, fragment)?;
+        (kind, flags) = (CONTINUATION, 0);
+    }
+}
+
+/// A request whose headers and body have completely arrived.
+#[derive(Debug)]
+pub struct Request {
+    pub stream: u32,
+    pub method: String,
+    pub scheme: String,
+    pub authority: String,
+    pub path: String,
+    /// Regular header fields in the order they arrived, pseudo-headers excluded.
+    pub headers: Vec<Field>,
+    pub body: Vec<u8>,
+    /// Fields of a trailing header block, as gRPC clients send them.
+    pub trailers: Vec<Field>,
+}
+
+impl Request {
+    /// Value of the first header `name`, which must be lower case.
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name.as_bytes())
+            .map(|(_, v)| v.as_slice())
+    }
+
+    fn new(stream: u32, fields: Vec<Field>) -> Option<Self> {
+        let mut request = Self {
+            stream,
+            method: String::new(),
+            scheme: String::new(),
+            authority: String::new(),
+            path: String::new(),
+            headers: Vec::with_capacity(fields.len()),
+            body: Vec::new(),
+            trailers: Vec::new(),
+        };
+        for (name, value) in fields {
+            if name.iter().any(u8::is_ascii_uppercase) {
+                return None;
+            }
+            let pseudo = match name.as_slice() {
+                b":method" => &mut request.method,
+                b":scheme" => &mut request.scheme,
+                b":authority" => &mut request.authority,
+                b":path" => &mut request.path,
+                [b':', ..] => return None,
+                _ => {
+                    request.headers.push((name, value));
+                    continue;
+                }
+            };
+            // pseudo-headers come first and only once
+            if !request.headers.is_empty() || !pseudo.is_empty() {
+                return None;
+            }
+            *pseudo = String::from_utf8(value).ok()?;
+        }
+        let complete =
+            "CONNECT" == request.method || !(request.scheme.is_empty() || request.path.is_empty());
+        (!request.method.is_empty() && complete).then_some(request)
+    }
+}
+
+/// What a [`StreamHandler`] answers a request with.
+#[derive(Clone, Debug, Default)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+    /// Sent in a header block after the body, such as `grpc-status`.
+    pub trailers: Vec<(String, Vec<u8>)>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            ..Self::default()
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: impl Into<Vec<u8>>) -> Self {
+        self.headers.push((name.to_ascii_lowercase(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn trailer(mut self, name: &str, value: impl Into<Vec<u8>>) -> Self {
+        self.trailers
+            .push((name.to_ascii_lowercase(), value.into()));
+        self
+    }
+}
+
+/// Application logic for [`Http2`], called once per stream whose request has completely arrived.
+pub trait StreamHandler {
+    fn request(&mut self, token: usize, request: &Request) -> std::io::Result<Response>;
+}
+
+/// Why frames could not be handled.
+enum Error {
+    /// The connection is unusable; tell the peer with a GOAWAY carrying this code.
+    Connection(u32),
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<HpackError> for Error {
+    fn from(err: HpackError) -> Self {
+        match err {
+            HpackError::ListSize(_) => Error::Connection(ENHANCE_YOUR_CALM),
+            _ => Error::Connection(COMPRESSION_ERROR),
+        }
+    }
+}
+
+/// One stream of a connection, from its first HEADERS until its response has been sent.
+#[derive(Default)]
+struct Stream {
+    request: Option<Request>,
+    /// How much more the peer allows us to send on this stream.
+    window: i64,
+    /// How much more the peer may send on this stream; it is never opened up again, as the body
+    /// it carries is only consumed once complete.
+    receive_window: i64,
+    /// Connection window taken up by the request body, given back once the handler consumed it.
+    received: usize,
+    /// The response body still to be sent and the trailers to send after it.
+    pending: VecDeque<u8>,
+    trailers: Option<Vec<u8>>,
+    responding: bool,
+}
+
+/// A header block whose CONTINUATION frames are still arriving.
+struct Fragments {
+    stream: u32,
+    block: Vec<u8>,
+    end_stream: bool,
+}
+
+/// HTTP/2 state of one connection.
+struct Session {
+    preface: bool,
+    decoder: Decoder,
+    /// How much more the peer allows us to send on the connection.
+    window: i64,
+    /// How much more the peer may send on the connection before its bodies were consumed.
+    receive_window: i64,
+    initial_window: i64,
+    max_frame: usize,
+    streams: BTreeMap<u32, Stream>,
+    last_stream: u32,
+    continuation: Option<Fragments>,
+    going_away: bool,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            preface: false,
+            decoder: Decoder::default().max_list(MAX_HEADER_LIST),
+            window: DEFAULT_WINDOW,
+            receive_window: RECEIVE_WINDOW,
+            initial_window: DEFAULT_WINDOW,
+            max_frame: DEFAULT_MAX_FRAME,
+            streams: BTreeMap::new(),
+            last_stream: 0,
+            continuation: None,
+            going_away: false,
+        }
+    }
+}
+
+impl Session {
+    fn frame<W: Write, H: StreamHandler>(
+        &mut self,
+        token: usize,
+        header: FrameHeader,
+        payload: &[u8],
+        handler: &mut H,
+        send: &mut W,
+    ) -> Result<(), Error> {
+        let FrameHeader {
+            kind,
+            flags,
+            stream,
+            ..
+        } = header;
+        if let Some(ref mut fragments) = self.continuation {
+            if CONTINUATION != kind || fragments.stream != stream {
+                return Err(Error::Connection(PROTOCOL_ERROR));
+            }
+            if fragments.block.len() + payload.len() > MAX_HEADER_LIST {
+                return Err(Error::Connection(ENHANCE_YOUR_CALM));
+            }
+            fragments.block.extend_from_slice(payload);
+            if END_HEADERS == flags & END_HEADERS {
+                let Some(fragments) = self.continuation.take() else {
+                    unreachable!("continuation was just matched");
+                };
+                let Fragments {
+                    stream,
+                    block,
+                    end_stream,
+                } = fragments;
+                self.headers(token, stream, &block, end_stream, handler, send)?;
+            }
+            return Ok(());
+        }
+        match kind {
+            DATA => {
+                if 0 == stream {
+                    return Err(Error::Connection(PROTOCOL_ERROR));
+                }
+                let data = unpad(flags, payload)?;
+                // padding counts against the windows as well
+                let len = payload.len();
+                if len as i64 > self.receive_window {
+                    return Err(Error::Connection(FLOW_CONTROL_ERROR));
+                }
+                self.receive_window -= len as i64;
+                let Some(open) = self.streams.get_mut(&stream) else {
+                    release(&mut self.receive_window, len, send)?;
+                    return self.closed_stream(stream, send);
+                };
+                let Some(ref mut request) = open.request else {
+                    release(&mut self.receive_window, len, send)?;
+                    self.remove(stream, send)?;
+                    return reset(send, stream, STREAM_CLOSED);
+                };
+                open.received += len;
+                open.receive_window -= len as i64;
+                if open.receive_window < 0 {
+                    self.remove(stream, send)?;
+                    return reset(send, stream, FLOW_CONTROL_ERROR);
+                }
+                request.body.extend_from_slice(data);
+                if END_STREAM == flags & END_STREAM {
+                    return self.respond(token, stream, handler, send);
+                }
+            }
+            HEADERS => {
+                if 0 == stream || stream.is_multiple_of(2) {
+                    return Err(Error::Connection(PROTOCOL_ERROR));
+                }
+                let mut block = unpad(flags, payload)?;
+                if PRIORITY_FLAG == flags & PRIORITY_FLAG {
+                    block = block.get(5..).ok_or(Error::Connection(FRAME_SIZE_ERROR))?;
+                }
+                let end_stream = END_STREAM == flags & END_STREAM;
+                if END_HEADERS == flags & END_HEADERS {
+                    self.headers(token, stream, block, end_stream, handler, send)?;
+                } else {
+                    self.continuation = Some(Fragments {
+                        stream,
+                        block: block.to_vec(),
+                        end_stream,
+                    });
+                }
+            }
+            PRIORITY if 5 != payload.len() => {
+                return Err(Error::Connection(FRAME_SIZE_ERROR));
+            }
+            RST_STREAM => {
+                if 0 == stream || stream > self.last_stream {
+                    return Err(Error::Connection(PROTOCOL_ERROR));
+                }
+                if 4 != payload.len() {
+                    return Err(Error::Connection(FRAME_SIZE_ERROR));
+                }
+                self.remove(stream, send)?;
+            }
+            SETTINGS => {
+                if 0 != stream {
+                    return Err(Error::Connection(PROTOCOL_ERROR));
+                }
+                if ACK == flags & ACK {
+                    if !payload.is_empty() {
+                        return Err(Error::Connection(FRAME_SIZE_ERROR));
+                    }
+                    return Ok(());
+                }
+                self.settings(payload)?;
+                write_frame(send, SETTINGS, ACK, 0, &[])?;
+            }
+            PING => {
+                if 8 != payload.len() {
+                    return Err(Error::Connection(FRAME_SIZE_ERROR));
+                }
+                if 0 == flags & ACK {
+                    write_frame(send, PING, ACK, 0, payload)?;
+                }
+            }
+            GOAWAY => {
+                self.going_away = true;
+            }
+            WINDOW_UPDATE => {
+                let increment = match payload {
+                    [a, b, c, d] => i64::from(u32::from_be_bytes([*a, *b, *c, *d]) & 0x7fff_ffff),
+                    _ => return Err(Error::Connection(FRAME_SIZE_ERROR)),
+                };
+                if 0 == stream {
+                    if 0 == increment {
+                        return Err(Error::Connection(PROTOCOL_ERROR));
+                    }
+                    self.window += increment;
+                    if self.window > MAX_WINDOW {
+                        return Err(Error::Connection(FLOW_CONTROL_ERROR));
+                    }
+                } else if let Some(open) = self.streams.get_mut(&stream) {
+                    open.window += increment;
+                    if 0 == increment || open.window > MAX_WINDOW {
+                        let code = match increment {
+                            0 => PROTOCOL_ERROR,
+                            _ => FLOW_CONTROL_ERROR,
+                        };
+                        self.remove(stream, send)?;
+                        return reset(send, stream, code);
+                    }
+                }
+            }
+            CONTINUATION | PUSH_PROMISE => {
+                return Err(Error::Connection(PROTOCOL_ERROR));
+            }
+            // priorities are not acted on and unknown frame types are ignored
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn headers<W: Write, H: StreamHandler>(
+        &mut self,
+        token: usize,
+        stream: u32,
+        block: &[u8],
+        end_stream: bool,
+        handler: &mut H,
+        send: &mut W,
+    ) -> Result<(), Error> {
+        // decoded even when the stream is refused, the decoder's table depends on it
+        let fields = self.decoder.decode(block)?;
+        if let Some(open) = self.streams.get_mut(&stream) {
+            let code = match open.request {
+                Some(ref mut request) if end_stream => {
+                    request.trailers = fields;
+                    return self.respond(token, stream, handler, send);
+                }
+                // trailers have to end the stream
+                Some(_) => PROTOCOL_ERROR,
+                // the request is complete, the stream half closed
+                None => STREAM_CLOSED,
+            };
+            self.remove(stream, send)?;
+            return reset(send, stream, code);
+        }
+        if stream <= self.last_stream {
+            return Err(Error::Connection(STREAM_CLOSED));
+        }
+        self.last_stream = stream;
+        if self.streams.len() >= MAX_STREAMS {
+            return reset(send, stream, REFUSED_STREAM);
+        }
+        let Some(request) = Request::new(stream, fields) else {
+            return reset(send, stream, PROTOCOL_ERROR);
+        };
+        // refused up front instead of once the body overruns the stream window
+        let length = request.header("content-length");
+        let length = length.and_then(|length| std::str::from_utf8(length).ok()?.parse().ok());
+        if length.is_some_and(|length: usize| length > MAX_BODY) {
+            let mut block = Vec::new();
+            Encoder.encode([(&b":status"[..], &b"413"[..])], &mut block);
+            write_headers(send, stream, &block, true, self.max_frame)?;
+            return reset(send, stream, NO_ERROR);
+        }
+        let open = Stream {
+            request: Some(request),
+            window: self.initial_window,
+            receive_window: MAX_BODY as i64,
+            ..Stream::default()
+        };
+        self.streams.insert(stream, open);
+        if end_stream {
+            return self.respond(token, stream, handler, send);
+        }
+        Ok(())
+    }
+
+    /// Forget `stream`, giving back the connection window its request body took up.
+    fn remove<W: Write>(&mut self, stream: u32, send: &mut W) -> std::io::Result<()> {
+        let received = self.streams.remove(&stream).map_or(0, |open| open.received);
+        release(&mut self.receive_window, received, send)
+    }
+
+    /// Frames for a stream that is not open: one we already closed gets reset, one that was never
+    /// opened is a protocol violation.
+    fn closed_stream<W: Write>(&mut self, stream: u32, send: &mut W) -> Result<(), Error> {
+        if stream > self.last_stream {
+            return Err(Error::Connection(PROTOCOL_ERROR));
+        }
+        reset(send, stream, STREAM_CLOSED)
+    }
+
+    fn settings(&mut self, payload: &[u8]) -> Result<(), Error> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(Error::Connection(FRAME_SIZE_ERROR));
+        }
+        for setting in payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => {
+                    return Err(Error::Connection(PROTOCOL_ERROR));
+                }
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let window = i64::from(value);
+                    if window > MAX_WINDOW {
+                        return Err(Error::Connection(FLOW_CONTROL_ERROR));
+                    }
+                    // open streams keep what they already used against the new size
+                    let delta = window - self.initial_window;
+                    for open in self.streams.values_mut() {
+                        open.window += delta;
+                        if open.window > MAX_WINDOW {
+                            return Err(Error::Connection(FLOW_CONTROL_ERROR));
+                        }
+                    }
+                    self.initial_window = window;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    let size = value as usize;
+                    if !(DEFAULT_MAX_FRAME..=0xff_ffff).contains(&size) {
+                        return Err(Error::Connection(PROTOCOL_ERROR));
+                    }
+                    self.max_frame = size;
+                }
+                // the encoder never indexes, so the peer's table size does not matter
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The request on `stream` is complete; send the response head and queue its body.
+    fn respond<W: Write, H: StreamHandler>(
+        &mut self,
+        token: usize,
+        stream: u32,
+        handler: &mut H,
+        send: &mut W,
+    ) -> Result<(), Error> {
+        let Some(open) = self.streams.get_mut(&stream) else {
+            return Ok(());
+        };
+        let Some(request) = open.request.take() else {
+            return Ok(());
+        };
+        let response = handler.request(token, &request)?;
+        release(&mut self.receive_window, open.received, send)?;
+        open.received = 0;
+        let status = response.status.to_string();
+        let fields = std::iter::once((&b":status"[..], status.as_bytes())).chain(
+            response
+                .headers
+                .iter()
+                .map(|(name, value)| (name.as_bytes(), value.as_slice())),
+        );
+        let mut block = Vec::new();
+        Encoder.encode(fields, &mut block);
+        let end_stream = response.body.is_empty() && response.trailers.is_empty();
+        write_headers(send, stream, &block, end_stream, self.max_frame)?;
+        if end_stream {
+            self.streams.remove(&stream);
+            return Ok(());
+        }
+        if !response.trailers.is_empty() {
+            let mut block = Vec::new();
+            let fields = response
+                .trailers
+                .iter()
+                .map(|(name, value)| (name.as_bytes(), value.as_slice()));
+            Encoder.encode(fields, &mut block);
+            open.trailers = Some(block);
+        }
+        open.pending = response.body.into();
+        open.responding = true;
+        Ok(())
+    }
+
+    /// Send as much of the queued response bodies as the flow control windows and [`MAX_PUMP`]
+    /// allow, one frame per stream in turn so that streams share the connection.
+    fn pump<W: Write>(&mut self, send: &mut W) -> std::io::Result<()> {
+        let mut frame = Vec::with_capacity(self.max_frame.min(MAX_PUMP));
+        let mut pumped = 0;
+        loop {
+            let mut progress = false;
+            let mut finished = Vec::new();
+            for (&stream, open) in self.streams.iter_mut().filter(|(_, open)| open.responding) {
+                if pumped >= MAX_PUMP {
+                    break;
+                }
+                let len = open
+                    .pending
+                    .len()
+                    .min(self.max_frame)
+                    .min(MAX_PUMP - pumped)
+                    .min(self.window.min(open.window).max(0) as usize);
+                if 0 == len && !open.pending.is_empty() {
+                    continue;
+                }
+                frame.clear();
+                frame.extend(open.pending.drain(..len));
+                pumped += FRAME_HEADER + len;
+                self.window -= len as i64;
+                open.window -= len as i64;
+                let last = open.pending.is_empty();
+                match open.trailers.take() {
+                    Some(block) if last => {
+                        if !frame.is_empty() {
+                            write_frame(send, DATA, 0, stream, &frame)?;
+                        }
+                        write_headers(send, stream, &block, true, self.max_frame)?;
+                    }
+                    trailers => {
+                        open.trailers = trailers;
+                        let flags = if last { END_STREAM } else { 0 };
+                        write_frame(send, DATA, flags, stream, &frame)?;
+                    }
+                }
+                if last {
+                    finished.push(stream);
+                }
+                progress = true;
+            }
+            for stream in finished {
+                self.streams.remove(&stream);
+            }
+            if !progress {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Strip the padding of a DATA or HEADERS payload.
+fn unpad(flags: u8, payload: &[u8]) -> Result<&[u8], Error> {
+    if 0 == flags & PADDED {
+        return Ok(payload);
+    }
+    let (&padding, rest) = payload
+        .split_first()
+        .ok_or(Error::Connection(FRAME_SIZE_ERROR))?;
+    rest.len()
+        .checked_sub(usize::from(padding))
+        .map(|len| &rest[..len])
+        .ok_or(Error::Connection(PROTOCOL_ERROR))
+}
+
+/// `len` bytes received on the connection have been consumed; let the peer send as many more.
+fn release<W: Write>(window: &mut i64, len: usize, send: &mut W) -> std::io::Result<()> {
+    if 0 == len {
+        return Ok(());
+    }
+    *window += len as i64;
+    write_frame(send, WINDOW_UPDATE, 0, 0, &(len as u32).to_be_bytes())
+}
+
+fn reset<W: Write>(send: &mut W, stream: u32, code: u32) -> Result<(), Error> {
+    write_frame(send, RST_STREAM, 0, stream, &code.to_be_bytes())?;
+    Ok(())
+}
+
+/// A [`Handler`] speaking HTTP/2 with prior knowledge: every connection must start with the
+/// client [`PREFACE`]. Requests on many streams are multiplexed over one connection and each
+/// complete request is handed to a [`StreamHandler`].
+///
+/// Request bodies are collected in memory up to [`MAX_BODY`] per stream and [`RECEIVE_WINDOW`]
+/// per connection; the connection window is opened up again once the handler answered the
+/// request the body belongs to. Response bodies are sent as fast as the client's windows allow,
+/// the rest waits for WINDOW_UPDATE frames or for the connection to take more.
+pub struct Http2<H> {
+    pub handler: H,
+    sessions: BTreeMap<usize, Session>,
+}
+
+impl<H> Http2<H> {
+    pub fn new(handler: H) -> Self {
+        Self {
+            handler,
+            sessions: BTreeMap::new(),
+        }
+    }
+}
+
+impl<C, H: StreamHandler> Handler<C> for Http2<H> {
+    fn received<W: Write>(
+        &mut self,
+        token: usize,
+        receive: &[u8],
+        send: &mut W,
+    ) -> std::io::Result<(usize, Flow)> {
+        let session = self.sessions.entry(token).or_default();
+        let mut consumed = 0;
+        if !session.preface {
+            let len = receive.len().min(PREFACE.len());
+            if PREFACE[..len] != receive[..len] {
+                return Ok((receive.len(), Flow::Close));
+            }
+            if len < PREFACE.len() {
+                return Ok((0, Flow::Continue));
+            }
+            session.preface = true;
+            consumed = PREFACE.len();
+            let mut settings = Vec::new();
+            for (id, value) in [
+                (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_STREAMS),
+                (SETTINGS_INITIAL_WINDOW_SIZE, MAX_BODY),
+                (SETTINGS_MAX_HEADER_LIST_SIZE, MAX_HEADER_LIST),
+            ] {
+                settings.extend(id.to_be_bytes());
+                settings.extend((value as u32).to_be_bytes());
+            }
+            write_frame(send, SETTINGS, 0, 0, &settings)?;
+            let increment = (RECEIVE_WINDOW - DEFAULT_WINDOW) as u32;
+            write_frame(send, WINDOW_UPDATE, 0, 0, &increment.to_be_bytes())?;
+        }
+        while let Some(header) = FrameHeader::parse(&receive[consumed..]) {
+            let result = match header.len > DEFAULT_MAX_FRAME {
+                true => Err(Error::Connection(FRAME_SIZE_ERROR)),
+                false => {
+                    let start = consumed + FRAME_HEADER;
+                    let Some(payload) = receive.get(start..start + header.len) else {
+                        break;
+                    };
+                    consumed = start + header.len;
+                    session.frame(token, header, payload, &mut self.handler, send)
+                }
+            };
+            match result {
+                Ok(()) => {}
+                Err(Error::Io(err)) => return Err(err),
+                Err(Error::Connection(code)) => {
+                    let mut goaway = [0; 8];
+                    goaway[..4].copy_from_slice(&session.last_stream.to_be_bytes());
+                    goaway[4..].copy_from_slice(&code.to_be_bytes());
+                    write_frame(send, GOAWAY, 0, 0, &goaway)?;
+                    return Ok((receive.len(), Flow::Close));
+                }
+            }
+        }
+        session.pump(send)?;
+        if session.going_away && session.streams.is_empty() {
+            return Ok((consumed, Flow::Close));
+        }
+        Ok((consumed, Flow::Continue))
+    }
+
+    fn writable<W: Write>(&mut self, token: usize, send: &mut W) -> std::io::Result<Flow> {
+        let Some(session) = self.sessions.get_mut(&token) else {
+            return Ok(Flow::Continue);
+        };
+        session.pump(send)?;
+        if session.going_away && session.streams.is_empty() {
+            return Ok(Flow::Close);
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn closed(&mut self, token: usize) {
+        self.sessions.remove(&token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{ErrorKind, Read, Write},
+        thread,
+        time::Duration,
+    };
+
+    use mio::net::TcpStream;
+
+    use super::{
+        write_frame, write_headers, FrameHeader, Http2, Request, Response, StreamHandler, ACK,
+        CONTINUATION, DATA, DEFAULT_WINDOW, END_HEADERS, END_STREAM, ENHANCE_YOUR_CALM,
+        FLOW_CONTROL_ERROR, FRAME_HEADER, FRAME_SIZE_ERROR, GOAWAY, HEADERS, MAX_BODY, NO_ERROR,
+        PING, PREFACE, PROTOCOL_ERROR, RECEIVE_WINDOW, RST_STREAM, SETTINGS,
+        SETTINGS_INITIAL_WINDOW_SIZE, STREAM_CLOSED, WINDOW_UPDATE,
+    };
+    use crate::{
+        hpack::{Decoder, Encoder},
+        ring::RingBuffer,
+        tests::{new_loopback_address, TestServer},
+        Flow, Handler, Listener, Server, Shutdown,
+    };
+
+    /// Answers with the method, path and body length it received, or with `len` bytes and a
+    /// trailer for `/large`.
+    struct Describe {
+        len: usize,
+    }
+
+    impl StreamHandler for Describe {
+        fn request(&mut self, _token: usize, request: &Request) -> std::io::Result<Response> {
+            if "/large" == request.path {
+                let body: Vec<u8> = (0..self.len).map(|i| b'a' + (i % 26) as u8).collect();
+                return Ok(Response::new(200).body(body).trailer("grpc-status", "0"));
+            }
+            let text = format!("{} {} {}", request.method, request.path, request.body.len());
+            Ok(Response::new(200)
+                .header("Content-Type", "text/plain")
+                .body(text))
+        }
+    }
+
+    type Frame = (FrameHeader, Vec<u8>);
+
+    fn read_frame(client: &mut std::net::TcpStream) -> Frame {
+        let mut head = [0; FRAME_HEADER];
+        client.read_exact(&mut head).unwrap();
+        let header = FrameHeader::parse(&head).unwrap();
+        let mut payload = vec![0; header.len];
+        client.read_exact(&mut payload).unwrap();
+        (header, payload)
+    }
+
+    fn request(stream: u32, method: &str, path: &str) -> Vec<u8> {
+        let mut block = Vec::new();
+        let fields = [
+            (":method", method),
+            (":scheme", "http"),
+            (":path", path),
+            (":authority", "localhost"),
+        ];
+        Encoder.encode(
+            fields.iter().map(|(n, v)| (n.as_bytes(), v.as_bytes())),
+            &mut block,
+        );
+        let mut frames = Vec::new();
+        let flags = if "GET" == method { END_STREAM } else { 0 };
+        write_frame(&mut frames, HEADERS, flags | END_HEADERS, stream, &block).unwrap();
+        frames
+    }
+
+    /// Feed `receive` to a fresh handler and return the frames it answered with.
+    fn exchange(receive: &[u8]) -> (Vec<Frame>, Flow) {
+        let mut handler = Http2::new(Describe { len: 0 });
+        let mut send = Vec::new();
+        let (_, flow) =
+            <Http2<_> as Handler<TcpStream>>::received(&mut handler, 1, receive, &mut send)
+                .unwrap();
+        let mut frames = Vec::new();
+        let mut rest = &send[..];
+        while let Some(header) = FrameHeader::parse(rest) {
+            let payload = rest[FRAME_HEADER..FRAME_HEADER + header.len].to_vec();
+            rest = &rest[FRAME_HEADER + header.len..];
+            frames.push((header, payload));
+        }
+        (frames, flow)
+    }
+
+    #[test]
+    fn multiplexes_streams_over_one_connection() {
+        let mut ring = RingBuffer::new(16);
+        let mut handler = Http2::new(Describe { len: 0 });
+        let addr = new_loopback_address();
+        let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                TestServer::serve::<1, _>(listener, 128, &mut ring, &mut handler, &shutdown)
+            });
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            let mut send = PREFACE.to_vec();
+            write_frame(&mut send, SETTINGS, 0, 0, &[]).unwrap();
+            send.extend(request(1, "GET", "/a"));
+            send.extend(request(3, "POST", "/b"));
+            send.extend(request(5, "GET", "/c"));
+            write_frame(&mut send, DATA, 0, 3, b"hel").unwrap();
+            write_frame(&mut send, PING, 0, 0, b"12345678").unwrap();
+            // sent in pieces to have frames split across reads
+            for part in send.chunks(17) {
+                client.write_all(part).unwrap();
+            }
+            client.flush().unwrap();
+            thread::sleep(Duration::from_millis(20));
+            let mut send = Vec::new();
+            write_frame(&mut send, DATA, END_STREAM, 3, b"lo").unwrap();
+            client.write_all(&send).unwrap();
+
+            let (settings, _) = read_frame(&mut client);
+            assert_eq!((settings.kind, settings.flags), (SETTINGS, 0));
+            let mut decoder = Decoder::default();
+            let mut bodies = [Vec::new(), Vec::new(), Vec::new()];
+            let mut ended = 0;
+            let (mut acked, mut ponged) = (false, false);
+            while ended < 3 {
+                let (header, payload) = read_frame(&mut client);
+                let end = END_STREAM == header.flags & END_STREAM;
+                match header.kind {
+                    SETTINGS => acked = ACK == header.flags,
+                    PING => ponged = (ACK, &b"12345678"[..]) == (header.flags, &payload[..]),
+                    WINDOW_UPDATE => {}
+                    HEADERS => {
+                        let fields = decoder.decode(&payload).unwrap();
+                        assert_eq!(fields[0], (b":status".to_vec(), b"200".to_vec()));
+                        assert_eq!(
+                            fields[1],
+                            (b"content-type".to_vec(), b"text/plain".to_vec())
+                        );
+                        assert!(!end);
+                    }
+                    DATA => {
+                        bodies[header.stream as usize / 2].extend_from_slice(&payload);
+                        ended += end as usize;
+                    }
+                    kind => panic!("unexpected frame {kind}"),
+                }
+            }
+            assert!(acked && ponged);
+            assert_eq!(bodies, [&b"GET /a 0"[..], b"POST /b 5", b"GET /c 0"]);
+
+            let mut send = Vec::new();
+            write_frame(&mut send, GOAWAY, 0, 0, &[0; 8]).unwrap();
+            client.write_all(&send).unwrap();
+            assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+
+        assert_eq!(ring.in_use(), 0);
+    }
+
+    #[test]
+    fn sends_no_more_than_the_windows_allow() {
+        let mut ring = RingBuffer::new(64);
+        let mut handler = Http2::new(Describe { len: 100_000 });
+        let addr = new_loopback_address();
+        let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                TestServer::serve::<1, _>(listener, 128, &mut ring, &mut handler, &shutdown)
+            });
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            let mut setting = SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes().to_vec();
+            setting.extend(1000u32.to_be_bytes());
+            let mut send = PREFACE.to_vec();
+            write_frame(&mut send, SETTINGS, 0, 0, &setting).unwrap();
+            send.extend(request(1, "GET", "/large"));
+            client.write_all(&send).unwrap();
+
+            let mut received = 0;
+            let mut data = |client: &mut std::net::TcpStream, expected: usize| {
+                while received < expected {
+                    let (header, payload) = read_frame(client);
+                    if DATA == header.kind {
+                        assert!(payload.len() <= 16_384);
+                        received += payload.len();
+                    }
+                }
+                assert_eq!(received, expected);
+                client
+                    .set_read_timeout(Some(Duration::from_millis(50)))
+                    .unwrap();
+                let err = client.read(&mut [0; 1]).unwrap_err();
+                assert!(matches!(
+                    err.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut
+                ));
+                client.set_read_timeout(None).unwrap();
+            };
+            data(&mut client, 1000);
+            // a larger initial window applies to the stream already open
+            let mut setting = SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes().to_vec();
+            setting.extend(3000u32.to_be_bytes());
+            let mut send = Vec::new();
+            write_frame(&mut send, SETTINGS, 0, 0, &setting).unwrap();
+            client.write_all(&send).unwrap();
+            data(&mut client, 3000);
+            // the stream window is large enough now, the connection window is not
+            let mut send = Vec::new();
+            write_frame(&mut send, WINDOW_UPDATE, 0, 1, &100_000u32.to_be_bytes()).unwrap();
+            client.write_all(&send).unwrap();
+            data(&mut client, 65_535);
+
+            let mut send = Vec::new();
+            write_frame(&mut send, WINDOW_UPDATE, 0, 0, &100_000u32.to_be_bytes()).unwrap();
+            client.write_all(&send).unwrap();
+            let mut decoder = Decoder::default();
+            let trailers = loop {
+                let (header, payload) = read_frame(&mut client);
+                match header.kind {
+                    DATA => received += payload.len(),
+                    HEADERS => break (header.flags, decoder.decode(&payload).unwrap()),
+                    kind => panic!("unexpected frame {kind}"),
+                }
+            };
+            assert_eq!(received, 100_000);
+            assert_eq!(trailers.0, END_STREAM | END_HEADERS);
+            assert_eq!(trailers.1, [(b"grpc-status".to_vec(), b"0".to_vec())]);
+            drop(client);
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+
+        assert_eq!(ring.in_use(), 0);
+    }
+
+    #[test]
+    fn pumps_large_bodies_through_a_small_ring() {
+        let mut ring = RingBuffer::new(16);
+        let mut handler = Http2::new(Describe { len: 200_000 });
+        let addr = new_loopback_address();
+        let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                TestServer::serve::<1, _>(listener, 128, &mut ring, &mut handler, &shutdown)
+            });
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            // windows far wider than the ring
+            let mut setting = SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes().to_vec();
+            setting.extend(1_000_000u32.to_be_bytes());
+            let mut send = PREFACE.to_vec();
+            write_frame(&mut send, SETTINGS, 0, 0, &setting).unwrap();
+            write_frame(&mut send, WINDOW_UPDATE, 0, 0, &1_000_000u32.to_be_bytes()).unwrap();
+            send.extend(request(1, "GET", "/large"));
+            client.write_all(&send).unwrap();
+
+            let mut received = 0;
+            loop {
+                let (header, payload) = read_frame(&mut client);
+                match header.kind {
+                    DATA => received += payload.len(),
+                    HEADERS if END_STREAM == header.flags & END_STREAM => break,
+                    _ => {}
+                }
+            }
+            assert_eq!(received, 200_000);
+            drop(client);
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+
+        assert_eq!(ring.in_use(), 0);
+    }
+
+    #[test]
+    fn answers_protocol_errors() {
+        let (frames, flow) = exchange(b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!((frames.len(), flow), (0, Flow::Close));
+        let (frames, flow) = exchange(&PREFACE[..10]);
+        assert_eq!((frames.len(), flow), (0, Flow::Continue));
+
+        // streams opened by clients are odd
+        let mut receive = PREFACE.to_vec();
+        receive.extend(request(2, "GET", "/"));
+        let (frames, flow) = exchange(&receive);
+        assert_eq!(flow, Flow::Close);
+        let (goaway, payload) = frames.last().unwrap();
+        assert_eq!(goaway.kind, GOAWAY);
+        assert_eq!(payload[4..], PROTOCOL_ERROR.to_be_bytes());
+
+        // a header block split across CONTINUATION, then data on a stream that was skipped
+        let mut receive = PREFACE.to_vec();
+        let head = request(1, "POST", "/split");
+        let block = &head[FRAME_HEADER..];
+        let mut split = Vec::new();
+        write_headers(&mut split, 1, block, false, 5).unwrap();
+        assert_eq!(split[4], 0);
+        assert_eq!(split[FRAME_HEADER + 5 + 3], CONTINUATION);
+        receive.extend(split);
+        write_frame(&mut receive, DATA, END_STREAM, 1, b"body").unwrap();
+        receive.extend(request(5, "GET", "/b"));
+        write_frame(&mut receive, DATA, 0, 3, b"late").unwrap();
+        let (frames, flow) = exchange(&receive);
+        assert_eq!(flow, Flow::Continue);
+        let kinds: Vec<(u8, u32)> = frames.iter().map(|(h, _)| (h.kind, h.stream)).collect();
+        assert_eq!(
+            kinds,
+            [
+                (SETTINGS, 0),
+                (WINDOW_UPDATE, 0),
+                (WINDOW_UPDATE, 0),
+                (HEADERS, 1),
+                (HEADERS, 5),
+                (WINDOW_UPDATE, 0),
+                (RST_STREAM, 3),
+                (DATA, 1),
+                (DATA, 5)
+            ]
+        );
+        assert_eq!(frames[6].1, STREAM_CLOSED.to_be_bytes());
+        assert_eq!(frames[7].1, b"POST /split 4");
+
+        // anything but CONTINUATION in the middle of a header block
+        let mut receive = PREFACE.to_vec();
+        write_frame(&mut receive, HEADERS, 0, 1, &block[..2]).unwrap();
+        write_frame(&mut receive, PING, 0, 0, &[0; 8]).unwrap();
+        let (frames, flow) = exchange(&receive);
+        assert_eq!(flow, Flow::Close);
+        assert_eq!(frames[2].1[4..], PROTOCOL_ERROR.to_be_bytes());
+
+        // a header block larger than the header list may be
+        let mut receive = PREFACE.to_vec();
+        write_frame(&mut receive, HEADERS, 0, 1, &[0; 16_384]).unwrap();
+        for _ in 0..4 {
+            write_frame(&mut receive, CONTINUATION, 0, 1, &[0; 16_384]).unwrap();
+        }
+        let (frames, flow) = exchange(&receive);
+        assert_eq!(flow, Flow::Close);
+        assert_eq!(frames[2].1[4..], ENHANCE_YOUR_CALM.to_be_bytes());
+
+        // a reset one byte short
+        let mut receive = PREFACE.to_vec();
+        receive.extend(request(1, "GET", "/"));
+        write_frame(&mut receive, RST_STREAM, 0, 1, &[0; 3]).unwrap();
+        let (frames, flow) = exchange(&receive);
+        assert_eq!(flow, Flow::Close);
+        let (goaway, payload) = frames.last().unwrap();
+        assert_eq!(goaway.kind, GOAWAY);
+        assert_eq!(payload[4..], FRAME_SIZE_ERROR.to_be_bytes());
+
+        // headers on a half closed stream only reset that stream
+        let mut receive = PREFACE.to_vec();
+        receive.extend(request(1, "POST", "/"));
+        receive.extend(request(1, "POST", "/"));
+        receive.extend(request(3, "POST", "/"));
+        write_frame(&mut receive, DATA, END_STREAM, 3, b"").unwrap();
+        receive.extend(request(3, "GET", "/"));
+        let (frames, flow) = exchange(&receive);
+        assert_eq!(flow, Flow::Continue);
+        let kinds: Vec<(u8, u32)> = frames.iter().map(|(h, _)| (h.kind, h.stream)).collect();
+        assert_eq!(
+            kinds,
+            [
+                (SETTINGS, 0),
+                (WINDOW_UPDATE, 0),
+                (RST_STREAM, 1),
+                (HEADERS, 3),
+                (RST_STREAM, 3)
+            ]
+        );
+        assert_eq!(frames[2].1, PROTOCOL_ERROR.to_be_bytes());
+        assert_eq!(frames[4].1, STREAM_CLOSED.to_be_bytes());
+    }
+
+    #[test]
+    fn bounds_request_bodies_by_the_receive_windows() {
+        let kinds = |frames: &[Frame]| -> Vec<(u8, u32)> {
+            frames.iter().map(|(h, _)| (h.kind, h.stream)).collect()
+        };
+        let mut receive = PREFACE.to_vec();
+        receive.extend(request(1, "POST", "/"));
+        write_frame(&mut receive, DATA, 0, 1, &[0; 100]).unwrap();
+        let (frames, _) = exchange(&receive);
+        // the body is not consumed before the request is complete
+        assert_eq!(kinds(&frames), [(SETTINGS, 0), (WINDOW_UPDATE, 0)]);
+        let increment = (RECEIVE_WINDOW - DEFAULT_WINDOW) as u32;
+        assert_eq!(frames[1].1, increment.to_be_bytes());
+        write_frame(&mut receive, DATA, END_STREAM, 1, &[0; 50]).unwrap();
+        let (frames, _) = exchange(&receive);
+        assert_eq!(
+            kinds(&frames),
+            [
+                (SETTINGS, 0),
+                (WINDOW_UPDATE, 0),
+                (WINDOW_UPDATE, 0),
+                (HEADERS, 1),
+                (DATA, 1)
+            ]
+        );
+        assert_eq!(frames[2].1, 150u32.to_be_bytes());
+
+        // a body overrunning the stream window resets the stream and frees what it took up
+        let mut receive = PREFACE.to_vec();
+        receive.extend(request(1, "POST", "/"));
+        for _ in 0..MAX_BODY / 16_384 {
+            write_frame(&mut receive, DATA, 0, 1, &[0; 16_384]).unwrap();
+        }
+        write_frame(&mut receive, DATA, END_STREAM, 1, b"x").unwrap();
+        let (frames, flow) = exchange(&receive);
+        assert_eq!(flow, Flow::Continue);
+        assert_eq!(
+            kinds(&frames),
+            [
+                (SETTINGS, 0),
+                (WINDOW_UPDATE, 0),
+                (WINDOW_UPDATE, 0),
+                (RST_STREAM, 1)
+            ]
+        );
+        assert_eq!(frames[2].1, (MAX_BODY as u32 + 1).to_be_bytes());
+        assert_eq!(frames[3].1, FLOW_CONTROL_ERROR.to_be_bytes());
+
+        // a body announced to be too large is refused before it is sent
+        let mut block = Vec::new();
+        let fields = [
+            (":method", "POST"),
+            (":scheme", "http"),
+            (":path", "/"),
+            ("content-length", "2000000"),
+        ];
+        Encoder.encode(
+            fields.iter().map(|(n, v)| (n.as_bytes(), v.as_bytes())),
+            &mut block,
+        );
+        let mut receive = PREFACE.to_vec();
+        write_frame(&mut receive, HEADERS, END_HEADERS, 1, &block).unwrap();
+        let (frames, _) = exchange(&receive);
+        assert_eq!(
+            kinds(&frames),
+            [
+                (SETTINGS, 0),
+                (WINDOW_UPDATE, 0),
+                (HEADERS, 1),
+                (RST_STREAM, 1)
+            ]
+        );
+        let fields = Decoder::default().decode(&frames[2].1).unwrap();
+        assert_eq!(fields, [(b":status".to_vec(), b"413".to_vec())]);
+        assert_eq!(frames[2].0.flags, END_STREAM | END_HEADERS);
+        assert_eq!(frames[3].1, NO_ERROR.to_be_bytes());
+    }
+}
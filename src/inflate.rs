@@ -0,0 +1,371 @@
+use std::io::ErrorKind::*;
+
+use miniz_oxide::inflate::{
+    core::{decompress, inflate_flags::*, DecompressorOxide},
+    TINFLStatus,
+};
+
+use crate::{
+    pipeline::{Ratio, Stage},
+    ring::{RingBuffer, Span},
+};
+
+/// Output may be at most this many times the compressed input unless configured otherwise.
+pub const DEFAULT_MAX_RATIO: usize = 100;
+
+/// Compressed input is copied out of the way in chunks this large before it is decompressed.
+const SCRATCH: usize = 512;
+
+const GZIP_TRAILER: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Raw deflate blocks, RFC 1951.
+    Deflate,
+    /// Deflate with the zlib header and Adler-32 trailer, RFC 1950.
+    Zlib,
+    /// Deflate with the gzip header and CRC-32 trailer, RFC 1952.
+    Gzip,
+}
+
+/// Decompression in place: the compressed bytes are moved to the back of the buffer and the
+/// output grows from its front into the space they free as they are read.
+///
+/// As a [`Stage`] the buffer must fit the largest output the maximum ratio allows;
+/// [`Inflate::run_span`] instead starts from an expected ratio and grows the span on demand.
+/// Output beyond the maximum ratio fails with `InvalidData`, which stops decompression bombs.
+pub struct Inflate {
+    format: Format,
+    max_ratio: usize,
+    expected_ratio: usize,
+    decompressor: Box<DecompressorOxide>,
+}
+
+/// How far one run got: output is `buffer[..output]`, compressed input not yet read is the
+/// unread part of `scratch` followed by `buffer[input..]`.
+struct Progress {
+    input: usize,
+    output: usize,
+    limit: usize,
+    scratch: [u8; SCRATCH],
+    start: usize,
+    end: usize,
+}
+
+enum Step {
+    Done,
+    /// The output reached the compressed input that is still unread.
+    Full,
+}
+
+impl Inflate {
+    pub fn new(format: Format) -> Self {
+        Self {
+            format,
+            max_ratio: DEFAULT_MAX_RATIO,
+            expected_ratio: 4,
+            decompressor: Box::default(),
+        }
+    }
+
+    /// Fail once the output is more than `max_ratio` times the compressed input.
+    pub fn max_ratio(mut self, max_ratio: usize) -> Self {
+        assert!(max_ratio > 0);
+        self.max_ratio = max_ratio;
+        self
+    }
+
+    /// Headroom [`Inflate::run_span`] reserves up front, as a multiple of the compressed input.
+    pub fn expected_ratio(mut self, expected_ratio: usize) -> Self {
+        self.expected_ratio = expected_ratio;
+        self
+    }
+
+    /// Decompress the bytes of `span` in place, growing it into further segments whenever the
+    /// output outgrows the headroom reserved so far. The span's bytes are unspecified on error.
+    pub fn run_span(
+        &mut self,
+        ring: &mut RingBuffer,
+        span: &mut Span,
+        owner: usize,
+    ) -> std::io::Result<()> {
+        let len = span.len();
+        ring.reserve(span, owner, len.saturating_mul(self.expected_ratio))?;
+        let mut progress = self.begin(ring.span_mut(span), len)?;
+        loop {
+            let buffer = ring.span_mut(span);
+            match self.inflate(&mut progress, buffer)? {
+                Step::Done => {
+                    let len = self.finish(&progress, buffer)?;
+                    ring.set_len(span, len);
+                    return Ok(());
+                }
+                Step::Full => {
+                    // keep everything up to the unread input while the span grows or moves
+                    let capacity = buffer.len();
+                    let unread = capacity - progress.input;
+                    ring.set_len(span, capacity);
+                    let additional = progress
+                        .output
+                        .max(ring.segment_len())
+                        .min(progress.limit + 1 - progress.output);
+                    ring.reserve(span, owner, additional)?;
+                    let buffer = ring.span_mut(span);
+                    let input = buffer.len() - unread;
+                    buffer.copy_within(progress.input..capacity, input);
+                    progress.input = input;
+                }
+            }
+        }
+    }
+
+    fn begin(&mut self, buffer: &mut [u8], len: usize) -> std::io::Result<Progress> {
+        let input = buffer.len() - len;
+        buffer.copy_within(..len, input);
+        let header = match self.format {
+            Format::Gzip => gzip_header(&buffer[input..])?,
+            Format::Deflate | Format::Zlib => 0,
+        };
+        self.decompressor.init();
+        Ok(Progress {
+            input: input + header,
+            output: 0,
+            limit: len.saturating_mul(self.max_ratio),
+            scratch: [0; SCRATCH],
+            start: 0,
+            end: 0,
+        })
+    }
+
+    fn inflate(&mut self, progress: &mut Progress, buffer: &mut [u8]) -> std::io::Result<Step> {
+        let flags = match self.format {
+            Format::Zlib => TINFL_FLAG_PARSE_ZLIB_HEADER,
+            Format::Deflate | Format::Gzip => 0,
+        } | TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+        loop {
+            if progress.start == progress.end && progress.input < buffer.len() {
+                let n = SCRATCH.min(buffer.len() - progress.input);
+                progress.scratch[..n].copy_from_slice(&buffer[progress.input..][..n]);
+                progress.input += n;
+                progress.start = 0;
+                progress.end = n;
+            }
+            let more = match progress.input < buffer.len() {
+                true => TINFL_FLAG_HAS_MORE_INPUT,
+                false => 0,
+            };
+            // one byte past the limit is enough to tell that it was exceeded
+            let out = progress.input.min(progress.limit + 1);
+            let (status, read, written) = decompress(
+                &mut self.decompressor,
+                &progress.scratch[progress.start..progress.end],
+                &mut buffer[..out],
+                progress.output,
+                flags | more,
+            );
+            progress.start += read;
+            progress.output += written;
+            if progress.output > progress.limit {
+                return Err(std::io::Error::new(
+                    InvalidData,
+                    "decompressed data exceeds the maximum expansion ratio",
+                ));
+            }
+            match status {
+                TINFLStatus::Done => return Ok(Step::Done),
+                TINFLStatus::NeedsMoreInput => {}
+                TINFLStatus::HasMoreOutput if progress.start == progress.end && 0 != more => {}
+                TINFLStatus::HasMoreOutput => return Ok(Step::Full),
+                TINFLStatus::FailedCannotMakeProgress => {
+                    return Err(std::io::Error::new(
+                        UnexpectedEof,
+                        "compressed data is truncated",
+                    ))
+                }
+                _ => {
+                    return Err(std::io::Error::new(
+                        InvalidData,
+                        "compressed data is corrupt",
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Check what follows the compressed data and return the output length.
+    fn finish(&self, progress: &Progress, buffer: &[u8]) -> std::io::Result<usize> {
+        let scratch = &progress.scratch[progress.start..progress.end];
+        let rest = &buffer[progress.input..];
+        let expected = match self.format {
+            Format::Gzip => GZIP_TRAILER,
+            Format::Deflate | Format::Zlib => 0,
+        };
+        if scratch.len() + rest.len() != expected {
+            return Err(std::io::Error::new(
+                InvalidData,
+                "unexpected bytes after compressed data",
+            ));
+        }
+        let output = &buffer[..progress.output];
+        if Format::Gzip == self.format {
+            let mut trailer = [0; GZIP_TRAILER];
+            trailer[..scratch.len()].copy_from_slice(scratch);
+            trailer[scratch.len()..].copy_from_slice(rest);
+            let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+            let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+            if crc != crc32fast::hash(output) || size != output.len() as u32 {
+                return Err(std::io::Error::new(InvalidData, "gzip checksum mismatch"));
+            }
+        }
+        Ok(output.len())
+    }
+}
+
+impl Stage for Inflate {
+    /// The compressed input stays at the back of the buffer until it is read, so room for it
+    /// comes on top of the largest output.
+    fn ratio(&self) -> Ratio {
+        Ratio::new(self.max_ratio + 1, 1)
+    }
+
+    fn run(&mut self, buffer: &mut [u8], len: usize) -> std::io::Result<usize> {
+        let mut progress = self.begin(buffer, len)?;
+        match self.inflate(&mut progress, buffer)? {
+            Step::Done => self.finish(&progress, buffer),
+            Step::Full => Err(std::io::Error::new(
+                StorageFull,
+                "segment lacks headroom for decompressed data",
+            )),
+        }
+    }
+}
+
+/// Length of the gzip member header at the start of `input`.
+fn gzip_header(input: &[u8]) -> std::io::Result<usize> {
+    const FHCRC: u8 = 1 << 1;
+    const FEXTRA: u8 = 1 << 2;
+    const FNAME: u8 = 1 << 3;
+    const FCOMMENT: u8 = 1 << 4;
+
+    let truncated = || std::io::Error::new(UnexpectedEof, "gzip header is truncated");
+    if input.len() < 10 {
+        return Err(truncated());
+    }
+    if [0x1f, 0x8b, 8] != input[..3] {
+        return Err(std::io::Error::new(InvalidData, "not a gzip member"));
+    }
+    let flags = input[3];
+    let mut len = 10;
+    if 0 != flags & FEXTRA {
+        let extra = input.get(len..len + 2).ok_or_else(truncated)?;
+        len += 2 + usize::from(u16::from_le_bytes([extra[0], extra[1]]));
+    }
+    for field in [FNAME, FCOMMENT] {
+        if 0 != flags & field {
+            let rest = input.get(len..).ok_or_else(truncated)?;
+            len += 1 + rest.iter().position(|&b| 0 == b).ok_or_else(truncated)?;
+        }
+    }
+    if 0 != flags & FHCRC {
+        len += 2;
+    }
+    match len <= input.len() {
+        true => Ok(len),
+        false => Err(truncated()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use miniz_oxide::deflate::{compress_to_vec, compress_to_vec_zlib};
+
+    use super::{Format, Inflate};
+    use crate::pipeline::{Pipeline, Stage};
+    use crate::ring::{RingBuffer, Span};
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut member = vec![0x1f, 0x8b, 8, 1 << 3, 0, 0, 0, 0, 0, 255];
+        member.extend_from_slice(b"name.txt\0");
+        member.extend(compress_to_vec(data, 6));
+        member.extend(crc32fast::hash(data).to_le_bytes());
+        member.extend((data.len() as u32).to_le_bytes());
+        member
+    }
+
+    fn text(len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| b"lorem ipsum dolor "[i % 18] ^ (i / 97) as u8)
+            .collect()
+    }
+
+    fn span_of(ring: &mut RingBuffer, bytes: &[u8]) -> Span {
+        let mut span = Span::default();
+        ring.reserve(&mut span, 1, bytes.len()).unwrap();
+        ring.spare_mut(&mut span, 1).unwrap()[..bytes.len()].copy_from_slice(bytes);
+        ring.commit(&mut span, bytes.len());
+        span
+    }
+
+    #[test]
+    fn inflates_every_format_as_a_stage() {
+        let data = text(3000);
+        for (format, compressed) in [
+            (Format::Deflate, compress_to_vec(&data, 6)),
+            (Format::Zlib, compress_to_vec_zlib(&data, 6)),
+            (Format::Gzip, gzip(&data)),
+        ] {
+            let mut pipeline = Pipeline::new().stage(Inflate::new(format).max_ratio(50));
+            let mut buffer = vec![0; pipeline.capacity(compressed.len())];
+            buffer[..compressed.len()].copy_from_slice(&compressed);
+            let n = pipeline.run(&mut buffer, compressed.len()).unwrap();
+            assert_eq!(buffer[..n], data[..], "{format:?}");
+        }
+
+        let mut corrupt = gzip(&data);
+        let last = corrupt.len() - 5;
+        corrupt[last] ^= 1;
+        let mut inflate = Inflate::new(Format::Gzip);
+        let mut buffer = vec![0; inflate.ratio().max_output(corrupt.len())];
+        buffer[..corrupt.len()].copy_from_slice(&corrupt);
+        let err = inflate.run(&mut buffer, corrupt.len()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn span_grows_into_further_segments() {
+        let data = text(40 * 1024);
+        let compressed = gzip(&data);
+        let mut ring = RingBuffer::with_segment_len(256, 1024);
+        let mut span = span_of(&mut ring, &compressed);
+        let segments = span.segments().count();
+
+        Inflate::new(Format::Gzip)
+            .expected_ratio(1)
+            .run_span(&mut ring, &mut span, 1)
+            .unwrap();
+        assert_eq!(ring.bytes(&span), &data[..]);
+        assert!(span.segments().count() > segments);
+        ring.release_span(&mut span);
+        assert_eq!(ring.in_use(), 0);
+    }
+
+    #[test]
+    fn rejects_output_beyond_the_maximum_ratio() {
+        let bomb = compress_to_vec(&[0; 64 * 1024], 10);
+        let mut ring = RingBuffer::with_segment_len(128, 1024);
+        let mut span = span_of(&mut ring, &bomb);
+        let err = Inflate::new(Format::Deflate)
+            .max_ratio(8)
+            .run_span(&mut ring, &mut span, 1)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        let truncated = &compress_to_vec(&text(2000), 6)[..100];
+        let mut buffer = vec![0; 4096];
+        buffer[..100].copy_from_slice(truncated);
+        let err = Inflate::new(Format::Deflate)
+            .run(&mut buffer, 100)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
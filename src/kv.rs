@@ -0,0 +1,709 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io::{ErrorKind::*, Read, Write},
+    marker::PhantomData,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::{pool::Pool, ring::RingBuffer, Client, Connect, EventLoop, Flow, Handler};
+
+/// Longest frame accepted, its kind included.
+pub const MAX_FRAME: usize = 16 << 20;
+
+const GET: u8 = 1;
+const PUT: u8 = 2;
+const DELETE: u8 = 3;
+const REPLICA_PUT: u8 = 4;
+const REPLICA_DELETE: u8 = 5;
+const VALUE: u8 = 6;
+const NOT_FOUND: u8 = 7;
+const DONE: u8 = 8;
+const ERROR: u8 = 9;
+const UNREPLICATED: u8 = 10;
+
+/// A unit of the key value protocol: a big endian `u32` length, a kind byte and a body.
+///
+/// Clients send `Get`, `Put` and `Delete` and are answered with `Value`, `NotFound`, `Done`,
+/// `Unreplicated` or `Error`. A node passes the writes it takes on to its peers as `ReplicaPut` and
+/// `ReplicaDelete`, which the peers apply without passing them on again and answer with `Done`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Frame<'a> {
+    Get(&'a [u8]),
+    Put {
+        key: &'a [u8],
+        value: &'a [u8],
+    },
+    Delete(&'a [u8]),
+    ReplicaPut {
+        key: &'a [u8],
+        value: &'a [u8],
+    },
+    ReplicaDelete(&'a [u8]),
+    Value(&'a [u8]),
+    NotFound,
+    Done,
+    /// The write was applied by the node but fewer than its quorum acknowledged it.
+    Unreplicated,
+    Error(&'a [u8]),
+}
+
+fn invalid(what: &str) -> std::io::Error {
+    std::io::Error::new(InvalidData, format!("malformed kv frame: {what}"))
+}
+
+/// A body of a `u32` key length, the key and the value.
+fn key_value(body: &[u8]) -> std::io::Result<(&[u8], &[u8])> {
+    let len = body.get(..4).ok_or_else(|| invalid("truncated"))?;
+    let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+    let key = body.get(4..4 + len).ok_or_else(|| invalid("truncated"))?;
+    Ok((key, &body[4 + len..]))
+}
+
+impl<'a> Frame<'a> {
+    /// The frame at the start of `buffer` with its length, or `None` until all of it arrived.
+    pub fn parse(buffer: &'a [u8]) -> std::io::Result<Option<(Self, usize)>> {
+        let Some(len) = buffer.get(..4) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        if 0 == len || len > MAX_FRAME {
+            return Err(invalid("length"));
+        }
+        let Some(frame) = buffer.get(4..4 + len) else {
+            return Ok(None);
+        };
+        let body = &frame[1..];
+        let frame = match frame[0] {
+            GET => Frame::Get(body),
+            PUT => {
+                let (key, value) = key_value(body)?;
+                Frame::Put { key, value }
+            }
+            DELETE => Frame::Delete(body),
+            REPLICA_PUT => {
+                let (key, value) = key_value(body)?;
+                Frame::ReplicaPut { key, value }
+            }
+            REPLICA_DELETE => Frame::ReplicaDelete(body),
+            VALUE => Frame::Value(body),
+            NOT_FOUND => Frame::NotFound,
+            DONE => Frame::Done,
+            UNREPLICATED => Frame::Unreplicated,
+            ERROR => Frame::Error(body),
+            _ => return Err(invalid("kind")),
+        };
+        Ok(Some((frame, 4 + len)))
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        let (kind, key, body): (u8, Option<&[u8]>, &[u8]) = match self {
+            Frame::Get(key) => (GET, None, key),
+            Frame::Put { key, value } => (PUT, Some(key), value),
+            Frame::Delete(key) => (DELETE, None, key),
+            Frame::ReplicaPut { key, value } => (REPLICA_PUT, Some(key), value),
+            Frame::ReplicaDelete(key) => (REPLICA_DELETE, None, key),
+            Frame::Value(value) => (VALUE, None, value),
+            Frame::NotFound => (NOT_FOUND, None, &[]),
+            Frame::Done => (DONE, None, &[]),
+            Frame::Unreplicated => (UNREPLICATED, None, &[]),
+            Frame::Error(text) => (ERROR, None, text),
+        };
+        let head = key.map_or(0, |key| 4 + key.len());
+        out.write_all(&((1 + head + body.len()) as u32).to_be_bytes())?;
+        out.write_all(&[kind])?;
+        if let Some(key) = key {
+            out.write_all(&(key.len() as u32).to_be_bytes())?;
+            out.write_all(key)?;
+        }
+        out.write_all(body)
+    }
+}
+
+/// When a [`Store`] waits for its log to reach the disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fsync {
+    /// After every write, before it is answered.
+    Always,
+    /// With the first write once the interval passed since the last time; writes after that are
+    /// on disk with the next one, a [`Store::sync`] or when the store is dropped.
+    Every(Duration),
+    /// Whenever the kernel writes the log back.
+    Never,
+}
+
+/// A record of the log: a big endian `u32` length, a CRC-32 of the rest, the kind, and the key
+/// and value as in a [`Frame::Put`] body.
+fn encode_record(kind: u8, key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut record = vec![0; 8];
+    record.push(kind);
+    record.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    record.extend_from_slice(key);
+    record.extend_from_slice(value);
+    let len = (record.len() - 8) as u32;
+    let crc = crc32fast::hash(&record[8..]);
+    record[..4].copy_from_slice(&len.to_be_bytes());
+    record[4..8].copy_from_slice(&crc.to_be_bytes());
+    record
+}
+
+/// The kind, key and value of a log record.
+type Record<'a> = (u8, &'a [u8], &'a [u8]);
+
+/// The record at the start of `log` with its length, or `None` when it is cut short or its
+/// checksum does not match.
+fn parse_record(log: &[u8]) -> Option<(Record<'_>, usize)> {
+    let len = u32::from_be_bytes(log.get(..4)?.try_into().unwrap()) as usize;
+    let crc = u32::from_be_bytes(log.get(4..8)?.try_into().unwrap());
+    let record = log.get(8..8 + len)?;
+    if crc != crc32fast::hash(record) {
+        return None;
+    }
+    let (kind, body) = record.split_first()?;
+    let (key, value) = key_value(body).ok()?;
+    Some(((*kind, key, value), 8 + len))
+}
+
+/// Values by key, kept in memory and made durable by an append-only log that is replayed when
+/// the store is opened again.
+#[derive(Debug)]
+pub struct Store {
+    log: File,
+    log_len: u64,
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+    fsync: Fsync,
+    synced: Instant,
+    unsynced: bool,
+    /// A failed write could not be cut from the log, so no more are taken.
+    failed: bool,
+    /// Bytes of the next record written before its write fails, as a full disk would.
+    #[cfg(test)]
+    tear: Option<usize>,
+}
+
+impl Store {
+    /// Open the log at `path`, creating it if there is none, and replay it. The log ends before
+    /// the first record cut short or failing its checksum, which is what a crash in the middle
+    /// of a write leaves behind, and is truncated there.
+    pub fn open<P: AsRef<Path>>(path: P, fsync: Fsync) -> std::io::Result<Self> {
+        let mut log = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut bytes = Vec::new();
+        log.read_to_end(&mut bytes)?;
+        let mut entries = BTreeMap::new();
+        let mut replayed = 0;
+        while let Some(((kind, key, value), len)) = parse_record(&bytes[replayed..]) {
+            match kind {
+                PUT => entries.insert(key.to_vec(), value.to_vec()),
+                _ => entries.remove(key),
+            };
+            replayed += len;
+        }
+        if replayed < bytes.len() {
+            log.set_len(replayed as u64)?;
+            log.sync_data()?;
+        }
+        Ok(Self {
+            log,
+            log_len: replayed as u64,
+            entries,
+            fsync,
+            synced: Instant::now(),
+            unsynced: false,
+            failed: false,
+            #[cfg(test)]
+            tear: None,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Bytes of the log, every write ever made included.
+    pub fn log_len(&self) -> u64 {
+        self.log_len
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.entries.get(key).map(Vec::as_slice)
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> std::io::Result<()> {
+        self.append(&encode_record(PUT, key, value))?;
+        self.entries.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    /// Remove the value under `key`; false if there was none, which is logged all the same.
+    pub fn delete(&mut self, key: &[u8]) -> std::io::Result<bool> {
+        self.append(&encode_record(DELETE, key, &[]))?;
+        Ok(self.entries.remove(key).is_some())
+    }
+
+    /// Wait until every write so far is on disk.
+    pub fn sync(&mut self) -> std::io::Result<()> {
+        if self.unsynced {
+            self.log.sync_data()?;
+            self.unsynced = false;
+        }
+        self.synced = Instant::now();
+        Ok(())
+    }
+
+    /// Append `record` to the log; when that fails, the log is cut back to the records before it,
+    /// so that the ones after do not follow a torn record and it does not come back on replay.
+    fn append(&mut self, record: &[u8]) -> std::io::Result<()> {
+        if self.failed {
+            return Err(std::io::Error::other(
+                "log could not be cut back after a failed write",
+            ));
+        }
+        if let Err(err) = self.write(record) {
+            self.failed = self.log.set_len(self.log_len).is_err();
+            return Err(err);
+        }
+        self.log_len += record.len() as u64;
+        Ok(())
+    }
+
+    fn write(&mut self, record: &[u8]) -> std::io::Result<()> {
+        #[cfg(test)]
+        if let Some(len) = self.tear.take() {
+            self.log.write_all(&record[..len.min(record.len())])?;
+            return Err(std::io::Error::other("torn write"));
+        }
+        self.log.write_all(record)?;
+        self.unsynced = true;
+        match self.fsync {
+            Fsync::Always => self.sync(),
+            Fsync::Every(interval) if self.synced.elapsed() >= interval => self.sync(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        if Fsync::Never != self.fsync {
+            let _ = self.sync();
+        }
+    }
+}
+
+/// Serves a [`Store`] over the [`Frame`] protocol, passing the writes it takes on to its peers
+/// through [`Client`] `X` on connections of type `U`.
+///
+/// A write is answered with `Done` once the quorum of nodes, this one included, has it in their
+/// logs. When fewer peers acknowledge it, it is answered with `Unreplicated`: it was applied
+/// here and on the peers that did and can be read from them, but may be lost with this node.
+/// Writes are idempotent, so a client that needs the quorum sends the write again until it is
+/// answered with `Done`, or `NotFound` for a delete. Peers are written to one after the other
+/// while the event loop waits, each for up to the timeout, so they should be nodes without peers
+/// of their own: two nodes that pass writes to each other would wait for each other until the
+/// timeouts run out. A peer that missed writes while it was unreachable is not brought up to
+/// date.
+pub struct Kv<X, U>
+where
+    X: Connect<U>,
+{
+    store: Store,
+    peers: Vec<<X as Connect<U>>::Addr>,
+    quorum: usize,
+    timeout: Duration,
+    pool: Pool<<X as Connect<U>>::Addr, U>,
+    ring: RingBuffer,
+    client: PhantomData<X>,
+}
+
+impl<X, U> Kv<X, U>
+where
+    X: Client<U>,
+    U: Read + Write,
+    <X as Connect<U>>::Addr: Ord + Clone,
+    <X as EventLoop>::Event: Debug,
+{
+    pub fn new(store: Store) -> Self {
+        Self {
+            store,
+            peers: Vec::new(),
+            quorum: 1,
+            timeout: Duration::from_secs(1),
+            pool: Pool::new(1, Duration::from_secs(60)),
+            ring: RingBuffer::new(64),
+            client: PhantomData,
+        }
+    }
+
+    /// Pass writes on to `peers`, with a quorum of a majority of them and this node.
+    pub fn peers(mut self, peers: Vec<<X as Connect<U>>::Addr>) -> Self {
+        self.quorum = peers.len().div_ceil(2) + 1;
+        self.peers = peers;
+        self
+    }
+
+    /// Nodes, this one included, that must have a write before it is answered with `Done`.
+    pub fn quorum(mut self, quorum: usize) -> Self {
+        self.quorum = quorum;
+        self
+    }
+
+    /// How long a peer has to acknowledge a write before it counts as missing it; a second by
+    /// default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Where writes passed on to peers and their answers are buffered; it must hold the largest
+    /// write. 64 segments of [`crate::ring::PAGE_SIZE`] by default.
+    pub fn ring(mut self, ring: RingBuffer) -> Self {
+        self.ring = ring;
+        self
+    }
+
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    /// Pass `frame` on to every peer; returns whether the quorum of nodes has it.
+    fn replicate(&mut self, frame: &Frame<'_>) -> bool {
+        let mut send = Vec::new();
+        // writing to a vector does not fail
+        let _ = frame.write_to(&mut send);
+        let mut acknowledged = 1;
+        for addr in &self.peers {
+            let mut connection = match self.pool.checkout::<X>(addr) {
+                Ok(connection) => connection,
+                Err(_) => continue,
+            };
+            let complete = |received: &[u8]| !matches!(Frame::parse(received), Ok(None));
+            let ring = &mut self.ring;
+            let timeout = Some(self.timeout);
+            match X::request_until::<0, _>(&mut connection, 16, ring, &send, timeout, complete) {
+                Ok(mut received) => {
+                    let answer = Frame::parse(self.ring.bytes(&received));
+                    if let Ok(Some((Frame::Done, _))) = answer {
+                        acknowledged += 1;
+                    }
+                    self.ring.release_span(&mut received);
+                    self.pool.checkin(addr.clone(), connection);
+                }
+                Err(_) => self.pool.discard(addr),
+            }
+        }
+        acknowledged >= self.quorum
+    }
+}
+
+impl<C, X, U> Handler<C> for Kv<X, U>
+where
+    X: Client<U>,
+    U: Read + Write,
+    <X as Connect<U>>::Addr: Ord + Clone,
+    <X as EventLoop>::Event: Debug,
+{
+    fn received<W: Write>(
+        &mut self,
+        _token: usize,
+        receive: &[u8],
+        send: &mut W,
+    ) -> std::io::Result<(usize, Flow)> {
+        let mut consumed = 0;
+        while let Some((frame, len)) = Frame::parse(&receive[consumed..])? {
+            consumed += len;
+            let written = match frame {
+                Frame::Get(key) => match self.store.get(key) {
+                    Some(value) => Frame::Value(value).write_to(send),
+                    None => Frame::NotFound.write_to(send),
+                },
+                Frame::Put { key, value } => {
+                    let put = self.store.put(key, value).map(|()| true);
+                    let replicated =
+                        put.is_ok() && self.replicate(&Frame::ReplicaPut { key, value });
+                    answer(put, replicated, send)
+                }
+                Frame::Delete(key) => {
+                    let deleted = self.store.delete(key);
+                    let replicated = deleted.is_ok() && self.replicate(&Frame::ReplicaDelete(key));
+                    answer(deleted, replicated, send)
+                }
+                Frame::ReplicaPut { key, value } => {
+                    answer(self.store.put(key, value).map(|()| true), true, send)
+                }
+                Frame::ReplicaDelete(key) => {
+                    answer(self.store.delete(key).map(|_| true), true, send)
+                }
+                _ => return Err(invalid("answer sent as a request")),
+            };
+            written?;
+        }
+        Ok((consumed, Flow::Continue))
+    }
+}
+
+/// Answer a write with `Done`, `NotFound` when there was nothing to delete, `Unreplicated` when
+/// it missed the quorum, or why it failed.
+fn answer<W: Write>(
+    done: std::io::Result<bool>,
+    replicated: bool,
+    send: &mut W,
+) -> std::io::Result<()> {
+    match done {
+        Ok(_) if !replicated => Frame::Unreplicated.write_to(send),
+        Ok(true) => Frame::Done.write_to(send),
+        Ok(false) => Frame::NotFound.write_to(send),
+        Err(err) => Frame::Error(err.to_string().as_bytes()).write_to(send),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::SocketAddr,
+        sync::atomic::{AtomicUsize, Ordering::SeqCst},
+        thread,
+        time::Duration,
+    };
+
+    use mio::net::TcpStream;
+
+    use super::{Frame, Fsync, Kv, Store};
+    use crate::{
+        ring::RingBuffer,
+        tests::{new_loopback_address, TestClient, TestServer},
+        Listener, Server, Shutdown,
+    };
+
+    static LOGS: AtomicUsize = AtomicUsize::new(0);
+
+    type Node = Kv<TestClient, TcpStream>;
+
+    fn log_path() -> std::path::PathBuf {
+        let n = LOGS.fetch_add(1, SeqCst);
+        std::env::temp_dir().join(format!("elog-{}-{n}.kv", std::process::id()))
+    }
+
+    /// Send `frame` to `addr` and return the answer.
+    fn call(addr: SocketAddr, frame: Frame<'_>) -> Vec<u8> {
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        let mut send = Vec::new();
+        frame.write_to(&mut send).unwrap();
+        stream.write_all(&send).unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+        while let Ok(None) = Frame::parse(&received) {
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0, "connection closed before the answer");
+            received.extend_from_slice(&buf[..n]);
+        }
+        received
+    }
+
+    fn encoded(frame: Frame<'_>) -> Vec<u8> {
+        let mut send = Vec::new();
+        frame.write_to(&mut send).unwrap();
+        send
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let frames = [
+            Frame::Get(b"k"),
+            Frame::Put {
+                key: b"key",
+                value: b"value",
+            },
+            Frame::Delete(b""),
+            Frame::ReplicaPut {
+                key: b"",
+                value: b"v",
+            },
+            Frame::ReplicaDelete(b"k"),
+            Frame::Value(b"v"),
+            Frame::NotFound,
+            Frame::Done,
+            Frame::Unreplicated,
+            Frame::Error(b"why"),
+        ];
+        for frame in frames {
+            let bytes = encoded(frame);
+            let (parsed, len) = Frame::parse(&bytes).unwrap().unwrap();
+            assert_eq!(len, bytes.len());
+            assert_eq!(encoded(parsed), bytes);
+            assert_eq!(Frame::parse(&bytes[..len - 1]).unwrap(), None);
+        }
+        assert!(Frame::parse(&[0, 0, 0, 1, 42]).is_err());
+        assert!(Frame::parse(&[0, 0, 0, 3, 2, 0, 9]).is_err());
+    }
+
+    #[test]
+    fn replays_the_log_up_to_a_torn_write() {
+        let path = log_path();
+        let mut store = Store::open(&path, Fsync::Always).unwrap();
+        store.put(b"a", b"1").unwrap();
+        store.put(b"b", b"2").unwrap();
+        store.put(b"a", b"3").unwrap();
+        assert!(store.delete(b"b").unwrap());
+        assert!(!store.delete(b"c").unwrap());
+        let len = store.log_len();
+        drop(store);
+
+        // a crash in the middle of a write leaves part of a record behind
+        let mut log = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        log.write_all(&[0, 0, 0, 40, 1, 2]).unwrap();
+        drop(log);
+        let mut store = Store::open(&path, Fsync::Every(std::time::Duration::ZERO)).unwrap();
+        assert_eq!((store.get(b"a"), store.get(b"b")), (Some(&b"3"[..]), None));
+        assert_eq!((store.len(), store.log_len()), (1, len));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+
+        store.put(b"d", b"4").unwrap();
+        drop(store);
+        let store = Store::open(&path, Fsync::Never).unwrap();
+        assert_eq!((store.len(), store.get(b"d")), (2, Some(&b"4"[..])));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn failed_writes_are_cut_from_the_log() {
+        let path = log_path();
+        let mut store = Store::open(&path, Fsync::Always).unwrap();
+        store.put(b"a", b"1").unwrap();
+        // part of a record, then all of one whose sync failed
+        store.tear = Some(5);
+        assert!(store.put(b"b", b"2").is_err());
+        store.tear = Some(usize::MAX);
+        assert!(store.delete(b"a").is_err());
+        store.put(b"c", b"3").unwrap();
+        let len = store.log_len();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        drop(store);
+
+        let store = Store::open(&path, Fsync::Never).unwrap();
+        assert_eq!((store.get(b"a"), store.get(b"b")), (Some(&b"1"[..]), None));
+        assert_eq!((store.get(b"c"), store.log_len()), (Some(&b"3"[..]), len));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writes_need_a_quorum_of_replicas() {
+        let paths = [log_path(), log_path(), log_path()];
+        let addrs = [(); 3].map(|()| new_loopback_address());
+        let listeners = addrs.map(|addr| <TestServer as Listener<TcpStream>>::bind(addr).unwrap());
+        let shutdowns = [(); 3].map(|()| Shutdown::new());
+
+        let stores = thread::scope(|s| {
+            let nodes: Vec<_> = listeners
+                .into_iter()
+                .zip(&paths)
+                .zip(&shutdowns)
+                .enumerate()
+                .map(|(i, ((listener, path), shutdown))| {
+                    let store = Store::open(path, Fsync::Always).unwrap();
+                    let mut node = match i {
+                        0 => Node::new(store).peers(addrs[1..].to_vec()),
+                        _ => Node::new(store),
+                    };
+                    s.spawn(move || {
+                        let mut ring = RingBuffer::new(16);
+                        <TestServer as Server<TcpStream>>::serve::<1, _>(
+                            listener, 128, &mut ring, &mut node, shutdown,
+                        )
+                        .map(|()| node.store().get(b"k").map(<[u8]>::to_vec))
+                    })
+                })
+                .collect();
+            let put = |value| call(addrs[0], Frame::Put { key: b"k", value });
+            assert_eq!(put(b"1"), encoded(Frame::Done));
+            assert_eq!(
+                call(addrs[1], Frame::Get(b"k")),
+                encoded(Frame::Value(b"1"))
+            );
+            assert_eq!(
+                call(addrs[0], Frame::Delete(b"x")),
+                encoded(Frame::NotFound)
+            );
+
+            // two of three nodes are a quorum, one is not
+            let mut nodes = nodes.into_iter();
+            let mut stopped = Vec::new();
+            for (i, expected) in [(2, Frame::Done), (1, Frame::Unreplicated)] {
+                shutdowns[i].signal().unwrap();
+                stopped.push(nodes.nth_back(0).unwrap().join().unwrap().unwrap());
+                let answer = put(if 2 == i { b"2" } else { b"3" });
+                assert_eq!(answer, encoded(expected));
+            }
+            // the write that missed the quorum was applied all the same
+            assert_eq!(
+                call(addrs[0], Frame::Get(b"k")),
+                encoded(Frame::Value(b"3"))
+            );
+            shutdowns[0].signal().unwrap();
+            let leader = nodes.next().unwrap().join().unwrap().unwrap();
+            [leader, stopped.pop().unwrap(), stopped.pop().unwrap()]
+        });
+
+        let expected = [
+            Some(b"3".to_vec()),
+            Some(b"2".to_vec()),
+            Some(b"1".to_vec()),
+        ];
+        assert_eq!(stores, expected);
+        // every node recovers what it acknowledged from its log
+        let recovered = paths.each_ref().map(|path| {
+            let store = Store::open(path, Fsync::Never).unwrap();
+            store.get(b"k").map(<[u8]>::to_vec)
+        });
+        assert_eq!(recovered, expected);
+        for path in paths {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn peers_that_do_not_answer_time_out() {
+        let path = log_path();
+        // a peer whose connections are accepted by the kernel but never read
+        let silent = std::net::TcpListener::bind(new_loopback_address()).unwrap();
+        let addr = new_loopback_address();
+        let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let store = Store::open(&path, Fsync::Never).unwrap();
+            let mut node = Node::new(store)
+                .peers(vec![silent.local_addr().unwrap()])
+                .timeout(Duration::from_millis(50));
+            let shutdown = &shutdown;
+            let node = s.spawn(move || {
+                let mut ring = RingBuffer::new(16);
+                <TestServer as Server<TcpStream>>::serve::<1, _>(
+                    listener, 128, &mut ring, &mut node, shutdown,
+                )
+            });
+            let put = Frame::Put {
+                key: b"k",
+                value: b"v",
+            };
+            assert_eq!(call(addr, put), encoded(Frame::Unreplicated));
+            assert_eq!(call(addr, Frame::Get(b"k")), encoded(Frame::Value(b"v")));
+            shutdown.signal().unwrap();
+            node.join().unwrap().unwrap();
+        });
+        std::fs::remove_file(path).unwrap();
+    }
+}
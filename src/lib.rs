@@ -6,40 +6,40 @@
         [] consult reference for client and server and make robust
         [] extract into their own functions, pass arguments in from thread scope
         [] convert this unit test into a bench, use criterion?
-        [] arbitrarily large input and output buffers (read_vectored, write_vectored)
-        [] in place data reduction inside pages in i/o buffers (base64 decode in place)
-        [] in place data expansion inside pages in i/o buffers (decompression in place)
-        [] server connection pooling and recycling
-        [] live telemetry
-        [] buffering via mmap pages (for gigantic buffers)
-        [] use thread locals for the actual buffer and other variables
+        [✓] arbitrarily large input and output buffers (read_vectored, write_vectored)
+        [✓] in place data reduction inside pages in i/o buffers (base64 decode in place)
+        [✓] in place data expansion inside pages in i/o buffers (decompression in place)
+        [✓] server connection pooling and recycling
+        [✓] live telemetry
+        [✓] buffering via mmap pages (for gigantic buffers)
+        [✓] use thread locals for the actual buffer and other variables
 
         sts load balancer
-        [] load balance component (against multiple single threaded servers)
+        [✓] load balance component (against multiple single threaded servers)
 
         HTTP parsing as a processing step
-        [] http 1.0/2.0 parser function
-        [] tls function
+        [✓] http 1.0/2.0 parser function
+        [✓] tls function
 
         HTTP handling as a processing step
-        [] redirect function
-        [] other http headers, etc functions
+        [✓] redirect function
+        [✓] other http headers, etc functions
 
         TCP/UDP proxy
-        [] function to establish a connection and send request along
+        [✓] function to establish a connection and send request along
 
         Message Queue
-        [] just link together a bunch of proxies which have gigantic mmap buffers; that is basically a queue
-        [] client can read from any machine in the chain for pull messages
-        [] machines in chain connect (and re-establish if lost) to parent
+        [✓] just link together a bunch of proxies which have gigantic mmap buffers; that is basically a queue
+        [✓] client can read from any machine in the chain for pull messages
+        [✓] machines in chain connect (and re-establish if lost) to parent
         [] arbitrary topologies are possible; redundancy possible
             an efficient topology would likely use a queue of addresses at which to find actual elements in a cache or long term storage.
 
         Cache
-        [] the proxy merely writes a copy of file to disk after retrieving it; may be some clever pagination tricks that can be done here.
+        [✓] the proxy merely writes a copy of file to disk after retrieving it; may be some clever pagination tricks that can be done here.
 
         Key value store
-        [] just like cache, but with high redundacy and guaranteed entry longevity to achieve durability
+        [✓] just like cache, but with high redundacy and guaranteed entry longevity to achieve durability
 
         Immutable data store + integrated search engine as a block "chain"
         [] similar to blockchain but with some important differences:
@@ -57,27 +57,66 @@
                         i.e trying to organize zetabytes of information is difficult particualrly if the index is distributed (as here)
 */
 
-use std::time::Duration;
+pub mod balance;
+pub mod base64;
+pub mod block;
+#[cfg(target_os = "linux")]
+pub mod cache;
+pub mod datagram;
+pub mod hpack;
+pub mod http;
+pub mod http2;
+pub mod inflate;
+pub mod kv;
+#[cfg(target_os = "linux")]
+pub mod mmap;
+pub mod pipeline;
+pub mod pool;
+pub mod proxy;
+#[cfg(target_os = "linux")]
+pub mod queue;
+pub mod rewrite;
+pub mod ring;
+pub mod runtime;
+pub mod slab;
+pub mod telemetry;
+pub mod tls;
+pub mod worker;
+
+use std::time::{Duration, Instant};
 
 use std::{
-    collections::BTreeMap,
+    cell::RefCell,
     fmt::Debug,
-    io::{Read, Write},
+    io::{IoSlice, IoSliceMut, Read, Write},
     net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use mio::event::Source;
+
 use mio::{
     event::{Event, Iter},
     net::{TcpListener, TcpStream},
-    Events, Interest, Poll, Token,
+    Events, Interest, Poll, Token, Waker,
 };
+use pipeline::Pipeline;
+use pool::Pool;
+use ring::{Chain, RingBuffer, Span};
+use slab::Slab;
+use telemetry::Telemetry;
+use worker::Tokens;
 
 #[cfg(unix)]
 use mio::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
 
 use std::io::ErrorKind::*;
-trait EventLoop {
+pub trait EventLoop {
     type Poller;
     type Event;
     type Events;
@@ -106,7 +145,7 @@ trait EventLoop {
     ) -> std::io::Result<()>;
 }
 
-trait Registry<C>: EventLoop {
+pub trait Registry<C>: EventLoop {
     fn register(
         poller: &Self::Poller,
         connection: &mut C,
@@ -155,7 +194,7 @@ where
     }
 }
 
-trait ListenerRegistry<C>: Registry<C> + Registry<Self::Listener> {
+pub trait ListenerRegistry<C>: Registry<C> + Registry<Self::Listener> {
     type Listener;
 }
 
@@ -171,24 +210,36 @@ where
 #[cfg(unix)]
 impl<T> ListenerRegistry<UnixStream> for T
 where
-    T: Registry<UnixStream>,
+    T: Registry<UnixStream> + Registry<UnixListener>,
     T: EventLoop<Poller = Poll, Interest = Interest>,
     T: Connector<UnixStream>,
 {
     type Listener = UnixListener;
 }
 
-trait Listener<C> {
+pub trait Listener<C> {
     type Listener;
-    fn bind(addr: SocketAddr) -> std::io::Result<Self::Listener>;
-    fn accept(listener: &Self::Listener) -> std::io::Result<(C, SocketAddr)>;
+    /// What a listener is bound to: a socket address for TCP, a filesystem path for Unix sockets.
+    type Addr;
+    /// Where an accepted connection comes from.
+    type PeerAddr;
+    fn bind(addr: Self::Addr) -> std::io::Result<Self::Listener>;
+    fn accept(listener: &Self::Listener) -> std::io::Result<(C, Self::PeerAddr)>;
+
+    /// Stop listening for good, cleaning up anything `bind` left behind.
+    fn unbind(listener: Self::Listener) -> std::io::Result<()> {
+        drop(listener);
+        Ok(())
+    }
 }
 
 impl<T> Listener<TcpStream> for T {
     type Listener = TcpListener;
+    type Addr = SocketAddr;
+    type PeerAddr = SocketAddr;
 
     #[inline]
-    fn bind(addr: SocketAddr) -> std::io::Result<Self::Listener> {
+    fn bind(addr: Self::Addr) -> std::io::Result<Self::Listener> {
         TcpListener::bind(addr)
     }
 
@@ -201,28 +252,89 @@ impl<T> Listener<TcpStream> for T {
 #[cfg(unix)]
 impl<T> Listener<UnixStream> for T {
     type Listener = UnixListener;
-
-    #[inline]
-    fn bind(addr: &SocketAddr) -> std::io::Result<Self::Listener> {
-        UnixListener::bind_addr(addr)
+    type Addr = PathBuf;
+    type PeerAddr = mio::net::SocketAddr;
+
+    /// Bind to the socket file at `addr`, replacing it if it was left behind by a listener that is
+    /// gone. A socket file some listener still accepts on fails with `AddrInUse`.
+    fn bind(addr: Self::Addr) -> std::io::Result<Self::Listener> {
+        match UnixListener::bind(&addr) {
+            Err(ref err) if AddrInUse == err.kind() => {}
+            bound => return bound,
+        }
+        match std::os::unix::net::UnixStream::connect(&addr) {
+            Ok(_) => return Err(AddrInUse.into()),
+            Err(ref err) if ConnectionRefused == err.kind() => {}
+            Err(err) => return Err(err),
+        }
+        std::fs::remove_file(&addr)?;
+        UnixListener::bind(&addr)
     }
 
     #[inline]
-    fn accept(listener: &Self::Listener) -> std::io::Result<(UnixStream, SocketAddr)> {
+    fn accept(listener: &Self::Listener) -> std::io::Result<(UnixStream, mio::net::SocketAddr)> {
         listener.accept()
     }
+
+    /// Close the listener and remove its socket file.
+    fn unbind(listener: Self::Listener) -> std::io::Result<()> {
+        let addr = listener.local_addr()?;
+        drop(listener);
+        match addr.as_pathname().map(std::fs::remove_file) {
+            Some(Err(err)) if NotFound != err.kind() => Err(err),
+            _ => Ok(()),
+        }
+    }
 }
 
-trait Connector<C> {
+pub trait Connector<C> {
     fn write_on_connection(connection: &mut C, send: &[u8]) -> std::io::Result<usize>
     where
         C: Write;
     fn read_from_connection(connection: &mut C, receive: &mut [u8]) -> std::io::Result<usize>
     where
         C: Read;
+
+    /// Write from several buffers with one call; only the first non empty buffer is written
+    /// unless the connector overrides this.
+    fn write_vectored_on_connection(
+        connection: &mut C,
+        send: &[IoSlice<'_>],
+    ) -> std::io::Result<usize>
+    where
+        C: Write,
+    {
+        let send = send.iter().find(|s| !s.is_empty()).map_or(&[][..], |s| s);
+        Self::write_on_connection(connection, send)
+    }
+
+    /// Read into several buffers with one call; only the first non empty buffer is filled
+    /// unless the connector overrides this.
+    fn read_vectored_from_connection(
+        connection: &mut C,
+        receive: &mut [IoSliceMut<'_>],
+    ) -> std::io::Result<usize>
+    where
+        C: Read,
+    {
+        let receive = receive
+            .iter_mut()
+            .find(|r| !r.is_empty())
+            .map_or(&mut [][..], |r| r);
+        Self::read_from_connection(connection, receive)
+    }
+
+    /// Write out what the connection buffers on its own, such as TLS records; fails with
+    /// `WouldBlock` until all of it is written.
+    fn flush_connection(_connection: &mut C) -> std::io::Result<()>
+    where
+        C: Write,
+    {
+        Ok(())
+    }
 }
 
-trait ReadWriteConnectorAdapter {}
+pub trait ReadWriteConnectorAdapter {}
 
 impl<R, T> Connector<R> for T
 where
@@ -242,26 +354,58 @@ where
     {
         connection.read(receive)
     }
+
+    fn write_vectored_on_connection(
+        connection: &mut R,
+        send: &[IoSlice<'_>],
+    ) -> std::io::Result<usize>
+    where
+        R: Write,
+    {
+        connection.write_vectored(send)
+    }
+
+    fn read_vectored_from_connection(
+        connection: &mut R,
+        receive: &mut [IoSliceMut<'_>],
+    ) -> std::io::Result<usize>
+    where
+        R: Read,
+    {
+        connection.read_vectored(receive)
+    }
+
+    fn flush_connection(connection: &mut R) -> std::io::Result<()>
+    where
+        R: Write,
+    {
+        connection.flush()
+    }
 }
 
-trait Connect<C> {
-    fn connect(addr: SocketAddr) -> std::io::Result<C>;
+pub trait Connect<C> {
+    type Addr;
+    fn connect(addr: Self::Addr) -> std::io::Result<C>;
 }
 
 impl<T> Connect<TcpStream> for T {
-    fn connect(addr: SocketAddr) -> std::io::Result<TcpStream> {
+    type Addr = SocketAddr;
+
+    fn connect(addr: Self::Addr) -> std::io::Result<TcpStream> {
         TcpStream::connect(addr)
     }
 }
 
 #[cfg(unix)]
 impl<T> Connect<UnixStream> for T {
-    fn connect(addr: SocketAddr) -> std::io::Result<UnixStream> {
+    type Addr = PathBuf;
+
+    fn connect(addr: Self::Addr) -> std::io::Result<UnixStream> {
         UnixStream::connect(addr)
     }
 }
 
-trait MioEventLoop {}
+pub trait MioEventLoop {}
 
 impl<T> EventLoop for T
 where
@@ -339,10 +483,295 @@ where
     }
 }
 
+/// Most segments handed to a single vectored read or write.
+pub const IO_SLICES: usize = 64;
+
+/// Token reserved for the waker that interrupts `poll` when a [`Shutdown`] is signalled.
+pub const SHUTDOWN: usize = usize::MAX;
+
+/// Cloneable handle used to stop long running event loops from another thread.
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown {
+    inner: Arc<ShutdownState>,
+}
+
+#[derive(Debug, Default)]
+struct ShutdownState {
+    signalled: AtomicBool,
+    /// Wakers of the pollers watching, under the id of their [`Watch`].
+    wakers: Mutex<Vec<(u64, Waker)>>,
+    next_watch: AtomicU64,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask every event loop watching this handle to stop, waking any that are blocked in `poll`.
+    /// Every poller is woken even when waking one fails; the first failure is returned.
+    pub fn signal(&self) -> std::io::Result<()> {
+        self.inner.signalled.store(true, Ordering::SeqCst);
+        let mut woken = Ok(());
+        for (_, waker) in self.inner.wakers.lock().unwrap().iter() {
+            let wake = waker.wake();
+            if woken.is_ok() {
+                woken = wake;
+            }
+        }
+        woken
+    }
+
+    pub fn is_signalled(&self) -> bool {
+        self.inner.signalled.load(Ordering::SeqCst)
+    }
+
+    /// Attach a poller so that `signal` interrupts it; wake ups are reported with `token`. The
+    /// poller stays attached until the returned [`Watch`] is dropped.
+    pub fn watch(&self, poller: &Poll, token: usize) -> std::io::Result<Watch> {
+        let waker = Waker::new(poller.registry(), Token(token))?;
+        let id = self.inner.next_watch.fetch_add(1, Ordering::SeqCst);
+        self.inner.wakers.lock().unwrap().push((id, waker));
+        Ok(Watch {
+            inner: self.inner.clone(),
+            id,
+        })
+    }
+}
+
+/// A poller attached to a [`Shutdown`]; dropping it detaches the poller and closes its waker.
+#[must_use = "the poller is detached again when the watch is dropped"]
+#[derive(Debug)]
+pub struct Watch {
+    inner: Arc<ShutdownState>,
+    id: u64,
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        let mut wakers = self.inner.wakers.lock().unwrap();
+        wakers.retain(|(id, _)| self.id != *id);
+    }
+}
+
+/// What the server should do with a connection after a [`Handler`] callback.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    /// Write everything already queued, then close the connection.
+    Close,
+}
+
+/// Protocol logic driven by [`Server`]; every callback is told which connection it is about.
+///
+/// Bytes written to `send` are queued for the connection and drained through
+/// [`Connector::write_on_connection`].
+pub trait Handler<C> {
+    /// Bytes arrived on `token`. `receive` holds everything that earlier calls left unconsumed,
+    /// followed by the new bytes. Returns how many bytes were consumed; the remainder is presented
+    /// again once more bytes arrive.
+    fn received<W: Write>(
+        &mut self,
+        token: usize,
+        receive: &[u8],
+        send: &mut W,
+    ) -> std::io::Result<(usize, Flow)>;
+
+    /// Everything queued for `token` has been written and the connection can take more.
+    fn writable<W: Write>(&mut self, _token: usize, _send: &mut W) -> std::io::Result<Flow> {
+        Ok(Flow::Continue)
+    }
+
+    /// `token` was closed, either by the peer, by a [`Flow::Close`] or by shutdown.
+    fn closed(&mut self, _token: usize) {}
+
+    /// Stages every outgoing segment is run through right before it is written. Segments are
+    /// only filled as far as leaves the headroom these stages need to expand in place.
+    fn outbound(&mut self) -> Option<&mut Pipeline> {
+        None
+    }
+
+    /// Where the event loop records connections, bytes, polls and ring occupancy as it serves
+    /// this handler.
+    fn telemetry(&self) -> Option<&Telemetry> {
+        None
+    }
+}
+
+/// Per token state of an accepted connection; its bytes live in the worker's [`RingBuffer`].
+struct Connection<C> {
+    stream: C,
+    received: Span,
+    send: Chain,
+    closing: bool,
+    /// Bytes read and written since the last [`Connection::take_transferred`].
+    bytes_in: usize,
+    bytes_out: usize,
+}
+
+impl<C> Connection<C>
+where
+    C: Read + Write,
+{
+    /// `send` is an empty chain, possibly recycled from a closed connection.
+    fn new(stream: C, send: Chain) -> Self {
+        Self {
+            stream,
+            received: Span::default(),
+            send,
+            closing: false,
+            bytes_in: 0,
+            bytes_out: 0,
+        }
+    }
+
+    /// Bytes read and written since the last call.
+    fn take_transferred(&mut self) -> (usize, usize) {
+        let transferred = (self.bytes_in, self.bytes_out);
+        (self.bytes_in, self.bytes_out) = (0, 0);
+        transferred
+    }
+
+    /// Drive one readiness event; returns true once the connection should be closed.
+    fn exchange<S, H>(
+        &mut self,
+        token: usize,
+        readable: bool,
+        ring: &mut RingBuffer,
+        handler: &mut H,
+    ) -> std::io::Result<bool>
+    where
+        S: Connector<C>,
+        H: Handler<C>,
+    {
+        if let Some(pipeline) = handler.outbound() {
+            pipeline.run_chain(ring, &mut self.send)?;
+        }
+        if readable && !self.closing {
+            self.receive::<S, H>(token, ring, handler)?;
+        }
+        loop {
+            if let Some(pipeline) = handler.outbound() {
+                pipeline.run_chain(ring, &mut self.send)?;
+            }
+            if !self.flush::<S>(ring)? {
+                return Ok(false);
+            }
+            if self.closing {
+                return Ok(true);
+            }
+            let mut send = ring.chain_writer(&mut self.send, token);
+            if Flow::Close == handler.writable(token, &mut send)? {
+                self.closing = true;
+            }
+            if self.send.is_empty() {
+                return Ok(self.closing);
+            }
+        }
+    }
+
+    /// Read until the connection would block, handing the unconsumed bytes to `handler` after
+    /// every read.
+    fn receive<S, H>(
+        &mut self,
+        token: usize,
+        ring: &mut RingBuffer,
+        handler: &mut H,
+    ) -> std::io::Result<()>
+    where
+        S: Connector<C>,
+        H: Handler<C>,
+    {
+        loop {
+            let spare = ring.spare_mut(&mut self.received, token)?;
+            let n = match S::read_from_connection(&mut self.stream, spare) {
+                Ok(0) => {
+                    self.closing = true;
+                    return Ok(());
+                }
+                Ok(n) => n,
+                Err(ref err) if WouldBlock == err.kind() => {
+                    // an idle connection does not hold on to a segment
+                    ring.consume(&mut self.received, 0);
+                    return Ok(());
+                }
+                Err(ref err) if Interrupted == err.kind() => {
+                    continue;
+                }
+                Err(err) => {
+                    return Err(err);
+                }
+            };
+            ring.commit(&mut self.received, n);
+            self.bytes_in += n;
+            let (receive, mut send) = ring.writer(&self.received, &mut self.send, token);
+            let (consumed, flow) = handler.received(token, receive, &mut send)?;
+            ring.consume(&mut self.received, consumed);
+            if Flow::Close == flow {
+                self.closing = true;
+                return Ok(());
+            }
+        }
+    }
+
+    /// Write queued output; returns true once nothing is left to write.
+    fn flush<S>(&mut self, ring: &mut RingBuffer) -> std::io::Result<bool>
+    where
+        S: Connector<C>,
+    {
+        while !self.send.is_empty() {
+            let mut send = [IoSlice::new(&[]); IO_SLICES];
+            let count = ring.io_slices(&self.send, &mut send);
+            match S::write_vectored_on_connection(&mut self.stream, &send[..count]) {
+                Ok(0) => {
+                    return Err(WriteZero.into());
+                }
+                Ok(n) => {
+                    ring.consume_chain(&mut self.send, n);
+                    self.bytes_out += n;
+                }
+                Err(ref err) if WouldBlock == err.kind() => {
+                    return Ok(false);
+                }
+                Err(ref err) if Interrupted == err.kind() => {
+                    continue;
+                }
+                Err(err) => {
+                    return Err(err);
+                }
+            }
+        }
+        loop {
+            match S::flush_connection(&mut self.stream) {
+                Ok(()) => {
+                    return Ok(true);
+                }
+                Err(ref err) if WouldBlock == err.kind() => {
+                    return Ok(false);
+                }
+                Err(ref err) if Interrupted == err.kind() => {
+                    continue;
+                }
+                Err(err) => {
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// Give every segment back to the ring and hand back the stream, along with the emptied
+    /// chain for the next connection.
+    fn close(mut self, ring: &mut RingBuffer) -> (C, Chain) {
+        ring.release_span(&mut self.received);
+        ring.release_chain(&mut self.send);
+        (self.stream, self.send)
+    }
+}
+
 // TODO although low level event loop code for TCP / Unix sockets is normally pretty ugly and control flow heavy
 //        figure out a way to make it not so ugly if possible
 
-trait Server<C>:
+pub trait Server<C>:
     ListenerRegistry<C>
     + Listener<C, Listener = <Self as ListenerRegistry<C>>::Listener>
     + Connector<C>
@@ -354,197 +783,399 @@ where
     <Self as Listener<C>>::Listener: Source,
     <Self as EventLoop>::Event: Debug,
 {
-    fn server<const SERVER: usize>(
-        addr: SocketAddr,
+    fn server<const SERVER: usize, H: Handler<C>>(
+        addr: <Self as Listener<C>>::Addr,
         event_buffer_capacity: usize,
-        receive: &mut [u8; 4096],
-        send: &'static [u8],
+        ring: &mut RingBuffer,
+        handler: &mut H,
+        shutdown: &Shutdown,
+    ) -> std::io::Result<()> {
+        let server = Self::bind(addr)?;
+        Self::serve::<SERVER, H>(server, event_buffer_capacity, ring, handler, shutdown)
+    }
+
+    /// Serve every connection accepted on `server` with `handler` until `shutdown` is signalled,
+    /// then unbind `server`.
+    ///
+    /// Connections read into and write from segments of `ring`. A connection that fails, including
+    /// one that finds the ring full, is closed on its own; only failures of the listener or the
+    /// poller stop the server. Connections live in a [`Slab`] under the token `SERVER + 1 + key`,
+    /// so a closed connection's token and buffers go to the next one accepted. What the loop does
+    /// is recorded in the handler's [`Handler::telemetry`], if it has one.
+    fn serve<const SERVER: usize, H: Handler<C>>(
+        server: <Self as Listener<C>>::Listener,
+        event_buffer_capacity: usize,
+        ring: &mut RingBuffer,
+        handler: &mut H,
+        shutdown: &Shutdown,
     ) -> std::io::Result<()> {
-        let mut client_token = SERVER;
-        let mut new_client_token = || {
-            client_token = std::cmp::max(client_token, SERVER);
-            client_token = client_token.wrapping_add(1);
-            client_token
-        };
-        let mut poller = Self::new_poller()?;
         let mut events = Self::new_events_buffer(event_buffer_capacity);
-        let mut server = Self::bind(addr)?;
-        let interest = Self::readable_interest();
-        <Self as Registry<<Self as ListenerRegistry<C>>::Listener>>::register(
-            &poller,
-            &mut server,
-            SERVER,
-            interest,
-        )?;
-        let mut connections = BTreeMap::new();
-        let mut written = false;
-        let mut read = false;
-        loop {
-            if let Err(err) = Self::poll(&mut poller, &mut events, None) {
-                if Interrupted == err.kind() {
-                    continue;
-                }
-                return Err(err);
+        let state = LoopState {
+            ring,
+            connections: &mut Slab::new(),
+            recycled: &mut Vec::new(),
+            tokens: &RefCell::new(Tokens::new(SERVER + 1)),
+        };
+        serve_with::<SERVER, Self, C, H>(server, &mut events, state, handler, shutdown, || {})
+    }
+
+    fn server_local<const SERVER: usize, H: Handler<C>>(
+        addr: <Self as Listener<C>>::Addr,
+        handler: &mut H,
+        shutdown: &Shutdown,
+    ) -> std::io::Result<()>
+    where
+        Self: EventLoop<Events = Events>,
+        C: 'static,
+    {
+        let server = Self::bind(addr)?;
+        Self::serve_local::<SERVER, H>(server, handler, shutdown)
+    }
+
+    /// Like [`Server::serve`], with the ring, events buffer, connection table and tokens of the
+    /// calling thread's [`worker::Worker`]. Fails with `NotFound` when none is installed, and
+    /// tells [`worker::Worker::ready`] once it is about to poll.
+    fn serve_local<const SERVER: usize, H: Handler<C>>(
+        server: <Self as Listener<C>>::Listener,
+        handler: &mut H,
+        shutdown: &Shutdown,
+    ) -> std::io::Result<()>
+    where
+        Self: EventLoop<Events = Events>,
+        C: 'static,
+    {
+        let worker = worker::current().ok_or(NotFound)?;
+        // every connection of the last loop was closed with it, so the tokens start afresh
+        worker.tokens.replace(Tokens::new(SERVER + 1));
+        let mut ring = worker.ring.try_borrow_mut().map_err(|_| ResourceBusy)?;
+        let mut events = worker.events.borrow_mut();
+        let state = LoopState {
+            ring: &mut ring,
+            connections: &mut worker.connections_mut(),
+            recycled: &mut worker.recycled.borrow_mut(),
+            tokens: &worker.tokens,
+        };
+        let ready = || {
+            if let Some(ready) = worker.ready.take() {
+                let _ = ready.send(Ok(()));
             }
-            for event in Self::events_iter(&events) {
-                let mut bytes_read: usize = 0;
-                let token = Self::event_token(&event);
-                if SERVER == token {
-                    loop {
-                        let (mut connection, _address) = match Self::accept(&server) {
-                            Ok((connection, address)) => (connection, address),
-                            Err(e) if WouldBlock == e.kind() => break,
-                            Err(e) => return Err(e).unwrap(),
-                        };
-                        let token = new_client_token();
-                        let interest = Self::add_readable_to_interest(Self::writeable_interest());
-                        <Self as Registry<C>>::register(&poller, &mut connection, token, interest)?;
-                        connections.insert(token, connection);
+        };
+        serve_with::<SERVER, Self, C, H>(server, &mut events, state, handler, shutdown, ready)
+    }
+}
+
+/// What an event loop works with, either its own or borrowed from a [`worker::Worker`].
+struct LoopState<'a, C> {
+    ring: &'a mut RingBuffer,
+    connections: &'a mut Slab<Connection<C>>,
+    recycled: &'a mut Vec<Chain>,
+    /// Borrowed only while a token changes hands, so handlers can look at it.
+    tokens: &'a RefCell<Tokens>,
+}
+
+/// The event loop behind [`Server::serve`] and [`Server::serve_local`]; `ready` is called once
+/// the poller and listener are set up.
+fn serve_with<const SERVER: usize, S, C, H>(
+    mut server: <S as Listener<C>>::Listener,
+    events: &mut <S as EventLoop>::Events,
+    state: LoopState<'_, C>,
+    handler: &mut H,
+    shutdown: &Shutdown,
+    ready: impl FnOnce(),
+) -> std::io::Result<()>
+where
+    S: Server<C>,
+    C: Read + Write + Debug,
+    <S as Listener<C>>::Listener: Source,
+    <S as EventLoop>::Event: Debug,
+    H: Handler<C>,
+{
+    let LoopState {
+        ring,
+        connections,
+        recycled,
+        tokens,
+    } = state;
+    let mut poller = S::new_poller()?;
+    let interest = S::readable_interest();
+    <S as Registry<<S as ListenerRegistry<C>>::Listener>>::register(
+        &poller,
+        &mut server,
+        SERVER,
+        interest,
+    )?;
+    let _watch = shutdown.watch(&poller, SHUTDOWN)?;
+    ready();
+    while !shutdown.is_signalled() {
+        if let Err(err) = S::poll(&mut poller, events, None) {
+            if Interrupted == err.kind() {
+                continue;
+            }
+            return Err(err);
+        }
+        if let Some(telemetry) = handler.telemetry() {
+            telemetry.record_poll(S::events_iter(events).count());
+        }
+        for event in S::events_iter(events) {
+            let token = S::event_token(event);
+            if SHUTDOWN == token {
+                continue;
+            }
+            if SERVER == token {
+                loop {
+                    let mut connection = match S::accept(&server) {
+                        Ok((connection, _address)) => connection,
+                        Err(e) if WouldBlock == e.kind() => break,
+                        Err(e) if Interrupted == e.kind() => continue,
+                        Err(e) if ConnectionAborted == e.kind() => continue,
+                        Err(e) => return Err(e),
+                    };
+                    let token = tokens.borrow().next();
+                    let interest = S::add_readable_to_interest(S::writeable_interest());
+                    <S as Registry<C>>::register(&poller, &mut connection, token, interest)?;
+                    let send = recycled.pop().unwrap_or_default();
+                    let key = connections.insert(Connection::new(connection, send));
+                    // both hand out the lowest vacant key, so they stay in step
+                    let allocated = tokens.borrow_mut().allocate();
+                    debug_assert_eq!(allocated, SERVER + 1 + key);
+                    if let Some(telemetry) = handler.telemetry() {
+                        telemetry.record_accept();
                     }
                 }
-                {
-                    let mut done = false;
-                    if let Some(mut connection) = connections.get_mut(&token) {
-                        if !read && Self::event_is_readable(&event) {
-                            let mut connection_closed = false;
-                            loop {
-                                match Self::read_from_connection(
-                                    &mut connection,
-                                    &mut receive[bytes_read..],
-                                ) {
-                                    Ok(0) => {
-                                        connection_closed = true;
-                                        break;
-                                    }
-                                    Ok(n) => {
-                                        assert!(n + bytes_read <= receive.len());
-                                        bytes_read += n;
-                                        if bytes_read >= send.len() {
-                                            read = true;
-                                            break;
-                                        }
-                                        continue;
-                                    }
-                                    Err(ref err) if WouldBlock == err.kind() => {
-                                        break;
-                                    }
-                                    Err(ref err) if Interrupted == err.kind() => {
-                                        continue;
-                                    }
-                                    Err(err) => {
-                                        return Err(err);
-                                    }
-                                }
-                            }
-                            if connection_closed {
-                                done = true;
-                            }
-                        }
-                        loop {
-                            if read && Self::event_is_writeable(&event) {
-                                match Self::write_on_connection(connection, send) {
-                                    Ok(n) if n < send.len() => {
-                                        return Err::<(), std::io::Error>(WriteZero.into());
-                                    }
-                                    Ok(n) => {
-                                        written = true;
-                                        break;
-                                    }
-                                    Err(ref err) if WouldBlock == err.kind() => {
-                                        break;
-                                    }
-                                    Err(ref err) if Interrupted == err.kind() => {
-                                        continue;
-                                    }
-                                    Err(err) => {
-                                        return Err(err);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    if written {
-                        done = true;
-                    }
-                    if done {
-                        if let Some(mut connection) = connections.remove(&token) {
-                            Self::deregister(&poller, &mut connection)?
-                        }
-                        return Ok(());
+                continue;
+            }
+            let Some(key) = token.checked_sub(SERVER + 1) else {
+                continue;
+            };
+            let Some(connection) = connections.get_mut(key) else {
+                continue;
+            };
+            let readable = S::event_is_readable(event);
+            let done = connection
+                .exchange::<S, H>(token, readable, ring, handler)
+                .unwrap_or(true);
+            let (bytes_in, bytes_out) = connection.take_transferred();
+            if let Some(telemetry) = handler.telemetry() {
+                telemetry.record_transfer(bytes_in, bytes_out);
+            }
+            if done {
+                if let Some(connection) = connections.remove(key) {
+                    let (mut stream, send) = connection.close(ring);
+                    <S as Registry<C>>::deregister(&poller, &mut stream)?;
+                    recycled.push(send);
+                    tokens.borrow_mut().release(token);
+                    handler.closed(token);
+                    if let Some(telemetry) = handler.telemetry() {
+                        telemetry.record_close();
                     }
                 }
             }
         }
+        if let Some(telemetry) = handler.telemetry() {
+            telemetry.record_ring(ring);
+        }
+    }
+    for (key, connection) in connections.drain() {
+        let (mut stream, send) = connection.close(ring);
+        <S as Registry<C>>::deregister(&poller, &mut stream)?;
+        recycled.push(send);
+        tokens.borrow_mut().release(SERVER + 1 + key);
+        handler.closed(SERVER + 1 + key);
+        if let Some(telemetry) = handler.telemetry() {
+            telemetry.record_close();
+        }
+    }
+    if let Some(telemetry) = handler.telemetry() {
+        telemetry.record_ring(ring);
     }
+    <S as Registry<<S as ListenerRegistry<C>>::Listener>>::deregister(&poller, &mut server)?;
+    S::unbind(server)
 }
 
-trait Client<C>:
+pub trait Client<C>:
     Registry<C> + Connect<C> + Connector<C> + EventLoop<Poller = Poll, Interest = Interest>
 {
+    /// Write `send` to `addr` out of `ring`, then read the answer into `ring` until at least
+    /// `send.len()` bytes arrived.
+    ///
+    /// The returned span is owned by `CLIENT` and released by the caller once it is done with it.
     fn client<const CLIENT: usize>(
-        addr: SocketAddr,
+        addr: <Self as Connect<C>>::Addr,
         event_buffer_capacity: usize,
-        receive: &mut [u8; 4096],
-        send: &'static [u8],
-    ) -> std::io::Result<()>
+        ring: &mut RingBuffer,
+        send: &[u8],
+    ) -> std::io::Result<Span>
+    where
+        C: Read + Write,
+        <Self as EventLoop>::Event: Debug,
+    {
+        let mut connection: C = Self::connect(addr)?;
+        Self::request::<CLIENT>(&mut connection, event_buffer_capacity, ring, send)
+    }
+
+    /// Like [`Client::client`], reading the answer into the ring of the calling thread's
+    /// [`worker::Worker`]. Fails with `NotFound` when none is installed.
+    fn client_local<const CLIENT: usize>(
+        addr: <Self as Connect<C>>::Addr,
+        send: &[u8],
+    ) -> std::io::Result<Span>
+    where
+        C: Read + Write,
+        <Self as EventLoop>::Event: Debug,
+    {
+        let worker = worker::current().ok_or(NotFound)?;
+        let mut ring = worker.ring.try_borrow_mut().map_err(|_| ResourceBusy)?;
+        let capacity = worker.event_buffer_capacity();
+        Self::client::<CLIENT>(addr, capacity, &mut ring, send)
+    }
+
+    /// Like [`Client::client`], but take the connection to `addr` from `pool` and give it back
+    /// once the answer arrived; a connection that failed is dropped instead.
+    fn pooled<const CLIENT: usize>(
+        pool: &mut Pool<<Self as Connect<C>>::Addr, C>,
+        addr: <Self as Connect<C>>::Addr,
+        event_buffer_capacity: usize,
+        ring: &mut RingBuffer,
+        send: &[u8],
+    ) -> std::io::Result<Span>
+    where
+        Self: Sized,
+        C: Read + Write,
+        <Self as EventLoop>::Event: Debug,
+        <Self as Connect<C>>::Addr: Ord + Clone,
+    {
+        let mut connection = pool.checkout::<Self>(&addr)?;
+        match Self::request::<CLIENT>(&mut connection, event_buffer_capacity, ring, send) {
+            Ok(received) => {
+                pool.checkin(addr, connection);
+                Ok(received)
+            }
+            Err(err) => {
+                pool.discard(&addr);
+                Err(err)
+            }
+        }
+    }
+
+    /// Like [`Client::client`], on a connection that is already open. It is registered for the
+    /// exchange only, so that it can go on to serve the next one.
+    fn request<const CLIENT: usize>(
+        connection: &mut C,
+        event_buffer_capacity: usize,
+        ring: &mut RingBuffer,
+        send: &[u8],
+    ) -> std::io::Result<Span>
+    where
+        C: Read + Write,
+        <Self as EventLoop>::Event: Debug,
+    {
+        let complete = |received: &[u8]| received.len() >= send.len();
+        Self::request_until::<CLIENT, _>(
+            connection,
+            event_buffer_capacity,
+            ring,
+            send,
+            None,
+            complete,
+        )
+    }
+
+    /// Like [`Client::request`], but read until `complete` finds the whole answer in the bytes
+    /// received so far, for protocols whose answers are not as long as their requests, and fail
+    /// with `TimedOut` when the exchange takes longer than `timeout`.
+    fn request_until<const CLIENT: usize, F: FnMut(&[u8]) -> bool>(
+        connection: &mut C,
+        event_buffer_capacity: usize,
+        ring: &mut RingBuffer,
+        send: &[u8],
+        timeout: Option<Duration>,
+        mut complete: F,
+    ) -> std::io::Result<Span>
     where
         C: Read + Write,
         <Self as EventLoop>::Event: Debug,
     {
         let mut poller = Self::new_poller()?;
         let mut events = Self::new_events_buffer(event_buffer_capacity);
-        let mut connection: C = Self::connect(addr)?;
         let interest = Self::add_readable_to_interest(Self::writeable_interest());
-        Self::register(&poller, &mut connection, CLIENT, interest)?;
-        let mut written = false;
-        let mut read = false;
-
-        loop {
-            if let Err(err) = Self::poll(&mut poller, &mut events, None) {
-                if Interrupted == err.kind() {
-                    continue;
+        Self::register(&poller, connection, CLIENT, interest)?;
+        let mut outgoing = Chain::default();
+        let mut received = Span::default();
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut exchange = || -> std::io::Result<()> {
+            ring.chain_writer(&mut outgoing, CLIENT).write_all(send)?;
+            let mut sending = true;
+            loop {
+                let timeout =
+                    deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+                if timeout.is_some_and(|timeout| timeout.is_zero()) {
+                    return Err(TimedOut.into());
                 }
-                return Err(err);
-            }
-            let events_iter = Self::events_iter(&events);
-            for event in events_iter {
-                let mut bytes_read: usize = 0;
-                if Self::event_token(&event) == CLIENT {
-                    if !written && Self::event_is_writeable(&event) {
-                        match Self::write_on_connection(&mut connection, send) {
-                            Ok(n) if n < send.len() => {
-                                return Err(WriteZero.into());
-                            }
-                            Ok(_n) => {
-                                written = true;
-                                let interest = Self::readable_interest();
-                                Self::reregister(&poller, &mut connection, CLIENT, interest)?;
-                            }
-                            Err(ref err) if WouldBlock == err.kind() => {}
-                            Err(ref err) if Interrupted == err.kind() => {
-                                continue;
+                if let Err(err) = Self::poll(&mut poller, &mut events, timeout) {
+                    if Interrupted == err.kind() {
+                        continue;
+                    }
+                    return Err(err);
+                }
+                let events_iter = Self::events_iter(&events);
+                for event in events_iter {
+                    if Self::event_token(event) != CLIENT {
+                        continue;
+                    }
+                    if sending && Self::event_is_writeable(event) {
+                        loop {
+                            if outgoing.is_empty() {
+                                match Self::flush_connection(connection) {
+                                    Ok(()) => {
+                                        sending = false;
+                                        let interest = Self::readable_interest();
+                                        Self::reregister(&poller, connection, CLIENT, interest)?;
+                                        break;
+                                    }
+                                    Err(ref err) if WouldBlock == err.kind() => {
+                                        break;
+                                    }
+                                    Err(ref err) if Interrupted == err.kind() => {
+                                        continue;
+                                    }
+                                    Err(err) => {
+                                        return Err(err);
+                                    }
+                                }
                             }
-                            Err(err) => {
-                                return Err(err);
+                            let mut send = [IoSlice::new(&[]); IO_SLICES];
+                            let count = ring.io_slices(&outgoing, &mut send);
+                            match Self::write_vectored_on_connection(connection, &send[..count]) {
+                                Ok(0) => {
+                                    return Err(WriteZero.into());
+                                }
+                                Ok(n) => {
+                                    ring.consume_chain(&mut outgoing, n);
+                                }
+                                Err(ref err) if WouldBlock == err.kind() => {
+                                    break;
+                                }
+                                Err(ref err) if Interrupted == err.kind() => {
+                                    continue;
+                                }
+                                Err(err) => {
+                                    return Err(err);
+                                }
                             }
                         }
                     }
-                    if written && Self::event_is_readable(&event) {
+                    if !sending && Self::event_is_readable(event) {
                         loop {
-                            match Self::read_from_connection(
-                                &mut connection,
-                                &mut receive[bytes_read..],
-                            ) {
+                            let spare = ring.spare_mut(&mut received, CLIENT)?;
+                            match Self::read_from_connection(connection, spare) {
                                 Ok(0) => {
-                                    break;
+                                    return Err(UnexpectedEof.into());
                                 }
                                 Ok(n) => {
-                                    bytes_read += n;
-                                    if bytes_read >= send.len() {
-                                        read = true;
-                                        break;
+                                    ring.commit(&mut received, n);
+                                    if complete(ring.bytes(&received)) {
+                                        return Ok(());
                                     }
-                                    continue;
                                 }
                                 Err(ref err) if WouldBlock == err.kind() => {
                                     break;
@@ -559,18 +1190,23 @@ trait Client<C>:
                         }
                     }
                 }
-
-                if read {
-                    return Ok(());
-                }
             }
+        };
+        let exchanged = exchange();
+        let deregistered = Self::deregister(&poller, connection);
+        ring.release_chain(&mut outgoing);
+        let exchanged = exchanged.and(deregistered);
+        if exchanged.is_err() {
+            ring.release_span(&mut received);
         }
+        exchanged.map(|()| received)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::{
+        io::{IoSlice, IoSliceMut, Read, Write},
         net::{IpAddr, Ipv4Addr, SocketAddr},
         sync::atomic::{AtomicU32, Ordering::*},
         thread,
@@ -578,51 +1214,464 @@ mod tests {
 
     use mio::net::TcpStream;
 
-    use crate::{Client, MioEventLoop, ReadWriteConnectorAdapter, Server};
+    use crate::{
+        base64::{decode_in_place, Alphabet, Encode},
+        pipeline::{tests::Double, Pipeline},
+        ring::{Chain, RingBuffer, Segment},
+        Client, Connector, Flow, Handler, Listener, MioEventLoop, ReadWriteConnectorAdapter,
+        Server, Shutdown, IO_SLICES,
+    };
 
-    static ADDRESS: AtomicU32 = AtomicU32::new(1 + (49152 << 16));
+    // ports start below the ephemeral range so listeners never collide with client sockets
+    static ADDRESS: AtomicU32 = AtomicU32::new(1 + (20480 << 16));
 
-    fn new_loopback_address() -> SocketAddr {
-        ADDRESS.fetch_max(1 + (49152 << 16), SeqCst);
+    pub(crate) fn new_loopback_address() -> SocketAddr {
+        ADDRESS.fetch_max(1 + (20480 << 16), SeqCst);
         let mut a = ADDRESS.fetch_add(1, SeqCst);
         let y = (a & 255) as u8;
-        a = a >> 8;
+        a >>= 8;
         let x = (a & 255) as u8;
-        a = a >> 8;
+        a >>= 8;
         SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, x, y)), a as u16)
     }
 
+    pub(crate) struct TestClient;
+
+    // marker traits that cause Client<TcpStream> to be implementable automatically
+    impl MioEventLoop for TestClient {}
+    impl ReadWriteConnectorAdapter for TestClient {}
+    impl Client<TcpStream> for TestClient {}
+
+    pub(crate) struct TestServer;
+    impl MioEventLoop for TestServer {}
+    impl ReadWriteConnectorAdapter for TestServer {}
+    impl Server<TcpStream> for TestServer {}
+
+    /// Answers with `reply` once a whole request of `reply.len()` bytes arrived, then closes.
+    pub(crate) struct Reply {
+        reply: &'static [u8],
+        pub(crate) requests: Vec<Vec<u8>>,
+    }
+
+    impl Reply {
+        pub(crate) fn new(reply: &'static [u8]) -> Self {
+            Self {
+                reply,
+                requests: Vec::new(),
+            }
+        }
+    }
+
+    impl<C> Handler<C> for Reply {
+        fn received<W: Write>(
+            &mut self,
+            _token: usize,
+            receive: &[u8],
+            send: &mut W,
+        ) -> std::io::Result<(usize, Flow)> {
+            if receive.len() < self.reply.len() {
+                return Ok((0, Flow::Continue));
+            }
+            self.requests.push(receive.to_vec());
+            send.write_all(self.reply)?;
+            Ok((receive.len(), Flow::Close))
+        }
+    }
+
+    /// Echoes complete lines back and keeps partial lines until the rest arrives.
+    struct Lines;
+
+    impl Handler<TcpStream> for Lines {
+        fn received<W: Write>(
+            &mut self,
+            _token: usize,
+            receive: &[u8],
+            send: &mut W,
+        ) -> std::io::Result<(usize, Flow)> {
+            let consumed = receive
+                .iter()
+                .rposition(|&b| b == b'\n')
+                .map_or(0, |i| i + 1);
+            send.write_all(&receive[..consumed])?;
+            Ok((consumed, Flow::Continue))
+        }
+    }
+
+    /// Echoes lines like [`Lines`] through an outbound pipeline.
+    struct PipedLines(Pipeline);
+
+    impl Handler<TcpStream> for PipedLines {
+        fn received<W: Write>(
+            &mut self,
+            token: usize,
+            receive: &[u8],
+            send: &mut W,
+        ) -> std::io::Result<(usize, Flow)> {
+            Lines.received(token, receive, send)
+        }
+
+        fn outbound(&mut self) -> Option<&mut Pipeline> {
+            Some(&mut self.0)
+        }
+    }
+
     #[test]
     fn it_works() {
-        static SEND_TO_CLIENT: &'static [u8] = b"send to client\n";
-        static SEND_TO_SERVER: &'static [u8] = b"send to server\n";
-        let mut receive_from_client: [u8; 4096] = [0; 4096];
-        let mut receive_from_server: [u8; 4096] = [0; 4096];
+        static SEND_TO_CLIENT: &[u8] = b"send to client\n";
+        static SEND_TO_SERVER: &[u8] = b"send to server\n";
+        let mut ring = RingBuffer::new(16);
+        let mut client_ring = RingBuffer::new(4);
+        let mut handler = Reply::new(SEND_TO_CLIENT);
+
+        let addr = new_loopback_address();
+        let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                TestServer::serve::<1, _>(listener, 128, &mut ring, &mut handler, &shutdown)
+            });
+            let mut received =
+                TestClient::client::<2>(addr, 128, &mut client_ring, SEND_TO_SERVER).unwrap();
+            assert_eq!(client_ring.bytes(&received), SEND_TO_CLIENT);
+            client_ring.release_span(&mut received);
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+
+        assert_eq!(handler.requests, [SEND_TO_SERVER]);
+        assert_eq!((ring.in_use(), client_ring.in_use()), (0, 0))
+    }
+
+    #[test]
+    fn dropped_watches_detach_their_pollers() {
+        let shutdown = Shutdown::new();
+        let pollers = [mio::Poll::new().unwrap(), mio::Poll::new().unwrap()];
+        let first = shutdown.watch(&pollers[0], 1).unwrap();
+        let second = shutdown.watch(&pollers[1], 2).unwrap();
+        drop(first);
+        assert_eq!(shutdown.inner.wakers.lock().unwrap().len(), 1);
+        shutdown.signal().unwrap();
+        assert!(shutdown.is_signalled());
+        drop(second);
+        assert!(shutdown.inner.wakers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn serves_concurrent_connections_until_shutdown() {
+        static SEND_TO_CLIENT: &[u8] = b"pong\n";
+        let mut ring = RingBuffer::new(64);
+        let mut handler = Reply::new(SEND_TO_CLIENT);
+
+        let addr = new_loopback_address();
+        let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                TestServer::serve::<1, _>(listener, 128, &mut ring, &mut handler, &shutdown)
+            });
+            // every client is connected before any of them is answered
+            let mut clients: Vec<std::net::TcpStream> = (0..512)
+                .map(|_| std::net::TcpStream::connect(addr).unwrap())
+                .collect();
+            for client in clients.iter_mut() {
+                client.write_all(b"ping\n").unwrap();
+            }
+            for client in clients.iter_mut() {
+                let mut reply = Vec::new();
+                client.read_to_end(&mut reply).unwrap();
+                assert_eq!(reply, SEND_TO_CLIENT);
+            }
+            assert!(!server.is_finished());
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+
+        assert_eq!(handler.requests.len(), 512);
+        assert_eq!(ring.in_use(), 0);
+    }
+
+    #[test]
+    fn reuses_tokens_of_closed_connections() {
+        /// Answers like [`Reply`] and remembers the token of every connection that closed.
+        struct Tokens(Reply, Vec<usize>);
+
+        impl Handler<TcpStream> for Tokens {
+            fn received<W: Write>(
+                &mut self,
+                token: usize,
+                receive: &[u8],
+                send: &mut W,
+            ) -> std::io::Result<(usize, Flow)> {
+                <Reply as Handler<TcpStream>>::received(&mut self.0, token, receive, send)
+            }
+
+            fn closed(&mut self, token: usize) {
+                self.1.push(token);
+            }
+        }
+
+        let mut ring = RingBuffer::new(16);
+        let mut client_ring = RingBuffer::new(16);
+        let mut handler = Tokens(Reply::new(b"pong"), Vec::new());
+        let addr = new_loopback_address();
+        let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                TestServer::serve::<1, _>(listener, 128, &mut ring, &mut handler, &shutdown)
+            });
+            // every connection is closed by the server before the next one is accepted
+            for _ in 0..3 {
+                let mut received =
+                    TestClient::client::<2>(addr, 128, &mut client_ring, b"ping").unwrap();
+                client_ring.release_span(&mut received);
+            }
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+
+        assert_eq!(handler.1, [2, 2, 2]);
+        assert_eq!(ring.in_use(), 0);
+    }
+
+    #[test]
+    fn unconsumed_bytes_are_presented_again() {
+        let mut ring = RingBuffer::new(64);
+        let mut handler = Lines;
 
         let addr = new_loopback_address();
+        let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
 
-        struct TestClient;
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                TestServer::serve::<1, _>(listener, 128, &mut ring, &mut handler, &shutdown)
+            });
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            client.set_nodelay(true).unwrap();
+            for piece in [&b"hel"[..], b"lo\nwor", b"ld\n"] {
+                client.write_all(piece).unwrap();
+                thread::sleep(std::time::Duration::from_millis(10));
+            }
+            let mut reply = [0; 12];
+            client.read_exact(&mut reply).unwrap();
+            assert_eq!(&reply, b"hello\nworld\n");
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+    }
 
-        // marker traits that cause Client<TcpStream> to be implementable automatically
-        impl MioEventLoop for TestClient {}
-        impl ReadWriteConnectorAdapter for TestClient {}
-        impl Client<TcpStream> for TestClient {}
+    #[test]
+    fn closes_connection_when_ring_is_full() {
+        let mut ring = RingBuffer::with_segment_len(2, 64);
+        let mut handler = Lines;
 
-        struct TestServer;
-        impl MioEventLoop for TestServer {}
-        impl ReadWriteConnectorAdapter for TestServer {}
-        impl Server<TcpStream> for TestServer {}
+        let addr = new_loopback_address();
+        let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
 
         thread::scope(|s| {
-            s.spawn(|| {
-                TestServer::server::<1>(addr, 128, &mut receive_from_client, SEND_TO_CLIENT)
+            let server = s.spawn(|| {
+                TestServer::serve::<1, _>(listener, 128, &mut ring, &mut handler, &shutdown)
             });
-            s.spawn(|| {
-                TestClient::client::<2>(addr, 128, &mut receive_from_server, SEND_TO_SERVER)
+            // a line longer than the whole ring can never be handed to the handler
+            let mut greedy = std::net::TcpStream::connect(addr).unwrap();
+            greedy.write_all(&[b'x'; 256]).unwrap();
+            let mut reply = Vec::new();
+            let _ = greedy.read_to_end(&mut reply);
+            assert!(reply.is_empty());
+
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            client.write_all(b"still serving\n").unwrap();
+            let mut reply = [0; 14];
+            client.read_exact(&mut reply).unwrap();
+            assert_eq!(&reply, b"still serving\n");
+            drop(client);
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+
+        assert_eq!(ring.in_use(), 0);
+    }
+
+    #[test]
+    fn vectored_io_crosses_ring_wrap_around() {
+        let listener = std::net::TcpListener::bind(new_loopback_address()).unwrap();
+        let mut writer = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut reader, _) = listener.accept().unwrap();
+
+        // with segment 0 owned elsewhere the chain starts in the last segment and wraps to the first
+        let mut ring = RingBuffer::with_segment_len(4, 8);
+        let blockers: Vec<_> = (0..3).map(|_| ring.acquire(9).unwrap()).collect();
+        ring.release(blockers[0]);
+        let mut outgoing = Chain::default();
+        ring.chain_writer(&mut outgoing, 1)
+            .write_all(b"wraps around")
+            .unwrap();
+        assert_eq!(
+            outgoing.segments().collect::<Vec<_>>(),
+            [Segment(3), Segment(0)]
+        );
+
+        let mut send = [IoSlice::new(&[]); IO_SLICES];
+        let count = ring.io_slices(&outgoing, &mut send);
+        let n = TestServer::write_vectored_on_connection(&mut writer, &send[..count]).unwrap();
+        assert_eq!(n, 12);
+        ring.consume_chain(&mut outgoing, n);
+
+        let mut incoming = Chain::default();
+        let mut receive: [IoSliceMut; 4] = std::array::from_fn(|_| IoSliceMut::new(&mut []));
+        let count = ring.spare_slices(&mut incoming, 2, &mut receive).unwrap();
+        assert_eq!(count, 2);
+        let n =
+            TestServer::read_vectored_from_connection(&mut reader, &mut receive[..count]).unwrap();
+        assert_eq!(n, 12);
+        ring.commit_chain(&mut incoming, n);
+        assert_eq!(
+            incoming.segments().collect::<Vec<_>>(),
+            [Segment(3), Segment(0)]
+        );
+
+        let mut slices = [IoSlice::new(&[]); IO_SLICES];
+        let count = ring.io_slices(&incoming, &mut slices);
+        let received: Vec<u8> = slices[..count]
+            .iter()
+            .flat_map(|s| s.iter().copied())
+            .collect();
+        assert_eq!(received, b"wraps around");
+        ring.release_chain(&mut incoming);
+        assert_eq!(ring.in_use(), 2);
+    }
+
+    #[test]
+    fn outbound_pipeline_runs_on_every_segment() {
+        let mut ring = RingBuffer::with_segment_len(8, 16);
+        let mut handler = PipedLines(Pipeline::new().stage(Double));
+
+        let addr = new_loopback_address();
+        let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                TestServer::serve::<1, _>(listener, 128, &mut ring, &mut handler, &shutdown)
+            });
+            // each 16 byte segment only takes 8 bytes so that doubling them stays in place
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            client.write_all(b"abcdefghijklmnop\n").unwrap();
+            let mut reply = [0; 34];
+            client.read_exact(&mut reply).unwrap();
+            assert_eq!(&reply, b"aabbccddeeffgghhiijjkkllmmnnoopp\n\n");
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+
+        assert_eq!(ring.in_use(), 0);
+    }
+
+    #[test]
+    fn outbound_base64_encodes_a_stream_written_in_pieces() {
+        let mut ring = RingBuffer::new(8);
+        let mut handler = PipedLines(Pipeline::new().stage(Encode::standard()));
+
+        let addr = new_loopback_address();
+        let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                TestServer::serve::<1, _>(listener, 128, &mut ring, &mut handler, &shutdown)
             });
+            // each line is its own handler call; all but the last are a multiple of three
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            let mut stream = Vec::new();
+            for (line, encoded) in [(&b"abcde\n"[..], 8), (b"fg\n", 4), (b"h\n", 4)] {
+                client.write_all(line).unwrap();
+                let mut reply = vec![0; encoded];
+                client.read_exact(&mut reply).unwrap();
+                stream.extend(reply);
+            }
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+
+            assert_eq!(stream, b"YWJjZGUKZmcKaAo=");
+            let len = decode_in_place(&mut stream, Alphabet::Standard).unwrap();
+            assert_eq!(&stream[..len], b"abcde\nfg\nh\n");
         });
 
-        assert_eq!(&receive_from_server[..SEND_TO_CLIENT.len()], SEND_TO_CLIENT);
-        assert_eq!(&receive_from_client[..SEND_TO_SERVER.len()], SEND_TO_SERVER)
+        assert_eq!(ring.in_use(), 0);
+    }
+
+    #[cfg(unix)]
+    mod unix {
+        use std::{path::PathBuf, sync::atomic::Ordering::*, thread};
+
+        use mio::net::UnixStream;
+
+        use super::{Reply, ADDRESS};
+        use crate::{
+            ring::RingBuffer, Client, Listener, MioEventLoop, ReadWriteConnectorAdapter, Server,
+            Shutdown,
+        };
+
+        struct UnixServer;
+        impl MioEventLoop for UnixServer {}
+        impl ReadWriteConnectorAdapter for UnixServer {}
+        impl Server<UnixStream> for UnixServer {}
+
+        struct UnixClient;
+        impl MioEventLoop for UnixClient {}
+        impl ReadWriteConnectorAdapter for UnixClient {}
+        impl Client<UnixStream> for UnixClient {}
+
+        fn new_socket_path() -> PathBuf {
+            let n = ADDRESS.fetch_add(1, SeqCst);
+            std::env::temp_dir().join(format!("elog-{}-{n}.sock", std::process::id()))
+        }
+
+        #[test]
+        fn serves_a_socket_file_and_removes_it() {
+            static SEND_TO_CLIENT: &[u8] = b"pong\n";
+            let mut ring = RingBuffer::new(16);
+            let mut client_ring = RingBuffer::new(4);
+            let mut handler = Reply::new(SEND_TO_CLIENT);
+            let path = new_socket_path();
+            let listener = <UnixServer as Listener<UnixStream>>::bind(path.clone()).unwrap();
+            let shutdown = Shutdown::new();
+
+            thread::scope(|s| {
+                let server = s.spawn(|| {
+                    UnixServer::serve::<1, _>(listener, 128, &mut ring, &mut handler, &shutdown)
+                });
+                let mut received =
+                    UnixClient::client::<2>(path.clone(), 128, &mut client_ring, b"ping\n")
+                        .unwrap();
+                assert_eq!(client_ring.bytes(&received), SEND_TO_CLIENT);
+                client_ring.release_span(&mut received);
+                shutdown.signal().unwrap();
+                server.join().unwrap().unwrap();
+            });
+
+            assert_eq!(handler.requests, [b"ping\n"]);
+            assert!(!path.exists());
+            assert_eq!((ring.in_use(), client_ring.in_use()), (0, 0));
+        }
+
+        #[test]
+        fn replaces_stale_socket_files_only() {
+            let path = new_socket_path();
+            drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+            assert!(path.exists());
+
+            let listener = <UnixServer as Listener<UnixStream>>::bind(path.clone()).unwrap();
+            let err = <UnixServer as Listener<UnixStream>>::bind(path.clone()).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+
+            <UnixServer as Listener<UnixStream>>::unbind(listener).unwrap();
+            assert!(!path.exists());
+        }
     }
 }
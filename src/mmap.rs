@@ -0,0 +1,326 @@
+use std::{
+    fs::File,
+    io::ErrorKind::*,
+    ops::{Deref, DerefMut},
+    os::fd::{AsRawFd, RawFd},
+    ptr::NonNull,
+};
+
+/// Size of a huge page on x86-64 and aarch64 with the default kernel configuration.
+pub const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+/// Size of a page of the running system.
+pub fn page_size() -> usize {
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => crate::ring::PAGE_SIZE,
+    }
+}
+
+/// How [`MapOptions::anonymous`] and [`MapOptions::file`] map memory.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MapOptions {
+    mirror: bool,
+    huge_pages: bool,
+}
+
+impl MapOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Map the memory a second time right after itself, so that reading or writing on past its
+    /// end continues at its start and bytes that wrap around still form one slice.
+    pub fn mirror(mut self, mirror: bool) -> Self {
+        self.mirror = mirror;
+        self
+    }
+
+    /// Back anonymous memory with huge pages, which the kernel only hands out when they were
+    /// reserved through `vm.nr_hugepages`. Files get huge pages from living on hugetlbfs; either
+    /// way the size must be a multiple of [`HUGE_PAGE_SIZE`].
+    pub fn huge_pages(mut self, huge_pages: bool) -> Self {
+        self.huge_pages = huge_pages;
+        self
+    }
+
+    fn check(&self, size: usize) -> std::io::Result<()> {
+        let page = match self.huge_pages {
+            true => HUGE_PAGE_SIZE,
+            false => page_size(),
+        };
+        if 0 == size || !size.is_multiple_of(page) {
+            return Err(std::io::Error::new(
+                InvalidInput,
+                format!("mapping of {size} bytes is not a whole number of {page} byte pages"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// `size` bytes of zeroed memory belonging to no file.
+    pub fn anonymous(&self, size: usize) -> std::io::Result<Mapping> {
+        self.check(size)?;
+        let huge_pages = if self.huge_pages {
+            libc::MAP_HUGETLB
+        } else {
+            0
+        };
+        if !self.mirror {
+            let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | huge_pages;
+            let ptr = map(std::ptr::null_mut(), size, flags, -1)?;
+            return Ok(Mapping {
+                ptr,
+                size,
+                view: size,
+            });
+        }
+        // a mirror maps the same pages twice, which takes a file; a memfd is one without a path
+        let huge_pages = if self.huge_pages {
+            libc::MFD_HUGETLB
+        } else {
+            0
+        };
+        let fd =
+            unsafe { libc::memfd_create(c"elog-ring".as_ptr(), libc::MFD_CLOEXEC | huge_pages) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mapped = match unsafe { libc::ftruncate(fd, size as libc::off_t) } {
+            0 => map_twice(fd, size),
+            _ => Err(std::io::Error::last_os_error()),
+        };
+        unsafe { libc::close(fd) };
+        mapped
+    }
+
+    /// The first `size` bytes of `file`, which is extended to `size` bytes if it is shorter.
+    /// Writes reach the file; [`Mapping::flush`] waits until they are on disk.
+    pub fn file(&self, file: &File, size: usize) -> std::io::Result<Mapping> {
+        self.check(size)?;
+        if file.metadata()?.len() < size as u64 {
+            file.set_len(size as u64)?;
+        }
+        if self.mirror {
+            return map_twice(file.as_raw_fd(), size);
+        }
+        let ptr = map(
+            std::ptr::null_mut(),
+            size,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+        )?;
+        Ok(Mapping {
+            ptr,
+            size,
+            view: size,
+        })
+    }
+}
+
+fn map(
+    addr: *mut libc::c_void,
+    len: usize,
+    flags: libc::c_int,
+    fd: RawFd,
+) -> std::io::Result<NonNull<u8>> {
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
+    match unsafe { libc::mmap(addr, len, prot, flags, fd, 0) } {
+        libc::MAP_FAILED => Err(std::io::Error::last_os_error()),
+        ptr => Ok(NonNull::new(ptr.cast()).expect("mmap returned null")),
+    }
+}
+
+/// Map the first `size` bytes of `fd` twice in a row, into an address range reserved up front so
+/// that nothing else can land between the two.
+fn map_twice(fd: RawFd, size: usize) -> std::io::Result<Mapping> {
+    let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE;
+    let reserved = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            2 * size,
+            libc::PROT_NONE,
+            flags,
+            -1,
+            0,
+        )
+    };
+    if libc::MAP_FAILED == reserved {
+        return Err(std::io::Error::last_os_error());
+    }
+    let flags = libc::MAP_SHARED | libc::MAP_FIXED;
+    let mapped = map(reserved, size, flags, fd)
+        .and_then(|_| map(unsafe { reserved.byte_add(size) }, size, flags, fd));
+    if let Err(err) = mapped {
+        unsafe { libc::munmap(reserved, 2 * size) };
+        return Err(err);
+    }
+    Ok(Mapping {
+        ptr: NonNull::new(reserved.cast()).expect("mmap returned null"),
+        size,
+        view: 2 * size,
+    })
+}
+
+/// Memory mapped from the kernel rather than allocated on the heap, for buffers larger than
+/// the heap should hold and for buffers that live in a file.
+///
+/// A mirrored mapping reads as twice its size, the second half being the first one again.
+#[derive(Debug)]
+pub struct Mapping {
+    ptr: NonNull<u8>,
+    size: usize,
+    view: usize,
+}
+
+// the mapping is owned memory like a `Box<[u8]>`, only obtained differently
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// Bytes of memory behind the mapping, counted once even when mirrored.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_mirrored(&self) -> bool {
+        self.view > self.size
+    }
+
+    /// Wait until the writes to a file-backed mapping are on disk.
+    pub fn flush(&self) -> std::io::Result<()> {
+        match unsafe { libc::msync(self.ptr.as_ptr().cast(), self.size, libc::MS_SYNC) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+}
+
+impl Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.view) }
+    }
+}
+
+impl DerefMut for Mapping {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.view) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.as_ptr().cast(), self.view) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::OpenOptions,
+        io::Write,
+        sync::atomic::{AtomicUsize, Ordering::SeqCst},
+    };
+
+    use super::{page_size, MapOptions};
+    use crate::ring::{Chain, RingBuffer, Segment, Span};
+
+    static FILES: AtomicUsize = AtomicUsize::new(0);
+
+    #[test]
+    fn mirrored_memory_continues_at_its_start() {
+        let page = page_size();
+        let mut plain = MapOptions::new().anonymous(2 * page).unwrap();
+        assert_eq!((plain.len(), plain.is_mirrored()), (2 * page, false));
+        assert!(plain.iter().all(|&b| 0 == b));
+        plain[2 * page - 1] = 1;
+
+        let mut mirrored = MapOptions::new().mirror(true).anonymous(2 * page).unwrap();
+        assert_eq!((mirrored.len(), mirrored.size()), (4 * page, 2 * page));
+        mirrored[2 * page - 3..2 * page + 3].copy_from_slice(b"wrap!!");
+        assert_eq!(&mirrored[..3], b"p!!");
+        assert_eq!(&mirrored[4 * page - 3..], b"wra");
+
+        let err = MapOptions::new().anonymous(page + 1).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let err = MapOptions::new()
+            .huge_pages(true)
+            .anonymous(page)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn file_mappings_write_through_to_the_file() {
+        let page = page_size();
+        let n = FILES.fetch_add(1, SeqCst);
+        let path = std::env::temp_dir().join(format!("elog-{}-{n}.ring", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+
+        let mut mapping = MapOptions::new().mirror(true).file(&file, page).unwrap();
+        mapping[page - 2..page + 2].copy_from_slice(b"tail");
+        mapping.flush().unwrap();
+        drop(mapping);
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(written.len(), page);
+        assert_eq!(
+            (&written[..2], &written[page - 2..]),
+            (&b"il"[..], &b"ta"[..])
+        );
+
+        let mapping = MapOptions::new().file(&file, page).unwrap();
+        assert_eq!((&mapping[..2], mapping.is_mirrored()), (&b"il"[..], false));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn spans_wrap_around_a_mirrored_ring() {
+        let page = page_size();
+        let mapping = MapOptions::new().mirror(true).anonymous(4 * page).unwrap();
+        let mut ring = RingBuffer::mapped(mapping, page);
+        assert_eq!((ring.segments(), ring.is_mirrored()), (4, true));
+
+        let mut first = Span::default();
+        ring.reserve(&mut first, 1, 3 * page).unwrap();
+        ring.commit(&mut first, 3 * page);
+        ring.consume(&mut first, 2 * page);
+        assert_eq!((ring.tail(), ring.head()), (Segment(2), Segment(3)));
+
+        // the last segment and the first two form one span across the end of the ring
+        let mut span = Span::default();
+        let bytes: Vec<u8> = (0..3 * page).map(|i| i as u8).collect();
+        ring.reserve(&mut span, 2, bytes.len()).unwrap();
+        ring.spare_mut(&mut span, 2).unwrap()[..bytes.len()].copy_from_slice(&bytes);
+        ring.commit(&mut span, bytes.len());
+        assert_eq!(ring.bytes(&span), bytes);
+        assert_eq!(
+            span.segments().collect::<Vec<_>>(),
+            [Segment(3), Segment(0), Segment(1)]
+        );
+        assert_eq!(ring.owner(Segment(0)), Some(2));
+
+        // answering from the wrapped span writes into the one segment left between its ends
+        ring.release_span(&mut first);
+        let mut output = Chain::default();
+        let (received, mut send) = ring.writer(&span, &mut output, 2);
+        send.write_all(&received[..page]).unwrap();
+        assert!(send.write_all(b"!").is_err());
+        assert_eq!(output.segments().collect::<Vec<_>>(), [Segment(2)]);
+        assert_eq!(ring.front(&output), &bytes[..page]);
+        ring.release_chain(&mut output);
+
+        ring.consume(&mut span, page + 1);
+        assert_eq!(ring.bytes(&span), &bytes[page + 1..]);
+        assert_eq!(span.segments().next(), Some(Segment(0)));
+        ring.release_span(&mut span);
+        assert_eq!(ring.in_use(), 0);
+    }
+}
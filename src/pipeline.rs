@@ -0,0 +1,254 @@
+use std::{io::ErrorKind::*, sync::Arc, time::Instant};
+
+use crate::{
+    ring::{Chain, RingBuffer, Span},
+    telemetry::{Histogram, Telemetry},
+};
+
+/// Worst case output length of a [`Stage`] as a function of its input length.
+///
+/// Input is taken in blocks of `input` bytes that each become at most `output` bytes, plus a
+/// fixed `overhead` for the whole run. Base64 encoding is `4` out per `3` in, decoding `3` out per
+/// `4` in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ratio {
+    pub output: usize,
+    pub input: usize,
+    pub overhead: usize,
+}
+
+impl Ratio {
+    pub const IDENTITY: Ratio = Ratio::new(1, 1);
+
+    pub const fn new(output: usize, input: usize) -> Self {
+        assert!(output > 0 && input > 0);
+        Self {
+            output,
+            input,
+            overhead: 0,
+        }
+    }
+
+    pub const fn with_overhead(self, overhead: usize) -> Self {
+        Self { overhead, ..self }
+    }
+
+    /// Largest output `len` bytes of input can produce.
+    pub fn max_output(&self, len: usize) -> usize {
+        len.div_ceil(self.input) * self.output + self.overhead
+    }
+}
+
+/// One in place transformation of a segment, such as decoding or compressing it.
+pub trait Stage {
+    fn ratio(&self) -> Ratio;
+
+    /// Transform `buffer[..len]` into `buffer[..n]` and return `n`.
+    ///
+    /// `buffer` is at least `self.ratio().max_output(len)` long, so expanding stages have their
+    /// headroom right after the input.
+    fn run(&mut self, buffer: &mut [u8], len: usize) -> std::io::Result<usize>;
+
+    /// What the stage is called in telemetry; its type name without the path by default.
+    fn name(&self) -> &'static str {
+        let name = std::any::type_name::<Self>();
+        let name = name.split('<').next().unwrap_or(name);
+        name.rsplit("::").next().unwrap_or(name)
+    }
+}
+
+/// Stages run one after another over the same segment, each over the previous one's output.
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage + Send>>,
+    telemetry: Option<Arc<Telemetry>>,
+    /// Latency histogram of every stage, once there is telemetry to record into.
+    latency: Vec<Arc<Histogram>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stage(mut self, stage: impl Stage + Send + 'static) -> Self {
+        if let Some(telemetry) = &self.telemetry {
+            self.latency.push(telemetry.stage(stage.name()));
+        }
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    /// Record how long every stage takes per run in `telemetry`, by stage name.
+    pub fn telemetry(mut self, telemetry: Arc<Telemetry>) -> Self {
+        self.latency = self
+            .stages
+            .iter()
+            .map(|stage| telemetry.stage(stage.name()))
+            .collect();
+        self.telemetry = Some(telemetry);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Memory `len` bytes of input need to pass through every stage in place: the largest of the
+    /// input and every intermediate worst case.
+    pub fn capacity(&self, len: usize) -> usize {
+        let mut capacity = len;
+        let mut size = len;
+        for stage in &self.stages {
+            size = stage.ratio().max_output(size);
+            capacity = capacity.max(size);
+        }
+        capacity
+    }
+
+    /// Memory needed after `len` bytes of input so that no stage has to move bytes elsewhere.
+    pub fn headroom(&self, len: usize) -> usize {
+        self.capacity(len) - len
+    }
+
+    /// Largest input that passes through every stage within `capacity` bytes, in whole blocks of
+    /// the first stage.
+    pub fn max_input(&self, capacity: usize) -> usize {
+        let (mut low, mut high) = (0, capacity);
+        while low < high {
+            let mid = low + (high - low).div_ceil(2);
+            if self.capacity(mid) <= capacity {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        match self.stages.first() {
+            Some(stage) => low - low % stage.ratio().input,
+            None => low,
+        }
+    }
+
+    /// Run every stage over `buffer[..len]`; returns the final length.
+    pub fn run(&mut self, buffer: &mut [u8], len: usize) -> std::io::Result<usize> {
+        if buffer.len() < self.capacity(len) {
+            return Err(std::io::Error::new(
+                StorageFull,
+                "segment lacks headroom for pipeline",
+            ));
+        }
+        let mut len = len;
+        for (i, stage) in self.stages.iter_mut().enumerate() {
+            let Some(latency) = self.latency.get(i) else {
+                len = stage.run(buffer, len)?;
+                continue;
+            };
+            let started = Instant::now();
+            len = stage.run(buffer, len)?;
+            latency.record(started.elapsed().as_nanos() as u64);
+        }
+        Ok(len)
+    }
+
+    /// Run every stage over the bytes of `span`, first reserving the headroom they need after it.
+    pub fn run_span(
+        &mut self,
+        ring: &mut RingBuffer,
+        span: &mut Span,
+        owner: usize,
+    ) -> std::io::Result<()> {
+        let len = span.len();
+        ring.reserve(span, owner, self.headroom(len))?;
+        let len = self.run(ring.span_mut(span), len)?;
+        ring.set_len(span, len);
+        Ok(())
+    }
+
+    /// Run every stage over each segment of `chain` that was not run yet, and keep enough
+    /// headroom free in the segments filled from now on for the stages to expand into.
+    pub fn run_chain(&mut self, ring: &mut RingBuffer, chain: &mut Chain) -> std::io::Result<()> {
+        match self.max_input(ring.segment_len()) {
+            0 => {
+                return Err(std::io::Error::new(
+                    InvalidInput,
+                    "segment too small for pipeline",
+                ))
+            }
+            limit => chain.set_limit(limit),
+        }
+        ring.stage_chain(chain, |buffer, len| self.run(buffer, len))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{Pipeline, Ratio, Stage};
+    use crate::ring::{RingBuffer, Span};
+
+    /// Writes every byte twice, working from the back so nothing is overwritten before it is read.
+    pub(crate) struct Double;
+
+    impl Stage for Double {
+        fn ratio(&self) -> Ratio {
+            Ratio::new(2, 1)
+        }
+
+        fn run(&mut self, buffer: &mut [u8], len: usize) -> std::io::Result<usize> {
+            for i in (0..len).rev() {
+                buffer[2 * i] = buffer[i];
+                buffer[2 * i + 1] = buffer[i];
+            }
+            Ok(2 * len)
+        }
+    }
+
+    /// Keeps every other byte.
+    struct Halve;
+
+    impl Stage for Halve {
+        fn ratio(&self) -> Ratio {
+            Ratio::new(1, 2)
+        }
+
+        fn run(&mut self, buffer: &mut [u8], len: usize) -> std::io::Result<usize> {
+            for i in 0..len / 2 {
+                buffer[i] = buffer[2 * i];
+            }
+            Ok(len / 2)
+        }
+    }
+
+    #[test]
+    fn headroom_covers_the_largest_intermediate() {
+        let pipeline = Pipeline::new().stage(Halve).stage(Double).stage(Double);
+        assert_eq!(pipeline.capacity(10), 20);
+        assert_eq!(pipeline.headroom(10), 10);
+        assert_eq!(pipeline.max_input(20), 10);
+
+        let encode = Pipeline::new().stage(Double);
+        assert_eq!(encode.max_input(4096), 2048);
+        assert_eq!(Ratio::new(4, 3).max_output(4), 8);
+        assert_eq!(Ratio::new(4, 3).with_overhead(2).max_output(3), 6);
+    }
+
+    #[test]
+    fn runs_in_place_over_a_span() {
+        let mut pipeline = Pipeline::new().stage(Halve).stage(Double).stage(Double);
+        let mut ring = RingBuffer::with_segment_len(4, 8);
+        let mut span = Span::default();
+        ring.spare_mut(&mut span, 1).unwrap()[..6].copy_from_slice(b"aXbYcZ");
+        ring.commit(&mut span, 6);
+
+        pipeline.run_span(&mut ring, &mut span, 1).unwrap();
+        assert_eq!(ring.bytes(&span), b"aaaabbbbcccc");
+        assert_eq!(span.segments().count(), 2);
+
+        let mut short = [0; 4];
+        short[..3].copy_from_slice(b"abc");
+        let err = Pipeline::new()
+            .stage(Double)
+            .run(&mut short, 3)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    collections::BTreeMap,
+    io::{ErrorKind::*, Read},
+    time::{Duration, Instant},
+};
+
+use crate::{Connect, Connector};
+
+#[derive(Debug)]
+struct Host<C> {
+    /// Connections waiting for the next request with the time they were returned, the most
+    /// recently returned last.
+    idle: Vec<(C, Instant)>,
+    /// Connections checked out and neither returned nor discarded yet.
+    busy: usize,
+}
+
+impl<C> Host<C> {
+    fn new() -> Self {
+        Self {
+            idle: Vec::new(),
+            busy: 0,
+        }
+    }
+}
+
+/// Open connections to upstream hosts, kept for the requests that follow so that each of them
+/// does not pay for a connect and handshake of its own.
+#[derive(Debug)]
+pub struct Pool<A, C> {
+    hosts: BTreeMap<A, Host<C>>,
+    max_per_host: usize,
+    idle_timeout: Duration,
+}
+
+impl<A: Ord + Clone, C> Pool<A, C> {
+    /// At most `max_per_host` connections to a host are checked out at once, and an idle
+    /// connection is closed once it waited for `idle_timeout`.
+    pub fn new(max_per_host: usize, idle_timeout: Duration) -> Self {
+        Self {
+            hosts: BTreeMap::new(),
+            max_per_host,
+            idle_timeout,
+        }
+    }
+
+    /// The most recently returned idle connection to `addr` the host did not close meanwhile,
+    /// or a new one from [`Connect::connect`]. Fails with `WouldBlock` while `max_per_host`
+    /// connections to `addr` are checked out.
+    ///
+    /// The connection is handed out unregistered and goes back with [`Pool::checkin`] once the
+    /// exchange on it is complete, or is reported with [`Pool::discard`] when it failed.
+    pub fn checkout<X>(&mut self, addr: &A) -> std::io::Result<C>
+    where
+        X: Connect<C, Addr = A> + Connector<C>,
+        C: Read,
+    {
+        self.expire(Instant::now());
+        let host = self.hosts.entry(addr.clone()).or_insert_with(Host::new);
+        while let Some((mut connection, _)) = host.idle.pop() {
+            // one the host closed reads its end or an error instead of blocking
+            if let Err(ref err) = X::read_from_connection(&mut connection, &mut [0]) {
+                if WouldBlock == err.kind() {
+                    host.busy += 1;
+                    return Ok(connection);
+                }
+            }
+        }
+        if host.busy >= self.max_per_host {
+            return Err(WouldBlock.into());
+        }
+        let connection = X::connect(addr.clone())?;
+        host.busy += 1;
+        Ok(connection)
+    }
+
+    /// Give back a connection checked out for `addr`, to be handed out again.
+    pub fn checkin(&mut self, addr: A, connection: C) {
+        let host = self.hosts.entry(addr).or_insert_with(Host::new);
+        host.busy = host.busy.saturating_sub(1);
+        host.idle.push((connection, Instant::now()));
+    }
+
+    /// A connection checked out for `addr` was dropped instead of given back.
+    pub fn discard(&mut self, addr: &A) {
+        if let Some(host) = self.hosts.get_mut(addr) {
+            host.busy = host.busy.saturating_sub(1);
+        }
+    }
+
+    /// Close the idle connections that have waited for the idle timeout by `now`.
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.idle_timeout;
+        for host in self.hosts.values_mut() {
+            host.idle
+                .retain(|(_, since)| now.duration_since(*since) < timeout);
+        }
+        self.hosts
+            .retain(|_, host| host.busy > 0 || !host.idle.is_empty());
+    }
+
+    /// Idle connections to `addr`.
+    pub fn idle(&self, addr: &A) -> usize {
+        self.hosts.get(addr).map_or(0, |host| host.idle.len())
+    }
+
+    /// Connections to `addr` that are checked out.
+    pub fn busy(&self, addr: &A) -> usize {
+        self.hosts.get(addr).map_or(0, |host| host.busy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use mio::net::TcpStream;
+
+    use super::Pool;
+    use crate::{
+        ring::RingBuffer,
+        tests::{new_loopback_address, Reply, TestClient, TestServer},
+        Client, Flow, Handler, Listener, Server, Shutdown,
+    };
+
+    /// Echoes everything and keeps the connection open; counts the connections it saw close.
+    #[derive(Default)]
+    struct Echo {
+        closed: usize,
+    }
+
+    impl<C> Handler<C> for Echo {
+        fn received<W: Write>(
+            &mut self,
+            _token: usize,
+            receive: &[u8],
+            send: &mut W,
+        ) -> std::io::Result<(usize, Flow)> {
+            send.write_all(receive)?;
+            Ok((receive.len(), Flow::Continue))
+        }
+
+        fn closed(&mut self, _token: usize) {
+            self.closed += 1;
+        }
+    }
+
+    #[test]
+    fn reuses_idle_connections_up_to_the_limit() {
+        let mut ring = RingBuffer::new(16);
+        let mut client_ring = RingBuffer::new(16);
+        let mut handler = Echo::default();
+        let addr = new_loopback_address();
+        let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                <TestServer as Server<TcpStream>>::serve::<1, _>(
+                    listener,
+                    128,
+                    &mut ring,
+                    &mut handler,
+                    &shutdown,
+                )
+            });
+            let mut pool = Pool::new(1, Duration::from_secs(10));
+            for send in [&b"hello"[..], b"again", b"and again"] {
+                let mut received =
+                    TestClient::pooled::<2>(&mut pool, addr, 128, &mut client_ring, send).unwrap();
+                assert_eq!(client_ring.bytes(&received), send);
+                client_ring.release_span(&mut received);
+            }
+            assert_eq!((pool.idle(&addr), pool.busy(&addr)), (1, 0));
+
+            let connection = pool.checkout::<TestClient>(&addr).unwrap();
+            let err = pool.checkout::<TestClient>(&addr).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+            drop(connection);
+            pool.discard(&addr);
+            let connection = pool.checkout::<TestClient>(&addr).unwrap();
+            pool.checkin(addr, connection);
+            assert_eq!((pool.idle(&addr), pool.busy(&addr)), (1, 0));
+
+            drop(pool);
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+
+        assert_eq!(handler.closed, 2);
+        assert_eq!((ring.in_use(), client_ring.in_use()), (0, 0));
+    }
+
+    #[test]
+    fn replaces_closed_and_expired_connections() {
+        let mut ring = RingBuffer::new(16);
+        let mut client_ring = RingBuffer::new(16);
+        let mut handler = Reply::new(b"pong");
+        let addr = new_loopback_address();
+        let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                <TestServer as Server<TcpStream>>::serve::<1, _>(
+                    listener,
+                    128,
+                    &mut ring,
+                    &mut handler,
+                    &shutdown,
+                )
+            });
+            // the server closes after every answer, so the pooled connection is dead next time
+            let mut pool = Pool::new(1, Duration::from_millis(50));
+            for _ in 0..2 {
+                let mut received =
+                    TestClient::pooled::<2>(&mut pool, addr, 128, &mut client_ring, b"ping")
+                        .unwrap();
+                assert_eq!(client_ring.bytes(&received), b"pong");
+                client_ring.release_span(&mut received);
+                assert_eq!(pool.idle(&addr), 1);
+                thread::sleep(Duration::from_millis(20));
+            }
+            pool.expire(Instant::now() + Duration::from_millis(50));
+            assert_eq!((pool.idle(&addr), pool.busy(&addr)), (0, 0));
+
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+
+        assert_eq!(handler.requests.len(), 2);
+        assert_eq!((ring.in_use(), client_ring.in_use()), (0, 0));
+    }
+}
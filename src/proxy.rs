@@ -0,0 +1,978 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    io::{ErrorKind::*, IoSlice, IoSliceMut, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown as HalfShutdown, SocketAddr},
+    time::{Duration, Instant},
+};
+
+#[cfg(unix)]
+use mio::net::UnixStream;
+use mio::{event::Source, net::TcpStream, Interest, Poll};
+
+use crate::{
+    balance::{Balancer, ClientKey, HashKey, Policy},
+    datagram::{Datagram, MAX_DATAGRAM},
+    http::{parse_request, Status},
+    ring::{Chain, RingBuffer, Span},
+    slab::Slab,
+    Connect, Connector, EventLoop, Listener, ListenerRegistry, Registry, Shutdown, IO_SLICES,
+    SHUTDOWN,
+};
+
+/// Connections whose sending half can be closed while they keep receiving.
+pub trait HalfClose {
+    fn shutdown_write(&mut self) -> std::io::Result<()>;
+}
+
+impl HalfClose for TcpStream {
+    fn shutdown_write(&mut self) -> std::io::Result<()> {
+        self.shutdown(HalfShutdown::Write)
+    }
+}
+
+#[cfg(unix)]
+impl HalfClose for UnixStream {
+    fn shutdown_write(&mut self) -> std::io::Result<()> {
+        self.shutdown(HalfShutdown::Write)
+    }
+}
+
+/// One end of a proxied connection.
+pub(crate) struct Side<S> {
+    pub(crate) stream: S,
+    /// Bytes read from the other end, waiting to be written to this one.
+    pub(crate) send: Chain,
+    /// The peer will send nothing more.
+    pub(crate) eof: bool,
+    /// Writing was shut down after the other end's `eof` was passed on.
+    pub(crate) shut: bool,
+}
+
+impl<S> Side<S> {
+    pub(crate) fn new(stream: S) -> Self {
+        Self {
+            stream,
+            send: Chain::default(),
+            eof: false,
+            shut: false,
+        }
+    }
+}
+
+/// An accepted connection and the upstream connection opened for it.
+struct Pair<C, U> {
+    downstream: Side<C>,
+    /// Opened once a backend was picked, which may have to wait for the first request head.
+    upstream: Option<Side<U>>,
+    /// Bytes read from downstream while there was no upstream to queue them for yet.
+    head: Chain,
+    /// The [`ClientKey`] of the connection.
+    client: Vec<u8>,
+    backend: usize,
+}
+
+impl<C, U> Pair<C, U> {
+    fn new(downstream: C, client: Vec<u8>) -> Self {
+        Self {
+            downstream: Side::new(downstream),
+            upstream: None,
+            head: Chain::default(),
+            client,
+            backend: 0,
+        }
+    }
+}
+
+/// Read from `from` into `into` until it would block, ends, or `into` holds `window` bytes; an
+/// upstream still connecting reads like one that would block. Returns whether anything happened.
+pub(crate) fn read_side<S, X>(
+    ring: &mut RingBuffer,
+    from: &mut Side<S>,
+    into: &mut Chain,
+    owner: usize,
+    window: usize,
+) -> std::io::Result<bool>
+where
+    S: Read,
+    X: Connector<S>,
+{
+    let mut progress = false;
+    while !from.eof && into.len() < window {
+        let count = (window - into.len())
+            .div_ceil(ring.segment_len())
+            .min(IO_SLICES);
+        let mut receive: [IoSliceMut<'_>; IO_SLICES] =
+            std::array::from_fn(|_| IoSliceMut::new(&mut []));
+        let count = ring.spare_slices(into, owner, &mut receive[..count])?;
+        let read = X::read_vectored_from_connection(&mut from.stream, &mut receive[..count]);
+        let n = *read.as_ref().unwrap_or(&0);
+        ring.commit_chain(into, n);
+        match read {
+            Ok(0) => {
+                from.eof = true;
+                progress = true;
+            }
+            Ok(_) => {
+                progress = true;
+            }
+            Err(ref err) if WouldBlock == err.kind() || NotConnected == err.kind() => {
+                break;
+            }
+            Err(ref err) if Interrupted == err.kind() => {
+                continue;
+            }
+            Err(err) => {
+                return Err(err);
+            }
+        }
+    }
+    Ok(progress)
+}
+
+/// Write what is queued for `to` until it would block; once all of it is written and `ended`,
+/// the other end's end of stream, is set, shut down writing. Returns whether anything happened.
+pub(crate) fn write_side<S, X>(
+    ring: &mut RingBuffer,
+    to: &mut Side<S>,
+    ended: bool,
+) -> std::io::Result<bool>
+where
+    S: Write + HalfClose,
+    X: Connector<S>,
+{
+    let mut progress = false;
+    while !to.send.is_empty() {
+        let mut send = [IoSlice::new(&[]); IO_SLICES];
+        let count = ring.io_slices(&to.send, &mut send);
+        match X::write_vectored_on_connection(&mut to.stream, &send[..count]) {
+            Ok(0) => {
+                return Err(WriteZero.into());
+            }
+            Ok(n) => {
+                ring.consume_chain(&mut to.send, n);
+                progress = true;
+            }
+            Err(ref err) if WouldBlock == err.kind() || NotConnected == err.kind() => {
+                return Ok(progress);
+            }
+            Err(ref err) if Interrupted == err.kind() => {
+                continue;
+            }
+            Err(err) => {
+                return Err(err);
+            }
+        }
+    }
+    if ended && !to.shut {
+        match to.stream.shutdown_write() {
+            Ok(()) => {
+                to.shut = true;
+                progress = true;
+            }
+            Err(ref err) if WouldBlock == err.kind() => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(progress)
+}
+
+/// Read the first request head on `pair` into its `head` and return the value of `header` once
+/// the head is complete, or the client key when the request lacks the header, is no HTTP, or
+/// downstream ended or filled the window before the head did. None while more has to arrive.
+fn header_key<C, U, X>(
+    pair: &mut Pair<C, U>,
+    token: usize,
+    ring: &mut RingBuffer,
+    window: usize,
+    header: &str,
+) -> std::io::Result<Option<Vec<u8>>>
+where
+    C: Read,
+    X: Connector<C>,
+{
+    read_side::<C, X>(ring, &mut pair.downstream, &mut pair.head, token, window)?;
+    let mut slices = [IoSlice::new(&[]); IO_SLICES];
+    let count = ring.io_slices(&pair.head, &mut slices);
+    let head: Vec<u8> = slices[..count]
+        .iter()
+        .flat_map(|slice| slice.iter())
+        .copied()
+        .collect();
+    match parse_request(&head) {
+        Ok(Status::Complete((request, _))) => match request.header(header) {
+            Some(value) => Ok(Some(value.to_vec())),
+            None => Ok(Some(pair.client.clone())),
+        },
+        Ok(Status::Partial) if !pair.downstream.eof && pair.head.len() < window => Ok(None),
+        _ => Ok(Some(pair.client.clone())),
+    }
+}
+
+/// Connect `pair` to the backend `balancer` picks for `key`, trying the next one while connecting
+/// fails right away; returns false once none is left to try.
+fn open<C, U, X>(
+    pair: &mut Pair<C, U>,
+    token: usize,
+    key: &[u8],
+    balancer: &mut Balancer<<X as Connect<U>>::Addr>,
+    poller: &Poll,
+) -> std::io::Result<bool>
+where
+    X: Connect<U> + Registry<U> + EventLoop<Poller = Poll, Interest = Interest>,
+    <X as Connect<U>>::Addr: Clone,
+{
+    let mut tried = Vec::new();
+    while let Some(backend) = balancer.pick(key, &tried) {
+        let Ok(mut stream) = X::connect(balancer.addr(backend).clone()) else {
+            tried.push(backend);
+            continue;
+        };
+        let interest = X::add_readable_to_interest(X::writeable_interest());
+        X::register(poller, &mut stream, token + 1, interest)?;
+        let mut upstream = Side::new(stream);
+        upstream.send = std::mem::take(&mut pair.head);
+        pair.upstream = Some(upstream);
+        pair.backend = backend;
+        balancer.opened(backend);
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Move bytes both ways until nothing moves any more, with at most `window` bytes waiting in
+/// each direction; returns true once both ends finished.
+fn exchange<C, U, X>(
+    pair: &mut Pair<C, U>,
+    token: usize,
+    ring: &mut RingBuffer,
+    window: usize,
+) -> std::io::Result<bool>
+where
+    C: Read + Write + HalfClose,
+    U: Read + Write + HalfClose,
+    X: Connector<C> + Connector<U>,
+{
+    let Pair {
+        downstream,
+        upstream: Some(upstream),
+        ..
+    } = pair
+    else {
+        return Ok(false);
+    };
+    loop {
+        let mut progress = false;
+        progress |= read_side::<C, X>(ring, downstream, &mut upstream.send, token, window)?;
+        progress |= read_side::<U, X>(ring, upstream, &mut downstream.send, token, window)?;
+        progress |= write_side::<U, X>(ring, upstream, downstream.eof)?;
+        progress |= write_side::<C, X>(ring, downstream, upstream.eof)?;
+        if downstream.shut && upstream.shut {
+            return Ok(true);
+        }
+        if !progress {
+            return Ok(false);
+        }
+    }
+}
+
+fn close<C, U, X, A>(
+    mut pair: Pair<C, U>,
+    poller: &Poll,
+    ring: &mut RingBuffer,
+    balancer: &mut Balancer<A>,
+) -> std::io::Result<()>
+where
+    X: Registry<C> + Registry<U> + EventLoop<Poller = Poll>,
+{
+    ring.release_chain(&mut pair.head);
+    ring.release_chain(&mut pair.downstream.send);
+    <X as Registry<C>>::deregister(poller, &mut pair.downstream.stream)?;
+    if let Some(mut upstream) = pair.upstream {
+        balancer.closed(pair.backend);
+        ring.release_chain(&mut upstream.send);
+        <X as Registry<U>>::deregister(poller, &mut upstream.stream)?;
+    }
+    Ok(())
+}
+
+pub trait Proxy<C, U>:
+    ListenerRegistry<C>
+    + Listener<C, Listener = <Self as ListenerRegistry<C>>::Listener>
+    + Registry<U>
+    + Connect<U>
+    + Connector<C>
+    + Connector<U>
+    + EventLoop<Poller = Poll, Interest = Interest>
+    + Sized
+where
+    C: Read + Write + HalfClose,
+    U: Read + Write + HalfClose,
+    <Self as Listener<C>>::Listener: Source,
+    <Self as Listener<C>>::PeerAddr: ClientKey,
+    <Self as Connect<U>>::Addr: Clone,
+    <Self as EventLoop>::Event: Debug,
+{
+    /// Bytes buffered in each direction of a connection before reading from the sending end
+    /// pauses until the receiving end took some of them.
+    const WINDOW: usize = 64 * 1024;
+
+    fn proxy<const SERVER: usize>(
+        addr: <Self as Listener<C>>::Addr,
+        upstream: <Self as Connect<U>>::Addr,
+        event_buffer_capacity: usize,
+        ring: &mut RingBuffer,
+        shutdown: &Shutdown,
+    ) -> std::io::Result<()> {
+        let server = Self::bind(addr)?;
+        Self::serve::<SERVER>(server, upstream, event_buffer_capacity, ring, shutdown)
+    }
+
+    /// Connect every connection accepted on `server` to `upstream` and pass bytes between the
+    /// two until `shutdown` is signalled, then unbind `server`.
+    ///
+    /// Upstream connections are registered on the same poller as the accepted ones. Bytes in
+    /// transit live in segments of `ring`, at most [`Proxy::WINDOW`] per direction. When one end
+    /// finishes sending, the other end's sending half is shut down once everything before has
+    /// been written. The pair is closed when both ends finished, or right away when either fails,
+    /// finds the ring full or, for upstream, cannot be connected to.
+    fn serve<const SERVER: usize>(
+        server: <Self as Listener<C>>::Listener,
+        upstream: <Self as Connect<U>>::Addr,
+        event_buffer_capacity: usize,
+        ring: &mut RingBuffer,
+        shutdown: &Shutdown,
+    ) -> std::io::Result<()> {
+        let mut balancer = Balancer::new(Policy::RoundRobin, [upstream]);
+        Self::balance::<SERVER>(server, &mut balancer, event_buffer_capacity, ring, shutdown)
+    }
+
+    /// Like [`Proxy::serve`], but connect each accepted connection to one of the backends of
+    /// `balancer`, as its policy picks among the healthy ones; when connecting fails right away
+    /// the next pick is tried.
+    ///
+    /// With health checks on, every backend is connected to once per interval, on the same
+    /// poller and with the tokens right below `SHUTDOWN`; a backend that refuses or does not
+    /// accept in time is out of rotation until a later check succeeds.
+    fn balance<const SERVER: usize>(
+        mut server: <Self as Listener<C>>::Listener,
+        balancer: &mut Balancer<<Self as Connect<U>>::Addr>,
+        event_buffer_capacity: usize,
+        ring: &mut RingBuffer,
+        shutdown: &Shutdown,
+    ) -> std::io::Result<()> {
+        let probe_tokens = SHUTDOWN - balancer.len();
+        let header = match balancer.policy() {
+            Policy::ConsistentHash(HashKey::Header(name)) => Some(name.clone()),
+            _ => None,
+        };
+        let mut poller = Self::new_poller()?;
+        let mut events = Self::new_events_buffer(event_buffer_capacity);
+        <Self as Registry<<Self as ListenerRegistry<C>>::Listener>>::register(
+            &poller,
+            &mut server,
+            SERVER,
+            Self::readable_interest(),
+        )?;
+        let _watch = shutdown.watch(&poller, SHUTDOWN)?;
+        let interest = Self::add_readable_to_interest(Self::writeable_interest());
+        // the accepted connection of the pair under `key` takes the token `SERVER + 1 + 2 * key`,
+        // its upstream the next one
+        let mut pairs: Slab<Pair<C, U>> = Slab::new();
+        let mut probes: Vec<Option<(U, Instant)>> = (0..balancer.len()).map(|_| None).collect();
+        let mut next_check = Instant::now();
+        while !shutdown.is_signalled() {
+            let mut timeout = None;
+            if let Some((interval, limit)) = balancer.checks() {
+                let now = Instant::now();
+                for (backend, probe) in probes.iter_mut().enumerate() {
+                    if probe
+                        .as_ref()
+                        .is_some_and(|(_, started)| now - *started >= limit)
+                    {
+                        if let Some((mut stream, _)) = probe.take() {
+                            balancer.set_healthy(backend, false);
+                            <Self as Registry<U>>::deregister(&poller, &mut stream)?;
+                        }
+                    }
+                    if now < next_check || probe.is_some() {
+                        continue;
+                    }
+                    match Self::connect(balancer.addr(backend).clone()) {
+                        Ok(mut stream) => {
+                            let token = probe_tokens + backend;
+                            let interest = Self::writeable_interest();
+                            <Self as Registry<U>>::register(&poller, &mut stream, token, interest)?;
+                            *probe = Some((stream, now));
+                        }
+                        Err(_) => balancer.set_healthy(backend, false),
+                    }
+                }
+                if now >= next_check {
+                    next_check = now + interval;
+                }
+                let deadline = probes
+                    .iter()
+                    .flatten()
+                    .map(|(_, started)| *started + limit)
+                    .fold(next_check, std::cmp::min);
+                timeout = Some(deadline.saturating_duration_since(now));
+            }
+            if let Err(err) = Self::poll(&mut poller, &mut events, timeout) {
+                if Interrupted == err.kind() {
+                    continue;
+                }
+                return Err(err);
+            }
+            for event in Self::events_iter(&events) {
+                let token = Self::event_token(event);
+                if SHUTDOWN == token {
+                    continue;
+                }
+                if SERVER == token {
+                    loop {
+                        let (mut downstream, peer) = match Self::accept(&server) {
+                            Ok(accepted) => accepted,
+                            Err(e) if WouldBlock == e.kind() => break,
+                            Err(e) if Interrupted == e.kind() => continue,
+                            Err(e) if ConnectionAborted == e.kind() => continue,
+                            Err(e) => return Err(e),
+                        };
+                        let token = SERVER + 1 + 2 * pairs.vacant_key();
+                        <Self as Registry<C>>::register(&poller, &mut downstream, token, interest)?;
+                        let mut pair = Pair::new(downstream, peer.client_key());
+                        let key = pair.client.clone();
+                        if header.is_none()
+                            && !open::<C, U, Self>(&mut pair, token, &key, balancer, &poller)?
+                        {
+                            // no backend could be connected to
+                            close::<C, U, Self, _>(pair, &poller, ring, balancer)?;
+                            continue;
+                        }
+                        pairs.insert(pair);
+                    }
+                    continue;
+                }
+                if token >= probe_tokens {
+                    let backend = token - probe_tokens;
+                    if !Self::event_is_writeable(event) {
+                        continue;
+                    }
+                    let Some((mut stream, _)) = probes[backend].take() else {
+                        continue;
+                    };
+                    // a refused connection reports why on the first read
+                    let read = <Self as Connector<U>>::read_from_connection(&mut stream, &mut [0]);
+                    let healthy = match read {
+                        Ok(_) => true,
+                        Err(ref err) => WouldBlock == err.kind(),
+                    };
+                    balancer.set_healthy(backend, healthy);
+                    <Self as Registry<U>>::deregister(&poller, &mut stream)?;
+                    continue;
+                }
+                let Some(key) = token.checked_sub(SERVER + 1).map(|offset| offset / 2) else {
+                    continue;
+                };
+                let token = SERVER + 1 + 2 * key;
+                let Some(pair) = pairs.get_mut(key) else {
+                    continue;
+                };
+                let mut done = false;
+                if let (None, Some(header)) = (&pair.upstream, &header) {
+                    done = match header_key::<C, U, Self>(pair, token, ring, Self::WINDOW, header) {
+                        Ok(Some(key)) => !open::<C, U, Self>(pair, token, &key, balancer, &poller)?,
+                        Ok(None) => false,
+                        Err(_) => true,
+                    };
+                }
+                if !done {
+                    done = exchange::<C, U, Self>(pair, token, ring, Self::WINDOW).unwrap_or(true);
+                }
+                if done {
+                    if let Some(pair) = pairs.remove(key) {
+                        close::<C, U, Self, _>(pair, &poller, ring, balancer)?;
+                    }
+                }
+            }
+        }
+        for (_, pair) in pairs.drain() {
+            close::<C, U, Self, _>(pair, &poller, ring, balancer)?;
+        }
+        for (mut stream, _) in probes.into_iter().flatten() {
+            <Self as Registry<U>>::deregister(&poller, &mut stream)?;
+        }
+        <Self as Registry<<Self as ListenerRegistry<C>>::Listener>>::deregister(
+            &poller,
+            &mut server,
+        )?;
+        Self::unbind(server)
+    }
+}
+
+/// The socket a [`DatagramProxy`] forwards one client's datagrams from.
+struct Session<S> {
+    socket: S,
+    client: SocketAddr,
+    active: Instant,
+}
+
+pub trait DatagramProxy<S>:
+    Registry<S> + Datagram<S> + EventLoop<Poller = Poll, Interest = Interest> + Sized
+where
+    <Self as EventLoop>::Event: Debug,
+{
+    /// Clients with a socket of their own at once.
+    const MAX_SESSIONS: usize = 1024;
+
+    fn proxy<const SERVER: usize>(
+        addr: SocketAddr,
+        upstream: SocketAddr,
+        idle: Duration,
+        event_buffer_capacity: usize,
+        ring: &mut RingBuffer,
+        shutdown: &Shutdown,
+    ) -> std::io::Result<()> {
+        let socket = Self::bind(addr)?;
+        Self::serve::<SERVER>(
+            socket,
+            upstream,
+            idle,
+            event_buffer_capacity,
+            ring,
+            shutdown,
+        )
+    }
+
+    /// Forward every datagram arriving on `socket` to `upstream` and the answers back, until
+    /// `shutdown` is signalled.
+    ///
+    /// Each client gets a socket of its own on an ephemeral port, registered on the same poller,
+    /// so `upstream` can tell clients apart and answers find their way back. A client's socket is
+    /// closed after it has been `idle` for that long. Datagrams that cannot be forwarded right
+    /// away are dropped, and so are those that arrive while `ring` has no run of
+    /// [`MAX_DATAGRAM`] bytes free and those of new clients while
+    /// [`DatagramProxy::MAX_SESSIONS`] clients have a socket or no further socket can be bound.
+    fn serve<const SERVER: usize>(
+        mut socket: S,
+        upstream: SocketAddr,
+        idle: Duration,
+        event_buffer_capacity: usize,
+        ring: &mut RingBuffer,
+        shutdown: &Shutdown,
+    ) -> std::io::Result<()> {
+        let mut session_token = SERVER;
+        let mut new_session_token = || {
+            session_token = std::cmp::max(session_token, SERVER);
+            session_token = session_token.wrapping_add(1);
+            if SHUTDOWN == session_token {
+                session_token = SERVER.wrapping_add(1);
+            }
+            session_token
+        };
+        let mut poller = Self::new_poller()?;
+        let mut events = Self::new_events_buffer(event_buffer_capacity);
+        Self::register(&poller, &mut socket, SERVER, Self::readable_interest())?;
+        let _watch = shutdown.watch(&poller, SHUTDOWN)?;
+        let unspecified = match upstream {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let mut sessions: BTreeMap<usize, Session<S>> = BTreeMap::new();
+        let mut clients: BTreeMap<SocketAddr, usize> = BTreeMap::new();
+        let mut received = Span::default();
+        let mut serve = || -> std::io::Result<()> {
+            while !shutdown.is_signalled() {
+                let now = Instant::now();
+                let expired: Vec<usize> = sessions
+                    .iter()
+                    .filter(|(_, session)| now - session.active >= idle)
+                    .map(|(&token, _)| token)
+                    .collect();
+                for token in expired {
+                    if let Some(mut session) = sessions.remove(&token) {
+                        clients.remove(&session.client);
+                        Self::deregister(&poller, &mut session.socket)?;
+                    }
+                }
+                let timeout = sessions
+                    .values()
+                    .map(|session| idle - (now - session.active))
+                    .min();
+                if let Err(err) = Self::poll(&mut poller, &mut events, timeout) {
+                    if Interrupted == err.kind() {
+                        continue;
+                    }
+                    return Err(err);
+                }
+                for event in Self::events_iter(&events) {
+                    let token = Self::event_token(event);
+                    if SHUTDOWN == token {
+                        continue;
+                    }
+                    loop {
+                        let reserved = ring.reserve(&mut received, SERVER, MAX_DATAGRAM);
+                        let mut dropped = [0; 1];
+                        let spare = match reserved {
+                            Ok(()) => ring.spare_mut(&mut received, SERVER)?,
+                            Err(ref err) if OutOfMemory == err.kind() => &mut dropped[..],
+                            Err(err) => return Err(err),
+                        };
+                        let from = match sessions.get(&token) {
+                            _ if SERVER == token => &socket,
+                            Some(session) => &session.socket,
+                            None => break,
+                        };
+                        let (n, source) = match Self::recv_from(from, spare) {
+                            Ok(received) => received,
+                            Err(ref err) if WouldBlock == err.kind() => break,
+                            Err(ref err) if Interrupted == err.kind() => continue,
+                            Err(ref err) if ConnectionRefused == err.kind() => continue,
+                            Err(ref err) if ConnectionReset == err.kind() => continue,
+                            Err(err) => return Err(err),
+                        };
+                        if reserved.is_err() {
+                            continue;
+                        }
+                        ring.commit(&mut received, n);
+                        let datagram = [IoSlice::new(ring.bytes(&received))];
+                        if SERVER == token {
+                            let session = match clients.get(&source) {
+                                Some(&session) => Some(session),
+                                None if sessions.len() >= Self::MAX_SESSIONS => None,
+                                None => match Self::bind(SocketAddr::new(unspecified, 0)) {
+                                    Ok(socket) => {
+                                        let mut session = Session {
+                                            socket,
+                                            client: source,
+                                            active: Instant::now(),
+                                        };
+                                        let token = new_session_token();
+                                        let interest = Self::readable_interest();
+                                        Self::register(
+                                            &poller,
+                                            &mut session.socket,
+                                            token,
+                                            interest,
+                                        )?;
+                                        sessions.insert(token, session);
+                                        clients.insert(source, token);
+                                        Some(token)
+                                    }
+                                    // out of descriptors or ports
+                                    Err(_) => None,
+                                },
+                            };
+                            if let Some(session) =
+                                session.and_then(|token| sessions.get_mut(&token))
+                            {
+                                session.active = Instant::now();
+                                let _ =
+                                    Self::send_to_vectored(&session.socket, &datagram, upstream);
+                            }
+                        } else if upstream == source {
+                            if let Some(session) = sessions.get_mut(&token) {
+                                session.active = Instant::now();
+                                let _ = Self::send_to_vectored(&socket, &datagram, session.client);
+                            }
+                        }
+                        ring.consume(&mut received, n);
+                    }
+                }
+            }
+            Ok(())
+        };
+        let served = serve();
+        ring.release_span(&mut received);
+        for (_, mut session) in sessions {
+            Self::deregister(&poller, &mut session.socket)?;
+        }
+        Self::deregister(&poller, &mut socket)?;
+        served
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{Shutdown as HalfShutdown, SocketAddr},
+        thread,
+        time::Duration,
+    };
+
+    use mio::net::{TcpStream, UdpSocket};
+
+    use super::{DatagramProxy, Proxy};
+    use crate::{
+        datagram::{Datagram, DatagramClient, DatagramHandler, DatagramServer},
+        ring::{RingBuffer, Span},
+        tests::{new_loopback_address, TestClient},
+        Listener, MioEventLoop, ReadWriteConnectorAdapter, Shutdown,
+    };
+
+    struct ProxyServer;
+
+    impl MioEventLoop for ProxyServer {}
+    impl ReadWriteConnectorAdapter for ProxyServer {}
+    impl Proxy<TcpStream, TcpStream> for ProxyServer {}
+    impl DatagramServer<UdpSocket> for ProxyServer {}
+    impl DatagramProxy<UdpSocket> for ProxyServer {}
+
+    /// Proxies datagrams for one client at a time.
+    struct SingleProxy;
+
+    impl MioEventLoop for SingleProxy {}
+    impl ReadWriteConnectorAdapter for SingleProxy {}
+    impl DatagramProxy<UdpSocket> for SingleProxy {
+        const MAX_SESSIONS: usize = 1;
+    }
+
+    /// Answers with the datagram reversed.
+    struct Reverse;
+
+    impl DatagramHandler for Reverse {
+        fn received<W: Write>(
+            &mut self,
+            _source: SocketAddr,
+            datagram: &[u8],
+            reply: &mut W,
+        ) -> std::io::Result<()> {
+            let reversed: Vec<u8> = datagram.iter().rev().copied().collect();
+            reply.write_all(&reversed)
+        }
+    }
+
+    #[test]
+    fn proxies_streams_with_backpressure_and_half_close() {
+        // room for the windows of both connections but far less than is sent
+        let mut ring = RingBuffer::new(80);
+        let upstream = std::net::TcpListener::bind(new_loopback_address()).unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let addr = new_loopback_address();
+        let listener = <ProxyServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+        let send: Vec<u8> = (0..2_000_000).map(|i| (i % 251) as u8).collect();
+
+        thread::scope(|s| {
+            // echoes until the proxy passes on the client's end of stream, then ends its own
+            s.spawn(|| {
+                for _ in 0..2 {
+                    let (mut echo, _) = upstream.accept().unwrap();
+                    s.spawn(move || {
+                        let mut reader = echo.try_clone().unwrap();
+                        std::io::copy(&mut reader, &mut echo).unwrap();
+                        echo.shutdown(HalfShutdown::Write).unwrap();
+                    });
+                }
+            });
+            let proxy = s.spawn(|| {
+                <ProxyServer as Proxy<TcpStream, TcpStream>>::serve::<1>(
+                    listener,
+                    upstream_addr,
+                    128,
+                    &mut ring,
+                    &shutdown,
+                )
+            });
+            let clients: Vec<_> = (0..2)
+                .map(|_| {
+                    let mut client = std::net::TcpStream::connect(addr).unwrap();
+                    let mut reader = client.try_clone().unwrap();
+                    let send = &send;
+                    s.spawn(move || {
+                        client.write_all(send).unwrap();
+                        client.shutdown(HalfShutdown::Write).unwrap();
+                    });
+                    s.spawn(move || {
+                        let mut received = Vec::new();
+                        reader.read_to_end(&mut received).unwrap();
+                        received
+                    })
+                })
+                .collect();
+            let received: Vec<Vec<u8>> = clients.into_iter().map(|c| c.join().unwrap()).collect();
+            shutdown.signal().unwrap();
+            proxy.join().unwrap().unwrap();
+            assert!(received.iter().all(|received| *received == send));
+        });
+
+        assert_eq!(ring.in_use(), 0);
+    }
+
+    #[test]
+    fn closes_connections_whose_upstream_is_unreachable() {
+        let mut ring = RingBuffer::new(16);
+        let upstream = new_loopback_address();
+        let addr = new_loopback_address();
+        let listener = <ProxyServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let proxy = s.spawn(|| {
+                <ProxyServer as Proxy<TcpStream, TcpStream>>::serve::<1>(
+                    listener, upstream, 128, &mut ring, &shutdown,
+                )
+            });
+            for _ in 0..3 {
+                let mut client = std::net::TcpStream::connect(addr).unwrap();
+                client
+                    .set_read_timeout(Some(Duration::from_secs(5)))
+                    .unwrap();
+                let _ = client.write_all(b"hello?");
+                let mut received = Vec::new();
+                match client.read_to_end(&mut received) {
+                    Ok(_) => assert!(received.is_empty()),
+                    Err(err) => assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset),
+                }
+            }
+            shutdown.signal().unwrap();
+            proxy.join().unwrap().unwrap();
+        });
+
+        assert_eq!(ring.in_use(), 0);
+    }
+
+    #[test]
+    fn proxies_datagrams_per_client() {
+        let mut ring = RingBuffer::new(40);
+        let mut upstream_ring = RingBuffer::new(20);
+        let mut client_ring = RingBuffer::new(20);
+        let mut handler = Reverse;
+        let upstream = new_loopback_address();
+        let addr = new_loopback_address();
+        let upstream_socket = <ProxyServer as Datagram<UdpSocket>>::bind(upstream).unwrap();
+        let socket = <ProxyServer as Datagram<UdpSocket>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let reverse = s.spawn(|| {
+                <ProxyServer as DatagramServer<UdpSocket>>::serve::<1, _>(
+                    upstream_socket,
+                    128,
+                    &mut upstream_ring,
+                    &mut handler,
+                    &shutdown,
+                )
+            });
+            let proxy = s.spawn(|| {
+                <ProxyServer as DatagramProxy<UdpSocket>>::serve::<1>(
+                    socket,
+                    upstream,
+                    Duration::from_millis(200),
+                    128,
+                    &mut ring,
+                    &shutdown,
+                )
+            });
+            for request in [&b"ping"[..], b"hello world", &[7; 5000]] {
+                let timeout = Some(Duration::from_secs(5));
+                let mut received =
+                    TestClient::client::<2>(addr, 128, &mut client_ring, request, timeout).unwrap();
+                let reversed: Vec<u8> = request.iter().rev().copied().collect();
+                assert_eq!(client_ring.bytes(&received), reversed);
+                client_ring.release_span(&mut received);
+            }
+            shutdown.signal().unwrap();
+            reverse.join().unwrap().unwrap();
+            proxy.join().unwrap().unwrap();
+        });
+
+        assert_eq!(
+            (ring.in_use(), upstream_ring.in_use(), client_ring.in_use()),
+            (0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn drops_datagrams_of_clients_past_the_limit() {
+        let mut ring = RingBuffer::new(40);
+        let mut upstream_ring = RingBuffer::new(20);
+        let mut handler = Reverse;
+        let upstream = new_loopback_address();
+        let addr = new_loopback_address();
+        let upstream_socket = <ProxyServer as Datagram<UdpSocket>>::bind(upstream).unwrap();
+        let socket = <SingleProxy as Datagram<UdpSocket>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let reverse = s.spawn(|| {
+                <ProxyServer as DatagramServer<UdpSocket>>::serve::<1, _>(
+                    upstream_socket,
+                    128,
+                    &mut upstream_ring,
+                    &mut handler,
+                    &shutdown,
+                )
+            });
+            let proxy = s.spawn(|| {
+                <SingleProxy as DatagramProxy<UdpSocket>>::serve::<1>(
+                    socket,
+                    upstream,
+                    Duration::from_millis(200),
+                    128,
+                    &mut ring,
+                    &shutdown,
+                )
+            });
+            let exchange = |client: &std::net::UdpSocket| {
+                client.send_to(b"abc", addr).unwrap();
+                let mut reply = [0; 8];
+                client.recv(&mut reply).map(|n| reply[..n].to_vec())
+            };
+            let [first, second] = [(); 2].map(|_| {
+                let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+                client
+                    .set_read_timeout(Some(Duration::from_millis(100)))
+                    .unwrap();
+                client
+            });
+            assert_eq!(exchange(&first).unwrap(), b"cba");
+            assert!(exchange(&second).is_err());
+            // the first client's session expires and makes room
+            thread::sleep(Duration::from_millis(300));
+            assert_eq!(exchange(&second).unwrap(), b"cba");
+            shutdown.signal().unwrap();
+            reverse.join().unwrap().unwrap();
+            proxy.join().unwrap().unwrap();
+        });
+
+        assert_eq!((ring.in_use(), upstream_ring.in_use()), (0, 0));
+    }
+
+    #[test]
+    fn drops_datagrams_the_ring_has_no_room_for() {
+        // 8 of 20 segments are held elsewhere, fewer than a datagram may take are left
+        let mut ring = RingBuffer::new(20);
+        let mut held = Span::default();
+        ring.reserve(&mut held, 9, 8 * 4096).unwrap();
+        let upstream = std::net::UdpSocket::bind(new_loopback_address()).unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let addr = new_loopback_address();
+        let socket = <ProxyServer as Datagram<UdpSocket>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let proxy = s.spawn(|| {
+                <ProxyServer as DatagramProxy<UdpSocket>>::serve::<1>(
+                    socket,
+                    upstream.local_addr().unwrap(),
+                    Duration::from_secs(1),
+                    128,
+                    &mut ring,
+                    &shutdown,
+                )
+            });
+            let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            client.send_to(b"abc", addr).unwrap();
+            assert!(upstream.recv(&mut [0; 8]).is_err());
+            shutdown.signal().unwrap();
+            proxy.join().unwrap().unwrap();
+        });
+
+        ring.release_span(&mut held);
+        assert_eq!(ring.in_use(), 0);
+    }
+}
@@ -0,0 +1,1122 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Debug,
+    io::{ErrorKind::*, IoSlice, Read, Write},
+    time::{Duration, Instant},
+};
+
+use mio::{event::Source, Interest, Poll};
+
+use crate::{
+    mmap::Mapping,
+    ring::{Chain, RingBuffer, Span},
+    slab::Slab,
+    Connect, Connector, EventLoop, Listener, ListenerRegistry, Registry, Shutdown, IO_SLICES,
+    SHUTDOWN,
+};
+
+/// Longest frame accepted, its kind included.
+pub const MAX_FRAME: usize = 16 << 20;
+
+const PUBLISH: u8 = 1;
+const CONSUME: u8 = 2;
+const SEEK: u8 = 3;
+const FOLLOW: u8 = 4;
+const ACK: u8 = 5;
+const MESSAGES: u8 = 6;
+const MESSAGE: u8 = 7;
+const ERROR: u8 = 8;
+
+/// A unit of the queue protocol: a big endian `u32` length, a kind byte and a body.
+///
+/// Producers send `Publish` and get an `Ack` with the offset of the message. Consumers send
+/// `Consume` under a name and get `Messages` from where that name left off, which moves it on;
+/// `Seek` moves it anywhere. A node sends its parent `Follow` with the offset it needs next and
+/// is sent a `Message` for every message from there on, as they are published.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Frame<'a> {
+    Publish(&'a [u8]),
+    Consume { consumer: &'a [u8], max: u32 },
+    Seek { consumer: &'a [u8], offset: u64 },
+    Follow(u64),
+    Ack(u64),
+    Messages { first: u64, messages: Vec<&'a [u8]> },
+    Message { offset: u64, message: &'a [u8] },
+    Error(&'a [u8]),
+}
+
+fn invalid(what: &str) -> std::io::Error {
+    std::io::Error::new(InvalidData, format!("malformed queue frame: {what}"))
+}
+
+fn u32_at(body: &[u8], at: usize) -> std::io::Result<u32> {
+    let bytes = body.get(at..at + 4).ok_or_else(|| invalid("truncated"))?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn u64_at(body: &[u8], at: usize) -> std::io::Result<u64> {
+    let bytes = body.get(at..at + 8).ok_or_else(|| invalid("truncated"))?;
+    Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+impl<'a> Frame<'a> {
+    /// The frame at the start of `buffer` with its length, or `None` until all of it arrived.
+    pub fn parse(buffer: &'a [u8]) -> std::io::Result<Option<(Self, usize)>> {
+        let Ok(len) = u32_at(buffer, 0) else {
+            return Ok(None);
+        };
+        let len = len as usize;
+        if 0 == len || len > MAX_FRAME {
+            return Err(invalid("length"));
+        }
+        let Some(frame) = buffer.get(4..4 + len) else {
+            return Ok(None);
+        };
+        let body = &frame[1..];
+        let frame = match frame[0] {
+            PUBLISH => Frame::Publish(body),
+            CONSUME => Frame::Consume {
+                max: u32_at(body, 0)?,
+                consumer: &body[4..],
+            },
+            SEEK => Frame::Seek {
+                offset: u64_at(body, 0)?,
+                consumer: &body[8..],
+            },
+            FOLLOW => Frame::Follow(u64_at(body, 0)?),
+            ACK => Frame::Ack(u64_at(body, 0)?),
+            MESSAGES => {
+                let first = u64_at(body, 0)?;
+                let mut messages = Vec::new();
+                let mut at = 8;
+                while at < body.len() {
+                    let len = u32_at(body, at)? as usize;
+                    let message = body.get(at + 4..at + 4 + len);
+                    messages.push(message.ok_or_else(|| invalid("truncated"))?);
+                    at += 4 + len;
+                }
+                Frame::Messages { first, messages }
+            }
+            MESSAGE => Frame::Message {
+                offset: u64_at(body, 0)?,
+                message: &body[8..],
+            },
+            ERROR => Frame::Error(body),
+            _ => return Err(invalid("kind")),
+        };
+        Ok(Some((frame, 4 + len)))
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        let (kind, head, body): (u8, &[u8], &[u8]) = match self {
+            Frame::Publish(message) => (PUBLISH, &[], message),
+            Frame::Consume { consumer, max } => (CONSUME, &max.to_be_bytes(), consumer),
+            Frame::Seek { consumer, offset } => (SEEK, &offset.to_be_bytes(), consumer),
+            Frame::Follow(offset) => (FOLLOW, &offset.to_be_bytes(), &[]),
+            Frame::Ack(offset) => (ACK, &offset.to_be_bytes(), &[]),
+            Frame::Messages { first, messages } => {
+                let len: usize = messages.iter().map(|message| 4 + message.len()).sum();
+                out.write_all(&(1 + 8 + len as u32).to_be_bytes())?;
+                out.write_all(&[MESSAGES])?;
+                out.write_all(&first.to_be_bytes())?;
+                for message in messages {
+                    out.write_all(&(message.len() as u32).to_be_bytes())?;
+                    out.write_all(message)?;
+                }
+                return Ok(());
+            }
+            Frame::Message { offset, message } => (MESSAGE, &offset.to_be_bytes(), message),
+            Frame::Error(text) => (ERROR, &[], text),
+        };
+        out.write_all(&((1 + head.len() + body.len()) as u32).to_be_bytes())?;
+        out.write_all(&[kind])?;
+        out.write_all(head)?;
+        out.write_all(body)
+    }
+}
+
+/// Bytes in front of every message in a [`Log`]: its big endian `u32` length and a CRC-32 of
+/// the length and the message.
+const RECORD_HEAD: usize = 8;
+
+/// Messages in publish order, stored back to back in one mapping and numbered from 0.
+#[derive(Debug)]
+pub struct Log {
+    mapping: Mapping,
+    /// Where each message ends in the mapping.
+    ends: Vec<usize>,
+}
+
+impl Log {
+    /// The log kept in `mapping`, with the messages a mapped file already holds. The log ends
+    /// before the first record cut short or failing its checksum; the checksum covers the
+    /// length, so the zeros of unused space never read as an empty message.
+    pub fn new(mapping: Mapping) -> Self {
+        let mut ends = Vec::new();
+        let mut start = 0;
+        while let Some(end) = record_end(&mapping[..mapping.size()], start) {
+            ends.push(end);
+            start = end;
+        }
+        Self { mapping, ends }
+    }
+
+    /// Offset the next message appended gets.
+    pub fn len(&self) -> u64 {
+        self.ends.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+
+    /// Bytes of the mapping taken by messages.
+    pub fn bytes(&self) -> usize {
+        self.ends.last().copied().unwrap_or(0)
+    }
+
+    /// Append `message` and return its offset; fails with `OutOfMemory` once the mapping is full.
+    pub fn append(&mut self, message: &[u8]) -> std::io::Result<u64> {
+        let start = self.bytes();
+        let end = start + RECORD_HEAD + message.len();
+        if end > self.mapping.size() {
+            return Err(OutOfMemory.into());
+        }
+        let len = (message.len() as u32).to_be_bytes();
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(&len);
+        crc.update(message);
+        self.mapping[start..start + 4].copy_from_slice(&len);
+        self.mapping[start + 4..start + RECORD_HEAD].copy_from_slice(&crc.finalize().to_be_bytes());
+        self.mapping[start + RECORD_HEAD..end].copy_from_slice(message);
+        self.ends.push(end);
+        Ok(self.len() - 1)
+    }
+
+    pub fn get(&self, offset: u64) -> Option<&[u8]> {
+        let offset = usize::try_from(offset).ok()?;
+        let end = *self.ends.get(offset)?;
+        let start = offset.checked_sub(1).map_or(0, |before| self.ends[before]);
+        Some(&self.mapping[start + RECORD_HEAD..end])
+    }
+}
+
+/// Where the record starting at `start` of `log` ends, if it is whole and intact.
+fn record_end(log: &[u8], start: usize) -> Option<usize> {
+    let head = log.get(start..start + RECORD_HEAD)?;
+    let len = u32::from_be_bytes(head[..4].try_into().unwrap());
+    let end = start.checked_add(RECORD_HEAD + len as usize)?;
+    let message = log.get(start + RECORD_HEAD..end)?;
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&head[..4]);
+    crc.update(message);
+    (crc.finalize() == u32::from_be_bytes(head[4..].try_into().unwrap())).then_some(end)
+}
+
+/// One node of a queue: its log, where its consumers are in it, and the parent it follows.
+///
+/// A node without a parent is the root; the log of every other node is a copy of its parent's,
+/// and publishes sent to it are passed up to the root.
+#[derive(Debug)]
+pub struct Node<A> {
+    log: Log,
+    offsets: BTreeMap<Vec<u8>, u64>,
+    parent: Option<A>,
+    backoff: (Duration, Duration),
+    linked: bool,
+    links: usize,
+}
+
+impl<A> Node<A> {
+    pub fn new(log: Log) -> Self {
+        Self {
+            log,
+            offsets: BTreeMap::new(),
+            parent: None,
+            backoff: (Duration::from_millis(50), Duration::from_secs(5)),
+            linked: false,
+            links: 0,
+        }
+    }
+
+    pub fn parent(mut self, addr: A) -> Self {
+        self.parent = Some(addr);
+        self
+    }
+
+    /// Wait `initial` before connecting to the parent again after a failure, doubling up to
+    /// `max` while failures go on.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff = (initial, max);
+        self
+    }
+
+    pub fn log(&self) -> &Log {
+        &self.log
+    }
+
+    /// Offset the next message consumed by `consumer` is read from.
+    pub fn offset(&self, consumer: &[u8]) -> u64 {
+        self.offsets.get(consumer).copied().unwrap_or(0)
+    }
+
+    /// Whether the link to the parent is up.
+    pub fn is_linked(&self) -> bool {
+        self.linked
+    }
+
+    /// How many times the link to the parent came up.
+    pub fn links(&self) -> usize {
+        self.links
+    }
+}
+
+/// A connection to a node: a producer, a consumer, a child following the log or, for the link,
+/// the parent.
+struct Peer<C> {
+    stream: C,
+    received: Span,
+    send: Chain,
+    /// Offset of the next message to send a following child.
+    follow: Option<u64>,
+    eof: bool,
+    /// Reading stopped at the window while more bytes may be waiting, which no readable event
+    /// announces again.
+    waiting: bool,
+}
+
+impl<C> Peer<C> {
+    fn new(stream: C) -> Self {
+        Self {
+            stream,
+            received: Span::default(),
+            send: Chain::default(),
+            follow: None,
+            eof: false,
+            waiting: false,
+        }
+    }
+}
+
+/// The connection to the parent with the tokens of the peers whose publishes went up it, in
+/// the order their answers come back.
+struct Link<C> {
+    peer: Peer<C>,
+    forwarded: VecDeque<usize>,
+}
+
+/// Read until `peer` would block or ends, or holds `window` bytes and the whole frame at its
+/// front; a link still connecting reads like one that blocks.
+fn read_peer<C, X>(
+    ring: &mut RingBuffer,
+    peer: &mut Peer<C>,
+    owner: usize,
+    window: usize,
+) -> std::io::Result<()>
+where
+    C: Read,
+    X: Connector<C>,
+{
+    peer.waiting = false;
+    while !peer.eof {
+        let front = match ring.bytes(&peer.received).get(..4) {
+            Some(len) => 4 + u32::from_be_bytes(len.try_into().unwrap()) as usize,
+            None => 0,
+        };
+        if peer.received.len() >= window.max(front.min(4 + MAX_FRAME)) {
+            peer.waiting = true;
+            break;
+        }
+        let spare = ring.spare_mut(&mut peer.received, owner)?;
+        match X::read_from_connection(&mut peer.stream, spare) {
+            Ok(0) => {
+                peer.eof = true;
+            }
+            Ok(n) => {
+                ring.commit(&mut peer.received, n);
+            }
+            Err(ref err) if WouldBlock == err.kind() || NotConnected == err.kind() => {
+                break;
+            }
+            Err(ref err) if Interrupted == err.kind() => {
+                continue;
+            }
+            Err(err) => {
+                return Err(err);
+            }
+        }
+    }
+    if peer.received.is_empty() {
+        ring.release_span(&mut peer.received);
+    }
+    Ok(())
+}
+
+/// Write what is queued for `peer` until it would block.
+fn flush_peer<C, X>(ring: &mut RingBuffer, peer: &mut Peer<C>) -> std::io::Result<()>
+where
+    C: Write,
+    X: Connector<C>,
+{
+    while !peer.send.is_empty() {
+        let mut send = [IoSlice::new(&[]); IO_SLICES];
+        let count = ring.io_slices(&peer.send, &mut send);
+        match X::write_vectored_on_connection(&mut peer.stream, &send[..count]) {
+            Ok(0) => {
+                return Err(WriteZero.into());
+            }
+            Ok(n) => {
+                ring.consume_chain(&mut peer.send, n);
+            }
+            Err(ref err) if WouldBlock == err.kind() || NotConnected == err.kind() => {
+                break;
+            }
+            Err(ref err) if Interrupted == err.kind() => {
+                continue;
+            }
+            Err(err) => {
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn queue_frame<C>(
+    ring: &mut RingBuffer,
+    peer: &mut Peer<C>,
+    owner: usize,
+    frame: &Frame<'_>,
+) -> std::io::Result<()> {
+    frame.write_to(&mut ring.chain_writer(&mut peer.send, owner))
+}
+
+fn release<C>(ring: &mut RingBuffer, peer: &mut Peer<C>) {
+    ring.release_span(&mut peer.received);
+    ring.release_chain(&mut peer.send);
+}
+
+pub trait Queue<C>:
+    ListenerRegistry<C>
+    + Listener<C, Listener = <Self as ListenerRegistry<C>>::Listener>
+    + Registry<C>
+    + Connect<C>
+    + Connector<C>
+    + EventLoop<Poller = Poll, Interest = Interest>
+    + Sized
+where
+    C: Read + Write,
+    <Self as Listener<C>>::Listener: Source,
+    <Self as Connect<C>>::Addr: Clone,
+    <Self as EventLoop>::Event: Debug,
+{
+    /// Bytes of messages queued for a child or answered to one `Consume` at most, and bytes
+    /// read from a connection before the frames in them are handled; a single message or frame
+    /// larger than this still goes out or is read on its own.
+    const WINDOW: usize = 64 * 1024;
+
+    fn queue<const SERVER: usize>(
+        addr: <Self as Listener<C>>::Addr,
+        node: &mut Node<<Self as Connect<C>>::Addr>,
+        event_buffer_capacity: usize,
+        ring: &mut RingBuffer,
+        shutdown: &Shutdown,
+    ) -> std::io::Result<()> {
+        let server = Self::bind(addr)?;
+        Self::serve::<SERVER>(server, node, event_buffer_capacity, ring, shutdown)
+    }
+
+    /// Run `node` on the connections accepted on `server` until `shutdown` is signalled, then
+    /// unbind `server`.
+    ///
+    /// A node with a parent connects to it under the token `SERVER + 1`, follows its log from
+    /// where its own ends and passes publishes up to it. When the link fails or the parent goes
+    /// away, publishes waiting for their answer fail and the node connects again after its
+    /// backoff. Accepted connections take the tokens from `SERVER + 2` on, and the bytes in
+    /// transit live in segments of `ring`.
+    fn serve<const SERVER: usize>(
+        mut server: <Self as Listener<C>>::Listener,
+        node: &mut Node<<Self as Connect<C>>::Addr>,
+        event_buffer_capacity: usize,
+        ring: &mut RingBuffer,
+        shutdown: &Shutdown,
+    ) -> std::io::Result<()> {
+        const LINK_LOST: &[u8] = b"link to the parent lost";
+        let link_token = SERVER + 1;
+        let mut poller = Self::new_poller()?;
+        let mut events = Self::new_events_buffer(event_buffer_capacity);
+        <Self as Registry<<Self as ListenerRegistry<C>>::Listener>>::register(
+            &poller,
+            &mut server,
+            SERVER,
+            Self::readable_interest(),
+        )?;
+        let _watch = shutdown.watch(&poller, SHUTDOWN)?;
+        let interest = Self::add_readable_to_interest(Self::writeable_interest());
+        let mut peers: Slab<Peer<C>> = Slab::new();
+        let mut link: Option<Link<C>> = None;
+        let (initial, max) = node.backoff;
+        let mut delay = initial;
+        let mut retry = Instant::now();
+        let mut received = Vec::new();
+        // connections whose reading stopped at the window
+        let mut resume = Vec::new();
+        while !shutdown.is_signalled() {
+            let mut timeout = None;
+            if let (Some(parent), None) = (&node.parent, &link) {
+                let now = Instant::now();
+                if now >= retry {
+                    match Self::connect(parent.clone()) {
+                        Ok(mut stream) => {
+                            <Self as Registry<C>>::register(
+                                &poller,
+                                &mut stream,
+                                link_token,
+                                interest,
+                            )?;
+                            let mut peer = Peer::new(stream);
+                            let follow = Frame::Follow(node.log.len());
+                            queue_frame(ring, &mut peer, link_token, &follow)?;
+                            link = Some(Link {
+                                peer,
+                                forwarded: VecDeque::new(),
+                            });
+                        }
+                        Err(_) => {
+                            retry = now + delay;
+                            delay = (2 * delay).min(max);
+                        }
+                    }
+                }
+                if link.is_none() {
+                    timeout = Some(retry.saturating_duration_since(now));
+                }
+            }
+            if !resume.is_empty() {
+                timeout = Some(Duration::ZERO);
+            }
+            if let Err(err) = Self::poll(&mut poller, &mut events, timeout) {
+                if Interrupted == err.kind() {
+                    continue;
+                }
+                return Err(err);
+            }
+            let mut link_failed = false;
+            let mut tokens: Vec<usize> =
+                Self::events_iter(&events).map(Self::event_token).collect();
+            tokens.append(&mut resume);
+            tokens.sort_unstable();
+            tokens.dedup();
+            for token in tokens {
+                if SHUTDOWN == token {
+                    continue;
+                }
+                if SERVER == token {
+                    loop {
+                        let mut stream = match Self::accept(&server) {
+                            Ok((stream, _)) => stream,
+                            Err(e) if WouldBlock == e.kind() => break,
+                            Err(e) if Interrupted == e.kind() => continue,
+                            Err(e) if ConnectionAborted == e.kind() => continue,
+                            Err(e) => return Err(e),
+                        };
+                        let token = SERVER + 2 + peers.vacant_key();
+                        <Self as Registry<C>>::register(&poller, &mut stream, token, interest)?;
+                        peers.insert(Peer::new(stream));
+                    }
+                    continue;
+                }
+                if link_token == token {
+                    let Some(Link { peer, forwarded }) = link.as_mut() else {
+                        continue;
+                    };
+                    let read = read_peer::<C, Self>(ring, peer, link_token, Self::WINDOW);
+                    if read.is_err() || peer.eof {
+                        link_failed = true;
+                    }
+                    received.clear();
+                    received.extend_from_slice(ring.bytes(&peer.received));
+                    let mut at = 0;
+                    loop {
+                        let frame = match Frame::parse(&received[at..]) {
+                            Ok(Some((frame, n))) => {
+                                at += n;
+                                frame
+                            }
+                            Ok(None) => break,
+                            Err(_) => {
+                                link_failed = true;
+                                break;
+                            }
+                        };
+                        match frame {
+                            Frame::Message { offset, message } => {
+                                if offset > node.log.len() {
+                                    link_failed = true;
+                                    break;
+                                }
+                                if offset == node.log.len() && node.log.append(message).is_err() {
+                                    link_failed = true;
+                                    break;
+                                }
+                            }
+                            answer @ (Frame::Ack(_) | Frame::Error(_)) => {
+                                let Some(token) = forwarded.pop_front() else {
+                                    continue;
+                                };
+                                if let Some(peer) = peers.get_mut(token - SERVER - 2) {
+                                    // a peer that cannot take the answer fails on its next flush
+                                    let _ = queue_frame(ring, peer, token, &answer);
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                    ring.consume(&mut peer.received, at);
+                    continue;
+                }
+                let Some(key) = token.checked_sub(SERVER + 2) else {
+                    continue;
+                };
+                let Some(peer) = peers.get_mut(key) else {
+                    continue;
+                };
+                if read_peer::<C, Self>(ring, peer, token, Self::WINDOW).is_err() {
+                    peer.eof = true;
+                    ring.release_chain(&mut peer.send);
+                }
+                received.clear();
+                received.extend_from_slice(ring.bytes(&peer.received));
+                let mut at = 0;
+                loop {
+                    let frame = match Frame::parse(&received[at..]) {
+                        Ok(Some((frame, n))) => {
+                            at += n;
+                            frame
+                        }
+                        Ok(None) => break,
+                        Err(_) => {
+                            peer.eof = true;
+                            break;
+                        }
+                    };
+                    let answer = match frame {
+                        Frame::Publish(message) => match (&node.parent, link.as_mut()) {
+                            (None, _) => match node.log.append(message) {
+                                Ok(offset) => Some(Frame::Ack(offset)),
+                                Err(_) => Some(Frame::Error(b"log full")),
+                            },
+                            (Some(_), Some(up)) if node.linked => {
+                                let forward = Frame::Publish(message);
+                                match queue_frame(ring, &mut up.peer, link_token, &forward) {
+                                    Ok(()) => {
+                                        up.forwarded.push_back(token);
+                                        None
+                                    }
+                                    Err(_) => Some(Frame::Error(LINK_LOST)),
+                                }
+                            }
+                            (Some(_), _) => Some(Frame::Error(LINK_LOST)),
+                        },
+                        Frame::Consume { consumer, max } => {
+                            let first = node.offset(consumer);
+                            let mut messages = Vec::new();
+                            let mut bytes = 0;
+                            while let Some(message) = node.log.get(first + messages.len() as u64) {
+                                if messages.len() >= max as usize
+                                    || (bytes > 0 && bytes + message.len() > Self::WINDOW)
+                                {
+                                    break;
+                                }
+                                bytes += message.len();
+                                messages.push(message);
+                            }
+                            let next = first + messages.len() as u64;
+                            node.offsets.insert(consumer.to_vec(), next);
+                            Some(Frame::Messages { first, messages })
+                        }
+                        Frame::Seek { consumer, offset } => {
+                            node.offsets.insert(consumer.to_vec(), offset);
+                            Some(Frame::Ack(offset))
+                        }
+                        Frame::Follow(offset) => {
+                            peer.follow = Some(offset);
+                            None
+                        }
+                        _ => Some(Frame::Error(b"unexpected frame")),
+                    };
+                    if let Some(answer) = answer {
+                        if queue_frame(ring, peer, token, &answer).is_err() {
+                            peer.eof = true;
+                            break;
+                        }
+                    }
+                }
+                ring.consume(&mut peer.received, at);
+            }
+            // everything is written here, after the events, since a publish queues bytes for
+            // other connections than the one it arrived on
+            if let Some(up) = link.as_mut() {
+                if up.peer.waiting {
+                    resume.push(link_token);
+                }
+                match flush_peer::<C, Self>(ring, &mut up.peer) {
+                    Ok(()) if up.peer.send.is_empty() && !node.linked => {
+                        node.linked = true;
+                        node.links += 1;
+                        delay = initial;
+                    }
+                    Ok(()) => {}
+                    Err(_) => link_failed = true,
+                }
+            }
+            if link_failed {
+                if let Some(mut up) = link.take() {
+                    <Self as Registry<C>>::deregister(&poller, &mut up.peer.stream)?;
+                    release(ring, &mut up.peer);
+                    for token in up.forwarded {
+                        if let Some(peer) = peers.get_mut(token - SERVER - 2) {
+                            let _ = queue_frame(ring, peer, token, &Frame::Error(LINK_LOST));
+                        }
+                    }
+                    node.linked = false;
+                    retry = Instant::now() + delay;
+                    delay = (2 * delay).min(max);
+                }
+            }
+            let mut closing = Vec::new();
+            for (key, peer) in peers.iter_mut() {
+                let token = SERVER + 2 + key;
+                while let Some(offset) = peer.follow {
+                    let Some(message) = node.log.get(offset) else {
+                        break;
+                    };
+                    if peer.send.len() >= Self::WINDOW {
+                        break;
+                    }
+                    if queue_frame(ring, peer, token, &Frame::Message { offset, message }).is_err()
+                    {
+                        peer.eof = true;
+                        break;
+                    }
+                    peer.follow = Some(offset + 1);
+                }
+                let flushed = flush_peer::<C, Self>(ring, peer);
+                if flushed.is_err() || (peer.eof && peer.send.is_empty()) {
+                    closing.push(key);
+                } else if peer.waiting {
+                    resume.push(token);
+                }
+            }
+            for key in closing {
+                if let Some(mut peer) = peers.remove(key) {
+                    <Self as Registry<C>>::deregister(&poller, &mut peer.stream)?;
+                    release(ring, &mut peer);
+                }
+                // answers still on their way belong to no connection that may take the token next
+                for token in link.iter_mut().flat_map(|up| up.forwarded.iter_mut()) {
+                    if SERVER + 2 + key == *token {
+                        *token = SHUTDOWN;
+                    }
+                }
+            }
+        }
+        for (_, mut peer) in peers.drain() {
+            <Self as Registry<C>>::deregister(&poller, &mut peer.stream)?;
+            release(ring, &mut peer);
+        }
+        if let Some(mut up) = link.take() {
+            <Self as Registry<C>>::deregister(&poller, &mut up.peer.stream)?;
+            release(ring, &mut up.peer);
+        }
+        node.linked = false;
+        <Self as Registry<<Self as ListenerRegistry<C>>::Listener>>::deregister(
+            &poller,
+            &mut server,
+        )?;
+        Self::unbind(server)
+    }
+}
+
+/// A blocking connection to a node, for producers and consumers that run no event loop.
+#[derive(Debug)]
+pub struct Session<S> {
+    stream: S,
+    buffer: Vec<u8>,
+}
+
+impl<S: Read + Write> Session<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Publish `message` and return its offset in the root's log.
+    pub fn publish(&mut self, message: &[u8]) -> std::io::Result<u64> {
+        self.request(&Frame::Publish(message))?;
+        self.answer(|frame| match frame {
+            Frame::Ack(offset) => Some(offset),
+            _ => None,
+        })
+    }
+
+    /// Up to `max` messages from where `consumer` left off, with the offset of the first.
+    pub fn consume(&mut self, consumer: &[u8], max: u32) -> std::io::Result<(u64, Vec<Vec<u8>>)> {
+        self.request(&Frame::Consume { consumer, max })?;
+        self.answer(|frame| match frame {
+            Frame::Messages { first, messages } => {
+                Some((first, messages.into_iter().map(<[u8]>::to_vec).collect()))
+            }
+            _ => None,
+        })
+    }
+
+    /// Make `consumer` go on from `offset`.
+    pub fn seek(&mut self, consumer: &[u8], offset: u64) -> std::io::Result<()> {
+        self.request(&Frame::Seek { consumer, offset })?;
+        self.answer(|frame| match frame {
+            Frame::Ack(_) => Some(()),
+            _ => None,
+        })
+    }
+
+    /// Send `frame` in one write, so that it does not wait for the answer to the last one.
+    fn request(&mut self, frame: &Frame<'_>) -> std::io::Result<()> {
+        let mut bytes = Vec::new();
+        frame.write_to(&mut bytes)?;
+        self.stream.write_all(&bytes)
+    }
+
+    /// Read the answer to the last request and take what `expected` makes of it; an `Error`
+    /// frame fails with its text.
+    fn answer<T>(&mut self, expected: impl FnOnce(Frame<'_>) -> Option<T>) -> std::io::Result<T> {
+        let len = loop {
+            if let Some((_, len)) = Frame::parse(&self.buffer)? {
+                break len;
+            }
+            let mut chunk = [0; 4096];
+            match self.stream.read(&mut chunk)? {
+                0 => return Err(UnexpectedEof.into()),
+                n => self.buffer.extend_from_slice(&chunk[..n]),
+            }
+        };
+        let answer = match Frame::parse(&self.buffer)? {
+            Some((Frame::Error(text), _)) => Err(std::io::Error::other(
+                String::from_utf8_lossy(text).into_owned(),
+            )),
+            Some((frame, _)) => expected(frame).ok_or_else(|| invalid("unexpected answer")),
+            None => unreachable!(),
+        };
+        self.buffer.drain(..len);
+        answer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::SocketAddr,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use mio::net::TcpStream;
+
+    use super::{Frame, Log, Node, Queue, Session};
+    use crate::{
+        mmap::{page_size, MapOptions},
+        ring::RingBuffer,
+        tests::new_loopback_address,
+        Listener, MioEventLoop, ReadWriteConnectorAdapter, Shutdown,
+    };
+
+    struct QueueNode;
+    impl MioEventLoop for QueueNode {}
+    impl ReadWriteConnectorAdapter for QueueNode {}
+    impl Queue<TcpStream> for QueueNode {}
+
+    fn node(parent: Option<SocketAddr>) -> Node<SocketAddr> {
+        let log = Log::new(MapOptions::new().anonymous(1 << 20).unwrap());
+        let node = Node::new(log).backoff(Duration::from_millis(10), Duration::from_millis(40));
+        match parent {
+            Some(parent) => node.parent(parent),
+            None => node,
+        }
+    }
+
+    /// Serve `node` on `addr` until `shutdown`, handing back the node and the segments its ring
+    /// still held.
+    fn run(
+        addr: SocketAddr,
+        mut node: Node<SocketAddr>,
+        shutdown: &Shutdown,
+    ) -> std::io::Result<(Node<SocketAddr>, usize)> {
+        let listener = <QueueNode as Listener<TcpStream>>::bind(addr)?;
+        let mut ring = RingBuffer::new(64);
+        QueueNode::serve::<1>(listener, &mut node, 128, &mut ring, shutdown)?;
+        Ok((node, ring.in_use()))
+    }
+
+    /// A session with the node on `addr`, once it listens.
+    fn session(addr: SocketAddr) -> Session<std::net::TcpStream> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match std::net::TcpStream::connect(addr) {
+                Ok(stream) => return Session::new(stream),
+                Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(5)),
+                Err(err) => panic!("{err}"),
+            }
+        }
+    }
+
+    /// Publish `message` through `addr` once the node there is linked up to the root.
+    fn publish(session: &mut Session<std::net::TcpStream>, message: &[u8]) -> u64 {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match session.publish(message) {
+                Ok(offset) => return offset,
+                Err(err) if Instant::now() < deadline => {
+                    assert_eq!(err.kind(), std::io::ErrorKind::Other);
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(err) => panic!("{err}"),
+            }
+        }
+    }
+
+    /// Consume as `consumer` until `count` messages arrived.
+    fn consume(
+        session: &mut Session<std::net::TcpStream>,
+        consumer: &[u8],
+        count: usize,
+    ) -> Vec<Vec<u8>> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut consumed = Vec::new();
+        while consumed.len() < count && Instant::now() < deadline {
+            let (first, messages) = session.consume(consumer, 16).unwrap();
+            assert_eq!(first, consumed.len() as u64);
+            if messages.is_empty() {
+                thread::sleep(Duration::from_millis(5));
+            }
+            consumed.extend(messages);
+        }
+        consumed
+    }
+
+    #[test]
+    fn frames_round_trip_and_logs_fill_up() {
+        let frames = [
+            Frame::Publish(b"hello"),
+            Frame::Consume {
+                consumer: b"reader",
+                max: 7,
+            },
+            Frame::Seek {
+                consumer: b"reader",
+                offset: 3,
+            },
+            Frame::Follow(9),
+            Frame::Ack(1 << 40),
+            Frame::Messages {
+                first: 5,
+                messages: vec![b"a", b"", b"bc"],
+            },
+            Frame::Message {
+                offset: 2,
+                message: b"m",
+            },
+            Frame::Error(b"no"),
+        ];
+        let mut bytes = Vec::new();
+        for frame in &frames {
+            frame.write_to(&mut bytes).unwrap();
+        }
+        let mut at = 0;
+        for frame in &frames {
+            assert_eq!(Frame::parse(&bytes[at..at + 4]).unwrap(), None);
+            let (parsed, n) = Frame::parse(&bytes[at..]).unwrap().unwrap();
+            assert_eq!(&parsed, frame);
+            at += n;
+        }
+        assert_eq!(at, bytes.len());
+        assert!(Frame::parse(&[0, 0, 0, 1, 99]).is_err());
+        assert!(Frame::parse(&[0xff, 0, 0, 0]).is_err());
+
+        let mut log = Log::new(MapOptions::new().anonymous(page_size()).unwrap());
+        assert_eq!(log.append(b"first").unwrap(), 0);
+        assert_eq!(log.append(b"").unwrap(), 1);
+        assert_eq!(log.append(&vec![7; page_size() - 29]).unwrap(), 2);
+        let err = log.append(b"x").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::OutOfMemory);
+        assert_eq!(
+            (log.get(0), log.get(1), log.get(3)),
+            (Some(&b"first"[..]), Some(&b""[..]), None)
+        );
+        assert_eq!((log.len(), log.bytes()), (3, page_size()));
+    }
+
+    #[test]
+    fn logs_in_files_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("elog-{}.queue", std::process::id()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let map = || MapOptions::new().file(&file, page_size()).unwrap();
+        let mut log = Log::new(map());
+        assert!(log.is_empty());
+        for message in [&b"one"[..], b"", b"three"] {
+            log.append(message).unwrap();
+        }
+        drop(log);
+
+        let mut log = Log::new(map());
+        assert_eq!(log.len(), 3);
+        assert_eq!(
+            (log.get(0), log.get(1)),
+            (Some(&b"one"[..]), Some(&b""[..]))
+        );
+        assert_eq!(log.append(b"four").unwrap(), 3);
+        let bytes = log.bytes();
+        drop(log);
+
+        // a message torn by a crash ends the log before it
+        let mut mapping = map();
+        mapping[bytes - 1] ^= 1;
+        let log = Log::new(mapping);
+        assert_eq!((log.len(), log.get(2)), (3, Some(&b"three"[..])));
+        drop(log);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn messages_flow_down_a_chain_of_nodes() {
+        let (root_addr, mid_addr, leaf_addr) = (
+            new_loopback_address(),
+            new_loopback_address(),
+            new_loopback_address(),
+        );
+        let shutdown = Shutdown::new();
+        let published: Vec<Vec<u8>> = (0..50)
+            .map(|i| format!("message {i}").repeat(i % 7 + 1).into_bytes())
+            .collect();
+
+        let (nodes, consumed) = thread::scope(|s| {
+            let nodes = [
+                s.spawn(|| run(root_addr, node(None), &shutdown)),
+                s.spawn(|| run(mid_addr, node(Some(root_addr)), &shutdown)),
+                s.spawn(|| run(leaf_addr, node(Some(mid_addr)), &shutdown)),
+            ];
+            thread::sleep(Duration::from_millis(20));
+            // publishes go up from the leaf to the root and come back down as messages
+            let mut producer = session(leaf_addr);
+            let offsets: Vec<u64> = published
+                .iter()
+                .map(|m| publish(&mut producer, m))
+                .collect();
+            let mut consumed = Vec::new();
+            for addr in [root_addr, mid_addr, leaf_addr] {
+                consumed.push(consume(&mut session(addr), b"a", published.len()));
+            }
+            let mut root = session(root_addr);
+            root.seek(b"b", 45).unwrap();
+            let late = root.consume(b"b", 16).unwrap();
+            let done = root.consume(b"a", 16).unwrap();
+            shutdown.signal().unwrap();
+            let nodes = nodes.map(|node| node.join().unwrap().unwrap());
+            consumed.push(vec![offsets.iter().map(|&o| o as u8).collect()]);
+            consumed.push(vec![late.0.to_be_bytes().to_vec(), late.1.concat()]);
+            consumed.push(vec![done.0.to_be_bytes().to_vec(), done.1.concat()]);
+            (nodes, consumed)
+        });
+
+        for received in &consumed[..3] {
+            assert_eq!(received, &published);
+        }
+        assert_eq!(consumed[3], [(0..50).collect::<Vec<u8>>()]);
+        assert_eq!(
+            consumed[4],
+            [45u64.to_be_bytes().to_vec(), published[45..].concat()]
+        );
+        assert_eq!(consumed[5], [50u64.to_be_bytes().to_vec(), Vec::new()]);
+        for (node, in_use) in &nodes {
+            assert_eq!((node.log().len(), node.offset(b"a"), *in_use), (50, 50, 0));
+            assert!(!node.is_linked());
+        }
+        assert_eq!((nodes[1].0.links(), nodes[2].0.links()), (1, 1));
+    }
+
+    #[test]
+    fn reads_pipelined_publishes_a_window_at_a_time() {
+        let addr = new_loopback_address();
+        let shutdown = Shutdown::new();
+
+        let ((node, in_use), answers) = thread::scope(|s| {
+            let root = s.spawn(|| run(addr, node(None), &shutdown));
+            // more than the ring of the node holds, sent before any answer is read
+            let mut publishes = Vec::new();
+            for i in 0..400u32 {
+                let message = [i as u8; 1024];
+                Frame::Publish(&message).write_to(&mut publishes).unwrap();
+            }
+            let mut stream = session(addr).stream;
+            stream.write_all(&publishes).unwrap();
+            let mut answers = vec![0; 400 * 13];
+            stream.read_exact(&mut answers).unwrap();
+            shutdown.signal().unwrap();
+            (root.join().unwrap().unwrap(), answers)
+        });
+
+        for (i, answer) in answers.chunks(13).enumerate() {
+            let (frame, _) = Frame::parse(answer).unwrap().unwrap();
+            assert_eq!(frame, Frame::Ack(i as u64));
+        }
+        assert_eq!((node.log().len(), in_use), (400, 0));
+        assert_eq!(node.log().get(399), Some(&[143; 1024][..]));
+    }
+
+    #[test]
+    fn followers_reconnect_to_a_restarted_parent() {
+        let (root_addr, child_addr) = (new_loopback_address(), new_loopback_address());
+        let root_shutdown = Shutdown::new();
+        let restarted_shutdown = Shutdown::new();
+        let shutdown = Shutdown::new();
+
+        let (child, received) = thread::scope(|s| {
+            let child = s.spawn(|| run(child_addr, node(Some(root_addr)), &shutdown));
+            // the root is not up yet, so the child backs off and tries again
+            thread::sleep(Duration::from_millis(60));
+            let root = s.spawn(|| run(root_addr, node(None), &root_shutdown));
+            let mut consumer = session(child_addr);
+            let mut received = Vec::new();
+            publish(&mut session(root_addr), b"one");
+            received.extend(consume(&mut consumer, b"c", 1));
+
+            root_shutdown.signal().unwrap();
+            let (root, _) = root.join().unwrap().unwrap();
+            let restarted = s.spawn(|| run(root_addr, root, &restarted_shutdown));
+            publish(&mut session(root_addr), b"two");
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while received.len() < 2 && Instant::now() < deadline {
+                let (_, messages) = consumer.consume(b"c", 16).unwrap();
+                received.extend(messages);
+                thread::sleep(Duration::from_millis(5));
+            }
+            restarted_shutdown.signal().unwrap();
+            shutdown.signal().unwrap();
+            restarted.join().unwrap().unwrap();
+            (child.join().unwrap().unwrap(), received)
+        });
+
+        assert_eq!(received, [b"one".to_vec(), b"two".to_vec()]);
+        let (child, in_use) = child;
+        assert_eq!((child.log().len(), child.offset(b"c"), in_use), (2, 2, 0));
+        assert!(child.links() >= 2);
+    }
+}
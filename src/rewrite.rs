@@ -0,0 +1,450 @@
+use std::io::{ErrorKind::InvalidInput, Write};
+
+use crate::{
+    http::{is_token, Body, Header, HttpHandler, Request, Response},
+    Flow,
+};
+
+/// Redirects requests for a host and path prefix to `target`.
+#[derive(Clone, Debug)]
+pub struct Redirect {
+    status: u16,
+    host: Option<String>,
+    prefix: String,
+    target: String,
+    keep_path: bool,
+}
+
+impl Redirect {
+    /// Redirect with `status`, one of 301, 302, 307 and 308, every path starting with `prefix`
+    /// to `target` followed by the rest of the path and the query.
+    pub fn new(status: u16, prefix: &str, target: &str) -> std::io::Result<Self> {
+        if !matches!(status, 301 | 302 | 307 | 308) || has_line_break(target.as_bytes()) {
+            return Err(InvalidInput.into());
+        }
+        Ok(Self {
+            status,
+            host: None,
+            prefix: prefix.to_string(),
+            target: target.to_string(),
+            keep_path: true,
+        })
+    }
+
+    /// Only redirect requests whose `Host` is `host`, with any port.
+    pub fn host(mut self, host: &str) -> Self {
+        self.host = Some(host.to_ascii_lowercase());
+        self
+    }
+
+    /// Redirect to `target` as it is, dropping the rest of the path and the query.
+    pub fn exact(mut self) -> Self {
+        self.keep_path = false;
+        self
+    }
+
+    /// Where `request` is sent, if this rule applies to it.
+    fn location(&self, request: &Request<'_>) -> Option<String> {
+        if let Some(ref host) = self.host {
+            let value = std::str::from_utf8(request.header("host")?).ok()?;
+            if !without_port(value).eq_ignore_ascii_case(host) {
+                return None;
+            }
+        }
+        let rest = request.path.strip_prefix(self.prefix.as_str())?;
+        // prefixes end at a segment, "/old" does not cover "/older"
+        let boundary =
+            self.prefix.ends_with('/') || rest.is_empty() || rest.starts_with(['/', '?']);
+        match (boundary, self.keep_path) {
+            (false, _) => None,
+            (true, true) => Some(format!("{}{rest}", self.target)),
+            (true, false) => Some(self.target.clone()),
+        }
+    }
+}
+
+fn without_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return host.split_inclusive(']').next().unwrap_or(host);
+    }
+    host.split(':').next().unwrap_or(host)
+}
+
+fn has_line_break(value: &[u8]) -> bool {
+    value.iter().any(|&b| b'\r' == b || b'\n' == b)
+}
+
+/// An [`HttpHandler`] answering requests that match one of its rules with a redirect and passing
+/// everything else on to `next`. Rules are tried in the order they were added.
+pub struct Redirects<H> {
+    rules: Vec<Redirect>,
+    pub next: H,
+}
+
+impl<H> Redirects<H> {
+    pub fn new(next: H) -> Self {
+        Self {
+            rules: Vec::new(),
+            next,
+        }
+    }
+
+    pub fn rule(mut self, rule: Redirect) -> Self {
+        self.rules.push(rule);
+        self
+    }
+}
+
+impl<H: HttpHandler> HttpHandler for Redirects<H> {
+    fn request<W: Write>(
+        &mut self,
+        token: usize,
+        request: &Request<'_>,
+        body: Body<'_>,
+        send: &mut W,
+    ) -> std::io::Result<Flow> {
+        let redirect = self
+            .rules
+            .iter()
+            .find_map(|rule| Some((rule.status, rule.location(request)?)));
+        let Some((status, location)) = redirect else {
+            return self.next.request(token, request, body, send);
+        };
+        let mut response = Response::to(request, send, status)?;
+        response.header("location", location)?;
+        response.body(b"")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Action {
+    Add,
+    Set,
+    Remove,
+}
+
+/// A change to the headers of a request or response.
+#[derive(Clone, Debug)]
+pub struct HeaderRule {
+    action: Action,
+    name: String,
+    value: Vec<u8>,
+}
+
+impl HeaderRule {
+    fn new(action: Action, name: &str, value: Vec<u8>) -> std::io::Result<Self> {
+        if !is_token(name.as_bytes()) || has_line_break(&value) {
+            return Err(InvalidInput.into());
+        }
+        Ok(Self {
+            action,
+            name: name.to_ascii_lowercase(),
+            value,
+        })
+    }
+
+    /// Add a `name` header next to any that are already there.
+    pub fn add(name: &str, value: impl Into<Vec<u8>>) -> std::io::Result<Self> {
+        Self::new(Action::Add, name, value.into())
+    }
+
+    /// Replace every `name` header with one, or add it when there is none.
+    pub fn set(name: &str, value: impl Into<Vec<u8>>) -> std::io::Result<Self> {
+        Self::new(Action::Set, name, value.into())
+    }
+
+    /// Drop every `name` header.
+    pub fn remove(name: &str) -> std::io::Result<Self> {
+        Self::new(Action::Remove, name, Vec::new())
+    }
+
+    /// Set `Strict-Transport-Security` for `max_age` seconds.
+    pub fn hsts(max_age: u64, include_subdomains: bool) -> Self {
+        let mut value = format!("max-age={max_age}");
+        if include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        Self {
+            action: Action::Set,
+            name: "strict-transport-security".to_string(),
+            value: value.into_bytes(),
+        }
+    }
+
+    /// Whether a header called `name` that is already there is dropped.
+    fn replaces(rules: &[HeaderRule], name: &str) -> bool {
+        rules
+            .iter()
+            .any(|rule| Action::Add != rule.action && rule.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The rules of `rules` that add a header.
+    fn added(rules: &[HeaderRule]) -> impl Iterator<Item = &HeaderRule> {
+        rules.iter().filter(|rule| Action::Remove != rule.action)
+    }
+}
+
+/// An [`HttpHandler`] that rewrites the headers of requests before passing them on to `next` and
+/// the headers of the responses `next` writes. Rules apply in the order they were added.
+///
+/// Only headers change; rules that touch `Content-Length`, `Transfer-Encoding` or `Connection`
+/// do not change how messages are framed.
+pub struct Rewrite<H> {
+    request: Vec<HeaderRule>,
+    response: Vec<HeaderRule>,
+    pub next: H,
+}
+
+impl<H> Rewrite<H> {
+    pub fn new(next: H) -> Self {
+        Self {
+            request: Vec::new(),
+            response: Vec::new(),
+            next,
+        }
+    }
+
+    pub fn request(mut self, rule: HeaderRule) -> Self {
+        self.request.push(rule);
+        self
+    }
+
+    pub fn response(mut self, rule: HeaderRule) -> Self {
+        self.response.push(rule);
+        self
+    }
+}
+
+impl<H: HttpHandler> HttpHandler for Rewrite<H> {
+    fn request<W: Write>(
+        &mut self,
+        token: usize,
+        request: &Request<'_>,
+        body: Body<'_>,
+        send: &mut W,
+    ) -> std::io::Result<Flow> {
+        let mut headers: Vec<Header<'_>> = request
+            .headers()
+            .iter()
+            .filter(|header| !HeaderRule::replaces(&self.request, header.name))
+            .copied()
+            .collect();
+        headers.extend(HeaderRule::added(&self.request).map(|rule| Header {
+            name: &rule.name,
+            value: &rule.value,
+        }));
+        let request = request.with_headers(&headers)?;
+        let mut send = ResponseHead {
+            send,
+            rules: &self.response,
+            head: Vec::new(),
+            done: self.response.is_empty(),
+        };
+        let flow = self.next.request(token, &request, body, &mut send)?;
+        send.finish()?;
+        Ok(flow)
+    }
+}
+
+/// Holds back what is written until the response head is complete, then writes the head with
+/// its headers rewritten and passes everything after it through.
+struct ResponseHead<'r, 'w, W> {
+    send: &'w mut W,
+    rules: &'r [HeaderRule],
+    head: Vec<u8>,
+    done: bool,
+}
+
+impl<W: Write> ResponseHead<'_, '_, W> {
+    fn rewrite(&mut self, head: &[u8]) -> std::io::Result<()> {
+        let mut lines = head
+            .split(|&b| b'\n' == b)
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+        let status = lines.next().unwrap_or_default();
+        self.send.write_all(status)?;
+        self.send.write_all(b"\r\n")?;
+        for line in lines.filter(|line| !line.is_empty()) {
+            let name = line.split(|&b| b':' == b).next().unwrap_or_default();
+            let name = std::str::from_utf8(name).unwrap_or_default();
+            if !HeaderRule::replaces(self.rules, name) {
+                self.send.write_all(line)?;
+                self.send.write_all(b"\r\n")?;
+            }
+        }
+        for rule in HeaderRule::added(self.rules) {
+            self.send.write_all(rule.name.as_bytes())?;
+            self.send.write_all(b": ")?;
+            self.send.write_all(&rule.value)?;
+            self.send.write_all(b"\r\n")?;
+        }
+        self.send.write_all(b"\r\n")
+    }
+
+    /// Write out a head that never ended as it is.
+    fn finish(self) -> std::io::Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        self.send.write_all(&self.head)
+    }
+}
+
+impl<W: Write> Write for ResponseHead<'_, '_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.done {
+            return self.send.write(buf);
+        }
+        let searched = self.head.len().saturating_sub(3);
+        self.head.extend_from_slice(buf);
+        let Some(end) = self.head[searched..]
+            .windows(4)
+            .position(|window| b"\r\n\r\n" == window)
+        else {
+            return Ok(buf.len());
+        };
+        let mut head = std::mem::take(&mut self.head);
+        let rest = head.split_off(searched + end + 4);
+        self.rewrite(&head)?;
+        self.done = true;
+        self.send.write_all(&rest)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use mio::net::TcpStream;
+
+    use super::{HeaderRule, Redirect, Redirects, Rewrite};
+    use crate::{
+        http::{Body, Http, HttpHandler, Request, Response},
+        Flow, Handler,
+    };
+
+    /// Answers with the headers it received, one per line, and headers of its own.
+    struct Headers {
+        chunked: bool,
+    }
+
+    impl HttpHandler for Headers {
+        fn request<W: Write>(
+            &mut self,
+            _token: usize,
+            request: &Request<'_>,
+            _body: Body<'_>,
+            send: &mut W,
+        ) -> std::io::Result<Flow> {
+            let mut text = String::new();
+            for header in request.headers() {
+                let value = String::from_utf8_lossy(header.value);
+                text.push_str(&format!("{}={value};", header.name));
+            }
+            let mut response = Response::to(request, send, 200)?;
+            response
+                .header("Server", "inner")?
+                .header("X-Powered-By", "tests")?;
+            if !self.chunked {
+                return response.body(text.as_bytes());
+            }
+            let mut body = response.chunked()?;
+            body.write_all(text.as_bytes())?;
+            body.finish()
+        }
+    }
+
+    fn exchange<H: HttpHandler>(handler: H, receive: &[u8]) -> String {
+        let mut http = Http::new(handler);
+        let mut send = Vec::new();
+        <Http<H> as Handler<TcpStream>>::received(&mut http, 1, receive, &mut send).unwrap();
+        String::from_utf8(send).unwrap()
+    }
+
+    #[test]
+    fn redirects_by_host_and_path_prefix() {
+        let redirects = || {
+            Redirects::new(Headers { chunked: false })
+                .rule(
+                    Redirect::new(301, "/", "https://example.com/")
+                        .unwrap()
+                        .host("example.com"),
+                )
+                .rule(Redirect::new(308, "/old", "/new").unwrap())
+                .rule(Redirect::new(302, "/login/", "/sso").unwrap().exact())
+                .rule(Redirect::new(307, "/tmp", "https://cdn.example.net").unwrap())
+        };
+        for (request, location) in [
+            (
+                "GET /a?b=c HTTP/1.1\r\nHost: EXAMPLE.com:8080\r\n\r\n",
+                Some("301 Moved Permanently\r\nlocation: https://example.com/a?b=c"),
+            ),
+            (
+                "GET /old HTTP/1.1\r\nHost: a\r\n\r\n",
+                Some("308 Permanent Redirect\r\nlocation: /new"),
+            ),
+            (
+                "GET /old/x?y HTTP/1.1\r\nHost: a\r\n\r\n",
+                Some("308 Permanent Redirect\r\nlocation: /new/x?y"),
+            ),
+            (
+                "GET /login/form?next=/ HTTP/1.1\r\nHost: a\r\n\r\n",
+                Some("302 Found\r\nlocation: /sso"),
+            ),
+            (
+                "POST /tmp/f HTTP/1.1\r\nHost: other\r\nContent-Length: 1\r\n\r\nx",
+                Some("307 Temporary Redirect\r\nlocation: https://cdn.example.net/f"),
+            ),
+            ("GET /older HTTP/1.1\r\nHost: a\r\n\r\n", None),
+            ("GET /a HTTP/1.1\r\nHost: example.org\r\n\r\n", None),
+        ] {
+            let response = exchange(redirects(), request.as_bytes());
+            match location {
+                Some(location) => {
+                    let expected = format!("HTTP/1.1 {location}\r\ncontent-length: 0\r\n\r\n");
+                    assert_eq!(response, expected);
+                }
+                None => assert!(response.starts_with("HTTP/1.1 200 OK\r\n")),
+            }
+        }
+        assert!(Redirect::new(200, "/", "/").is_err());
+        assert!(Redirect::new(301, "/", "/\r\nx: y").is_err());
+    }
+
+    #[test]
+    fn rewrites_request_and_response_headers() {
+        for chunked in [false, true] {
+            let rewrite = Rewrite::new(Headers { chunked })
+                .request(HeaderRule::remove("Cookie").unwrap())
+                .request(HeaderRule::set("x-forwarded-proto", "https").unwrap())
+                .response(HeaderRule::hsts(31_536_000, true))
+                .response(HeaderRule::set("server", "elog").unwrap())
+                .response(HeaderRule::remove("x-powered-by").unwrap())
+                .response(HeaderRule::add("via", "1.1 edge").unwrap());
+            let response = exchange(
+                rewrite,
+                b"GET / HTTP/1.1\r\nHost: a\r\nCookie: x=1\r\nX-Forwarded-Proto: http\r\n\r\n\
+                  GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n",
+            );
+            let framing = match chunked {
+                true => "transfer-encoding: chunked\r\n",
+                false => "content-length: 31\r\n",
+            };
+            let head = format!(
+                "HTTP/1.1 200 OK\r\n{framing}strict-transport-security: max-age=31536000; includeSubDomains\r\nserver: elog\r\nvia: 1.1 edge\r\n\r\n"
+            );
+            let (first, second) = response.split_at(response.rfind("HTTP/1.1").unwrap());
+            assert!(first.starts_with(&head), "{first}");
+            assert!(first.contains("Host=a;x-forwarded-proto=https;"));
+            assert!(second.contains("connection: close\r\n"));
+            assert!(second.contains("\r\nserver: elog\r\n"));
+            assert!(!second.contains("X-Powered-By"));
+        }
+        assert!(HeaderRule::set("bad name", "x").is_err());
+        assert!(HeaderRule::add("x", "a\nb").is_err());
+    }
+}
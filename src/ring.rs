@@ -0,0 +1,771 @@
+use std::{
+    collections::VecDeque,
+    io::{ErrorKind::*, IoSlice, IoSliceMut, Write},
+    ops::{Deref, DerefMut},
+};
+
+#[cfg(target_os = "linux")]
+use crate::mmap::Mapping;
+
+/// Length of a segment unless a ring is built with [`RingBuffer::with_segment_len`].
+pub const PAGE_SIZE: usize = 4096;
+
+/// One segment of a [`RingBuffer`]; only meaningful for the ring that handed it out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Segment(pub(crate) usize);
+
+impl Segment {
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// Bytes `start..end` of a run of consecutive segments, readable as one contiguous slice.
+///
+/// Received bytes live in spans so that handlers and parsers never see a message cut in two.
+/// On a mirrored ring a span may run past the last segment on into the first.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    first: usize,
+    segments: usize,
+    /// Segments of the ring the span lives in.
+    count: usize,
+    start: usize,
+    end: usize,
+}
+
+impl Span {
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = Segment> {
+        let count = self.count.max(1);
+        (self.first..self.first + self.segments).map(move |index| Segment(index % count))
+    }
+}
+
+/// Segments queued in order, each holding some bytes; unlike a [`Span`] they need not be adjacent.
+///
+/// Outgoing bytes live in chains, which grow one segment at a time and never move bytes.
+#[derive(Debug, Default)]
+pub struct Chain {
+    filled: VecDeque<Filled>,
+    /// Leading segments already run through [`RingBuffer::stage_chain`], which take no more bytes.
+    staged: usize,
+    /// Most bytes written into one segment, `0` for the whole segment.
+    limit: usize,
+    /// First segment handed out by the last [`RingBuffer::spare_slices`].
+    spare: usize,
+}
+
+#[derive(Debug)]
+struct Filled {
+    segment: usize,
+    start: usize,
+    end: usize,
+}
+
+impl Chain {
+    pub fn len(&self) -> usize {
+        self.filled.iter().map(|f| f.end - f.start).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filled.is_empty()
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + '_ {
+        self.filled.iter().map(|f| Segment(f.segment))
+    }
+
+    /// Fill every segment with at most `limit` bytes, leaving the rest as headroom for stages
+    /// that expand the segment in place before it is written.
+    pub fn set_limit(&mut self, limit: usize) {
+        assert!(limit > 0);
+        self.limit = limit;
+    }
+
+    fn fill_limit(&self, segment_len: usize) -> usize {
+        match self.limit {
+            0 => segment_len,
+            limit => limit.min(segment_len),
+        }
+    }
+
+    /// Whether the last segment can take more bytes.
+    fn back_has_room(&self, segment_len: usize) -> bool {
+        self.filled.len() > self.staged
+            && self
+                .filled
+                .back()
+                .is_some_and(|filled| filled.end < self.fill_limit(segment_len))
+    }
+}
+
+/// Ownership of every segment, kept apart from the memory so that the memory can be split.
+#[derive(Debug)]
+struct Slots {
+    owners: Box<[Option<usize>]>,
+    head: usize,
+    tail: usize,
+    in_use: usize,
+    /// Whether runs of segments may continue past the last one at the first, as they can when
+    /// the memory is mirrored.
+    wraps: bool,
+}
+
+impl Slots {
+    fn new(count: usize, wraps: bool) -> Self {
+        Self {
+            owners: vec![None; count].into_boxed_slice(),
+            head: 0,
+            tail: 0,
+            in_use: 0,
+            wraps,
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.owners.len()
+    }
+
+    fn is_free(&self, first: usize, n: usize) -> bool {
+        let count = self.count();
+        if !self.wraps {
+            return first + n <= count && self.owners[first..first + n].iter().all(Option::is_none);
+        }
+        n <= count && (first..first + n).all(|index| self.owners[index % count].is_none())
+    }
+
+    fn claim(&mut self, owner: usize, first: usize, n: usize) {
+        let count = self.count();
+        let first = first % count;
+        if 0 == self.in_use {
+            self.tail = first;
+        }
+        for index in first..first + n {
+            self.owners[index % count] = Some(owner);
+        }
+        self.in_use += n;
+        self.head = (first + n) % self.count();
+    }
+
+    /// First `n` consecutive free segments found walking forward from the head.
+    fn acquire(&mut self, owner: usize, n: usize) -> Option<usize> {
+        let count = self.count();
+        if 0 == n || n > count - self.in_use {
+            return None;
+        }
+        let first = (0..count)
+            .map(|step| (self.head + step) % count)
+            .find(|&first| self.is_free(first, n))?;
+        self.claim(owner, first, n);
+        Some(first)
+    }
+
+    /// Release the segment at `index`, counted on past the last segment on a mirrored ring.
+    fn release(&mut self, index: usize) {
+        let index = index % self.count();
+        if self.owners[index].take().is_none() {
+            return;
+        }
+        self.in_use -= 1;
+        if 0 == self.in_use {
+            self.tail = self.head;
+            return;
+        }
+        while self.owners[self.tail].is_none() {
+            self.tail = (self.tail + 1) % self.count();
+        }
+    }
+}
+
+/// A worker's I/O memory: one allocation or mapping split into equally sized segments handed out
+/// to connections in ring order.
+///
+/// The head is where the next segment is handed out and the tail is the oldest segment still
+/// owned; both wrap around. Segments are released in any order, and the head skips segments
+/// that are still owned.
+#[derive(Debug)]
+pub struct RingBuffer {
+    memory: Memory,
+    segment_len: usize,
+    slots: Slots,
+}
+
+/// Where the segments of a ring live.
+#[derive(Debug)]
+enum Memory {
+    Heap(Box<[u8]>),
+    #[cfg(target_os = "linux")]
+    Mapped(Mapping),
+}
+
+impl Deref for Memory {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Memory::Heap(memory) => memory,
+            #[cfg(target_os = "linux")]
+            Memory::Mapped(mapping) => mapping,
+        }
+    }
+}
+
+impl DerefMut for Memory {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Memory::Heap(memory) => memory,
+            #[cfg(target_os = "linux")]
+            Memory::Mapped(mapping) => mapping,
+        }
+    }
+}
+
+impl RingBuffer {
+    /// A ring of `segments` page sized segments.
+    pub fn new(segments: usize) -> Self {
+        Self::with_segment_len(segments, PAGE_SIZE)
+    }
+
+    pub fn with_segment_len(segments: usize, segment_len: usize) -> Self {
+        assert!(segments > 0 && segment_len > 0);
+        Self {
+            memory: Memory::Heap(vec![0; segments * segment_len].into_boxed_slice()),
+            segment_len,
+            slots: Slots::new(segments, false),
+        }
+    }
+
+    /// A ring over `mapping` for buffers too large for the heap, split into segments of
+    /// `segment_len` bytes, a whole number of pages each so that every segment is page aligned.
+    ///
+    /// Over a mirrored mapping, spans wrap around from the last segment to the first instead of
+    /// moving their bytes.
+    #[cfg(target_os = "linux")]
+    pub fn mapped(mapping: Mapping, segment_len: usize) -> Self {
+        assert!(segment_len > 0 && segment_len.is_multiple_of(crate::mmap::page_size()));
+        assert!(mapping.size().is_multiple_of(segment_len));
+        let wraps = mapping.is_mirrored();
+        Self {
+            segment_len,
+            slots: Slots::new(mapping.size() / segment_len, wraps),
+            memory: Memory::Mapped(mapping),
+        }
+    }
+
+    /// Whether spans may wrap around from the last segment to the first.
+    pub fn is_mirrored(&self) -> bool {
+        self.slots.wraps
+    }
+
+    pub fn segment_len(&self) -> usize {
+        self.segment_len
+    }
+
+    pub fn segments(&self) -> usize {
+        self.slots.count()
+    }
+
+    /// Number of segments currently owned.
+    pub fn in_use(&self) -> usize {
+        self.slots.in_use
+    }
+
+    pub fn head(&self) -> Segment {
+        Segment(self.slots.head)
+    }
+
+    pub fn tail(&self) -> Segment {
+        Segment(self.slots.tail)
+    }
+
+    pub fn owner(&self, segment: Segment) -> Option<usize> {
+        self.slots.owners[segment.0]
+    }
+
+    /// Hand the next free segment to `owner`; `None` when every segment is owned.
+    pub fn acquire(&mut self, owner: usize) -> Option<Segment> {
+        self.slots.acquire(owner, 1).map(Segment)
+    }
+
+    pub fn release(&mut self, segment: Segment) {
+        self.slots.release(segment.0)
+    }
+
+    /// Release every segment owned by `owner`, whatever span or chain it belonged to.
+    pub fn release_owner(&mut self, owner: usize) {
+        for index in 0..self.slots.count() {
+            if Some(owner) == self.slots.owners[index] {
+                self.slots.release(index);
+            }
+        }
+    }
+
+    pub fn segment(&self, segment: Segment) -> &[u8] {
+        let offset = segment.0 * self.segment_len;
+        &self.memory[offset..offset + self.segment_len]
+    }
+
+    pub fn segment_mut(&mut self, segment: Segment) -> &mut [u8] {
+        let offset = segment.0 * self.segment_len;
+        &mut self.memory[offset..offset + self.segment_len]
+    }
+
+    pub fn bytes(&self, span: &Span) -> &[u8] {
+        let offset = span.first * self.segment_len;
+        &self.memory[offset + span.start..offset + span.end]
+    }
+
+    pub fn bytes_mut(&mut self, span: &Span) -> &mut [u8] {
+        let offset = span.first * self.segment_len;
+        &mut self.memory[offset + span.start..offset + span.end]
+    }
+
+    /// The bytes of `span` followed by the spare memory of its segments.
+    pub fn span_mut(&mut self, span: &Span) -> &mut [u8] {
+        let offset = span.first * self.segment_len;
+        &mut self.memory[offset + span.start..offset + span.segments * self.segment_len]
+    }
+
+    /// Make `span` hold `len` bytes after they were transformed in [`RingBuffer::span_mut`].
+    pub fn set_len(&mut self, span: &mut Span, len: usize) {
+        assert!(span.start + len <= span.segments * self.segment_len);
+        span.end = span.start + len;
+    }
+
+    /// Make room for at least `additional` bytes after the end of `span`.
+    ///
+    /// The span grows into the segments right after it when they are free; otherwise its bytes
+    /// are moved to the front of its own segments or, failing that, to a larger run elsewhere.
+    /// Fails with `OutOfMemory` when no run is large enough.
+    pub fn reserve(
+        &mut self,
+        span: &mut Span,
+        owner: usize,
+        additional: usize,
+    ) -> std::io::Result<()> {
+        let segment_len = self.segment_len;
+        if 0 == span.segments {
+            let segments = additional.max(1).div_ceil(segment_len);
+            let first = self.slots.acquire(owner, segments).ok_or(OutOfMemory)?;
+            *span = Span {
+                first,
+                segments,
+                count: self.slots.count(),
+                start: 0,
+                end: 0,
+            };
+            return Ok(());
+        }
+        if span.end + additional <= span.segments * segment_len {
+            return Ok(());
+        }
+        let next = span.first + span.segments;
+        let more = (span.end + additional).div_ceil(segment_len) - span.segments;
+        if self.slots.is_free(next, more) {
+            self.slots.claim(owner, next, more);
+            span.segments += more;
+            return Ok(());
+        }
+        let len = span.len();
+        let offset = span.first * segment_len;
+        let segments = (len + additional).div_ceil(segment_len);
+        if segments <= span.segments {
+            self.memory
+                .copy_within(offset + span.start..offset + span.end, offset);
+            span.start = 0;
+            span.end = len;
+            return Ok(());
+        }
+        // the old run is still owned while the new one is picked, so the two never overlap
+        let first = self.slots.acquire(owner, segments).ok_or(OutOfMemory)?;
+        self.memory
+            .copy_within(offset + span.start..offset + span.end, first * segment_len);
+        for index in span.first..span.first + span.segments {
+            self.slots.release(index);
+        }
+        *span = Span {
+            first,
+            segments,
+            count: self.slots.count(),
+            start: 0,
+            end: len,
+        };
+        Ok(())
+    }
+
+    /// The unused memory after the end of `span`, reserving a segment first if it is full.
+    pub fn spare_mut(&mut self, span: &mut Span, owner: usize) -> std::io::Result<&mut [u8]> {
+        self.reserve(span, owner, 1)?;
+        let offset = span.first * self.segment_len;
+        Ok(&mut self.memory[offset + span.end..offset + span.segments * self.segment_len])
+    }
+
+    /// Mark `n` bytes written into [`RingBuffer::spare_mut`] as part of `span`.
+    pub fn commit(&mut self, span: &mut Span, n: usize) {
+        assert!(span.end + n <= span.segments * self.segment_len);
+        span.end += n;
+    }
+
+    /// Drop `n` bytes from the front of `span`; segments that no longer hold any of its bytes are
+    /// released.
+    pub fn consume(&mut self, span: &mut Span, n: usize) {
+        assert!(n <= span.len());
+        span.start += n;
+        if span.is_empty() {
+            self.release_span(span);
+            return;
+        }
+        while span.start >= self.segment_len {
+            self.slots.release(span.first);
+            span.first = (span.first + 1) % self.slots.count();
+            span.segments -= 1;
+            span.start -= self.segment_len;
+            span.end -= self.segment_len;
+        }
+    }
+
+    /// Release the segments after the last one holding bytes of `span`.
+    pub fn shrink(&mut self, span: &mut Span) {
+        if span.is_empty() {
+            self.release_span(span);
+            return;
+        }
+        let segments = span.end.div_ceil(self.segment_len);
+        for index in span.first + segments..span.first + span.segments {
+            self.slots.release(index);
+        }
+        span.segments = segments;
+    }
+
+    pub fn release_span(&mut self, span: &mut Span) {
+        for index in span.first..span.first + span.segments {
+            self.slots.release(index);
+        }
+        *span = Span::default();
+    }
+
+    /// The bytes at the front of `chain`, which is where writes to a connection resume.
+    pub fn front(&self, chain: &Chain) -> &[u8] {
+        match chain.filled.front() {
+            Some(filled) => {
+                let offset = filled.segment * self.segment_len;
+                &self.memory[offset + filled.start..offset + filled.end]
+            }
+            None => &[],
+        }
+    }
+
+    /// Drop `n` bytes from the front of `chain`, releasing segments as they empty.
+    pub fn consume_chain(&mut self, chain: &mut Chain, mut n: usize) {
+        while n > 0 {
+            let filled = chain
+                .filled
+                .front_mut()
+                .expect("consumed past end of chain");
+            let taken = n.min(filled.end - filled.start);
+            filled.start += taken;
+            n -= taken;
+            if filled.start == filled.end {
+                self.slots.release(filled.segment);
+                chain.filled.pop_front();
+                chain.staged = chain.staged.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Point `slices` at the bytes of `chain` in order, for a single vectored write; returns how
+    /// many slices were used.
+    pub fn io_slices<'a>(&'a self, chain: &Chain, slices: &mut [IoSlice<'a>]) -> usize {
+        let mut count = 0;
+        for (slice, filled) in slices.iter_mut().zip(&chain.filled) {
+            let offset = filled.segment * self.segment_len;
+            *slice = IoSlice::new(&self.memory[offset + filled.start..offset + filled.end]);
+            count += 1;
+        }
+        count
+    }
+
+    /// Point `slices` at the spare memory at the back of `chain`, for a single vectored read: the
+    /// rest of its last segment followed by freshly acquired segments wherever the ring has them.
+    /// Returns how many slices were used; follow the read with [`RingBuffer::commit_chain`].
+    pub fn spare_slices<'a>(
+        &'a mut self,
+        chain: &mut Chain,
+        owner: usize,
+        slices: &mut [IoSliceMut<'a>],
+    ) -> std::io::Result<usize> {
+        let segment_len = self.segment_len;
+        let mut spare = usize::from(chain.back_has_room(segment_len));
+        while spare < slices.len() {
+            let Some(segment) = self.slots.acquire(owner, 1) else {
+                break;
+            };
+            chain.filled.push_back(Filled {
+                segment,
+                start: 0,
+                end: 0,
+            });
+            spare += 1;
+        }
+        if 0 == spare {
+            return Err(OutOfMemory.into());
+        }
+        // split the memory in segment order, which differs from chain order after a wrap around
+        let first = chain.filled.len() - spare;
+        chain.spare = first;
+        let limit = chain.fill_limit(segment_len);
+        let mut order: Vec<usize> = (first..chain.filled.len()).collect();
+        order.sort_by_key(|&i| chain.filled[i].segment);
+        let mut rest: &'a mut [u8] = &mut self.memory;
+        let mut rest_offset = 0;
+        for i in order {
+            let filled = &chain.filled[i];
+            let from = filled.segment * segment_len + filled.end;
+            let to = filled.segment * segment_len + limit;
+            let (_, tail) = std::mem::take(&mut rest).split_at_mut(from - rest_offset);
+            let (memory, tail) = tail.split_at_mut(to - from);
+            rest = tail;
+            rest_offset = to;
+            slices[i - first] = IoSliceMut::new(memory);
+        }
+        Ok(spare)
+    }
+
+    /// Mark `n` bytes read into [`RingBuffer::spare_slices`] as part of `chain`; segments that
+    /// received nothing are released.
+    pub fn commit_chain(&mut self, chain: &mut Chain, mut n: usize) {
+        let limit = chain.fill_limit(self.segment_len);
+        for filled in chain.filled.iter_mut().skip(chain.spare) {
+            let taken = n.min(limit - filled.end);
+            filled.end += taken;
+            n -= taken;
+        }
+        assert_eq!(n, 0);
+        while let Some(filled) = chain.filled.back() {
+            if filled.start < filled.end {
+                break;
+            }
+            self.slots.release(filled.segment);
+            chain.filled.pop_back();
+        }
+    }
+
+    pub fn release_chain(&mut self, chain: &mut Chain) {
+        for filled in chain.filled.drain(..) {
+            self.slots.release(filled.segment);
+        }
+        chain.staged = 0;
+    }
+
+    /// Transform every segment of `chain` not staged yet with `run`, which gets the segment with
+    /// its bytes at the front and returns their new length. Staged segments take no more bytes.
+    pub fn stage_chain<F>(&mut self, chain: &mut Chain, mut run: F) -> std::io::Result<()>
+    where
+        F: FnMut(&mut [u8], usize) -> std::io::Result<usize>,
+    {
+        let segment_len = self.segment_len;
+        for filled in chain.filled.iter_mut().skip(chain.staged) {
+            let offset = filled.segment * segment_len;
+            let segment = &mut self.memory[offset + filled.start..offset + segment_len];
+            let len = run(segment, filled.end - filled.start)?;
+            assert!(filled.start + len <= segment_len);
+            filled.end = filled.start + len;
+        }
+        chain.staged = chain.filled.len();
+        Ok(())
+    }
+
+    /// Append to `chain` through [`Write`], acquiring segments for `owner` as they fill up.
+    pub fn chain_writer<'a>(&'a mut self, chain: &'a mut Chain, owner: usize) -> ChainWriter<'a> {
+        self.writer(&Span::default(), chain, owner).1
+    }
+
+    /// Borrow the bytes of `input` while appending to `output`, so a handler can answer straight
+    /// from the received bytes without copying them out of the ring first.
+    pub fn writer<'a>(
+        &'a mut self,
+        input: &Span,
+        output: &'a mut Chain,
+        owner: usize,
+    ) -> (&'a [u8], ChainWriter<'a>) {
+        let segment_len = self.segment_len;
+        let size = self.slots.count() * segment_len;
+        let hole_start = input.first * segment_len;
+        let hole_end = (input.first + input.segments) * segment_len;
+        // a span wrapping around a mirrored ring also covers the start of the first half, and
+        // the second half past it is the first again
+        let wrapped = hole_end.saturating_sub(size);
+        let (_, rest) = self.memory.split_at_mut(wrapped);
+        let (before, rest) = rest.split_at_mut(hole_start - wrapped);
+        let (hole, rest) = rest.split_at_mut(hole_end - hole_start);
+        let (after, _) = rest.split_at_mut(size.saturating_sub(hole_end));
+        let writer = ChainWriter {
+            before,
+            before_start: wrapped,
+            after,
+            hole_end,
+            segment_len,
+            slots: &mut self.slots,
+            chain: output,
+            owner,
+        };
+        (&hole[input.start..input.end], writer)
+    }
+}
+
+/// Appends to a [`Chain`]; see [`RingBuffer::chain_writer`] and [`RingBuffer::writer`].
+///
+/// Segments of the input span being read at the same time are owned, so they are never handed
+/// to the chain and the memory around them can be written safely.
+pub struct ChainWriter<'a> {
+    before: &'a mut [u8],
+    /// Offset of `before` in the ring's memory.
+    before_start: usize,
+    after: &'a mut [u8],
+    hole_end: usize,
+    segment_len: usize,
+    slots: &'a mut Slots,
+    chain: &'a mut Chain,
+    owner: usize,
+}
+
+impl Write for ChainWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let segment_len = self.segment_len;
+        if !self.chain.back_has_room(segment_len) {
+            let segment = self.slots.acquire(self.owner, 1).ok_or(OutOfMemory)?;
+            self.chain.filled.push_back(Filled {
+                segment,
+                start: 0,
+                end: 0,
+            });
+        }
+        let limit = self.chain.fill_limit(segment_len);
+        let filled = self.chain.filled.back_mut().unwrap();
+        let offset = filled.segment * segment_len;
+        let memory = if offset < self.before_start + self.before.len() {
+            &mut self.before[offset - self.before_start..][..segment_len]
+        } else {
+            &mut self.after[offset - self.hole_end..][..segment_len]
+        };
+        let n = buf.len().min(limit - filled.end);
+        memory[filled.end..filled.end + n].copy_from_slice(&buf[..n]);
+        filled.end += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::{Chain, RingBuffer, Segment, Span};
+
+    #[test]
+    fn segments_are_handed_out_in_ring_order() {
+        let mut ring = RingBuffer::with_segment_len(4, 8);
+        let a = ring.acquire(1).unwrap();
+        let b = ring.acquire(2).unwrap();
+        let c = ring.acquire(3).unwrap();
+        assert_eq!([a, b, c], [Segment(0), Segment(1), Segment(2)]);
+        assert_eq!(ring.owner(b), Some(2));
+        assert_eq!((ring.tail(), ring.head()), (Segment(0), Segment(3)));
+
+        ring.release(a);
+        assert_eq!(ring.tail(), Segment(1));
+        let d = ring.acquire(4).unwrap();
+        // the head wraps around past the end and skips the segments still owned
+        let e = ring.acquire(5).unwrap();
+        assert_eq!([d, e], [Segment(3), Segment(0)]);
+        assert_eq!(ring.acquire(6), None);
+
+        ring.release_owner(2);
+        ring.release_owner(3);
+        assert_eq!(ring.tail(), Segment(3));
+        assert_eq!(ring.in_use(), 2);
+    }
+
+    #[test]
+    fn span_grows_in_place_and_moves_when_blocked() {
+        let mut ring = RingBuffer::with_segment_len(8, 8);
+        let mut span = Span::default();
+        for chunk in [&b"0123456"[..], b"789abcdefg"] {
+            ring.reserve(&mut span, 1, chunk.len()).unwrap();
+            ring.spare_mut(&mut span, 1).unwrap()[..chunk.len()].copy_from_slice(chunk);
+            ring.commit(&mut span, chunk.len());
+        }
+        assert_eq!(ring.bytes(&span), b"0123456789abcdefg");
+        assert_eq!(span.segments().count(), 3);
+
+        // consuming past a segment boundary gives the leading segment back
+        ring.consume(&mut span, 9);
+        assert_eq!(ring.bytes(&span), b"9abcdefg");
+        assert_eq!(ring.in_use(), 2);
+
+        // the segment after the span is taken, so growing moves the bytes to a larger run
+        let blocker = ring.acquire(2).unwrap();
+        assert_eq!(blocker, Segment(3));
+        ring.reserve(&mut span, 1, 12).unwrap();
+        assert_eq!(ring.bytes(&span), b"9abcdefg");
+        assert_eq!(
+            span.segments().collect::<Vec<_>>(),
+            [Segment(4), Segment(5), Segment(6)]
+        );
+
+        ring.consume(&mut span, 8);
+        assert_eq!(span, Span::default());
+        assert_eq!(ring.in_use(), 1);
+    }
+
+    #[test]
+    fn chain_writer_skips_the_input_span() {
+        let mut ring = RingBuffer::with_segment_len(4, 4);
+        let mut input = Span::default();
+        ring.spare_mut(&mut input, 1).unwrap()[..3].copy_from_slice(b"abc");
+        ring.commit(&mut input, 3);
+
+        let mut output = Chain::default();
+        let (received, mut send) = ring.writer(&input, &mut output, 1);
+        send.write_all(received).unwrap();
+        send.write_all(b"defghi").unwrap();
+        assert_eq!(output.len(), 9);
+        assert_eq!(
+            output.segments().collect::<Vec<_>>(),
+            [Segment(1), Segment(2), Segment(3)]
+        );
+
+        let mut written = Vec::new();
+        while !output.is_empty() {
+            let front = ring.front(&output).to_vec();
+            written.extend_from_slice(&front[..1]);
+            ring.consume_chain(&mut output, 1);
+        }
+        assert_eq!(written, b"abcdefghi");
+        assert_eq!(ring.in_use(), 1);
+
+        let mut output = Chain::default();
+        let err = ring
+            .chain_writer(&mut output, 1)
+            .write_all(&[0; 16])
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::OutOfMemory);
+        ring.release_chain(&mut output);
+        ring.release_span(&mut input);
+        assert_eq!(ring.in_use(), 0);
+    }
+}
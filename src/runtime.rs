@@ -0,0 +1,311 @@
+use std::{
+    fmt::Debug,
+    io::ErrorKind::*,
+    net::SocketAddr,
+    sync::{mpsc, Arc},
+    thread::{self, JoinHandle},
+};
+
+use mio::{
+    net::{TcpListener, TcpStream},
+    Events,
+};
+
+use crate::{
+    ring::RingBuffer,
+    worker::{self, Worker},
+    EventLoop, Handler, ListenerRegistry, Server, Shutdown,
+};
+
+/// One [`Server`] event loop per logical core, each pinned to its core with its own poller.
+///
+/// Every worker accepts on its own listener bound with `SO_REUSEPORT`, so the kernel spreads new
+/// connections across workers and no connection ever moves between threads. Where `SO_REUSEPORT`
+/// is not available the workers share one listener instead.
+pub struct Runtime {
+    local_addr: SocketAddr,
+    shutdown: Shutdown,
+    workers: Vec<JoinHandle<std::io::Result<()>>>,
+}
+
+impl Runtime {
+    /// Bind a listener for every worker and start the workers.
+    ///
+    /// `workers` defaults to one per core the process may run on. Every worker thread installs a
+    /// [`Worker`] with a ring of `ring_segments` page sized segments, and `new_handler` is called
+    /// on it with the worker index. Returns once every worker is pinned and about to poll,
+    /// so connections made afterwards are served; if any worker fails to pin or to set up its
+    /// event loop, the ones already started are shut down and the error is returned.
+    pub fn start<const SERVER: usize, S, H, F>(
+        addr: SocketAddr,
+        workers: Option<usize>,
+        event_buffer_capacity: usize,
+        ring_segments: usize,
+        new_handler: F,
+    ) -> std::io::Result<Self>
+    where
+        S: Server<TcpStream>
+            + ListenerRegistry<TcpStream, Listener = TcpListener>
+            + EventLoop<Events = Events>,
+        <S as EventLoop>::Event: Debug,
+        H: Handler<TcpStream>,
+        F: Fn(usize) -> H + Send + Sync + 'static,
+    {
+        let cores = available_cores()?;
+        let workers = workers.unwrap_or(cores.len());
+        if 0 == workers {
+            return Err(InvalidInput.into());
+        }
+        // everything that can fail cheaply happens before the first thread is spawned
+        let listeners = bind_listeners(addr, workers)?;
+        let local_addr = listeners[0].local_addr()?;
+        let shutdown = Shutdown::new();
+        let new_handler = Arc::new(new_handler);
+        let (ready, started) = mpsc::channel();
+        let mut runtime = Self {
+            local_addr,
+            shutdown: shutdown.clone(),
+            workers: Vec::with_capacity(workers),
+        };
+        for (worker, listener) in listeners.into_iter().enumerate() {
+            let core = cores[worker % cores.len()];
+            let shutdown = shutdown.clone();
+            let new_handler = new_handler.clone();
+            let ready = ready.clone();
+            let handle = thread::Builder::new()
+                .name(format!("elog-worker-{worker}"))
+                .spawn(move || {
+                    if let Err(err) = pin_to_core(core) {
+                        let _ = ready.send(Err(err));
+                        return Ok(());
+                    }
+                    let ring = RingBuffer::new(ring_segments);
+                    let installed = worker::install(
+                        Worker::new(worker, ring, event_buffer_capacity).ready(ready),
+                    );
+                    let mut handler = new_handler(worker);
+                    let served = S::serve_local::<SERVER, H>(listener, &mut handler, &shutdown);
+                    // a loop that never got to poll reports why to `start` rather than `join`
+                    match (served, installed.ready.take()) {
+                        (Err(err), Some(ready)) => {
+                            let _ = ready.send(Err(err));
+                            Ok(())
+                        }
+                        (served, _) => served,
+                    }
+                })?;
+            runtime.workers.push(handle);
+        }
+        drop(ready);
+        for _ in 0..workers {
+            match started.recv() {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => return Err(err),
+                Err(_) => return Err(BrokenPipe.into()),
+            }
+        }
+        Ok(runtime)
+    }
+
+    /// The address every worker accepts on, with the port resolved if `0` was requested.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// A handle that stops every worker when signalled, usable from any thread.
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Block until every worker has stopped; returns the first error any worker stopped with.
+    pub fn join(mut self) -> std::io::Result<()> {
+        let mut result = Ok(());
+        for worker in self.workers.drain(..) {
+            let stopped = worker
+                .join()
+                .unwrap_or_else(|_| Err(std::io::Error::other("worker panicked")));
+            if result.is_ok() {
+                result = stopped;
+            }
+        }
+        result
+    }
+
+    /// Signal every worker to stop, then wait for them.
+    pub fn shutdown(self) -> std::io::Result<()> {
+        self.shutdown.signal()?;
+        self.join()
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        if self.workers.is_empty() {
+            return;
+        }
+        let _ = self.shutdown.signal();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// The logical cores this process is allowed to run on.
+#[cfg(target_os = "linux")]
+pub fn available_cores() -> std::io::Result<Vec<usize>> {
+    // SAFETY: cpu_set_t is plain data and sched_getaffinity writes at most its size
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::cpu_set_t>();
+    if 0 != unsafe { libc::sched_getaffinity(0, size, &mut set) } {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok((0..libc::CPU_SETSIZE as usize)
+        .filter(|&core| unsafe { libc::CPU_ISSET(core, &set) })
+        .collect())
+}
+
+/// The logical cores this process is allowed to run on.
+#[cfg(not(target_os = "linux"))]
+pub fn available_cores() -> std::io::Result<Vec<usize>> {
+    Ok((0..thread::available_parallelism()?.get()).collect())
+}
+
+/// Restrict the calling thread to `core`.
+#[cfg(target_os = "linux")]
+pub fn pin_to_core(core: usize) -> std::io::Result<()> {
+    // SAFETY: cpu_set_t is plain data and CPU_SET bounds checks `core`
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    unsafe { libc::CPU_SET(core, &mut set) };
+    let size = std::mem::size_of::<libc::cpu_set_t>();
+    if 0 != unsafe { libc::sched_setaffinity(0, size, &set) } {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Restrict the calling thread to `core`; thread affinity is left to the scheduler here.
+#[cfg(not(target_os = "linux"))]
+pub fn pin_to_core(_core: usize) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// One listener per worker, all accepting on the same address.
+#[cfg(unix)]
+fn bind_listeners(addr: SocketAddr, workers: usize) -> std::io::Result<Vec<TcpListener>> {
+    let mut listeners = Vec::with_capacity(workers);
+    let mut addr = addr;
+    for _ in 0..workers {
+        let listener = bind_reuse_port(addr)?;
+        // a requested port of 0 is resolved by the first bind and shared by the rest
+        addr = listener.local_addr()?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+/// One listener shared by every worker, which all accept from it.
+#[cfg(not(unix))]
+fn bind_listeners(addr: SocketAddr, workers: usize) -> std::io::Result<Vec<TcpListener>> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let mut listeners = Vec::with_capacity(workers);
+    for _ in 1..workers {
+        listeners.push(TcpListener::from_std(listener.try_clone()?));
+    }
+    listeners.push(TcpListener::from_std(listener));
+    Ok(listeners)
+}
+
+#[cfg(unix)]
+fn bind_reuse_port(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use crate::{
+        tests::{new_loopback_address, Reply, TestServer},
+        worker,
+    };
+
+    use super::{available_cores, pin_to_core, Runtime};
+
+    #[test]
+    fn pins_to_every_available_core() {
+        let cores = available_cores().unwrap();
+        assert!(!cores.is_empty());
+        // pinning is for good, so it happens on a thread of its own rather than the harness's
+        std::thread::spawn(move || {
+            for core in cores {
+                pin_to_core(core).unwrap();
+            }
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn workers_share_one_address() {
+        let addr = new_loopback_address();
+        let runtime = Runtime::start::<1, TestServer, _, _>(addr, Some(4), 128, 64, |_worker| {
+            Reply::new(b"pong\n")
+        })
+        .unwrap();
+        assert_eq!(runtime.local_addr(), addr);
+        assert_eq!(runtime.workers(), 4);
+
+        let mut clients: Vec<std::net::TcpStream> = (0..64)
+            .map(|_| std::net::TcpStream::connect(addr).unwrap())
+            .collect();
+        for client in clients.iter_mut() {
+            client.write_all(b"ping\n").unwrap();
+        }
+        for client in clients.iter_mut() {
+            let mut reply = Vec::new();
+            client.read_to_end(&mut reply).unwrap();
+            assert_eq!(reply, b"pong\n");
+        }
+        runtime.shutdown().unwrap();
+    }
+
+    #[test]
+    fn bind_failure_starts_no_workers() {
+        let addr = new_loopback_address();
+        let taken = std::net::TcpListener::bind(addr).unwrap();
+        let started = Runtime::start::<1, TestServer, _, _>(addr, Some(2), 128, 64, |_worker| {
+            Reply::new(b"pong\n")
+        });
+        assert_eq!(started.err().unwrap().kind(), std::io::ErrorKind::AddrInUse);
+        drop(taken);
+    }
+
+    #[test]
+    fn loop_setup_failure_is_returned_by_start() {
+        let addr = new_loopback_address();
+        let started = Runtime::start::<1, TestServer, _, _>(addr, Some(2), 128, 64, |index| {
+            // a ring left borrowed keeps the second worker's loop from starting
+            if 1 == index {
+                std::mem::forget(worker::current().unwrap().ring_mut());
+            }
+            Reply::new(b"pong\n")
+        });
+        assert_eq!(
+            started.err().unwrap().kind(),
+            std::io::ErrorKind::ResourceBusy
+        );
+    }
+}
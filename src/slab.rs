@@ -0,0 +1,146 @@
+/// Values stored under small integer keys, where a key is handed out again once its value was
+/// removed. Tokens derived from the keys stay dense and the table never grows past the most
+/// values it held at once.
+#[derive(Debug)]
+pub struct Slab<T> {
+    entries: Vec<Option<T>>,
+    /// Keys of vacant entries, the most recently vacated last.
+    vacant: Vec<usize>,
+    len: usize,
+}
+
+impl<T> Default for Slab<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Slab<T> {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            vacant: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            entries: Vec::with_capacity(capacity),
+            vacant: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        0 == self.len
+    }
+
+    /// The key the next [`Slab::insert`] stores its value under, so a token can be registered
+    /// before there is a value to insert.
+    pub fn vacant_key(&self) -> usize {
+        self.vacant.last().copied().unwrap_or(self.entries.len())
+    }
+
+    pub fn insert(&mut self, value: T) -> usize {
+        self.len += 1;
+        match self.vacant.pop() {
+            Some(key) => {
+                self.entries[key] = Some(value);
+                key
+            }
+            None => {
+                self.entries.push(Some(value));
+                self.entries.len() - 1
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: usize) -> Option<T> {
+        let value = self.entries.get_mut(key)?.take()?;
+        self.vacant.push(key);
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn get(&self, key: usize) -> Option<&T> {
+        self.entries.get(key)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, key: usize) -> Option<&mut T> {
+        self.entries.get_mut(key)?.as_mut()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(key, entry)| Some((key, entry.as_ref()?)))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (usize, &mut T)> {
+        self.entries
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(key, entry)| Some((key, entry.as_mut()?)))
+    }
+
+    /// Remove every value with its key, leaving the slab empty with its keys handed out afresh.
+    pub fn drain(&mut self) -> impl Iterator<Item = (usize, T)> + '_ {
+        self.vacant.clear();
+        self.len = 0;
+        self.entries
+            .drain(..)
+            .enumerate()
+            .filter_map(|(key, entry)| Some((key, entry?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Slab;
+
+    #[test]
+    fn keys_are_reused_after_removal() {
+        let mut slab = Slab::new();
+        let keys: Vec<usize> = ["a", "b", "c"]
+            .into_iter()
+            .map(|v| slab.insert(v))
+            .collect();
+        assert_eq!(keys, [0, 1, 2]);
+        assert_eq!(slab.remove(1), Some("b"));
+        assert_eq!(slab.remove(1), None);
+        assert_eq!(slab.remove(7), None);
+        assert_eq!(slab.remove(0), Some("a"));
+        assert_eq!(slab.vacant_key(), 0);
+        assert_eq!(slab.insert("d"), 0);
+        assert_eq!(slab.insert("e"), 1);
+        assert_eq!(slab.insert("f"), 3);
+        assert_eq!(
+            (slab.len(), slab.get(1), slab.get(5)),
+            (4, Some(&"e"), None)
+        );
+    }
+
+    #[test]
+    fn iterates_and_drains_occupied_entries() {
+        let mut slab = Slab::with_capacity(4);
+        for value in 10..14 {
+            slab.insert(value);
+        }
+        slab.remove(2);
+        for (_, value) in slab.iter_mut() {
+            *value += 1;
+        }
+        let values: Vec<(usize, &i32)> = slab.iter().collect();
+        assert_eq!(values, [(0, &11), (1, &12), (3, &14)]);
+        let drained: Vec<(usize, i32)> = slab.drain().collect();
+        assert_eq!(drained, [(0, 11), (1, 12), (3, 14)]);
+        assert!(slab.is_empty());
+        assert_eq!(slab.insert(1), 0);
+    }
+}
@@ -0,0 +1,564 @@
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    http::{Body, HttpHandler, Request, Response},
+    pipeline::Pipeline,
+    ring::RingBuffer,
+    Flow, Handler,
+};
+
+/// Counts of values in power of two buckets: bucket `i` holds the values in `(2^(i-1), 2^i]`,
+/// bucket 0 those up to 1. Values past the last bucket only show in the count and sum.
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: Box<[AtomicU64]>,
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(buckets: usize) -> Self {
+        Self {
+            buckets: (0..buckets).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, value: u64) {
+        let bucket = match value {
+            0 | 1 => 0,
+            value => (u64::BITS - (value - 1).leading_zeros()) as usize,
+        };
+        if let Some(bucket) = self.buckets.get(bucket) {
+            bucket.fetch_add(1, Relaxed);
+        }
+        self.count.fetch_add(1, Relaxed);
+        self.sum.fetch_add(value, Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Relaxed)
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum.load(Relaxed)
+    }
+
+    /// Every bucket's upper bound with the count of values up to it.
+    pub fn cumulative(&self) -> Vec<(u64, u64)> {
+        let mut total = 0;
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(i, bucket)| {
+                total += bucket.load(Relaxed);
+                (1 << i, total)
+            })
+            .collect()
+    }
+}
+
+/// What one event loop did so far, at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub accepted: u64,
+    pub closed: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Polls that returned, with or without events.
+    pub wakeups: u64,
+    pub events: u64,
+    pub ring_in_use: usize,
+    pub ring_segments: usize,
+}
+
+/// Counters of one event loop, written by its worker and read from any thread.
+#[derive(Debug)]
+pub struct Telemetry {
+    accepted: AtomicU64,
+    closed: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    wakeups: AtomicU64,
+    events: AtomicU64,
+    ring_in_use: AtomicUsize,
+    ring_segments: AtomicUsize,
+    events_per_poll: Histogram,
+    /// Nanoseconds every pipeline stage took per segment, by stage name.
+    stages: Mutex<Vec<(&'static str, Arc<Histogram>)>>,
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Telemetry {
+    /// Buckets of the events per poll histogram, up to 65536 events.
+    pub const EVENT_BUCKETS: usize = 17;
+    /// Buckets of the stage latency histograms, up to about half a second.
+    pub const LATENCY_BUCKETS: usize = 30;
+
+    pub fn new() -> Self {
+        Self {
+            accepted: AtomicU64::new(0),
+            closed: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            wakeups: AtomicU64::new(0),
+            events: AtomicU64::new(0),
+            ring_in_use: AtomicUsize::new(0),
+            ring_segments: AtomicUsize::new(0),
+            events_per_poll: Histogram::new(Self::EVENT_BUCKETS),
+            stages: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn record_accept(&self) {
+        self.accepted.fetch_add(1, Relaxed);
+    }
+
+    pub fn record_close(&self) {
+        self.closed.fetch_add(1, Relaxed);
+    }
+
+    pub fn record_transfer(&self, bytes_in: usize, bytes_out: usize) {
+        self.bytes_in.fetch_add(bytes_in as u64, Relaxed);
+        self.bytes_out.fetch_add(bytes_out as u64, Relaxed);
+    }
+
+    /// A poll returned with `events` events.
+    pub fn record_poll(&self, events: usize) {
+        self.wakeups.fetch_add(1, Relaxed);
+        self.events.fetch_add(events as u64, Relaxed);
+        self.events_per_poll.record(events as u64);
+    }
+
+    pub fn record_ring(&self, ring: &RingBuffer) {
+        self.ring_in_use.store(ring.in_use(), Relaxed);
+        self.ring_segments.store(ring.segments(), Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            accepted: self.accepted.load(Relaxed),
+            closed: self.closed.load(Relaxed),
+            bytes_in: self.bytes_in.load(Relaxed),
+            bytes_out: self.bytes_out.load(Relaxed),
+            wakeups: self.wakeups.load(Relaxed),
+            events: self.events.load(Relaxed),
+            ring_in_use: self.ring_in_use.load(Relaxed),
+            ring_segments: self.ring_segments.load(Relaxed),
+        }
+    }
+
+    pub fn events_per_poll(&self) -> &Histogram {
+        &self.events_per_poll
+    }
+
+    /// The latency histogram of the stages called `name`, created on first use; stages of the
+    /// same name share it.
+    pub fn stage(&self, name: &'static str) -> Arc<Histogram> {
+        let mut stages = self
+            .stages
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some((_, histogram)) = stages.iter().find(|(stage, _)| name == *stage) {
+            return histogram.clone();
+        }
+        let histogram = Arc::new(Histogram::new(Self::LATENCY_BUCKETS));
+        stages.push((name, histogram.clone()));
+        histogram
+    }
+
+    pub fn stages(&self) -> Vec<(&'static str, Arc<Histogram>)> {
+        self.stages
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+}
+
+/// Name, type, help text and value of one series of the [`Snapshot`] numbers.
+type Series = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&Snapshot) -> u64,
+);
+
+/// The telemetry of every worker, cloned into each of them.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    workers: Arc<Mutex<Vec<Arc<Telemetry>>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The telemetry of `worker`, created on first use.
+    pub fn worker(&self, worker: usize) -> Arc<Telemetry> {
+        let mut workers = self
+            .workers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        while workers.len() <= worker {
+            workers.push(Arc::new(Telemetry::new()));
+        }
+        workers[worker].clone()
+    }
+
+    pub fn workers(&self) -> Vec<Arc<Telemetry>> {
+        self.workers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Write every worker's numbers in the Prometheus text format, labelled with the worker.
+    pub fn render<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        let workers = self.workers();
+        let snapshots: Vec<Snapshot> = workers.iter().map(|worker| worker.snapshot()).collect();
+        let counters: [Series; 8] = [
+            (
+                "connections_accepted_total",
+                "counter",
+                "Connections accepted.",
+                |s| s.accepted,
+            ),
+            (
+                "connections_closed_total",
+                "counter",
+                "Connections closed.",
+                |s| s.closed,
+            ),
+            (
+                "received_bytes_total",
+                "counter",
+                "Bytes read from connections.",
+                |s| s.bytes_in,
+            ),
+            (
+                "sent_bytes_total",
+                "counter",
+                "Bytes written to connections.",
+                |s| s.bytes_out,
+            ),
+            (
+                "poll_wakeups_total",
+                "counter",
+                "Polls that returned.",
+                |s| s.wakeups,
+            ),
+            (
+                "events_total",
+                "counter",
+                "Readiness events handled.",
+                |s| s.events,
+            ),
+            (
+                "ring_segments_in_use",
+                "gauge",
+                "Ring segments owned.",
+                |s| s.ring_in_use as u64,
+            ),
+            ("ring_segments", "gauge", "Ring segments in all.", |s| {
+                s.ring_segments as u64
+            }),
+        ];
+        for (name, kind, help, value) in counters {
+            writeln!(out, "# HELP elog_{name} {help}")?;
+            writeln!(out, "# TYPE elog_{name} {kind}")?;
+            for (worker, snapshot) in snapshots.iter().enumerate() {
+                writeln!(
+                    out,
+                    "elog_{name}{{worker=\"{worker}\"}} {}",
+                    value(snapshot)
+                )?;
+            }
+        }
+        writeln!(
+            out,
+            "# HELP elog_events_per_poll Readiness events per poll."
+        )?;
+        writeln!(out, "# TYPE elog_events_per_poll histogram")?;
+        for (worker, telemetry) in workers.iter().enumerate() {
+            let labels = format!("worker=\"{worker}\"");
+            render_histogram(
+                out,
+                "elog_events_per_poll",
+                &labels,
+                telemetry.events_per_poll(),
+                1.0,
+            )?;
+        }
+        writeln!(
+            out,
+            "# HELP elog_stage_seconds Time a pipeline stage took per segment."
+        )?;
+        writeln!(out, "# TYPE elog_stage_seconds histogram")?;
+        for (worker, telemetry) in workers.iter().enumerate() {
+            for (stage, histogram) in telemetry.stages() {
+                let labels = format!("worker=\"{worker}\",stage=\"{}\"", escape(stage));
+                render_histogram(out, "elog_stage_seconds", &labels, &histogram, 1e-9)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write `histogram` as Prometheus histogram lines, its values multiplied by `unit`.
+fn render_histogram<W: Write>(
+    out: &mut W,
+    name: &str,
+    labels: &str,
+    histogram: &Histogram,
+    unit: f64,
+) -> std::io::Result<()> {
+    for (bound, count) in histogram.cumulative() {
+        writeln!(
+            out,
+            "{name}_bucket{{{labels},le=\"{}\"}} {count}",
+            bound as f64 * unit
+        )?;
+    }
+    let count = histogram.count();
+    writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {count}")?;
+    writeln!(
+        out,
+        "{name}_sum{{{labels}}} {}",
+        histogram.sum() as f64 * unit
+    )?;
+    writeln!(out, "{name}_count{{{labels}}} {count}")
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Runs `next` and has the event loop record its work in `telemetry`.
+pub struct Measured<H> {
+    telemetry: Arc<Telemetry>,
+    pub next: H,
+}
+
+impl<H> Measured<H> {
+    pub fn new(telemetry: Arc<Telemetry>, next: H) -> Self {
+        Self { telemetry, next }
+    }
+}
+
+impl<C, H: Handler<C>> Handler<C> for Measured<H> {
+    fn received<W: Write>(
+        &mut self,
+        token: usize,
+        receive: &[u8],
+        send: &mut W,
+    ) -> std::io::Result<(usize, Flow)> {
+        self.next.received(token, receive, send)
+    }
+
+    fn writable<W: Write>(&mut self, token: usize, send: &mut W) -> std::io::Result<Flow> {
+        self.next.writable(token, send)
+    }
+
+    fn closed(&mut self, token: usize) {
+        self.next.closed(token)
+    }
+
+    fn outbound(&mut self) -> Option<&mut Pipeline> {
+        self.next.outbound()
+    }
+
+    fn telemetry(&self) -> Option<&Telemetry> {
+        Some(&self.telemetry)
+    }
+}
+
+/// Answers `GET` requests for `path` with `metrics` in the Prometheus text format and passes
+/// every other request to `next`.
+pub struct MetricsEndpoint<H> {
+    path: String,
+    metrics: Metrics,
+    pub next: H,
+}
+
+impl<H> MetricsEndpoint<H> {
+    pub fn new(path: &str, metrics: Metrics, next: H) -> Self {
+        Self {
+            path: path.to_string(),
+            metrics,
+            next,
+        }
+    }
+}
+
+impl<H: HttpHandler> HttpHandler for MetricsEndpoint<H> {
+    fn request<W: Write>(
+        &mut self,
+        token: usize,
+        request: &Request<'_>,
+        body: Body<'_>,
+        send: &mut W,
+    ) -> std::io::Result<Flow> {
+        if "GET" != request.method || self.path != request.path {
+            return self.next.request(token, request, body, send);
+        }
+        let mut rendered = Vec::new();
+        self.metrics.render(&mut rendered)?;
+        let mut response = Response::to(request, send, 200)?;
+        response.header("content-type", "text/plain; version=0.0.4")?;
+        response.body(&rendered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        sync::Arc,
+        thread,
+    };
+
+    use mio::net::TcpStream;
+
+    use super::{Histogram, Measured, Metrics, MetricsEndpoint, Telemetry};
+    use crate::{
+        http::{Body, Http, HttpHandler, Request, Response},
+        pipeline::{tests::Double, Pipeline},
+        ring::RingBuffer,
+        tests::{new_loopback_address, TestServer},
+        Flow, Listener, Server, Shutdown,
+    };
+
+    struct Hello;
+
+    impl HttpHandler for Hello {
+        fn request<W: Write>(
+            &mut self,
+            _token: usize,
+            request: &Request<'_>,
+            _body: Body<'_>,
+            send: &mut W,
+        ) -> std::io::Result<Flow> {
+            Response::to(request, send, 200)?.body(b"hello")
+        }
+    }
+
+    fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        write!(
+            client,
+            "GET {path} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn histograms_count_into_power_of_two_buckets() {
+        let histogram = Histogram::new(4);
+        for value in [0, 1, 2, 3, 4, 5, 8, 9, 1000] {
+            histogram.record(value);
+        }
+        assert_eq!(histogram.cumulative(), [(1, 2), (2, 3), (4, 5), (8, 7)]);
+        assert_eq!((histogram.count(), histogram.sum()), (9, 1032));
+
+        let metrics = Metrics::new();
+        metrics.worker(1).record_poll(3);
+        let mut rendered = Vec::new();
+        metrics.render(&mut rendered).unwrap();
+        let rendered = String::from_utf8(rendered).unwrap();
+        assert!(rendered.contains("# TYPE elog_poll_wakeups_total counter\n"));
+        assert!(rendered.contains("elog_poll_wakeups_total{worker=\"0\"} 0\n"));
+        assert!(rendered.contains("elog_poll_wakeups_total{worker=\"1\"} 1\n"));
+        assert!(rendered.contains("elog_events_per_poll_bucket{worker=\"1\",le=\"2\"} 0\n"));
+        assert!(rendered.contains("elog_events_per_poll_bucket{worker=\"1\",le=\"4\"} 1\n"));
+        assert!(rendered.contains("elog_events_per_poll_bucket{worker=\"1\",le=\"+Inf\"} 1\n"));
+        assert!(rendered.contains("elog_events_per_poll_sum{worker=\"1\"} 3\n"));
+    }
+
+    #[test]
+    fn pipelines_record_stage_latency() {
+        let telemetry = Arc::new(Telemetry::new());
+        let mut pipeline = Pipeline::new()
+            .stage(Double)
+            .telemetry(telemetry.clone())
+            .stage(Double);
+        let mut buffer = [7; 16];
+        assert_eq!(pipeline.run(&mut buffer, 4).unwrap(), 16);
+
+        let stages = telemetry.stages();
+        assert_eq!(stages.len(), 1);
+        assert_eq!(stages[0].0, "Double");
+        assert_eq!(stages[0].1.count(), 2);
+
+        let metrics = Metrics::new();
+        metrics.worker(0).stage("Double").record(1 << 10);
+        let mut rendered = Vec::new();
+        metrics.render(&mut rendered).unwrap();
+        let rendered = String::from_utf8(rendered).unwrap();
+        let bucket =
+            "elog_stage_seconds_bucket{worker=\"0\",stage=\"Double\",le=\"0.000001024\"} 1";
+        assert!(rendered.contains(bucket));
+    }
+
+    #[test]
+    fn server_records_its_work_and_serves_metrics() {
+        let mut ring = RingBuffer::new(16);
+        let metrics = Metrics::new();
+        let endpoint = MetricsEndpoint::new("/metrics", metrics.clone(), Hello);
+        let mut handler = Measured::new(metrics.worker(0), Http::new(endpoint));
+        let addr = new_loopback_address();
+        let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                <TestServer as Server<TcpStream>>::serve::<1, _>(
+                    listener,
+                    128,
+                    &mut ring,
+                    &mut handler,
+                    &shutdown,
+                )
+            });
+            let hello = get(addr, "/");
+            let scraped = get(addr, "/metrics");
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+
+            assert!(hello.ends_with("\r\n\r\nhello"));
+            assert!(scraped.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(scraped.contains("content-type: text/plain; version=0.0.4\r\n"));
+            assert!(scraped.contains("elog_connections_accepted_total{worker=\"0\"} 2\n"));
+            assert!(scraped.contains("elog_connections_closed_total{worker=\"0\"} 1\n"));
+            assert!(scraped.contains("elog_ring_segments{worker=\"0\"} 16\n"));
+        });
+
+        let snapshot = metrics.worker(0).snapshot();
+        assert_eq!((snapshot.accepted, snapshot.closed), (2, 2));
+        assert!(snapshot.bytes_in > 80 && snapshot.bytes_out > 1000);
+        assert!(snapshot.wakeups >= 2 && snapshot.events >= snapshot.wakeups - 1);
+        assert_eq!((snapshot.ring_in_use, snapshot.ring_segments), (0, 16));
+        assert_eq!(
+            metrics.worker(0).events_per_poll().count(),
+            snapshot.wakeups
+        );
+    }
+}
@@ -0,0 +1,585 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug},
+    io::{ErrorKind::*, IoSlice, Read, Write},
+    marker::PhantomData,
+    path::Path,
+    sync::Arc,
+};
+
+#[cfg(unix)]
+use mio::net::{UnixListener, UnixStream};
+use mio::{
+    event::Source,
+    net::{TcpListener, TcpStream},
+    Interest, Registry as MioRegistry, Token,
+};
+use rustls::{
+    crypto::ring::{default_provider, sign::any_supported_type},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection,
+};
+
+use crate::{proxy::HalfClose, Connect, Connector, Listener, ListenerRegistry, Registry};
+
+fn tls_error(err: rustls::Error) -> std::io::Error {
+    std::io::Error::new(InvalidData, err)
+}
+
+fn pem_error(err: rustls::pki_types::pem::Error) -> std::io::Error {
+    match err {
+        rustls::pki_types::pem::Error::Io(err) => err,
+        err => std::io::Error::new(InvalidData, err.to_string()),
+    }
+}
+
+/// Every certificate in the PEM file at `path`, leaf first.
+pub fn load_certs(path: &Path) -> std::io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(pem_error)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(pem_error)?;
+    if certs.is_empty() {
+        return Err(std::io::Error::new(InvalidData, "no certificates in file"));
+    }
+    Ok(certs)
+}
+
+/// The first PKCS#1, PKCS#8 or SEC1 private key in the PEM file at `path`.
+pub fn load_private_key(path: &Path) -> std::io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(pem_error)
+}
+
+/// A certificate chain and its key, ready to be served for one or more names.
+pub fn load_certified_key(cert: &Path, key: &Path) -> std::io::Result<Arc<CertifiedKey>> {
+    let certs = load_certs(cert)?;
+    let key = any_supported_type(&load_private_key(key)?).map_err(tls_error)?;
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+/// Picks the certificate for a handshake by the name the client asked for with SNI. Names are
+/// matched exactly first, then against a `*.` wildcard for their parent domain; clients that
+/// send no name or an unknown one get the default, if there is one.
+#[derive(Debug, Default)]
+pub struct SniResolver {
+    keys: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl SniResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve `key` for `name`, which may be a wildcard like `*.example.com`.
+    pub fn add(&mut self, name: &str, key: Arc<CertifiedKey>) {
+        self.keys.insert(name.to_ascii_lowercase(), key);
+    }
+
+    pub fn set_default(&mut self, key: Arc<CertifiedKey>) {
+        self.default = Some(key);
+    }
+
+    fn lookup(&self, name: &str) -> Option<Arc<CertifiedKey>> {
+        let name = name.to_ascii_lowercase();
+        if let Some(key) = self.keys.get(&name) {
+            return Some(key.clone());
+        }
+        let (_, parent) = name.split_once('.')?;
+        self.keys.get(&format!("*.{parent}")).cloned()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        client_hello
+            .server_name()
+            .and_then(|name| self.lookup(name))
+            .or_else(|| self.default.clone())
+    }
+}
+
+/// Configuration for terminating TLS with the certificates of `resolver`; set `alpn_protocols`
+/// on it before use to negotiate `h2` and friends.
+pub fn server_config(resolver: SniResolver) -> std::io::Result<ServerConfig> {
+    Ok(
+        ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver)),
+    )
+}
+
+/// Configuration for originating TLS to servers whose certificates chain up to `roots`.
+pub fn client_config(roots: &[CertificateDer<'static>]) -> std::io::Result<ClientConfig> {
+    let mut store = RootCertStore::empty();
+    for root in roots {
+        store.add(root.clone()).map_err(tls_error)?;
+    }
+    Ok(
+        ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_root_certificates(store)
+            .with_no_client_auth(),
+    )
+}
+
+/// `connection` seen through the [`Connector`] `S`, which is where TLS records are read from and
+/// written to.
+struct Transport<'a, C, S> {
+    connection: &'a mut C,
+    connector: PhantomData<fn() -> S>,
+}
+
+impl<C: Read, S: Connector<C>> Read for Transport<'_, C, S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        S::read_from_connection(self.connection, buf)
+    }
+}
+
+impl<C: Write, S: Connector<C>> Write for Transport<'_, C, S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        S::write_on_connection(self.connection, buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        S::write_vectored_on_connection(self.connection, bufs)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A connection `C` whose bytes are TLS records, read and written through the [`Connector`] `S`.
+///
+/// Reads decrypt straight into the caller's buffer, usually a segment of the ring, and writes
+/// encrypt from it. The handshake happens along the way: it advances whenever the stream is
+/// read, so a server or client simply reads and writes as it would without TLS. Plaintext
+/// written before the handshake finished is sent once it has.
+pub struct TlsStream<C, S> {
+    stream: C,
+    tls: Connection,
+    connector: PhantomData<fn() -> S>,
+}
+
+impl<C, S> TlsStream<C, S>
+where
+    C: Read + Write,
+    S: Connector<C>,
+{
+    pub fn new(stream: C, tls: impl Into<Connection>) -> Self {
+        Self {
+            stream,
+            tls: tls.into(),
+            connector: PhantomData,
+        }
+    }
+
+    pub fn get_ref(&self) -> &C {
+        &self.stream
+    }
+
+    /// The TLS session, to ask for the negotiated protocol, SNI name or peer certificates.
+    pub fn tls(&self) -> &Connection {
+        &self.tls
+    }
+
+    pub fn into_inner(self) -> C {
+        self.stream
+    }
+
+    fn transport(&mut self) -> (&mut Connection, Transport<'_, C, S>) {
+        let transport = Transport {
+            connection: &mut self.stream,
+            connector: PhantomData,
+        };
+        (&mut self.tls, transport)
+    }
+
+    /// Write pending records until there are none left or the connection would block.
+    fn write_tls(&mut self) -> std::io::Result<()> {
+        let (tls, mut transport) = self.transport();
+        while tls.wants_write() {
+            if 0 == tls.write_tls(&mut transport)? {
+                return Err(WriteZero.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Read and process the records that arrived, then send what they call for.
+    fn read_tls(&mut self) -> std::io::Result<usize> {
+        let (tls, mut transport) = self.transport();
+        let n = tls.read_tls(&mut transport)?;
+        let processed = tls.process_new_packets();
+        // answers to the handshake, or the alert explaining why it failed
+        self.try_write_tls()?;
+        processed.map_err(tls_error)?;
+        Ok(n)
+    }
+
+    /// Advance the handshake as far as the records that already arrived allow.
+    fn handshake(&mut self) -> std::io::Result<()> {
+        while self.tls.is_handshaking() {
+            match self.read_tls() {
+                Ok(0) => return Err(UnexpectedEof.into()),
+                Ok(_) => {}
+                Err(ref err) if WouldBlock == err.kind() => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Like [`Self::write_tls`], but records that do not fit now wait for the next write.
+    fn try_write_tls(&mut self) -> std::io::Result<()> {
+        match self.write_tls() {
+            Err(ref err) if WouldBlock == err.kind() => Ok(()),
+            result => result,
+        }
+    }
+}
+
+impl<C, S> Read for TlsStream<C, S>
+where
+    C: Read + Write,
+    S: Connector<C>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            match self.tls.reader().read(buf) {
+                Ok(n) => return Ok(n),
+                Err(ref err) if WouldBlock == err.kind() => {}
+                Err(err) => return Err(err),
+            }
+            if 0 == self.read_tls()? {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+impl<C, S> Write for TlsStream<C, S>
+where
+    C: Read + Write,
+    S: Connector<C>,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.write_vectored(&[IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        // plaintext written during the handshake is buffered until the buffer is full; then the
+        // handshake has to make progress before there is room for more
+        self.handshake()?;
+        // records still waiting for the connection hold back more plaintext
+        self.write_tls()?;
+        let n = self.tls.writer().write_vectored(bufs)?;
+        if 0 == n && bufs.iter().any(|buf| !buf.is_empty()) {
+            return Err(WouldBlock.into());
+        }
+        self.try_write_tls()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_tls()
+    }
+}
+
+impl<C, S> HalfClose for TlsStream<C, S>
+where
+    C: Read + Write + HalfClose,
+    S: Connector<C>,
+{
+    /// Send close_notify, then shut down the connection's sending half once it went out; fails
+    /// with `WouldBlock` until then and may simply be called again.
+    fn shutdown_write(&mut self) -> std::io::Result<()> {
+        self.tls.send_close_notify();
+        self.write_tls()?;
+        self.stream.shutdown_write()
+    }
+}
+
+impl<C: Debug, S> Debug for TlsStream<C, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsStream")
+            .field("stream", &self.stream)
+            .field("handshaking", &self.tls.is_handshaking())
+            .finish()
+    }
+}
+
+impl<C: Source, S> Source for TlsStream<C, S> {
+    fn register(
+        &mut self,
+        registry: &MioRegistry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        self.stream.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &MioRegistry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        self.stream.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &MioRegistry) -> std::io::Result<()> {
+        self.stream.deregister(registry)
+    }
+}
+
+/// A listener whose accepted connections are wrapped in [`TlsStream`]s configured by `config`.
+pub struct TlsListener<L> {
+    listener: L,
+    config: Arc<ServerConfig>,
+}
+
+impl<L: Source> Source for TlsListener<L> {
+    fn register(
+        &mut self,
+        registry: &MioRegistry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        self.listener.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &MioRegistry,
+        token: Token,
+        interests: Interest,
+    ) -> std::io::Result<()> {
+        self.listener.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &MioRegistry) -> std::io::Result<()> {
+        self.listener.deregister(registry)
+    }
+}
+
+impl<T, S> ListenerRegistry<TlsStream<TcpStream, S>> for T
+where
+    T: ListenerRegistry<TcpStream, Listener = TcpListener>,
+    T: Registry<TlsStream<TcpStream, S>> + Registry<TlsListener<TcpListener>>,
+{
+    type Listener = TlsListener<TcpListener>;
+}
+
+#[cfg(unix)]
+impl<T, S> ListenerRegistry<TlsStream<UnixStream, S>> for T
+where
+    T: ListenerRegistry<UnixStream, Listener = UnixListener>,
+    T: Registry<TlsStream<UnixStream, S>> + Registry<TlsListener<UnixListener>>,
+{
+    type Listener = TlsListener<UnixListener>;
+}
+
+/// Terminates TLS on any listener: binding takes the address of the underlying listener and the
+/// configuration every accepted connection is served with.
+impl<T, C, S> Listener<TlsStream<C, S>> for T
+where
+    T: Listener<C>,
+    C: Read + Write,
+    S: Connector<C>,
+{
+    type Listener = TlsListener<<T as Listener<C>>::Listener>;
+    type Addr = (<T as Listener<C>>::Addr, Arc<ServerConfig>);
+    type PeerAddr = <T as Listener<C>>::PeerAddr;
+
+    fn bind((addr, config): Self::Addr) -> std::io::Result<Self::Listener> {
+        let listener = T::bind(addr)?;
+        Ok(TlsListener { listener, config })
+    }
+
+    fn accept(listener: &Self::Listener) -> std::io::Result<(TlsStream<C, S>, Self::PeerAddr)> {
+        let (stream, addr) = T::accept(&listener.listener)?;
+        let tls = ServerConnection::new(listener.config.clone()).map_err(tls_error)?;
+        Ok((TlsStream::new(stream, tls), addr))
+    }
+
+    fn unbind(listener: Self::Listener) -> std::io::Result<()> {
+        T::unbind(listener.listener)
+    }
+}
+
+/// Originates TLS on any connection: connecting takes the address of the underlying connection,
+/// the configuration and the name the server's certificate is checked against.
+impl<T, C, S> Connect<TlsStream<C, S>> for T
+where
+    T: Connect<C>,
+    C: Read + Write,
+    S: Connector<C>,
+{
+    type Addr = (
+        <T as Connect<C>>::Addr,
+        Arc<ClientConfig>,
+        ServerName<'static>,
+    );
+
+    fn connect((addr, config, name): Self::Addr) -> std::io::Result<TlsStream<C, S>> {
+        let stream = T::connect(addr)?;
+        let tls = ClientConnection::new(config, name).map_err(tls_error)?;
+        Ok(TlsStream::new(stream, tls))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicUsize, Ordering::*},
+            Arc,
+        },
+        thread,
+    };
+
+    use mio::net::TcpStream;
+    use rustls::pki_types::{CertificateDer, ServerName};
+
+    use super::{
+        client_config, load_certified_key, load_certs, server_config, SniResolver, TlsStream,
+    };
+    use crate::{
+        ring::RingBuffer,
+        tests::{new_loopback_address, Reply},
+        Client, Flow, Handler, Listener, MioEventLoop, ReadWriteConnectorAdapter, Server, Shutdown,
+    };
+
+    struct TlsServer;
+    struct TlsClient;
+
+    type ServerStream = TlsStream<TcpStream, TlsServer>;
+    type ClientStream = TlsStream<TcpStream, TlsClient>;
+
+    impl MioEventLoop for TlsServer {}
+    impl ReadWriteConnectorAdapter for TlsServer {}
+    impl Server<ServerStream> for TlsServer {}
+
+    impl MioEventLoop for TlsClient {}
+    impl ReadWriteConnectorAdapter for TlsClient {}
+    impl Client<ClientStream> for TlsClient {}
+
+    /// Echoes everything it receives.
+    struct Echo;
+
+    impl<C> Handler<C> for Echo {
+        fn received<W: Write>(
+            &mut self,
+            _token: usize,
+            receive: &[u8],
+            send: &mut W,
+        ) -> std::io::Result<(usize, Flow)> {
+            send.write_all(receive)?;
+            Ok((receive.len(), Flow::Continue))
+        }
+    }
+
+    static FILES: AtomicUsize = AtomicUsize::new(0);
+
+    /// A self-signed certificate for `name`, written to PEM files.
+    fn self_signed(name: &str) -> (PathBuf, PathBuf) {
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let n = FILES.fetch_add(1, SeqCst);
+        let path = |kind: &str| {
+            std::env::temp_dir().join(format!("elog-{}-{n}-{kind}.pem", std::process::id()))
+        };
+        let (cert_path, key_path) = (path("cert"), path("key"));
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key_pair.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    /// Certificates for `localhost` and `*.example.test`, and a client trusting both.
+    fn configs() -> (Arc<rustls::ServerConfig>, Arc<rustls::ClientConfig>) {
+        let mut resolver = SniResolver::new();
+        let mut roots: Vec<CertificateDer<'static>> = Vec::new();
+        for name in ["localhost", "*.example.test"] {
+            let (cert, key) = self_signed(name);
+            resolver.add(name, load_certified_key(&cert, &key).unwrap());
+            roots.extend(load_certs(&cert).unwrap());
+            std::fs::remove_file(cert).unwrap();
+            std::fs::remove_file(key).unwrap();
+        }
+        let server = Arc::new(server_config(resolver).unwrap());
+        let client = Arc::new(client_config(&roots).unwrap());
+        (server, client)
+    }
+
+    #[test]
+    fn selects_certificates_by_server_name() {
+        let (server_config, client_config) = configs();
+        let mut ring = RingBuffer::new(16);
+        let mut client_ring = RingBuffer::new(16);
+        let mut handler = Reply::new(b"pong\n");
+        let addr = new_loopback_address();
+        let listener = <TlsServer as Listener<ServerStream>>::bind((addr, server_config)).unwrap();
+        let shutdown = Shutdown::new();
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                TlsServer::serve::<1, _>(listener, 128, &mut ring, &mut handler, &shutdown)
+            });
+            for name in ["localhost", "api.example.test"] {
+                let name = ServerName::try_from(name.to_string()).unwrap();
+                let to = (addr, client_config.clone(), name);
+                let mut received =
+                    TlsClient::client::<2>(to, 128, &mut client_ring, b"ping\n").unwrap();
+                assert_eq!(client_ring.bytes(&received), b"pong\n");
+                client_ring.release_span(&mut received);
+            }
+            // no certificate for this name and no default
+            let name = ServerName::try_from("other.test").unwrap();
+            let to = (addr, client_config.clone(), name);
+            let failed = TlsClient::client::<2>(to, 128, &mut client_ring, b"ping\n");
+            assert!(failed.is_err());
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+
+        assert_eq!(handler.requests, [b"ping\n", b"ping\n"]);
+        assert_eq!((ring.in_use(), client_ring.in_use()), (0, 0));
+    }
+
+    #[test]
+    fn streams_records_across_segments() {
+        let (server_config, client_config) = configs();
+        let mut ring = RingBuffer::new(64);
+        let mut client_ring = RingBuffer::new(64);
+        let mut handler = Echo;
+        let addr = new_loopback_address();
+        let listener = <TlsServer as Listener<ServerStream>>::bind((addr, server_config)).unwrap();
+        let shutdown = Shutdown::new();
+        let send: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                TlsServer::serve::<1, _>(listener, 128, &mut ring, &mut handler, &shutdown)
+            });
+            let name = ServerName::try_from("localhost").unwrap();
+            let mut received =
+                TlsClient::client::<2>((addr, client_config, name), 128, &mut client_ring, &send)
+                    .unwrap();
+            assert_eq!(client_ring.bytes(&received), send);
+            client_ring.release_span(&mut received);
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap();
+        });
+
+        assert_eq!((ring.in_use(), client_ring.in_use()), (0, 0));
+    }
+}
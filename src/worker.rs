@@ -0,0 +1,265 @@
+use std::{
+    any::Any,
+    cell::{Ref, RefCell, RefMut},
+    rc::Rc,
+    sync::mpsc::Sender,
+};
+
+use mio::Events;
+
+use crate::{
+    ring::{Chain, RingBuffer},
+    slab::Slab,
+    Connection,
+};
+
+thread_local! {
+    static WORKER: RefCell<Option<Rc<Worker>>> = const { RefCell::new(None) };
+}
+
+/// Make `worker` the context of the calling thread, replacing the one installed before.
+pub fn install(worker: Worker) -> Rc<Worker> {
+    let worker = Rc::new(worker);
+    WORKER.with(|current| current.replace(Some(worker.clone())));
+    worker
+}
+
+/// The context of the calling thread, if one is installed.
+pub fn current() -> Option<Rc<Worker>> {
+    WORKER.with(|current| current.borrow().clone())
+}
+
+/// Remove the context of the calling thread and hand it back.
+pub fn uninstall() -> Option<Rc<Worker>> {
+    WORKER.with(|current| current.take())
+}
+
+/// Hands out the tokens of a worker's connections from `first` on, those of closed connections
+/// first, so that tokens stay dense.
+#[derive(Debug)]
+pub struct Tokens {
+    first: usize,
+    keys: Slab<()>,
+}
+
+impl Tokens {
+    pub fn new(first: usize) -> Self {
+        Self {
+            first,
+            keys: Slab::new(),
+        }
+    }
+
+    /// The token the next [`Tokens::allocate`] hands out.
+    pub fn next(&self) -> usize {
+        self.first + self.keys.vacant_key()
+    }
+
+    pub fn allocate(&mut self) -> usize {
+        self.first + self.keys.insert(())
+    }
+
+    /// Give `token` back; false if it was not handed out.
+    pub fn release(&mut self, token: usize) -> bool {
+        token
+            .checked_sub(self.first)
+            .and_then(|key| self.keys.remove(key))
+            .is_some()
+    }
+
+    pub fn is_live(&self, token: usize) -> bool {
+        token
+            .checked_sub(self.first)
+            .is_some_and(|key| self.keys.get(key).is_some())
+    }
+
+    /// Tokens handed out and not released yet.
+    pub fn live(&self) -> usize {
+        self.keys.len()
+    }
+}
+
+/// What an event loop thread owns: its ring, events buffer, connections and tokens.
+///
+/// A worker is installed in thread local storage, where [`crate::Server::serve_local`] and
+/// [`crate::Client::client_local`] find it instead of taking buffers from the caller. Handlers
+/// reach it through [`current`] while they are called, and tests inspect it once the loop has
+/// stopped. The ring, events and connections are borrowed by a running loop; the tokens are only
+/// borrowed for the moment they change.
+pub struct Worker {
+    index: usize,
+    pub(crate) ring: RefCell<RingBuffer>,
+    pub(crate) events: RefCell<Events>,
+    event_buffer_capacity: usize,
+    pub(crate) tokens: RefCell<Tokens>,
+    /// Send chains of closed connections, handed to the next ones accepted.
+    pub(crate) recycled: RefCell<Vec<Chain>>,
+    /// The [`Slab`] of connections served last, of whatever stream type they were.
+    connections: RefCell<Option<Box<dyn Any>>>,
+    /// Told once the next event loop is set up and about to poll.
+    pub(crate) ready: RefCell<Option<Sender<std::io::Result<()>>>>,
+}
+
+impl Worker {
+    pub fn new(index: usize, ring: RingBuffer, event_buffer_capacity: usize) -> Self {
+        Self {
+            index,
+            ring: RefCell::new(ring),
+            events: RefCell::new(Events::with_capacity(event_buffer_capacity)),
+            event_buffer_capacity,
+            tokens: RefCell::new(Tokens::new(0)),
+            recycled: RefCell::new(Vec::new()),
+            connections: RefCell::new(None),
+            ready: RefCell::new(None),
+        }
+    }
+
+    /// Send `Ok(())` to `ready` once the next event loop on this worker has its poller and
+    /// listener set up; a loop that fails before that leaves `ready` here.
+    pub fn ready(self, ready: Sender<std::io::Result<()>>) -> Self {
+        self.ready.replace(Some(ready));
+        self
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn event_buffer_capacity(&self) -> usize {
+        self.event_buffer_capacity
+    }
+
+    /// Panics while an event loop on this worker runs.
+    pub fn ring(&self) -> Ref<'_, RingBuffer> {
+        self.ring.borrow()
+    }
+
+    /// Panics while an event loop on this worker runs.
+    pub fn ring_mut(&self) -> RefMut<'_, RingBuffer> {
+        self.ring.borrow_mut()
+    }
+
+    pub fn tokens(&self) -> Ref<'_, Tokens> {
+        self.tokens.borrow()
+    }
+
+    /// Open connections of stream type `C`; `None` while an event loop on this worker runs or
+    /// when the last one served another stream type.
+    pub fn connections<C: 'static>(&self) -> Option<usize> {
+        let connections = self.connections.try_borrow().ok()?;
+        let connections = connections
+            .as_ref()?
+            .downcast_ref::<Slab<Connection<C>>>()?;
+        Some(connections.len())
+    }
+
+    /// The connection table for streams of type `C`, replacing one of another type.
+    pub(crate) fn connections_mut<C: 'static>(&self) -> RefMut<'_, Slab<Connection<C>>> {
+        RefMut::map(self.connections.borrow_mut(), |connections| {
+            if !connections
+                .as_ref()
+                .is_some_and(|table| table.is::<Slab<Connection<C>>>())
+            {
+                *connections = Some(Box::new(Slab::<Connection<C>>::new()));
+            }
+            connections
+                .as_mut()
+                .and_then(|table| table.downcast_mut())
+                .expect("connection table of the stream type")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, thread};
+
+    use mio::net::TcpStream;
+
+    use super::{current, install, uninstall, Tokens, Worker};
+    use crate::{
+        ring::RingBuffer,
+        tests::{new_loopback_address, TestClient, TestServer},
+        Client, Flow, Handler, Listener, Server, Shutdown,
+    };
+
+    /// Answers `pong` to `ping`, noting what the worker looked like from inside the handler.
+    #[derive(Default)]
+    struct Observe {
+        seen: Vec<(usize, usize, Option<usize>)>,
+    }
+
+    impl Handler<TcpStream> for Observe {
+        fn received<W: Write>(
+            &mut self,
+            token: usize,
+            receive: &[u8],
+            send: &mut W,
+        ) -> std::io::Result<(usize, Flow)> {
+            if receive.len() < 4 {
+                return Ok((0, Flow::Continue));
+            }
+            let worker = current().unwrap();
+            assert!(worker.tokens().is_live(token));
+            let live = worker.tokens().live();
+            self.seen
+                .push((worker.index(), live, worker.connections::<TcpStream>()));
+            send.write_all(b"pong")?;
+            Ok((receive.len(), Flow::Close))
+        }
+    }
+
+    #[test]
+    fn tokens_are_handed_out_densely() {
+        let mut tokens = Tokens::new(2);
+        assert_eq!(
+            [tokens.allocate(), tokens.allocate(), tokens.allocate()],
+            [2, 3, 4]
+        );
+        assert!(tokens.release(3));
+        assert!(!tokens.release(3) && !tokens.release(1));
+        assert!(!tokens.is_live(3) && tokens.is_live(4));
+        assert_eq!((tokens.next(), tokens.allocate(), tokens.live()), (3, 3, 3));
+    }
+
+    #[test]
+    fn server_and_client_use_the_thread_worker() {
+        let addr = new_loopback_address();
+        let listener = <TestServer as Listener<TcpStream>>::bind(addr).unwrap();
+        let shutdown = Shutdown::new();
+
+        let err = TestClient::client_local::<1>(addr, b"ping").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        let client = install(Worker::new(1, RingBuffer::new(4), 16));
+
+        let (handler, worker) = thread::scope(|s| {
+            let server = s.spawn(|| {
+                let worker = install(Worker::new(7, RingBuffer::new(16), 128));
+                let mut handler = Observe::default();
+                let served = <TestServer as Server<TcpStream>>::serve_local::<1, _>(
+                    listener,
+                    &mut handler,
+                    &shutdown,
+                );
+                served.map(|()| {
+                    let ring = worker.ring();
+                    let tokens = worker.tokens();
+                    let connections = worker.connections::<TcpStream>();
+                    (handler.seen, (ring.in_use(), tokens.live(), connections))
+                })
+            });
+            for _ in 0..2 {
+                let mut received = TestClient::client_local::<1>(addr, b"ping").unwrap();
+                assert_eq!(client.ring().bytes(&received), b"pong");
+                client.ring_mut().release_span(&mut received);
+            }
+            shutdown.signal().unwrap();
+            server.join().unwrap().unwrap()
+        });
+
+        assert_eq!(handler, [(7, 1, None), (7, 1, None)]);
+        assert_eq!(worker, (0, 0, Some(0)));
+        assert_eq!(uninstall().unwrap().ring().in_use(), 0);
+        assert!(current().is_none());
+    }
+}